@@ -1,6 +1,6 @@
 // Create a CPIO archive from filenames passed through stdin.
 
-use cpio::{NewcBuilder, write_cpio};
+use cpio::{NewcBuilder, try_write_cpio};
 use std::fs::File;
 use std::io::{self, BufRead, stdin, stdout};
 
@@ -19,7 +19,7 @@ fn main() {
 	let inputs = stdin
 		.lock()
 		.lines()
-		.map(|path| load_file(&path.unwrap()).unwrap());
+		.map(|path| path.and_then(|path| load_file(&path)));
 		
-	write_cpio(inputs, stdout()).unwrap();
+	try_write_cpio(inputs, stdout()).unwrap();
 }
@@ -0,0 +1,367 @@
+//! Whole archives held in memory.
+//!
+//! An [`Archive`] reads every entry and its data into memory, where entries can be added,
+//! removed, renamed, and modified before the archive is written back out. This suits small
+//! patches to small archives, such as swapping one file in an initrd; streaming through
+//! [`NewcReader`](crate::NewcReader) and [`ArchiveWriter`] scales to archives of any size.
+
+use std::io::{self, Read, Write};
+
+use crate::newc::{checksum, Builder, Entry, ModeFileType, Reader};
+use crate::writer::{entry_len, ArchiveWriter, Summary};
+
+/// One entry of an [`Archive`]: its metadata and its data.
+#[derive(Clone)]
+pub struct ArchiveEntry {
+    entry: Entry,
+    data: Vec<u8>,
+}
+
+impl ArchiveEntry {
+    /// Creates an entry with the metadata in `builder` holding `data`, in the "new ascii"
+    /// format.
+    pub fn new(builder: Builder, data: Vec<u8>) -> io::Result<Self> {
+        let entry = builder.to_entry(entry_len(data.len() as u64)?, None);
+        Ok(Self { entry, data })
+    }
+
+    /// Returns the entry's metadata. Its file size always matches its data, and its checksum,
+    /// if it has one, is that of its data.
+    pub fn entry(&self) -> &Entry {
+        &self.entry
+    }
+
+    /// Returns the name of the entry.
+    pub fn name(&self) -> &str {
+        self.entry.name()
+    }
+
+    /// Returns the entry's data.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Replaces the entry's data, updating its file size and checksum to match.
+    pub fn set_data(&mut self, data: Vec<u8>) -> io::Result<()> {
+        let len = entry_len(data.len() as u64)?;
+        let sum = self.entry.checksum().map(|_| checksum(&data));
+        self.entry = Builder::from_entry(&self.entry).to_entry(len, sum);
+        self.data = data;
+        Ok(())
+    }
+
+    /// Modifies the entry's metadata by passing a [`Builder`] holding it through `update`.
+    pub fn update<F: FnOnce(Builder) -> Builder>(&mut self, update: F) {
+        let builder = update(Builder::from_entry(&self.entry));
+        self.entry = builder.to_entry(self.entry.file_size(), self.entry.checksum());
+    }
+}
+
+/// An archive held in memory, as a list of entries in archive order.
+///
+/// Names are not required to be unique, as in archives themselves. Methods that look entries
+/// up by name act on the last entry with that name, which is the one that takes effect when
+/// the archive is unpacked.
+#[derive(Clone, Default)]
+pub struct Archive {
+    entries: Vec<ArchiveEntry>,
+}
+
+impl Archive {
+    /// Creates an empty archive.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the whole archive in `reader`, up to and including its trailer.
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut entries = vec![];
+        loop {
+            let mut entry_reader = Reader::new(reader)?;
+            let entry = entry_reader.entry().clone();
+            if entry.is_trailer() {
+                entry_reader.finish()?;
+                return Ok(Self { entries });
+            }
+            let mut data = Vec::with_capacity(entry.file_size() as usize);
+            entry_reader.read_to_end(&mut data)?;
+            reader = entry_reader.finish()?;
+            entries.push(ArchiveEntry { entry, data });
+        }
+    }
+
+    /// Reads the archive held in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        Self::from_reader(bytes)
+    }
+
+    /// Returns the entries, in order.
+    pub fn entries(&self) -> &[ArchiveEntry] {
+        &self.entries
+    }
+
+    /// Returns the number of entries, not counting the trailer.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the archive has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries.iter().rposition(|entry| entry.name() == name)
+    }
+
+    /// Returns the entry called `name`.
+    pub fn get(&self, name: &str) -> Option<&ArchiveEntry> {
+        self.position(name).map(|idx| &self.entries[idx])
+    }
+
+    /// Returns the entry called `name` for modification.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut ArchiveEntry> {
+        self.position(name).map(move |idx| &mut self.entries[idx])
+    }
+
+    /// Gives every entry whose mode lacks a file type a sane one with
+    /// [`Builder::infer_mode`]: entries that other entries' names lead through become
+    /// directories, and the rest regular files.
+    pub fn infer_modes(&mut self) {
+        let mut parents = std::collections::HashSet::new();
+        for entry in &self.entries {
+            let mut name = entry.name().trim_end_matches('/');
+            while let Some(slash) = name.rfind('/') {
+                name = &name[..slash];
+                parents.insert(name.trim_start_matches("./").to_string());
+            }
+        }
+        for entry in &mut self.entries {
+            let key = entry.name().trim_end_matches('/').trim_start_matches("./");
+            let has_children = parents.contains(key);
+            entry.update(|builder| builder.infer_mode(has_children));
+        }
+    }
+
+    /// Adds an entry with the metadata in `builder` holding `data`. An existing entry with the
+    /// same name is replaced where it stands; otherwise the entry is added at the end.
+    pub fn add(&mut self, builder: Builder, data: Vec<u8>) -> io::Result<()> {
+        let entry = ArchiveEntry::new(builder, data)?;
+        match self.position(entry.name()) {
+            Some(idx) => self.entries[idx] = entry,
+            None => self.entries.push(entry),
+        }
+        Ok(())
+    }
+
+    /// Removes and returns the entry called `name`.
+    pub fn remove(&mut self, name: &str) -> Option<ArchiveEntry> {
+        self.position(name).map(|idx| self.entries.remove(idx))
+    }
+
+    /// Renames the entry called `from` to `to`, returning false if there is no such entry.
+    pub fn rename(&mut self, from: &str, to: &str) -> bool {
+        match self.get_mut(from) {
+            Some(entry) => {
+                entry.update(|builder| builder.rename(to));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Writes the archive out to `output`, followed by a trailer, and returns the output along
+    /// with statistics about what was written.
+    pub fn write_to<W: Write>(&self, output: W) -> io::Result<(W, Summary)> {
+        let mut archive = ArchiveWriter::new(output);
+        for entry in &self.entries {
+            let builder = Builder::from_entry(&entry.entry);
+            match entry.entry.checksum() {
+                Some(sum) => {
+                    archive.append_crc(builder, entry.entry.file_size(), sum, &*entry.data)?
+                }
+                None => archive.append_bytes(builder, &entry.data)?,
+            }
+        }
+        archive.finish()
+    }
+}
+
+/// A description of a whole archive, built up one entry at a time.
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// use cpio::archive::ArchiveSpec;
+///
+/// let (archive, _) = ArchiveSpec::new()
+///     .dir("./etc", 0o755)
+///     .file("./etc/hostname", b"box\n", 0o644)
+///     .symlink("./init", "/sbin/init")
+///     .write_to(vec![])?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Entries are written in the order they were added, with inode numbers counting up from 1,
+/// and followed by the trailer.
+#[derive(Clone, Default)]
+pub struct ArchiveSpec {
+    entries: Vec<(Builder, Vec<u8>)>,
+}
+
+impl ArchiveSpec {
+    /// Creates a description of an empty archive.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a directory with the permissions in `mode`.
+    pub fn dir(self, name: &str, mode: u32) -> Self {
+        let mode = u32::from(ModeFileType::Directory) | mode & 0o7777;
+        self.entry(Builder::new(name).mode(mode), vec![])
+    }
+
+    /// Add a regular file holding `data`, with the permissions in `mode`.
+    pub fn file<D: AsRef<[u8]>>(self, name: &str, data: D, mode: u32) -> Self {
+        let mode = u32::from(ModeFileType::Regular) | mode & 0o7777;
+        self.entry(Builder::new(name).mode(mode), data.as_ref().to_vec())
+    }
+
+    /// Add a symlink to `target`.
+    pub fn symlink(self, name: &str, target: &str) -> Self {
+        let mode = u32::from(ModeFileType::Symlink) | 0o777;
+        self.entry(Builder::new(name).mode(mode), target.as_bytes().to_vec())
+    }
+
+    /// Add an entry with the metadata in `builder` holding `data`. Its inode number is
+    /// replaced when the archive is written.
+    pub fn entry(mut self, builder: Builder, data: Vec<u8>) -> Self {
+        self.entries.push((builder, data));
+        self
+    }
+
+    /// Builds the described archive in memory, for further editing.
+    pub fn into_archive(self) -> io::Result<Archive> {
+        let entries = self
+            .entries
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (builder, data))| ArchiveEntry::new(builder.ino(idx as u32 + 1), data))
+            .collect::<io::Result<_>>()?;
+        Ok(Archive { entries })
+    }
+
+    /// Writes the described archive out to `output`, and returns the output along with
+    /// statistics about what was written.
+    pub fn write_to<W: Write>(&self, output: W) -> io::Result<(W, Summary)> {
+        let mut archive = ArchiveWriter::new(output);
+        for (idx, (builder, data)) in self.entries.iter().enumerate() {
+            archive.append_bytes(builder.clone().ino(idx as u32 + 1), data)?;
+        }
+        archive.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_archive() {
+        let mut original = ArchiveWriter::new(vec![]);
+        original
+            .append_bytes(Builder::new("init").mode(0o100755), b"#!/bin/sh\n")
+            .unwrap();
+        let data = b"box\n";
+        original
+            .append_crc(Builder::new("etc/hostname"), 4, checksum(data), &data[..])
+            .unwrap();
+        original.append_bytes(Builder::new("junk"), b"").unwrap();
+        let (original, _) = original.finish().unwrap();
+
+        let mut archive = Archive::from_bytes(&original).unwrap();
+        assert_eq!(archive.len(), 3);
+        let (unchanged, _) = archive.write_to(vec![]).unwrap();
+        assert_eq!(unchanged, original);
+
+        assert!(archive.remove("junk").is_some());
+        assert!(archive.remove("junk").is_none());
+        assert!(archive.rename("init", "sbin/init"));
+        assert!(!archive.rename("init", "bin/init"));
+        archive
+            .add(Builder::new("init").mode(0o120777), b"sbin/init".to_vec())
+            .unwrap();
+        let hostname = archive.get_mut("etc/hostname").unwrap();
+        hostname.set_data(b"router\n".to_vec()).unwrap();
+        hostname.update(|builder| builder.mode(0o100644));
+
+        let (output, summary) = archive.write_to(vec![]).unwrap();
+        assert_eq!(summary.entries(), 3);
+        let archive = Archive::from_reader(output.as_slice()).unwrap();
+        let names: Vec<_> = archive.entries().iter().map(ArchiveEntry::name).collect();
+        assert_eq!(names, ["sbin/init", "etc/hostname", "init"]);
+        let hostname = archive.get("etc/hostname").unwrap();
+        assert_eq!(hostname.data(), b"router\n");
+        assert_eq!(hostname.entry().mode(), 0o100644);
+        assert_eq!(hostname.entry().file_size(), 7);
+        assert_eq!(hostname.entry().checksum(), Some(checksum(b"router\n")));
+        assert_eq!(archive.get("sbin/init").unwrap().entry().mode(), 0o100755);
+    }
+
+    #[test]
+    fn test_archive_spec() {
+        let spec = ArchiveSpec::new()
+            .dir("./etc", 0o755)
+            .file("./etc/hostname", b"box\n", 0o644)
+            .symlink("./init", "/sbin/init");
+        let (output, summary) = spec.write_to(vec![]).unwrap();
+        assert_eq!(summary.entries(), 3);
+
+        let archive = Archive::from_bytes(&output).unwrap();
+        let entries: Vec<_> = archive
+            .entries()
+            .iter()
+            .map(|entry| {
+                (
+                    entry.name(),
+                    entry.entry().ino(),
+                    entry.entry().mode(),
+                    entry.data(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("./etc", 1, 0o040755, &b""[..]),
+                ("./etc/hostname", 2, 0o100644, b"box\n"),
+                ("./init", 3, 0o120777, b"/sbin/init"),
+            ]
+        );
+        let (rewritten, _) = spec.into_archive().unwrap().write_to(vec![]).unwrap();
+        assert_eq!(rewritten, output);
+    }
+
+    #[test]
+    fn test_infer_modes() {
+        let mut archive = Archive::new();
+        archive.add(Builder::new("./etc"), vec![]).unwrap();
+        archive
+            .add(Builder::new("etc/hostname"), b"box\n".to_vec())
+            .unwrap();
+        archive.add(Builder::new("empty"), vec![]).unwrap();
+        archive
+            .add(Builder::new("var/").mode(0o700), vec![])
+            .unwrap();
+        archive
+            .add(Builder::new("init").mode(0o120777), b"/sbin/init".to_vec())
+            .unwrap();
+        archive.infer_modes();
+        let modes: Vec<_> = archive
+            .entries()
+            .iter()
+            .map(|entry| entry.entry().mode())
+            .collect();
+        assert_eq!(modes, [0o040755, 0o100644, 0o100644, 0o040700, 0o120777]);
+    }
+}
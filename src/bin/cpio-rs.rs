@@ -0,0 +1,329 @@
+//! A command-line front end to the library, for listing, creating, extracting, and verifying
+//! `newc` archives.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::process;
+
+use cpio::compress::{Decoder, Encoder, Format};
+use cpio::diagnostics::Diagnostics;
+use cpio::extract::Extractor;
+use cpio::name::NamePolicy;
+use cpio::newc::{ModeFileType, Reader};
+use cpio::owner::OwnerMap;
+use cpio::tree::TreeArchiver;
+
+const USAGE: &str = "\
+usage: cpio-rs list [-v] ARCHIVE
+       cpio-rs create [--owner UID:GID] [--portable] [--compress FORMAT] DIR ARCHIVE
+       cpio-rs extract [--owner UID:GID] [--atomic] [--strict-names] ARCHIVE DIR
+       cpio-rs verify ARCHIVE
+
+ARCHIVE may be `-` for standard input or output. Compressed archives are detected when read.";
+
+fn usage() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, USAGE)
+}
+
+/// Splits `args` into the flags before the first positional argument and the positional
+/// arguments, checking that there are `count` of the latter.
+fn split_args(args: &[String], count: usize) -> io::Result<(Vec<&str>, Vec<&str>)> {
+    let mut flags = vec![];
+    let mut positional = vec![];
+    let mut args = args.iter().map(String::as_str);
+    while let Some(arg) = args.next() {
+        match arg {
+            "--owner" | "--compress" => {
+                flags.push(arg);
+                flags.push(args.next().ok_or_else(usage)?);
+            }
+            "-" => positional.push(arg),
+            _ if arg.starts_with('-') => flags.push(arg),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != count {
+        return Err(usage());
+    }
+    Ok((flags, positional))
+}
+
+/// Parses an `--owner` argument: a user id, a `:`, and a group id, either of which may be
+/// empty to leave those ids unchanged.
+fn parse_owner(owner: &str) -> io::Result<OwnerMap> {
+    let parse = |id: &str| -> io::Result<Option<u32>> {
+        if id.is_empty() {
+            return Ok(None);
+        }
+        id.parse().map(Some).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid id {:?}", id))
+        })
+    };
+    let (uid, gid) = match owner.find(':') {
+        Some(colon) => (&owner[..colon], &owner[colon + 1..]),
+        None => (owner, ""),
+    };
+    Ok(OwnerMap::new().uid(parse(uid)?).gid(parse(gid)?))
+}
+
+fn parse_format(name: &str) -> io::Result<Format> {
+    let formats = [
+        Format::Uncompressed,
+        Format::Gzip,
+        Format::Zstd,
+        Format::Xz,
+        Format::Lzma,
+        Format::Lz4,
+        Format::Lz4Legacy,
+        Format::Bzip2,
+    ];
+    formats
+        .iter()
+        .copied()
+        .find(|format| format.name() == name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown compression format {:?}", name),
+            )
+        })
+}
+
+/// Opens an archive for reading, decompressing it if need be.
+fn open(path: &str) -> io::Result<Decoder<BufReader<Box<dyn Read>>>> {
+    let input: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    Decoder::detect(BufReader::new(input))
+}
+
+fn list(args: &[String], out: &mut dyn Write) -> io::Result<()> {
+    let (flags, paths) = split_args(args, 1)?;
+    let verbose = match flags.as_slice() {
+        [] => false,
+        ["-v"] => true,
+        _ => return Err(usage()),
+    };
+    let mut archive = open(paths[0])?;
+    loop {
+        let reader = Reader::new(archive)?;
+        let entry = reader.entry();
+        if entry.is_trailer() {
+            break;
+        }
+        if verbose {
+            writeln!(
+                out,
+                "{:06o} {:>5} {:>5} {:>10} {}",
+                entry.mode(),
+                entry.uid(),
+                entry.gid(),
+                entry.file_size(),
+                entry.name()
+            )?;
+        } else {
+            writeln!(out, "{}", entry.name())?;
+        }
+        archive = reader.finish()?;
+    }
+    Ok(())
+}
+
+fn create(args: &[String]) -> io::Result<()> {
+    let (flags, paths) = split_args(args, 2)?;
+    let mut archiver = TreeArchiver::new(paths[0]);
+    let mut format = Format::Uncompressed;
+    let mut flags = flags.into_iter();
+    while let Some(flag) = flags.next() {
+        match flag {
+            "--owner" => archiver = archiver.owner_map(parse_owner(flags.next().unwrap())?),
+            "--portable" => archiver = archiver.portable(Some(Default::default())),
+            "--compress" => format = parse_format(flags.next().unwrap())?,
+            _ => return Err(usage()),
+        }
+    }
+    let output: Box<dyn Write> = if paths[1] == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(paths[1])?)
+    };
+    let (output, _) = archiver.write(Encoder::new(format, output)?)?;
+    output.finish()?.flush()
+}
+
+fn extract(args: &[String]) -> io::Result<()> {
+    let (flags, paths) = split_args(args, 2)?;
+    let mut extractor = Extractor::new(paths[1]);
+    let mut flags = flags.into_iter();
+    while let Some(flag) = flags.next() {
+        match flag {
+            "--owner" => extractor = extractor.owner_map(parse_owner(flags.next().unwrap())?),
+            "--atomic" => extractor = extractor.atomic(true),
+            "--strict-names" => extractor = extractor.name_policy(NamePolicy::strict()),
+            _ => return Err(usage()),
+        }
+    }
+    extractor.extract(open(paths[0])?)?;
+    Ok(())
+}
+
+/// Reads every entry of an archive, reporting anything unusual about it and failing if any
+/// entry's checksum is wrong.
+fn verify(args: &[String], out: &mut dyn Write) -> io::Result<()> {
+    let (flags, paths) = split_args(args, 1)?;
+    if !flags.is_empty() {
+        return Err(usage());
+    }
+    let diagnostics = Diagnostics::new();
+    let mut archive = open(paths[0])?;
+    let mut entries = 0;
+    let mut bad = 0;
+    loop {
+        let mut reader = Reader::with_diagnostics(archive, &diagnostics)?;
+        if reader.entry().is_trailer() {
+            break;
+        }
+        entries += 1;
+        let expected = reader.entry().checksum();
+        let name = reader.entry().name().to_string();
+        let mut sum = 0u32;
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            sum = buf[..n]
+                .iter()
+                .fold(sum, |sum, &byte| sum.wrapping_add(u32::from(byte)));
+        }
+        match expected {
+            Some(expected) if expected != sum => {
+                bad += 1;
+                writeln!(
+                    out,
+                    "{}: checksum is {:08x}, but the data sums to {:08x}",
+                    name, expected, sum
+                )?;
+            }
+            _ => {}
+        }
+        // Symlinks are the one kind of file whose data must not be empty.
+        let mode = reader.entry().mode();
+        if ModeFileType::from_mode(mode) == Some(ModeFileType::Symlink)
+            && reader.entry().file_size() == 0
+        {
+            writeln!(out, "{}: symlink has no target", name)?;
+        }
+        archive = reader.finish()?;
+    }
+    for warning in diagnostics.take() {
+        writeln!(out, "warning: {}", warning)?;
+    }
+    if bad > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} of {} entries have bad checksums", bad, entries),
+        ));
+    }
+    writeln!(out, "{} entries ok", entries)
+}
+
+fn run(args: &[String], out: &mut dyn Write) -> io::Result<()> {
+    match args.first().map(String::as_str) {
+        Some("list") => list(&args[1..], out),
+        Some("create") => create(&args[1..]),
+        Some("extract") => extract(&args[1..]),
+        Some("verify") => verify(&args[1..], out),
+        _ => Err(usage()),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let stdout = io::stdout();
+    if let Err(err) = run(&args, &mut stdout.lock()) {
+        eprintln!("cpio-rs: {}", err);
+        process::exit(if err.kind() == io::ErrorKind::InvalidInput {
+            2
+        } else {
+            1
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cpio-cli-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn run_args(args: &[&str]) -> io::Result<String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut out = vec![];
+        run(&args, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_parse_owner() {
+        let map = parse_owner("0:0").unwrap();
+        assert_eq!((map.map_uid(7), map.map_gid(7)), (0, 0));
+        let map = parse_owner(":5").unwrap();
+        assert_eq!((map.map_uid(7), map.map_gid(7)), (7, 5));
+        assert!(parse_owner("root").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempdir("round-trip");
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join("etc")).unwrap();
+        fs::write(tree.join("etc/hostname"), "box\n").unwrap();
+        let archive = dir.join("archive.cpio");
+        let archive = archive.to_str().unwrap();
+
+        run_args(&["create", "--owner", "0:0", tree.to_str().unwrap(), archive]).unwrap();
+        assert_eq!(run_args(&["list", archive]).unwrap(), "etc\netc/hostname\n");
+        let listing = run_args(&["list", "-v", archive]).unwrap();
+        assert!(
+            listing.ends_with("0     0          4 etc/hostname\n"),
+            "{}",
+            listing
+        );
+        assert_eq!(run_args(&["verify", archive]).unwrap(), "2 entries ok\n");
+
+        let dest = dir.join("dest");
+        run_args(&["extract", "--atomic", archive, dest.to_str().unwrap()]).unwrap();
+        assert_eq!(fs::read(dest.join("etc/hostname")).unwrap(), b"box\n");
+
+        let err = run_args(&["list"]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_bad_checksum() {
+        let dir = tempdir("verify");
+        let mut archive = cpio::ArchiveWriter::new(vec![]);
+        archive
+            .append_crc(cpio::NewcBuilder::new("a"), 2, 0, &b"hi"[..])
+            .unwrap();
+        let (archive, _) = archive.finish().unwrap();
+        let path = dir.join("bad.cpio");
+        fs::write(&path, archive).unwrap();
+
+        let err = run_args(&["verify", path.to_str().unwrap()]).unwrap_err();
+        assert_eq!(err.to_string(), "1 of 1 entries have bad checksums");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,477 @@
+//! Splitting archives into a metadata manifest and a content-addressed blob store, and
+//! putting them back together.
+//!
+//! This module is only available with the `digest` feature. [`export`] reads an archive and
+//! writes the data of each entry into a [`BlobStore`] under the hex digest of its contents,
+//! so that data shared between entries, or between archives exported into the same store, is
+//! only stored once; [`rebuild`] writes the archive back out from the two. Everything else
+//! about the archive goes into a manifest, one line per entry: the entry's header as this
+//! crate would write it, the digest of its data (or `-` if it has none), and its name,
+//! separated by two spaces:
+//!
+//! ```text
+//! 070701000000000000816d000000000000000000000001000000000000000d...  8663bab6...  ./hello
+//! ```
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! use std::collections::HashMap;
+//!
+//! use cpio::blobs;
+//! use cpio::NewcBuilder;
+//!
+//! let (archive, _) = cpio::write_cpio_bytes(
+//!     vec![
+//!         (NewcBuilder::new("a").mode(0o100644), &b"same"[..]),
+//!         (NewcBuilder::new("b").mode(0o100644), &b"same"[..]),
+//!     ],
+//!     vec![],
+//! )?;
+//! let mut store: HashMap<String, Vec<u8>> = HashMap::new();
+//! let (manifest, summary) = blobs::export::<sha2::Sha256, _, _, _>(
+//!     archive.as_slice(),
+//!     &mut store,
+//!     vec![],
+//! )?;
+//! assert_eq!(summary.entries(), 2);
+//! assert_eq!(store.len(), 1);
+//!
+//! let (rebuilt, _) = blobs::rebuild::<sha2::Sha256, _, _, _>(
+//!     manifest.as_slice(),
+//!     &mut store,
+//!     vec![],
+//! )?;
+//! assert_eq!(rebuilt, archive);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+use digest::Digest;
+
+use crate::newc::{Builder, EntryType, Header, Reader, HEADER_LEN};
+use crate::{ArchiveWriter, Summary};
+
+/// Somewhere to keep blobs of entry data, keyed by the hex digest of their contents.
+pub trait BlobStore {
+    /// Returns true if the store already holds the blob `key`.
+    fn contains(&mut self, key: &str) -> io::Result<bool>;
+
+    /// Stores `data` as the blob `key`.
+    fn put(&mut self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Returns the data of the blob `key`, failing with a `NotFound` error if the store does
+    /// not hold it.
+    fn get(&mut self, key: &str) -> io::Result<Vec<u8>>;
+}
+
+fn missing_blob(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("Missing blob {}", key))
+}
+
+impl BlobStore for HashMap<String, Vec<u8>> {
+    fn contains(&mut self, key: &str) -> io::Result<bool> {
+        Ok(self.contains_key(key))
+    }
+
+    fn put(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&mut self, key: &str) -> io::Result<Vec<u8>> {
+        HashMap::get(self, key)
+            .cloned()
+            .ok_or_else(|| missing_blob(key))
+    }
+}
+
+impl<S: BlobStore + ?Sized> BlobStore for &mut S {
+    fn contains(&mut self, key: &str) -> io::Result<bool> {
+        (**self).contains(key)
+    }
+
+    fn put(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+        (**self).put(key, data)
+    }
+
+    fn get(&mut self, key: &str) -> io::Result<Vec<u8>> {
+        (**self).get(key)
+    }
+}
+
+/// A blob store keeping each blob in a file named after its key in a directory.
+#[derive(Clone, Debug)]
+pub struct DirStore {
+    root: PathBuf,
+}
+
+impl DirStore {
+    /// Returns a store keeping its blobs in `root`, which must already exist.
+    pub fn new<P: AsRef<Path>>(root: P) -> DirStore {
+        DirStore {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the path of the file holding the blob `key`.
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl BlobStore for DirStore {
+    fn contains(&mut self, key: &str) -> io::Result<bool> {
+        Ok(self.path(key).is_file())
+    }
+
+    fn put(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+        // Write to a temporary file and rename it into place, so that a blob that exists is
+        // always complete.
+        let path = self.path(key);
+        let partial = path.with_extension("partial");
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &path)
+    }
+
+    fn get(&mut self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => missing_blob(key),
+            _ => err,
+        })
+    }
+}
+
+/// Statistics about an archive split up by [`export`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    entries: u64,
+    data_bytes: u64,
+    new_blobs: u64,
+    new_bytes: u64,
+}
+
+impl ExportSummary {
+    /// Returns the number of entries exported, not counting the trailer.
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Returns the total size of the file data of all entries, in bytes.
+    pub fn data_bytes(&self) -> u64 {
+        self.data_bytes
+    }
+
+    /// Returns the number of blobs that were not already in the store, and were written.
+    pub fn new_blobs(&self) -> u64 {
+        self.new_blobs
+    }
+
+    /// Returns the total size of the blobs written, in bytes.
+    pub fn new_bytes(&self) -> u64 {
+        self.new_bytes
+    }
+}
+
+/// Splits `archive` into a manifest, written to `manifest`, and blobs of entry data, written
+/// to `store` under their `D` digests, skipping any blob the store already holds.
+///
+/// The archive is read in a single pass, but the data of each entry is held in memory while
+/// it is hashed, as it can only be stored once its digest is known. Entries without data,
+/// such as directories, have no blob. Fails with an `InvalidData` error on an entry whose
+/// name contains a newline, which cannot be written to the manifest. Returns `manifest` once
+/// the trailer has been reached.
+pub fn export<D, R, S, W>(
+    mut archive: R,
+    mut store: S,
+    mut manifest: W,
+) -> io::Result<(W, ExportSummary)>
+where
+    D: Digest,
+    R: Read,
+    S: BlobStore,
+    W: Write,
+{
+    let mut summary = ExportSummary::default();
+    let mut data = vec![];
+    let mut line = String::new();
+    loop {
+        let mut reader = Reader::new(archive)?;
+        let entry = reader.entry();
+        if entry.is_trailer() {
+            reader.finish()?;
+            manifest.flush()?;
+            return Ok((manifest, summary));
+        }
+        if entry.name().contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?}: name contains a newline", entry.name()),
+            ));
+        }
+
+        line.clear();
+        let header = entry.header().emit();
+        line.push_str(std::str::from_utf8(&header).expect("headers are ASCII"));
+        line.push_str("  ");
+        data.clear();
+        reader.read_to_end(&mut data)?;
+        if data.is_empty() {
+            line.push('-');
+        } else {
+            let key_start = line.len();
+            for byte in D::digest(&data) {
+                write!(line, "{:02x}", byte).unwrap();
+            }
+            let key = &line[key_start..];
+            if !store.contains(key)? {
+                store.put(key, &data)?;
+                summary.new_blobs += 1;
+                summary.new_bytes += data.len() as u64;
+            }
+        }
+        writeln!(line, "  {}", reader.entry().name()).unwrap();
+        manifest.write_all(line.as_bytes())?;
+
+        summary.entries += 1;
+        summary.data_bytes += data.len() as u64;
+        archive = reader.finish()?;
+    }
+}
+
+/// Writes the archive described by `manifest` to `output`, reading the data of its entries
+/// from `store`, and returns `output` along with statistics about the archive written.
+///
+/// Entries are written in manifest order with the headers in the manifest, and padded and
+/// ended with a trailer as [`ArchiveWriter`] does, so an archive written by this crate is
+/// rebuilt byte for byte from its [`export`]. Each blob is checked against its `D` digest
+/// and the size in its header before it is written, failing with an `InvalidData` error if
+/// either does not match; a malformed manifest line also fails with `InvalidData`, and a blob
+/// missing from the store with `NotFound`.
+pub fn rebuild<D, M, S, W>(manifest: M, mut store: S, output: W) -> io::Result<(W, Summary)>
+where
+    D: Digest,
+    M: BufRead,
+    S: BlobStore,
+    W: Write,
+{
+    let mut archive = ArchiveWriter::new(output);
+    let mut digest = String::new();
+    for (idx, line) in manifest.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid manifest line {}", idx + 1),
+            )
+        };
+        let (header, rest) = match (line.get(..HEADER_LEN), line.get(HEADER_LEN..)) {
+            (Some(header), Some(rest)) => (header, rest),
+            _ => return Err(invalid()),
+        };
+        let mut fields = rest.splitn(3, "  ");
+        let (key, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(""), Some(key), Some(name)) => (key, name),
+            _ => return Err(invalid()),
+        };
+        let mut buf = [0; HEADER_LEN];
+        buf.copy_from_slice(header.as_bytes());
+        let header = Header::parse(&buf).map_err(|_| invalid())?;
+        if header.name_size as usize != name.len() + 1 {
+            return Err(invalid());
+        }
+
+        let data = if key == "-" {
+            vec![]
+        } else {
+            let data = store.get(key)?;
+            digest.clear();
+            for byte in D::digest(&data) {
+                write!(digest, "{:02x}", byte).unwrap();
+            }
+            if digest != key {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Blob {} does not match its digest", key),
+                ));
+            }
+            data
+        };
+        if data.len() != header.file_size as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: manifest gives a size of {}, but the blob holds {} bytes",
+                    name,
+                    header.file_size,
+                    data.len()
+                ),
+            ));
+        }
+
+        let builder = Builder::new(name)
+            .ino(header.ino)
+            .mode(header.mode)
+            .uid(header.uid)
+            .gid(header.gid)
+            .nlink(header.nlink)
+            .mtime(header.mtime)
+            .dev_major(header.dev_major)
+            .dev_minor(header.dev_minor)
+            .rdev_major(header.rdev_major)
+            .rdev_minor(header.rdev_minor);
+        match header.entry_type {
+            EntryType::Crc => {
+                archive.append_crc(builder, header.file_size, header.checksum, data.as_slice())?
+            }
+            EntryType::Newc => archive.append_bytes(builder, &data)?,
+        }
+    }
+    archive.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newc::HEADER_LEN;
+    use crate::NewcBuilder;
+
+    fn archive() -> Vec<u8> {
+        crate::write_cpio_bytes(
+            vec![
+                (NewcBuilder::new("dir").mode(0o040755), &b""[..]),
+                (NewcBuilder::new("dir/a").mode(0o100644), b"firmware"),
+                (NewcBuilder::new("dir/b").mode(0o100644), b"firmware"),
+                (NewcBuilder::new("dir/c").mode(0o100755), b"loader"),
+            ],
+            vec![],
+        )
+        .unwrap()
+        .0
+    }
+
+    #[test]
+    fn test_export() {
+        let mut store: HashMap<String, Vec<u8>> = HashMap::new();
+        let (manifest, summary) =
+            export::<sha2::Sha256, _, _, _>(archive().as_slice(), &mut store, vec![]).unwrap();
+        assert_eq!(summary.entries(), 4);
+        assert_eq!(summary.data_bytes(), 22);
+        assert_eq!(summary.new_blobs(), 2);
+        assert_eq!(summary.new_bytes(), 14);
+
+        let key = |data: &[u8]| {
+            let mut hex = String::new();
+            for byte in sha2::Sha256::digest(data) {
+                write!(hex, "{:02x}", byte).unwrap();
+            }
+            hex
+        };
+        assert_eq!(store[&key(b"firmware")], b"firmware");
+        assert_eq!(store[&key(b"loader")], b"loader");
+
+        let manifest = String::from_utf8(manifest).unwrap();
+        let lines: Vec<&str> = manifest.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("070701"));
+        assert!(lines[0].ends_with("  -  dir"));
+        assert!(lines[1].ends_with(&format!("  {}  dir/a", key(b"firmware"))));
+        assert_eq!(
+            &lines[1][HEADER_LEN..],
+            &lines[2][HEADER_LEN..].replace("dir/b", "dir/a")
+        );
+
+        // Exporting again into the same store writes no new blobs.
+        let (_, summary) =
+            export::<sha2::Sha256, _, _, _>(archive().as_slice(), &mut store, vec![]).unwrap();
+        assert_eq!(summary.new_blobs(), 0);
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let original = archive();
+        let mut store: HashMap<String, Vec<u8>> = HashMap::new();
+        let (manifest, _) =
+            export::<sha2::Sha256, _, _, _>(original.as_slice(), &mut store, vec![]).unwrap();
+        let (rebuilt, summary) =
+            rebuild::<sha2::Sha256, _, _, _>(manifest.as_slice(), &mut store, vec![]).unwrap();
+        assert_eq!(rebuilt, original);
+        assert_eq!(summary.entries(), 4);
+
+        // Checksums of entries in the crc format are kept.
+        let mut crc = ArchiveWriter::new(vec![]);
+        let builder = NewcBuilder::new("crc").mode(0o100644);
+        crc.append_crc(builder, 3, crate::newc::checksum(b"crc"), &b"crc"[..])
+            .unwrap();
+        let (crc, _) = crc.finish().unwrap();
+        let (manifest, _) =
+            export::<sha2::Sha256, _, _, _>(crc.as_slice(), &mut store, vec![]).unwrap();
+        let (rebuilt, _) =
+            rebuild::<sha2::Sha256, _, _, _>(manifest.as_slice(), &mut store, vec![]).unwrap();
+        assert_eq!(rebuilt, crc);
+    }
+
+    #[test]
+    fn test_rebuild_errors() {
+        let mut store: HashMap<String, Vec<u8>> = HashMap::new();
+        let (manifest, _) =
+            export::<sha2::Sha256, _, _, _>(archive().as_slice(), &mut store, vec![]).unwrap();
+        let rebuild = |manifest: &[u8], store: &mut HashMap<String, Vec<u8>>| {
+            rebuild::<sha2::Sha256, _, _, _>(manifest, store, io::sink()).unwrap_err()
+        };
+
+        let err = rebuild(b"070701  -  dir\n", &mut store);
+        assert_eq!(err.to_string(), "Invalid manifest line 1");
+
+        let mut corrupt = store.clone();
+        for data in corrupt.values_mut() {
+            data[0] ^= 1;
+        }
+        let err = rebuild(&manifest, &mut corrupt);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().ends_with("does not match its digest"));
+
+        let err = rebuild(&manifest, &mut HashMap::new());
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_dir_store() {
+        let dir = std::env::temp_dir().join(format!("cpio-blobs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut store = DirStore::new(&dir);
+        let (_, summary) =
+            export::<sha2::Sha256, _, _, _>(archive().as_slice(), &mut store, io::sink()).unwrap();
+        assert_eq!(summary.new_blobs(), 2);
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        assert!(names.iter().all(|name| name.len() == 64));
+        let key = names[0].to_str().unwrap();
+        assert!(!store.get(key).unwrap().is_empty());
+        let err = store.get("missing").unwrap_err();
+        assert_eq!(err.to_string(), "Missing blob missing");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_newline_in_name() {
+        let store = HashMap::<String, Vec<u8>>::new;
+        let (archive, _) =
+            crate::write_cpio_bytes(vec![(NewcBuilder::new("a\nb"), &b""[..])], vec![]).unwrap();
+        let err =
+            export::<sha2::Sha256, _, _, _>(archive.as_slice(), store(), io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
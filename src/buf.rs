@@ -0,0 +1,114 @@
+//! Parsing archives held in chains of buffers.
+//!
+//! Archives that arrive as a sequence of network frames or other separate buffers can be read
+//! through [`Buf::reader`], which implements `Read` and `BufRead` over the chain without
+//! first gathering it into one contiguous allocation. [`BufArchive`] goes further for
+//! [`Bytes`]-backed buffers, handing out each entry's data as a [`Bytes`] that shares the
+//! underlying frame rather than copying it, wherever the data lies within a single frame.
+
+use std::io;
+
+use bytes::{Buf, Bytes};
+
+use crate::newc::{pad, Entry, Reader};
+
+/// Reads the entries of an archive held in a [`Buf`], such as [`Bytes`] or a [`Chain`] of
+/// them.
+///
+/// [`Chain`]: bytes::buf::Chain
+///
+/// ```
+/// use bytes::{Buf, Bytes};
+/// use cpio::buf::BufArchive;
+///
+/// # fn main() -> std::io::Result<()> {
+/// # let (archive, _) = cpio::write_cpio_bytes(vec![(cpio::NewcBuilder::new("a"), b"Hi")], vec![])?;
+/// # let (first, second) = archive.split_at(100);
+/// let frames = Bytes::copy_from_slice(first).chain(Bytes::copy_from_slice(second));
+/// let mut archive = BufArchive::new(frames);
+/// while let Some((entry, data)) = archive.next_entry()? {
+///     println!("{}: {} bytes", entry.name(), data.len());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct BufArchive<B: Buf> {
+    buf: B,
+}
+
+impl<B: Buf> BufArchive<B> {
+    /// Starts reading the archive at the start of `buf`.
+    pub fn new(buf: B) -> Self {
+        Self { buf }
+    }
+
+    /// Reads the next entry and its data, or returns `None` once the trailer has been read.
+    ///
+    /// The data is taken with [`Buf::copy_to_bytes`], which for [`Bytes`] and chains of them
+    /// only copies when the data spans more than one buffer.
+    pub fn next_entry(&mut self) -> io::Result<Option<(Entry, Bytes)>> {
+        let reader = Reader::new((&mut self.buf).reader())?;
+        let entry = reader.entry().clone();
+        drop(reader);
+        let len = entry.file_size() as usize;
+        let padded = len + pad(len);
+        if self.buf.remaining() < padded {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "archive ended before the end of the entry data",
+            ));
+        }
+        let data = self.buf.copy_to_bytes(len);
+        self.buf.advance(padded - len);
+        if entry.is_trailer() {
+            return Ok(None);
+        }
+        Ok(Some((entry, data)))
+    }
+
+    /// Returns the buffer, positioned after the last entry read.
+    pub fn into_inner(self) -> B {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcBuilder;
+
+    #[test]
+    fn test_chained_frames() {
+        let data = vec![7u8; 5000];
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (NewcBuilder::new("./big"), data.as_slice()),
+                (NewcBuilder::new("./small"), b"abc"),
+            ],
+            vec![],
+        )
+        .unwrap();
+        let archive = Bytes::from(archive);
+        // Split the archive so that a header, and the data of the first entry, each straddle
+        // two frames.
+        let frames = archive
+            .slice(..50)
+            .chain(archive.slice(50..200))
+            .chain(archive.slice(200..));
+
+        let mut reader = BufArchive::new(frames);
+        let (entry, big) = reader.next_entry().unwrap().unwrap();
+        assert_eq!(entry.name(), "./big");
+        assert_eq!(big, data);
+        let (entry, small) = reader.next_entry().unwrap().unwrap();
+        assert_eq!(entry.name(), "./small");
+        assert_eq!(small, &b"abc"[..]);
+        assert!(reader.next_entry().unwrap().is_none());
+        assert_eq!(reader.into_inner().remaining(), 0);
+
+        let mut truncated = BufArchive::new(archive.slice(..archive.len() - 200));
+        truncated.next_entry().unwrap();
+        let err = truncated.next_entry().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
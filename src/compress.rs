@@ -0,0 +1,822 @@
+//! Compressed archives.
+//!
+//! Archives, and initramfs images in particular, are usually stored compressed. [`Encoder`]
+//! compresses an archive as it is written and [`Decoder`] decompresses one as it is read, so
+//! either can sit between a [`Reader`](crate::NewcReader) or
+//! [`ArchiveWriter`](crate::ArchiveWriter) and the underlying file. Support for each
+//! [`Format`] is enabled by the feature of the same name. [`Decoder::detect`] opens an archive
+//! in any format, recognizing it from its magic number.
+
+use std::io::{self, BufRead, Read, Write};
+
+use crate::newc::{MAGIC_NUMBER_NEWASCII, MAGIC_NUMBER_NEWCRC};
+
+mod append;
+mod image;
+#[cfg(feature = "lz4")]
+mod lz4_legacy;
+mod segments;
+
+pub use append::{append_recompressed, append_segment};
+pub use image::ImageReader;
+pub use segments::{segment_map, ImageSegment};
+
+/// A compression format for archives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Format {
+    /// No compression.
+    Uncompressed,
+    /// gzip, with the `gzip` feature.
+    Gzip,
+    /// Zstandard, with the `zstd` feature.
+    Zstd,
+    /// xz, with the `xz` feature.
+    Xz,
+    /// The legacy `.lzma` container, used by some kernels, with the `xz` feature.
+    ///
+    /// The container cannot be flushed mid-stream, so flushing an lzma [`Encoder`] only
+    /// flushes its underlying writer.
+    Lzma,
+    /// The standard lz4 frame format, with the `lz4` feature.
+    Lz4,
+    /// The legacy lz4 frame format used by the kernel (`lz4 -l`), with the `lz4` feature.
+    ///
+    /// Every block but the last must be full, so flushing a legacy lz4 [`Encoder`] only
+    /// flushes its underlying writer.
+    Lz4Legacy,
+    /// bzip2, with the `bzip2` feature.
+    Bzip2,
+}
+
+impl Format {
+    /// Identifies the format of a stream from its first few bytes (six are enough for every
+    /// format), or returns `None` if it is not recognized.
+    ///
+    /// Streams starting with a `newc` magic number are [`Uncompressed`](Format::Uncompressed).
+    /// Formats are recognized whether or not their features are enabled.
+    pub fn detect(magic: &[u8]) -> Option<Format> {
+        const MAGIC_NUMBERS: &[(&[u8], Format)] = &[
+            (MAGIC_NUMBER_NEWASCII, Format::Uncompressed),
+            (MAGIC_NUMBER_NEWCRC, Format::Uncompressed),
+            (b"\x1f\x8b", Format::Gzip),
+            // Old gzip, which the kernel also accepts.
+            (b"\x1f\x9e", Format::Gzip),
+            (b"\x28\xb5\x2f\xfd", Format::Zstd),
+            (b"\xfd7zXZ\x00", Format::Xz),
+            (b"\x5d\x00\x00", Format::Lzma),
+            (b"\x04\x22\x4d\x18", Format::Lz4),
+            (b"\x02\x21\x4c\x18", Format::Lz4Legacy),
+            (b"BZh", Format::Bzip2),
+        ];
+        MAGIC_NUMBERS
+            .iter()
+            .find(|(number, _)| magic.starts_with(number))
+            .map(|&(_, format)| format)
+    }
+
+    /// Returns the name of this format, as used for its feature.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Uncompressed => "uncompressed",
+            Format::Gzip => "gzip",
+            Format::Zstd => "zstd",
+            Format::Xz => "xz",
+            Format::Lzma => "lzma",
+            Format::Lz4 => "lz4",
+            Format::Lz4Legacy => "lz4-legacy",
+            Format::Bzip2 => "bzip2",
+        }
+    }
+
+    /// Returns the name of the feature that enables this format.
+    fn feature(self) -> &'static str {
+        match self {
+            Format::Lzma => "xz",
+            Format::Lz4Legacy => "lz4",
+            format => format.name(),
+        }
+    }
+}
+
+/// Returns the error for a format whose feature is not enabled.
+fn unsupported(format: Format) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} support requires the `{}` feature",
+            format.name(),
+            format.feature()
+        ),
+    )
+}
+
+/// Compresses data written through it in one of the supported formats.
+///
+/// The compressed stream is only complete once [`finish`](Self::finish) has been called;
+/// dropping the encoder instead may leave it truncated.
+pub struct Encoder<W: Write> {
+    inner: EncoderInner<W>,
+}
+
+enum EncoderInner<W: Write> {
+    Uncompressed(W),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    #[cfg(feature = "xz")]
+    Xz(xz2::write::XzEncoder<W>),
+    // The lzma container cannot be flushed mid-stream, so it is kept apart from xz.
+    #[cfg(feature = "xz")]
+    Lzma(xz2::write::XzEncoder<W>),
+    #[cfg(feature = "lz4")]
+    Lz4(Box<lz4_flex::frame::FrameEncoder<W>>),
+    #[cfg(feature = "lz4")]
+    Lz4Legacy(lz4_legacy::Encoder<W>),
+    #[cfg(feature = "bzip2")]
+    Bzip2(bzip2::write::BzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Starts compressing into `inner` in `format` at its default level.
+    pub fn new(format: Format, inner: W) -> io::Result<Self> {
+        let inner = match format {
+            Format::Uncompressed => EncoderInner::Uncompressed(inner),
+            #[cfg(feature = "gzip")]
+            Format::Gzip => EncoderInner::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::default(),
+            )),
+            #[cfg(feature = "zstd")]
+            Format::Zstd => EncoderInner::Zstd(zstd::stream::write::Encoder::new(inner, 0)?),
+            #[cfg(feature = "xz")]
+            Format::Xz | Format::Lzma => return Self::with_level(format, 6, inner),
+            #[cfg(feature = "lz4")]
+            Format::Lz4 | Format::Lz4Legacy => return Self::with_level(format, 0, inner),
+            #[cfg(feature = "bzip2")]
+            Format::Bzip2 => return Self::with_level(format, 9, inner),
+            #[allow(unreachable_patterns)]
+            format => return Err(unsupported(format)),
+        };
+        Ok(Self { inner })
+    }
+
+    /// Starts compressing into `inner` in `format` at compression `level`, whose range
+    /// depends on the format (0-9 for gzip, xz, and lzma, 1-9 for bzip2, 1-22 for zstd). The
+    /// level is ignored when uncompressed and for lz4, which has a single level.
+    ///
+    /// xz streams are written with CRC32 integrity checks, which the kernel's decompressor
+    /// requires.
+    // `level` is unused when no compression features are enabled.
+    #[allow(unused_variables)]
+    pub fn with_level(format: Format, level: u32, inner: W) -> io::Result<Self> {
+        let inner = match format {
+            Format::Uncompressed => EncoderInner::Uncompressed(inner),
+            #[cfg(feature = "gzip")]
+            Format::Gzip => EncoderInner::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::new(level.min(9)),
+            )),
+            #[cfg(feature = "zstd")]
+            Format::Zstd => EncoderInner::Zstd(zstd::stream::write::Encoder::new(
+                inner,
+                level.min(22) as i32,
+            )?),
+            #[cfg(feature = "xz")]
+            Format::Xz => EncoderInner::Xz(xz2::write::XzEncoder::new_stream(
+                inner,
+                xz2::stream::Stream::new_easy_encoder(level.min(9), xz2::stream::Check::Crc32)?,
+            )),
+            #[cfg(feature = "xz")]
+            Format::Lzma => EncoderInner::Lzma(xz2::write::XzEncoder::new_stream(
+                inner,
+                xz2::stream::Stream::new_lzma_encoder(&xz2::stream::LzmaOptions::new_preset(
+                    level.min(9),
+                )?)?,
+            )),
+            #[cfg(feature = "lz4")]
+            Format::Lz4 => EncoderInner::Lz4(Box::new(lz4_flex::frame::FrameEncoder::new(inner))),
+            #[cfg(feature = "lz4")]
+            Format::Lz4Legacy => EncoderInner::Lz4Legacy(lz4_legacy::Encoder::new(inner)),
+            #[cfg(feature = "bzip2")]
+            Format::Bzip2 => EncoderInner::Bzip2(bzip2::write::BzEncoder::new(
+                inner,
+                bzip2::Compression::new(level.clamp(1, 9)),
+            )),
+            #[allow(unreachable_patterns)]
+            format => return Err(unsupported(format)),
+        };
+        Ok(Self { inner })
+    }
+
+    /// Starts compressing into `inner` as zstd at compression `level` (0 for the default),
+    /// spreading the compression across `threads` worker threads.
+    #[cfg(feature = "zstd")]
+    pub fn zstd_multithreaded(level: u32, threads: u32, inner: W) -> io::Result<Self> {
+        let mut encoder = zstd::stream::write::Encoder::new(inner, level.min(22) as i32)?;
+        encoder.multithread(threads)?;
+        Ok(Self {
+            inner: EncoderInner::Zstd(encoder),
+        })
+    }
+
+    /// Writes out the end of the compressed stream, and returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self.inner {
+            EncoderInner::Uncompressed(inner) => Ok(inner),
+            #[cfg(feature = "gzip")]
+            EncoderInner::Gzip(inner) => inner.finish(),
+            #[cfg(feature = "zstd")]
+            EncoderInner::Zstd(inner) => inner.finish(),
+            #[cfg(feature = "xz")]
+            EncoderInner::Xz(inner) | EncoderInner::Lzma(inner) => inner.finish(),
+            #[cfg(feature = "lz4")]
+            EncoderInner::Lz4(inner) => Ok(inner.finish()?),
+            #[cfg(feature = "lz4")]
+            EncoderInner::Lz4Legacy(inner) => inner.finish(),
+            #[cfg(feature = "bzip2")]
+            EncoderInner::Bzip2(inner) => inner.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner {
+            EncoderInner::Uncompressed(ref mut inner) => inner.write(buf),
+            #[cfg(feature = "gzip")]
+            EncoderInner::Gzip(ref mut inner) => inner.write(buf),
+            #[cfg(feature = "zstd")]
+            EncoderInner::Zstd(ref mut inner) => inner.write(buf),
+            #[cfg(feature = "xz")]
+            EncoderInner::Xz(ref mut inner) | EncoderInner::Lzma(ref mut inner) => inner.write(buf),
+            #[cfg(feature = "lz4")]
+            EncoderInner::Lz4(ref mut inner) => inner.write(buf),
+            #[cfg(feature = "lz4")]
+            EncoderInner::Lz4Legacy(ref mut inner) => inner.write(buf),
+            #[cfg(feature = "bzip2")]
+            EncoderInner::Bzip2(ref mut inner) => inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner {
+            EncoderInner::Uncompressed(ref mut inner) => inner.flush(),
+            #[cfg(feature = "gzip")]
+            EncoderInner::Gzip(ref mut inner) => inner.flush(),
+            #[cfg(feature = "zstd")]
+            EncoderInner::Zstd(ref mut inner) => inner.flush(),
+            #[cfg(feature = "xz")]
+            EncoderInner::Xz(ref mut inner) => inner.flush(),
+            #[cfg(feature = "xz")]
+            EncoderInner::Lzma(ref mut inner) => inner.get_mut().flush(),
+            #[cfg(feature = "lz4")]
+            EncoderInner::Lz4(ref mut inner) => inner.flush(),
+            #[cfg(feature = "lz4")]
+            EncoderInner::Lz4Legacy(ref mut inner) => inner.get_mut().flush(),
+            #[cfg(feature = "bzip2")]
+            EncoderInner::Bzip2(ref mut inner) => inner.flush(),
+        }
+    }
+}
+
+/// Decompresses data read through it from one of the supported formats.
+///
+/// A cpio [`Reader`](crate::NewcReader) stops at the trailer, which is usually followed by
+/// padding, so the end of the compressed stream (and any checksum it carries) is not reached.
+/// Call [`finish`](Self::finish) after the trailer to read through to the end of the stream
+/// and verify it.
+///
+/// When reading untrusted input, set [`limits`](Self::limits) to stop decompression bombs.
+pub struct Decoder<R: BufRead> {
+    format: Format,
+    inner: DecoderInner<CountingReader<R>>,
+    limits: Limits,
+    decompressed: u64,
+}
+
+/// Limits on how much data a [`Decoder`] will produce, and how much memory it may use, to
+/// guard against decompression bombs in untrusted input. No limits are set by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    max_bytes: Option<u64>,
+    max_ratio: Option<u64>,
+    max_memory: Option<u64>,
+}
+
+impl Limits {
+    /// Creates a set of limits with none set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail once more than `bytes` bytes have been decompressed.
+    pub fn max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Fail once the data decompressed so far is more than `ratio` times the size of the
+    /// compressed data consumed to produce it.
+    pub fn max_ratio(mut self, ratio: u64) -> Self {
+        self.max_ratio = Some(ratio);
+        self
+    }
+
+    /// Fail rather than let the decoder allocate more than `bytes` bytes for its own state,
+    /// as an xz or lzma header can demand gigabytes of it. Only the xz and lzma decoders take
+    /// this limit; the memory of the others is bounded by their formats.
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Returns an error if `decompressed` bytes from `compressed` exceed these limits.
+    fn check(&self, decompressed: u64, compressed: u64) -> io::Result<()> {
+        if self.max_bytes.map_or(false, |max| decompressed > max) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decompressed size exceeds limit",
+            ));
+        }
+        if self.max_ratio.map_or(false, |ratio| {
+            decompressed > compressed.saturating_mul(ratio)
+        }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compression ratio exceeds limit",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Counts the bytes consumed from a reader.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: BufRead> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.count += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+enum DecoderInner<R: BufRead> {
+    Uncompressed(R),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::bufread::MultiGzDecoder<R>),
+    #[cfg(feature = "gzip")]
+    GzipSingle(flate2::bufread::GzDecoder<R>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, R>),
+    #[cfg(feature = "xz")]
+    Xz(XzDecoder<R>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4_flex::frame::FrameDecoder<R>),
+    #[cfg(feature = "lz4")]
+    Lz4Legacy(lz4_legacy::Decoder<R>),
+    #[cfg(feature = "bzip2")]
+    Bzip2(bzip2::bufread::MultiBzDecoder<R>),
+    #[cfg(feature = "bzip2")]
+    Bzip2Single(bzip2::bufread::BzDecoder<R>),
+}
+
+impl<R: BufRead> Decoder<R> {
+    /// Starts decompressing `inner` from `format`.
+    ///
+    /// Concatenated compressed streams are decompressed as one.
+    pub fn new(format: Format, inner: R) -> io::Result<Self> {
+        Self::open(format, inner, true)
+    }
+
+    /// Starts decompressing a single compressed stream from `inner`, so that once all of the
+    /// decompressed data has been read, [`into_inner`](Self::into_inner) returns `inner`
+    /// positioned just past the end of the stream.
+    ///
+    /// lz4 streams have no end marker that can be found without reading past it, so they are
+    /// always decompressed through to the end of `inner`.
+    pub fn single_stream(format: Format, inner: R) -> io::Result<Self> {
+        Self::open(format, inner, false)
+    }
+
+    // `multi` is unused when no compression features are enabled.
+    #[allow(unused_variables)]
+    fn open(format: Format, inner: R, multi: bool) -> io::Result<Self> {
+        let inner = CountingReader { inner, count: 0 };
+        let inner = match format {
+            Format::Uncompressed => DecoderInner::Uncompressed(inner),
+            #[cfg(feature = "gzip")]
+            Format::Gzip if multi => {
+                DecoderInner::Gzip(flate2::bufread::MultiGzDecoder::new(inner))
+            }
+            #[cfg(feature = "gzip")]
+            Format::Gzip => DecoderInner::GzipSingle(flate2::bufread::GzDecoder::new(inner)),
+            #[cfg(feature = "zstd")]
+            Format::Zstd => {
+                let mut decoder = zstd::stream::read::Decoder::with_buffer(inner)?;
+                if !multi {
+                    decoder = decoder.single_frame();
+                }
+                DecoderInner::Zstd(decoder)
+            }
+            #[cfg(feature = "xz")]
+            Format::Xz => {
+                let flags = if multi { xz2::stream::CONCATENATED } else { 0 };
+                let stream = xz2::stream::Stream::new_stream_decoder(u64::MAX, flags)?;
+                DecoderInner::Xz(XzDecoder::new(inner, stream))
+            }
+            #[cfg(feature = "xz")]
+            Format::Lzma => DecoderInner::Xz(XzDecoder::new(
+                inner,
+                xz2::stream::Stream::new_lzma_decoder(u64::MAX)?,
+            )),
+            #[cfg(feature = "lz4")]
+            Format::Lz4 => DecoderInner::Lz4(lz4_flex::frame::FrameDecoder::new(inner)),
+            #[cfg(feature = "lz4")]
+            Format::Lz4Legacy => DecoderInner::Lz4Legacy(lz4_legacy::Decoder::new(inner)),
+            #[cfg(feature = "bzip2")]
+            Format::Bzip2 if multi => {
+                DecoderInner::Bzip2(bzip2::bufread::MultiBzDecoder::new(inner))
+            }
+            #[cfg(feature = "bzip2")]
+            Format::Bzip2 => DecoderInner::Bzip2Single(bzip2::bufread::BzDecoder::new(inner)),
+            #[allow(unreachable_patterns)]
+            format => return Err(unsupported(format)),
+        };
+        Ok(Self {
+            format,
+            inner,
+            limits: Limits::default(),
+            decompressed: 0,
+        })
+    }
+
+    /// Starts decompressing `inner`, detecting its format from its first few bytes with
+    /// [`Format::detect`].
+    ///
+    /// Returns an `InvalidData` error if the format is not recognized, and an `Unsupported`
+    /// error if its feature is not enabled.
+    pub fn detect(mut inner: R) -> io::Result<Self> {
+        let format = Format::detect(inner.fill_buf()?).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unrecognized compression format",
+            )
+        })?;
+        Self::new(format, inner)
+    }
+
+    /// Fail with an `InvalidData` error rather than decompress past `limits`.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        #[cfg(feature = "xz")]
+        if let DecoderInner::Xz(ref mut inner) = self.inner {
+            inner.memlimit = limits.max_memory;
+        }
+        self.limits = limits;
+        self
+    }
+
+    /// Returns the format being decompressed.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns the number of compressed bytes consumed so far.
+    pub fn compressed(&self) -> u64 {
+        match self.inner {
+            DecoderInner::Uncompressed(ref inner) => inner.count,
+            #[cfg(feature = "gzip")]
+            DecoderInner::Gzip(ref inner) => inner.get_ref().count,
+            #[cfg(feature = "gzip")]
+            DecoderInner::GzipSingle(ref inner) => inner.get_ref().count,
+            #[cfg(feature = "zstd")]
+            DecoderInner::Zstd(ref inner) => inner.get_ref().count,
+            #[cfg(feature = "xz")]
+            DecoderInner::Xz(ref inner) => inner.inner.count,
+            #[cfg(feature = "lz4")]
+            DecoderInner::Lz4(ref inner) => inner.get_ref().count,
+            #[cfg(feature = "lz4")]
+            DecoderInner::Lz4Legacy(ref inner) => inner.get_ref().count,
+            #[cfg(feature = "bzip2")]
+            DecoderInner::Bzip2(ref inner) => inner.get_ref().count,
+            #[cfg(feature = "bzip2")]
+            DecoderInner::Bzip2Single(ref inner) => inner.get_ref().count,
+        }
+    }
+
+    /// Returns the number of bytes decompressed so far.
+    pub fn decompressed(&self) -> u64 {
+        self.decompressed
+    }
+
+    /// Returns the underlying reader, positioned wherever decompression has reached.
+    pub fn into_inner(self) -> R {
+        // Only one arm remains when no compression features are enabled.
+        #[allow(clippy::infallible_destructuring_match)]
+        let counted = match self.inner {
+            DecoderInner::Uncompressed(inner) => inner,
+            #[cfg(feature = "gzip")]
+            DecoderInner::Gzip(inner) => inner.into_inner(),
+            #[cfg(feature = "gzip")]
+            DecoderInner::GzipSingle(inner) => inner.into_inner(),
+            #[cfg(feature = "zstd")]
+            DecoderInner::Zstd(inner) => inner.finish(),
+            #[cfg(feature = "xz")]
+            DecoderInner::Xz(inner) => inner.inner,
+            #[cfg(feature = "lz4")]
+            DecoderInner::Lz4(inner) => inner.into_inner(),
+            #[cfg(feature = "lz4")]
+            DecoderInner::Lz4Legacy(inner) => inner.into_inner(),
+            #[cfg(feature = "bzip2")]
+            DecoderInner::Bzip2(inner) => inner.into_inner(),
+            #[cfg(feature = "bzip2")]
+            DecoderInner::Bzip2Single(inner) => inner.into_inner(),
+        };
+        counted.inner
+    }
+
+    /// Reads and discards the rest of the decompressed data, verifying the end of the
+    /// compressed stream, and returns the underlying reader.
+    pub fn finish(mut self) -> io::Result<R> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(self.into_inner())
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.inner {
+            DecoderInner::Uncompressed(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "gzip")]
+            DecoderInner::Gzip(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "gzip")]
+            DecoderInner::GzipSingle(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "zstd")]
+            DecoderInner::Zstd(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "xz")]
+            DecoderInner::Xz(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "lz4")]
+            DecoderInner::Lz4(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "lz4")]
+            DecoderInner::Lz4Legacy(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "bzip2")]
+            DecoderInner::Bzip2(ref mut inner) => inner.read(buf),
+            #[cfg(feature = "bzip2")]
+            DecoderInner::Bzip2Single(ref mut inner) => inner.read(buf),
+        }?;
+        self.decompressed += n as u64;
+        self.limits.check(self.decompressed, self.compressed())?;
+        Ok(n)
+    }
+}
+
+/// Decompresses an xz or lzma stream.
+///
+/// Unlike `xz2::bufread::XzDecoder`, which reports any data after the end of the stream as
+/// corrupt, this returns end of file once the stream has ended, leaving whatever follows
+/// unread.
+#[cfg(feature = "xz")]
+struct XzDecoder<R> {
+    inner: R,
+    stream: xz2::stream::Stream,
+    /// The memory limit to set on the stream before it is next processed.
+    memlimit: Option<u64>,
+    done: bool,
+}
+
+#[cfg(feature = "xz")]
+impl<R: BufRead> XzDecoder<R> {
+    fn new(inner: R, stream: xz2::stream::Stream) -> Self {
+        Self {
+            inner,
+            stream,
+            memlimit: None,
+            done: false,
+        }
+    }
+}
+
+#[cfg(feature = "xz")]
+impl<R: BufRead> Read for XzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use xz2::stream::{Action, Error, Status};
+
+        let memory_error = |err| match err {
+            Error::MemLimit => {
+                io::Error::new(io::ErrorKind::InvalidData, "decoder memory exceeds limit")
+            }
+            err => io::Error::from(err),
+        };
+        if let Some(limit) = self.memlimit.take() {
+            self.stream.set_memlimit(limit).map_err(memory_error)?;
+        }
+        while !self.done && !buf.is_empty() {
+            let input = self.inner.fill_buf()?;
+            let eof = input.is_empty();
+            let (before_in, before_out) = (self.stream.total_in(), self.stream.total_out());
+            let action = if eof { Action::Finish } else { Action::Run };
+            let status = self.stream.process(input, buf, action);
+            let consumed = (self.stream.total_in() - before_in) as usize;
+            let read = (self.stream.total_out() - before_out) as usize;
+            self.inner.consume(consumed);
+
+            self.done = status.map_err(memory_error)? == Status::StreamEnd;
+            if read > 0 {
+                return Ok(read);
+            }
+            if !self.done && (eof || consumed == 0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "xz stream ended early",
+                ));
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NewcBuilder, NewcReader};
+
+    fn roundtrip(format: Format) {
+        let encoder = Encoder::new(format, vec![]).unwrap();
+        let (encoder, _) = crate::write_cpio_bytes(
+            vec![(NewcBuilder::new("./hello"), &b"Hello, World"[..])],
+            encoder,
+        )
+        .unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let decoder = Decoder::detect(compressed.as_slice()).unwrap();
+        assert_eq!(decoder.format(), format);
+        let reader = NewcReader::new(decoder).unwrap();
+        assert_eq!(reader.entry().name(), "./hello");
+        let mut contents = vec![];
+        let decoder = reader.to_writer(&mut contents).unwrap();
+        assert_eq!(contents, b"Hello, World");
+        let reader = NewcReader::new(decoder).unwrap();
+        assert!(reader.entry().is_trailer());
+        let rest = reader.finish().unwrap().finish().unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_uncompressed() {
+        roundtrip(Format::Uncompressed);
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            Format::detect(b"07070100000000"),
+            Some(Format::Uncompressed)
+        );
+        assert_eq!(Format::detect(b"\x1f\x8b\x08\x00"), Some(Format::Gzip));
+        assert_eq!(Format::detect(b"\xfd7zXZ\x00\x00"), Some(Format::Xz));
+        assert_eq!(Format::detect(b"\x02\x21\x4c\x18"), Some(Format::Lz4Legacy));
+        assert_eq!(Format::detect(b"BZh91AY&SY"), Some(Format::Bzip2));
+        assert_eq!(Format::detect(b"\x1f"), None);
+        assert_eq!(Format::detect(b"PK\x03\x04"), None);
+
+        let (archive, _) =
+            crate::write_cpio_bytes(vec![(NewcBuilder::new("./a"), &b"a"[..])], vec![]).unwrap();
+        let decoder = Decoder::detect(archive.as_slice()).unwrap();
+        assert_eq!(decoder.format(), Format::Uncompressed);
+        let err = Decoder::detect(&b"PK\x03\x04"[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_gzip() {
+        roundtrip(Format::Gzip);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_zstd() {
+        roundtrip(Format::Zstd);
+
+        let encoder = Encoder::zstd_multithreaded(3, 2, vec![]).unwrap();
+        let (encoder, _) = crate::write_cpio_bytes(
+            vec![(NewcBuilder::new("./zeros"), vec![0u8; 1 << 20])],
+            encoder,
+        )
+        .unwrap();
+        let compressed = encoder.finish().unwrap();
+        let decoder = Decoder::new(Format::Zstd, compressed.as_slice()).unwrap();
+        let reader = NewcReader::new(decoder).unwrap();
+        assert_eq!(reader.entry().file_size(), 1 << 20);
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_xz() {
+        roundtrip(Format::Xz);
+        roundtrip(Format::Lzma);
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn test_lz4() {
+        roundtrip(Format::Lz4);
+        roundtrip(Format::Lz4Legacy);
+
+        // A legacy stream holding more than one block, followed by a second stream.
+        let data: Vec<u8> = (0..(9 << 20)).map(|idx| (idx % 251) as u8).collect();
+        let mut compressed = vec![];
+        for _ in 0..2 {
+            let mut encoder = Encoder::new(Format::Lz4Legacy, compressed).unwrap();
+            encoder.write_all(&data).unwrap();
+            compressed = encoder.finish().unwrap();
+        }
+        let mut decompressed = vec![];
+        Decoder::new(Format::Lz4Legacy, compressed.as_slice())
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed.len(), data.len() * 2);
+        assert!(decompressed.chunks(data.len()).all(|chunk| chunk == data));
+    }
+
+    #[test]
+    #[cfg(feature = "bzip2")]
+    fn test_bzip2() {
+        roundtrip(Format::Bzip2);
+    }
+
+    #[test]
+    fn test_limits() {
+        let data = vec![0u8; 1 << 20];
+        let format = if cfg!(feature = "gzip") {
+            Format::Gzip
+        } else {
+            Format::Uncompressed
+        };
+        let mut encoder = Encoder::new(format, vec![]).unwrap();
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let read = |limits| {
+            let mut decoder = Decoder::new(format, compressed.as_slice())
+                .unwrap()
+                .limits(limits);
+            io::copy(&mut decoder, &mut io::sink())
+        };
+        assert_eq!(read(Limits::new()).unwrap(), data.len() as u64);
+        assert_eq!(
+            read(Limits::new().max_bytes(1 << 20)).unwrap(),
+            data.len() as u64
+        );
+        let err = read(Limits::new().max_bytes(1000)).unwrap_err();
+        assert_eq!(err.to_string(), "decompressed size exceeds limit");
+        if format != Format::Uncompressed {
+            let err = read(Limits::new().max_ratio(100)).unwrap_err();
+            assert_eq!(err.to_string(), "compression ratio exceeds limit");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "xz")]
+    fn test_memory_limit() {
+        let mut encoder = Encoder::new(Format::Xz, vec![]).unwrap();
+        encoder.write_all(b"Hello, World").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let read = |limits| {
+            let mut decoder = Decoder::new(Format::Xz, compressed.as_slice())
+                .unwrap()
+                .limits(limits);
+            io::copy(&mut decoder, &mut io::sink())
+        };
+        assert_eq!(read(Limits::new().max_memory(1 << 30)).unwrap(), 12);
+        let err = read(Limits::new().max_memory(1 << 16)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "decoder memory exceeds limit");
+    }
+
+    #[test]
+    #[cfg(not(feature = "gzip"))]
+    fn test_unsupported() {
+        let err = Decoder::new(Format::Gzip, &b""[..]).err().unwrap();
+        assert_eq!(err.to_string(), "gzip support requires the `gzip` feature");
+    }
+}
@@ -0,0 +1,127 @@
+//! Adding entries to compressed archives.
+
+use std::io::{self, BufRead, Write};
+
+use super::{Decoder, Encoder, Format};
+use crate::newc::{Builder, Reader};
+use crate::writer::{ArchiveWriter, Summary};
+
+/// Writes a new compressed segment holding the entries added by `append`, for adding to the
+/// end of an existing image.
+///
+/// The kernel unpacks each segment of an initramfs in turn, with later entries replacing
+/// earlier ones of the same name, so writing this onto the end of an existing image (for
+/// example by opening it in append mode) adds or overrides files without touching the rest.
+/// [`ImageReader`](super::ImageReader) reads the result.
+pub fn append_segment<W, F>(output: W, format: Format, append: F) -> io::Result<(W, Summary)>
+where
+    W: Write,
+    F: FnOnce(&mut ArchiveWriter<Encoder<W>>) -> io::Result<()>,
+{
+    let mut archive = ArchiveWriter::new(Encoder::new(format, output)?);
+    append(&mut archive)?;
+    let (encoder, summary) = archive.finish()?;
+    Ok((encoder.finish()?, summary))
+}
+
+/// Copies the compressed archive in `input` to `output`, adding the entries added by
+/// `append` before the trailer, and compressing the result in the same format as the input.
+///
+/// Unlike [`append_segment`], this produces a single archive, at the cost of decompressing
+/// and recompressing all of it. Existing entries keep their metadata and checksums.
+pub fn append_recompressed<R, W, F>(input: R, output: W, append: F) -> io::Result<(W, Summary)>
+where
+    R: BufRead,
+    W: Write,
+    F: FnOnce(&mut ArchiveWriter<Encoder<W>>) -> io::Result<()>,
+{
+    let mut decoder = Decoder::detect(input)?;
+    let mut archive = ArchiveWriter::new(Encoder::new(decoder.format(), output)?);
+    loop {
+        let mut reader = Reader::new(decoder)?;
+        if reader.entry().is_trailer() {
+            reader.finish()?.finish()?;
+            break;
+        }
+        let builder = Builder::from_entry(reader.entry());
+        let len = reader.entry().file_size();
+        match reader.entry().checksum() {
+            Some(checksum) => archive.append_crc(builder, len, checksum, &mut reader)?,
+            None => archive.append(builder, len, &mut reader)?,
+        }
+        decoder = reader.finish()?;
+    }
+    append(&mut archive)?;
+    let (encoder, summary) = archive.finish()?;
+    Ok((encoder.finish()?, summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::ImageReader;
+    use crate::NewcReader;
+
+    fn names<R: io::Read>(mut input: R) -> Vec<String> {
+        let mut names = vec![];
+        loop {
+            let reader = NewcReader::new(input).unwrap();
+            if reader.entry().is_trailer() {
+                reader.finish().unwrap();
+                return names;
+            }
+            names.push(reader.entry().name().to_string());
+            input = reader.finish().unwrap();
+        }
+    }
+
+    fn add_new(archive: &mut ArchiveWriter<Encoder<Vec<u8>>>) -> io::Result<()> {
+        archive.append_bytes(Builder::new("./new"), b"new")
+    }
+
+    #[test]
+    fn test_append_recompressed() {
+        let format = if cfg!(feature = "gzip") {
+            Format::Gzip
+        } else {
+            Format::Uncompressed
+        };
+        let (original, _) = append_segment(vec![], format, |archive| {
+            archive.append_bytes(Builder::new("./old"), b"old")
+        })
+        .unwrap();
+
+        let (appended, summary) =
+            append_recompressed(original.as_slice(), vec![], add_new).unwrap();
+        assert_eq!(summary.entries(), 2);
+        let decoder = Decoder::detect(appended.as_slice()).unwrap();
+        assert_eq!(decoder.format(), format);
+        assert_eq!(names(decoder), ["./old", "./new"]);
+    }
+
+    #[test]
+    fn test_append_segment() {
+        let format = if cfg!(feature = "zstd") {
+            Format::Zstd
+        } else {
+            Format::Uncompressed
+        };
+        let (mut image, _) =
+            crate::write_cpio_bytes(vec![(Builder::new("./old"), &b"old"[..])], vec![]).unwrap();
+        let (segment, _) = append_segment(vec![], format, add_new).unwrap();
+        image.extend(segment);
+
+        let mut reader = ImageReader::new(image.as_slice());
+        let mut archives = vec![];
+        while reader.next_archive().unwrap() {
+            archives.push((reader.format(), names(&mut reader)));
+        }
+        assert_eq!(
+            archives,
+            vec![
+                (Format::Uncompressed, vec!["./old".to_string()]),
+                (format, vec!["./new".to_string()]),
+            ]
+        );
+    }
+}
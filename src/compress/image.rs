@@ -0,0 +1,396 @@
+//! Images made of several concatenated, separately compressed archives.
+
+use std::io::{self, BufRead, BufReader, Read};
+
+use super::{Decoder, Format, Limits};
+
+/// The number of bytes [`Format::detect`] needs to recognize every format.
+const MAGIC_LEN: usize = 6;
+
+/// A reader that can look further ahead than the buffer of the reader it wraps, so that a
+/// magic number split across two fills of that buffer is still seen whole.
+pub(super) struct Peek<R> {
+    inner: R,
+    /// Bytes taken from `inner` but not yet consumed, from `pos` on.
+    ahead: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> Peek<R> {
+    pub(super) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            ahead: Vec::with_capacity(MAGIC_LEN),
+            pos: 0,
+        }
+    }
+
+    /// Returns the next bytes of input without consuming them: enough of them for
+    /// [`Format::detect`], or fewer only at the end of the input.
+    pub(super) fn peek_magic(&mut self) -> io::Result<&[u8]> {
+        self.ahead.drain(..self.pos);
+        self.pos = 0;
+        while self.ahead.len() < MAGIC_LEN {
+            let buf = self.inner.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let n = buf.len().min(MAGIC_LEN - self.ahead.len());
+            self.ahead.extend_from_slice(&buf[..n]);
+            self.inner.consume(n);
+        }
+        Ok(&self.ahead)
+    }
+}
+
+impl<R: BufRead> Read for Peek<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.ahead.len() {
+            return self.inner.read(buf);
+        }
+        let n = (&self.ahead[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Peek<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.ahead.len() {
+            return self.inner.fill_buf();
+        }
+        Ok(&self.ahead[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.pos == self.ahead.len() {
+            self.inner.consume(amt);
+        } else {
+            self.pos += amt;
+        }
+    }
+}
+
+/// The segment of the image currently being read.
+enum Segment<R: BufRead> {
+    /// Archives stored uncompressed, read straight from the image so that nothing past their
+    /// end is consumed.
+    Uncompressed(Peek<R>),
+    /// Archives inside a compressed stream. The buffer only ever holds decompressed data, so
+    /// the image is left just past the stream once it has been read to the end.
+    Compressed(Box<BufReader<Decoder<Peek<R>>>>),
+}
+
+/// Reads the archives in an image made of concatenated segments, each of which may be
+/// compressed differently, as the kernel does for an initramfs.
+///
+/// A typical initramfs is an uncompressed archive of CPU microcode followed by the main
+/// archive compressed with gzip, zstd, xz, or similar. Each segment holds one or more
+/// archives, and segments may be separated by zero padding. Reading the same image through a
+/// single [`Decoder`] stops at the first segment or fails on the second.
+///
+/// Call [`next_archive`](Self::next_archive) to move to each archive in turn and read its
+/// entries through this reader until the trailer:
+///
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// use std::fs::File;
+/// use std::io::BufReader;
+/// use cpio::compress::ImageReader;
+/// use cpio::NewcReader;
+///
+/// let mut image = ImageReader::new(BufReader::new(File::open("initrd.img")?));
+/// while image.next_archive()? {
+///     loop {
+///         let reader = NewcReader::new(&mut image)?;
+///         if reader.entry().is_trailer() {
+///             reader.finish()?;
+///             break;
+///         }
+///         println!("{}", reader.entry().name());
+///         reader.finish()?;
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Segments in a format whose feature is not enabled fail with an `Unsupported` error. As
+/// with the kernel, an lz4 segment extends to the end of the image.
+pub struct ImageReader<R: BufRead> {
+    segment: Option<Segment<R>>,
+    format: Format,
+    segments: usize,
+    limits: Limits,
+    /// Whether the last archive read was uncompressed, and so in the same segment as any
+    /// uncompressed archive that follows.
+    in_uncompressed: bool,
+}
+
+/// Consumes any zero bytes at the start of `reader`.
+pub(super) fn skip_zeros<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let zeros = buf.iter().take_while(|&&byte| byte == 0).count();
+        let done = zeros < buf.len();
+        reader.consume(zeros);
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+impl<R: BufRead> ImageReader<R> {
+    /// Starts reading the image in `inner`.
+    pub fn new(inner: R) -> Self {
+        Self {
+            segment: Some(Segment::Uncompressed(Peek::new(inner))),
+            format: Format::Uncompressed,
+            segments: 0,
+            limits: Limits::default(),
+            in_uncompressed: false,
+        }
+    }
+
+    /// Apply `limits` to the decompression of each compressed segment.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Moves to the start of the next archive in the image, skipping any padding after the
+    /// previous one and opening the next segment if the current one has ended. Returns
+    /// `false` at the end of the image.
+    ///
+    /// The previous archive must have been read through its trailer.
+    pub fn next_archive(&mut self) -> io::Result<bool> {
+        loop {
+            match self.segment.take() {
+                None => return Ok(false),
+                Some(Segment::Uncompressed(mut inner)) => {
+                    skip_zeros(&mut inner)?;
+                    let magic = inner.peek_magic()?;
+                    if magic.is_empty() {
+                        return Ok(false);
+                    }
+                    let format = Format::detect(magic).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Unrecognized data between archives",
+                        )
+                    })?;
+                    let uncompressed = format == Format::Uncompressed;
+                    if !(uncompressed && self.in_uncompressed) {
+                        self.segments += 1;
+                    }
+                    self.in_uncompressed = uncompressed;
+                    self.format = format;
+                    if uncompressed {
+                        self.segment = Some(Segment::Uncompressed(inner));
+                        return Ok(true);
+                    }
+                    let decoder = Decoder::single_stream(format, inner)?.limits(self.limits);
+                    self.segment = Some(Segment::Compressed(Box::new(BufReader::new(decoder))));
+                }
+                Some(Segment::Compressed(mut decoder)) => {
+                    skip_zeros(&mut decoder)?;
+                    if decoder.fill_buf()?.is_empty() {
+                        // The compressed stream has ended; carry on with what follows it.
+                        let inner = decoder.into_inner().into_inner();
+                        self.segment = Some(Segment::Uncompressed(inner));
+                        continue;
+                    }
+                    self.segment = Some(Segment::Compressed(decoder));
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Returns the compression format of the current segment.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns the number of segments reached so far, counting the current one.
+    pub fn segments(&self) -> usize {
+        self.segments
+    }
+}
+
+impl<R: BufRead> Read for ImageReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.segment {
+            None => Ok(0),
+            Some(Segment::Uncompressed(ref mut inner)) => inner.read(buf),
+            Some(Segment::Compressed(ref mut decoder)) => decoder.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NewcBuilder, NewcReader};
+
+    fn archive(names: &[&str]) -> Vec<u8> {
+        let inputs = names
+            .iter()
+            .map(|name| (NewcBuilder::new(name), name.as_bytes()));
+        crate::write_cpio_bytes(inputs, vec![]).unwrap().0
+    }
+
+    /// Returns the format and names of the entries of every archive in `image`.
+    fn walk(image: &mut ImageReader<&[u8]>) -> Vec<(Format, Vec<String>)> {
+        let mut archives = vec![];
+        while image.next_archive().unwrap() {
+            let mut names = vec![];
+            loop {
+                let reader = NewcReader::new(&mut *image).unwrap();
+                if reader.entry().is_trailer() {
+                    reader.finish().unwrap();
+                    break;
+                }
+                names.push(reader.entry().name().to_string());
+                reader.finish().unwrap();
+            }
+            archives.push((image.format(), names));
+        }
+        archives
+    }
+
+    #[test]
+    fn test_uncompressed_segments() {
+        let mut image = archive(&["kernel/x86/microcode/GenuineIntel.bin"]);
+        image.extend_from_slice(&[0u8; 512]);
+        image.extend(archive(&["init", "bin"]));
+
+        let mut reader = ImageReader::new(image.as_slice());
+        assert_eq!(
+            walk(&mut reader),
+            vec![
+                (
+                    Format::Uncompressed,
+                    vec!["kernel/x86/microcode/GenuineIntel.bin".to_string()]
+                ),
+                (
+                    Format::Uncompressed,
+                    vec!["init".to_string(), "bin".to_string()]
+                ),
+            ]
+        );
+
+        assert_eq!(reader.segments(), 1);
+
+        let err = ImageReader::new(&b"nonsense"[..])
+            .next_archive()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_split_magic() {
+        let mut image = archive(&["one"]);
+        image.extend_from_slice(&[0u8; 4]);
+        image.extend(archive(&["two"]));
+        #[cfg(feature = "gzip")]
+        {
+            use crate::compress::Encoder;
+            use std::io::Write;
+
+            let mut encoder = Encoder::new(Format::Gzip, vec![]).unwrap();
+            encoder.write_all(&archive(&["three"])).unwrap();
+            image.extend(encoder.finish().unwrap());
+        }
+
+        // Every fill of the buffer holds less than a magic number.
+        for capacity in 1..MAGIC_LEN {
+            let mut buffered = BufReader::with_capacity(capacity, image.as_slice());
+            let mut reader = ImageReader::new(&mut buffered);
+            let mut names = vec![];
+            while reader.next_archive().unwrap() {
+                loop {
+                    let entry = NewcReader::new(&mut reader).unwrap();
+                    if entry.entry().is_trailer() {
+                        entry.finish().unwrap();
+                        break;
+                    }
+                    names.push(entry.entry().name().to_string());
+                    entry.finish().unwrap();
+                }
+            }
+            let expected: &[&str] = if cfg!(feature = "gzip") {
+                &["one", "two", "three"]
+            } else {
+                &["one", "two"]
+            };
+            assert_eq!(names, expected);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "gzip", feature = "zstd"))]
+    fn test_mixed_segments() {
+        use crate::compress::Encoder;
+        use std::io::Write;
+
+        let compress = |format, data: &[u8]| {
+            let mut encoder = Encoder::new(format, vec![]).unwrap();
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let mut image = archive(&["microcode"]);
+        image.extend(compress(Format::Gzip, &archive(&["early"])));
+        image.extend_from_slice(&[0u8; 4]);
+        let mut two = archive(&["a"]);
+        two.extend_from_slice(&[0u8; 8]);
+        two.extend(archive(&["b"]));
+        image.extend(compress(Format::Zstd, &two));
+
+        let mut reader = ImageReader::new(image.as_slice());
+        assert_eq!(
+            walk(&mut reader),
+            vec![
+                (Format::Uncompressed, vec!["microcode".to_string()]),
+                (Format::Gzip, vec!["early".to_string()]),
+                (Format::Zstd, vec!["a".to_string()]),
+                (Format::Zstd, vec!["b".to_string()]),
+            ]
+        );
+        assert_eq!(reader.segments(), 3);
+    }
+
+    #[test]
+    fn test_segment_boundaries() {
+        use crate::compress::Encoder;
+        use std::io::Write;
+
+        let formats = [
+            (cfg!(feature = "gzip"), Format::Gzip),
+            (cfg!(feature = "zstd"), Format::Zstd),
+            (cfg!(feature = "xz"), Format::Xz),
+            (cfg!(feature = "xz"), Format::Lzma),
+            (cfg!(feature = "bzip2"), Format::Bzip2),
+        ];
+        for &(_, format) in formats.iter().filter(|(enabled, _)| *enabled) {
+            // Each compressed segment must end exactly where its stream does.
+            let mut encoder = Encoder::new(format, vec![]).unwrap();
+            encoder.write_all(&archive(&["compressed"])).unwrap();
+            let mut image = encoder.finish().unwrap();
+            image.extend(archive(&["after"]));
+
+            let mut reader = ImageReader::new(image.as_slice());
+            assert_eq!(
+                walk(&mut reader),
+                vec![
+                    (format, vec!["compressed".to_string()]),
+                    (Format::Uncompressed, vec!["after".to_string()]),
+                ]
+            );
+        }
+    }
+}
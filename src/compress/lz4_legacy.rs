@@ -0,0 +1,173 @@
+//! The legacy lz4 frame format, as produced by `lz4 -l` and understood by the kernel.
+//!
+//! A stream is a magic number followed by blocks, each a little-endian `u32` compressed size
+//! and then that many bytes of lz4 block data. Every block but the last decompresses to
+//! exactly 8 MiB. There is no end marker; a stream ends with its input, and another stream
+//! may follow directly, announced by its own magic number.
+
+use std::io::{self, Read, Write};
+
+/// Magic number that starts a legacy lz4 stream.
+pub(super) const MAGIC: u32 = 0x184c_2102;
+
+/// Uncompressed size of every block but the last.
+const BLOCK_SIZE: usize = 8 << 20;
+
+/// Compresses data written through it into a legacy lz4 stream.
+pub(super) struct Encoder<W: Write> {
+    inner: W,
+    block: Vec<u8>,
+    started: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    pub(super) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            block: vec![],
+            started: false,
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.inner.write_all(&MAGIC.to_le_bytes())?;
+            self.started = true;
+        }
+        if !self.block.is_empty() {
+            let compressed = lz4_flex::block::compress(&self.block);
+            self.inner
+                .write_all(&(compressed.len() as u32).to_le_bytes())?;
+            self.inner.write_all(&compressed)?;
+            self.block.clear();
+        }
+        Ok(())
+    }
+
+    /// Writes out the final, partial block, and returns the underlying writer.
+    pub(super) fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        Ok(self.inner)
+    }
+
+    pub(super) fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    /// Only flushes the underlying writer: every block but the last must be full, so a partial
+    /// block cannot be written out early.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decompresses a legacy lz4 stream, or several concatenated ones.
+pub(super) struct Decoder<R: Read> {
+    inner: R,
+    compressed: Vec<u8>,
+    block: Vec<u8>,
+    pos: usize,
+    started: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    pub(super) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            compressed: vec![],
+            block: vec![],
+            pos: 0,
+            started: false,
+        }
+    }
+
+    pub(super) fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub(super) fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads the next little-endian `u32`, or `None` at the end of the input.
+    fn read_u32(&mut self) -> io::Result<Option<u32>> {
+        let mut buf = [0u8; 4];
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.inner.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "lz4 stream ended within a block size",
+                    ))
+                }
+                Ok(n) => filled += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Some(u32::from_le_bytes(buf)))
+    }
+
+    /// Decompresses the next block, returning false at the end of the input.
+    fn next_block(&mut self) -> io::Result<bool> {
+        if !self.started {
+            if self.read_u32()? != Some(MAGIC) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid lz4 legacy magic number",
+                ));
+            }
+            self.started = true;
+        }
+        let size = loop {
+            match self.read_u32()? {
+                None => return Ok(false),
+                // Another stream follows.
+                Some(MAGIC) => continue,
+                Some(size) => break size as usize,
+            }
+        };
+        if size > lz4_flex::block::get_maximum_output_size(BLOCK_SIZE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "lz4 block size too large",
+            ));
+        }
+
+        self.compressed.resize(size, 0);
+        self.inner.read_exact(&mut self.compressed)?;
+        self.block.resize(BLOCK_SIZE, 0);
+        let n = lz4_flex::block::decompress_into(&self.compressed, &mut self.block)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        self.block.truncate(n);
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,253 @@
+//! Tables of the segments of concatenated images.
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::Range;
+
+use super::image::{skip_zeros, Peek};
+use super::{CountingReader, Decoder, Format, Limits};
+use crate::newc::Reader;
+
+/// One segment of an image, as found by [`segment_map`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageSegment {
+    range: Range<u64>,
+    format: Format,
+    archives: u64,
+    entries: u64,
+    trailer: bool,
+}
+
+impl ImageSegment {
+    /// Returns the bytes of the image the segment occupies, not counting any zero padding
+    /// around it.
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+
+    /// Returns the compression format of the segment.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns the number of archives in the segment.
+    pub fn archives(&self) -> u64 {
+        self.archives
+    }
+
+    /// Returns the number of entries in the segment's archives, not counting their trailers.
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Returns true if the last archive in the segment ends with a trailer.
+    pub fn trailer(&self) -> bool {
+        self.trailer
+    }
+}
+
+/// Scans the image in `image`, made of concatenated and possibly compressed segments as read
+/// by [`ImageReader`](super::ImageReader), and returns a table of its segments.
+///
+/// Consecutive uncompressed archives make up one segment, as do the archives inside one
+/// compressed stream. Each compressed segment is decompressed within `limits` to count its
+/// entries. A segment that ends early, or whose data can't be parsed, is reported with
+/// [`trailer`](ImageSegment::trailer) false and ends the table, as where anything after it
+/// starts can't be found. Data between segments that is neither padding nor a known format
+/// gives an `InvalidData` error, and a segment in a format whose feature is not enabled an
+/// `Unsupported` one.
+pub fn segment_map<R: BufRead>(image: R, limits: Limits) -> io::Result<Vec<ImageSegment>> {
+    // Bytes are counted as they are consumed from the lookahead, not as it takes them.
+    let mut image = CountingReader {
+        inner: Peek::new(image),
+        count: 0,
+    };
+    let mut segments = vec![];
+    loop {
+        skip_zeros(&mut image)?;
+        let magic = image.inner.peek_magic()?;
+        if magic.is_empty() {
+            return Ok(segments);
+        }
+        let format = Format::detect(magic).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unrecognized data between archives",
+            )
+        })?;
+        let start = image.count;
+        let mut segment = ImageSegment {
+            range: start..start,
+            format,
+            archives: 0,
+            entries: 0,
+            trailer: false,
+        };
+
+        let complete = if format == Format::Uncompressed {
+            loop {
+                let complete = scan_archive(&mut image, &mut segment)?;
+                segment.range.end = image.count;
+                if !complete {
+                    break false;
+                }
+                skip_zeros(&mut image)?;
+                if Format::detect(image.inner.peek_magic()?) != Some(Format::Uncompressed) {
+                    break true;
+                }
+            }
+        } else {
+            let decoder = Decoder::single_stream(format, &mut image)?.limits(limits);
+            let mut decoder = BufReader::new(decoder);
+            let complete = loop {
+                skip_zeros(&mut decoder)?;
+                if decoder.fill_buf()?.is_empty() {
+                    break true;
+                }
+                if !scan_archive(&mut decoder, &mut segment)? {
+                    // Carry on past the stream if it is intact despite the archive inside.
+                    break incomplete(io::copy(&mut decoder, &mut io::sink()))?.is_some();
+                }
+            };
+            drop(decoder);
+            segment.range.end = image.count;
+            complete
+        };
+        segments.push(segment);
+        if !complete {
+            return Ok(segments);
+        }
+    }
+}
+
+/// Maps the errors of data that ends early or is malformed to `None`.
+fn incomplete<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ref err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads one archive from `reader` through its trailer, counting it and its entries in
+/// `segment`. Returns false if the archive ends early or can't be parsed.
+fn scan_archive<R: Read>(mut reader: R, segment: &mut ImageSegment) -> io::Result<bool> {
+    segment.archives += 1;
+    segment.trailer = false;
+    loop {
+        let entry = match incomplete(Reader::new(&mut reader))? {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let trailer = entry.entry().is_trailer();
+        if incomplete(entry.finish())?.is_none() {
+            return Ok(false);
+        }
+        if trailer {
+            segment.trailer = true;
+            return Ok(true);
+        }
+        segment.entries += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcBuilder;
+
+    fn archive(names: &[&str]) -> Vec<u8> {
+        let inputs = names
+            .iter()
+            .map(|name| (NewcBuilder::new(name), name.as_bytes()));
+        crate::write_cpio_bytes(inputs, vec![]).unwrap().0
+    }
+
+    #[test]
+    fn test_uncompressed() {
+        let one = archive(&["a", "b"]);
+        let two = archive(&["c"]);
+        let mut image = one.clone();
+        image.extend_from_slice(&[0; 16]);
+        image.extend_from_slice(&two);
+        let end = image.len() as u64;
+
+        let segments = segment_map(image.as_slice(), Limits::default()).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].range(), 0..end);
+        assert_eq!(segments[0].format(), Format::Uncompressed);
+        assert_eq!(segments[0].archives(), 2);
+        assert_eq!(segments[0].entries(), 3);
+        assert!(segments[0].trailer());
+
+        // A truncated archive ends the table.
+        image.extend_from_slice(&one[..one.len() - 20]);
+        let segments = segment_map(image.as_slice(), Limits::default()).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].archives(), 3);
+        assert!(!segments[0].trailer());
+
+        assert!(segment_map(&b""[..], Limits::default()).unwrap().is_empty());
+
+        // Magic numbers split across fills of the buffer are still recognized.
+        let mut image = one.clone();
+        image.extend_from_slice(&[0; 16]);
+        image.extend_from_slice(&two);
+        for capacity in 1..6 {
+            let buffered = BufReader::with_capacity(capacity, image.as_slice());
+            let segments = segment_map(buffered, Limits::default()).unwrap();
+            assert_eq!(segments.len(), 1);
+            assert_eq!(segments[0].range(), 0..end);
+            assert_eq!(segments[0].archives(), 2);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compressed() {
+        use crate::compress::Encoder;
+        use std::io::Write;
+
+        let mut image = archive(&["microcode"]);
+        let microcode = image.len() as u64;
+        image.extend_from_slice(&[0; 4]);
+        let mut encoder = Encoder::new(Format::Gzip, vec![]).unwrap();
+        encoder
+            .write_all(&archive(&["init", "bin", "etc"]))
+            .unwrap();
+        let compressed = encoder.finish().unwrap();
+        image.extend_from_slice(&compressed);
+        let end = image.len() as u64;
+
+        let segments = segment_map(image.as_slice(), Limits::default()).unwrap();
+        assert_eq!(segments.len(), 2);
+        let buffered = BufReader::with_capacity(3, image.as_slice());
+        assert_eq!(segment_map(buffered, Limits::default()).unwrap(), segments);
+        assert_eq!(segments[0].range(), 0..microcode);
+        assert_eq!(segments[0].entries(), 1);
+        assert_eq!(segments[1].range(), microcode + 4..end);
+        assert_eq!(segments[1].format(), Format::Gzip);
+        assert_eq!(segments[1].entries(), 3);
+        assert!(segments[1].trailer());
+
+        // An archive without a trailer inside an intact stream doesn't stop the scan.
+        let mut encoder = Encoder::new(Format::Gzip, vec![]).unwrap();
+        let unfinished = archive(&["x"]);
+        encoder
+            .write_all(&unfinished[..unfinished.len() - 124])
+            .unwrap();
+        let mut image = encoder.finish().unwrap();
+        image.extend(archive(&["after"]));
+        let segments = segment_map(image.as_slice(), Limits::default()).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].entries(), 1);
+        assert!(!segments[0].trailer());
+        assert!(segments[1].trailer());
+    }
+}
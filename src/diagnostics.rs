@@ -0,0 +1,139 @@
+//! Non-fatal problems found while reading or writing archives.
+//!
+//! Some oddities in an archive don't stop it from being read, but are worth surfacing to
+//! anyone auditing it. A [`Diagnostics`] sink passed to
+//! [`Reader::with_diagnostics`](crate::NewcReader::with_diagnostics) collects a [`Warning`]
+//! for each one it finds, and one passed to
+//! [`ArchiveWriter::inode_collisions`](crate::ArchiveWriter::inode_collisions) or
+//! [`ArchiveWriter::non_portable_names`](crate::ArchiveWriter::non_portable_names) does the
+//! same for suspicious entries as they are written. One passed to
+//! [`ArchiveWriter::preallocate`](crate::ArchiveWriter::preallocate) collects a warning if the
+//! archive written differs in size from the space reserved for it.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::name::Portability;
+
+/// A condition in an archive that is unusual, but does not prevent reading it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// The entry's mode is zero, so it has neither a file type nor any permissions.
+    ZeroMode { name: String },
+    /// The entry's mode has permissions, but none of the known file types.
+    MissingFileType { name: String, mode: u32 },
+    /// The padding after the entry's name or data holds bytes other than zero.
+    NonzeroPadding { name: String },
+    /// The entry is in the "new ascii" format, which has no checksum, but its checksum field
+    /// is not zero.
+    UnexpectedChecksum { name: String, checksum: u32 },
+    /// The entry's link count is zero, which no file on disk can have.
+    ZeroNlink { name: String },
+    /// The entry's modification time is later than the time it was read, which usually means
+    /// the archiver wrapped a time the format couldn't hold, or the field is garbage.
+    FutureMtime { name: String, mtime: u32 },
+    /// The entry was written with the same device and inode numbers as the entry called
+    /// `other`, but doesn't look like a hard link to it.
+    InodeCollision { name: String, other: String },
+    /// The entry was written with a name that may not unpack as it is on other systems.
+    NonPortableName { problem: Portability },
+    /// The archive was preallocated for `predicted` bytes, but is `actual` bytes long.
+    SizeMismatch { predicted: u64, actual: u64 },
+}
+
+impl Warning {
+    /// Returns the name of the entry the warning is about, or an empty string for warnings about
+    /// the whole archive.
+    pub fn name(&self) -> &str {
+        match *self {
+            Warning::ZeroMode { ref name }
+            | Warning::MissingFileType { ref name, .. }
+            | Warning::NonzeroPadding { ref name }
+            | Warning::UnexpectedChecksum { ref name, .. }
+            | Warning::ZeroNlink { ref name }
+            | Warning::FutureMtime { ref name, .. }
+            | Warning::InodeCollision { ref name, .. } => name,
+            Warning::NonPortableName { ref problem } => problem.name(),
+            Warning::SizeMismatch { .. } => "",
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Warning::ZeroMode { ref name } => write!(f, "{}: mode is zero", name),
+            Warning::MissingFileType { ref name, mode } => {
+                write!(f, "{}: mode {:#o} has no file type", name, mode)
+            }
+            Warning::NonzeroPadding { ref name } => write!(f, "{}: padding is not zero", name),
+            Warning::UnexpectedChecksum { ref name, checksum } => write!(
+                f,
+                "{}: checksum {:08x} in an entry without checksums",
+                name, checksum
+            ),
+            Warning::ZeroNlink { ref name } => write!(f, "{}: link count is zero", name),
+            Warning::FutureMtime { ref name, mtime } => {
+                write!(f, "{}: modification time {} is in the future", name, mtime)
+            }
+            Warning::InodeCollision {
+                ref name,
+                ref other,
+            } => write!(
+                f,
+                "{}: shares an inode with {} but is not a hard link",
+                name, other
+            ),
+            Warning::NonPortableName { ref problem } => write!(f, "{}", problem),
+            Warning::SizeMismatch { predicted, actual } => write!(
+                f,
+                "archive is {} bytes, but {} were preallocated",
+                actual, predicted
+            ),
+        }
+    }
+}
+
+/// A shared sink that accumulates warnings.
+///
+/// Clones of a `Diagnostics` share the same warnings, so one sink can be handed to the reader
+/// of every entry in an archive and inspected afterwards.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics {
+    warnings: Arc<Mutex<Vec<Warning>>>,
+}
+
+impl Diagnostics {
+    /// Creates an empty sink.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the warnings collected so far.
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.lock().clone()
+    }
+
+    /// Removes and returns the warnings collected so far.
+    pub fn take(&self) -> Vec<Warning> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    /// Returns true if no warnings have been collected.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub(crate) fn push(&self, warning: Warning) {
+        trace_event!(warning = %warning, "archive warning");
+        self.lock().push(warning);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Warning>> {
+        // A panic while holding the lock can't leave the list inconsistent.
+        self.warnings
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
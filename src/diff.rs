@@ -0,0 +1,324 @@
+//! Comparing two archives as they stream past.
+//!
+//! [`diff`] walks two archives side by side, reading each once from start to end, so that
+//! archives of any size can be compared straight from pipes or decompressors without holding
+//! either in memory. It relies on both archives listing their entries sorted by name, as
+//! archives created from a sorted directory walk (such as by
+//! [`TreeArchiver`](crate::tree::TreeArchiver)) do; entries with the same name are then read
+//! at the same time, and their data compared byte for byte.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::newc::{Entry, Reader};
+
+/// Size of the chunks in which the data of entries with the same name is compared.
+const COMPARE_CHUNK_SIZE: usize = 8 * 1024;
+
+/// A difference between two archives, found by [`diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Change {
+    /// The entry is only in the second archive.
+    Added { name: String },
+    /// The entry is only in the first archive.
+    Removed { name: String },
+    /// The entry is in both archives, but differs. `fields` names the header fields that
+    /// differ, and `data` is true if the file data does.
+    ///
+    /// Inode and device numbers, which depend on the disk the files were archived from, and
+    /// checksums, which follow from the data, are not compared.
+    Changed {
+        name: String,
+        fields: Vec<&'static str>,
+        data: bool,
+    },
+}
+
+impl Change {
+    /// Returns the name of the entry that changed.
+    pub fn name(&self) -> &str {
+        match *self {
+            Change::Added { ref name }
+            | Change::Removed { ref name }
+            | Change::Changed { ref name, .. } => name,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Change::Added { ref name } => write!(f, "+ {}", name),
+            Change::Removed { ref name } => write!(f, "- {}", name),
+            Change::Changed {
+                ref name,
+                ref fields,
+                data,
+            } => {
+                let mut what = fields.clone();
+                if data {
+                    what.push("data");
+                }
+                write!(f, "~ {} ({})", name, what.join(", "))
+            }
+        }
+    }
+}
+
+/// One of the archives being compared.
+struct Side<R: Read> {
+    /// The entry the archive is positioned at, or `None` once it has ended.
+    reader: Option<Reader<R>>,
+    /// The name of the entry before the current one, to check the archive is sorted.
+    last: Option<String>,
+}
+
+impl<R: Read> Side<R> {
+    fn new(archive: R) -> io::Result<Self> {
+        let mut side = Side {
+            reader: None,
+            last: None,
+        };
+        side.read(archive)?;
+        Ok(side)
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.reader.as_ref().map(|reader| reader.entry().name())
+    }
+
+    /// Moves on to the entry after the current one.
+    fn advance(&mut self) -> io::Result<()> {
+        if let Some(reader) = self.reader.take() {
+            self.last = Some(reader.entry().name().to_string());
+            let archive = reader.finish()?;
+            self.read(archive)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the header of the next entry from `archive`.
+    fn read(&mut self, archive: R) -> io::Result<()> {
+        let reader = Reader::new(archive)?;
+        if reader.entry().is_trailer() {
+            reader.finish()?;
+            return Ok(());
+        }
+        if let Some(ref last) = self.last {
+            if compare_names(reader.entry().name(), last) != Ordering::Greater {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Archive is not sorted by name: {} follows {}",
+                        reader.entry().name(),
+                        last
+                    ),
+                ));
+            }
+        }
+        self.reader = Some(reader);
+        Ok(())
+    }
+}
+
+/// An iterator over the differences between two archives, returned by [`diff`].
+pub struct Diff<A: Read, B: Read> {
+    a: Side<A>,
+    b: Side<B>,
+    failed: bool,
+}
+
+/// Compares the archive `a` with the archive `b`, yielding the changes that turn `a` into
+/// `b` in order of name.
+///
+/// Both archives are read header by header as the changes are iterated over, so memory use
+/// is bounded whatever their size. Each must list its entries sorted by name, comparing the
+/// `/`-separated components of names in byte order, each name once; an archive that does not
+/// yields an `InvalidData` error when the entry out of order is reached, after which the
+/// iterator ends.
+pub fn diff<A: Read, B: Read>(a: A, b: B) -> io::Result<Diff<A, B>> {
+    Ok(Diff {
+        a: Side::new(a)?,
+        b: Side::new(b)?,
+        failed: false,
+    })
+}
+
+impl<A: Read, B: Read> Diff<A, B> {
+    fn step(&mut self) -> io::Result<Option<Change>> {
+        loop {
+            match (self.a.name(), self.b.name()) {
+                (None, None) => return Ok(None),
+                (Some(a), b) if b.map_or(true, |b| compare_names(a, b) == Ordering::Less) => {
+                    let name = a.to_string();
+                    self.a.advance()?;
+                    return Ok(Some(Change::Removed { name }));
+                }
+                (a, Some(b)) if a.map_or(true, |a| compare_names(b, a) == Ordering::Less) => {
+                    let name = b.to_string();
+                    self.b.advance()?;
+                    return Ok(Some(Change::Added { name }));
+                }
+                _ => {}
+            }
+
+            let a = self.a.reader.as_mut().expect("both archives have an entry");
+            let b = self.b.reader.as_mut().expect("both archives have an entry");
+            let fields = changed_fields(a.entry(), b.entry());
+            let data = if a.entry().file_size() == b.entry().file_size() {
+                !same_data(a, b)?
+            } else {
+                true
+            };
+            let name = a.entry().name().to_string();
+            self.a.advance()?;
+            self.b.advance()?;
+            if !fields.is_empty() || data {
+                return Ok(Some(Change::Changed { name, fields, data }));
+            }
+        }
+    }
+}
+
+impl<A: Read, B: Read> Iterator for Diff<A, B> {
+    type Item = io::Result<Change>;
+
+    fn next(&mut self) -> Option<io::Result<Change>> {
+        if self.failed {
+            return None;
+        }
+        let change = self.step();
+        self.failed = change.is_err();
+        change.transpose()
+    }
+}
+
+/// Orders entry names component by component, as a sorted directory walk produces them, so
+/// that a directory's contents come straight after it.
+fn compare_names(a: &str, b: &str) -> Ordering {
+    a.split('/').cmp(b.split('/'))
+}
+
+/// Returns the names of the compared header fields that differ between `a` and `b`.
+fn changed_fields(a: &Entry, b: &Entry) -> Vec<&'static str> {
+    let fields = [
+        ("mode", a.mode() == b.mode()),
+        ("uid", a.uid() == b.uid()),
+        ("gid", a.gid() == b.gid()),
+        ("nlink", a.nlink() == b.nlink()),
+        ("mtime", a.mtime() == b.mtime()),
+        ("file_size", a.file_size() == b.file_size()),
+        ("rdev_major", a.rdev_major() == b.rdev_major()),
+        ("rdev_minor", a.rdev_minor() == b.rdev_minor()),
+    ];
+    fields
+        .iter()
+        .filter(|(_, same)| !same)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Returns true if the data of two entries of the same size is the same.
+fn same_data<A: Read, B: Read>(a: &mut Reader<A>, b: &mut Reader<B>) -> io::Result<bool> {
+    let mut remaining = a.entry().file_size() as usize;
+    let mut buf_a = vec![0; COMPARE_CHUNK_SIZE.min(remaining)];
+    let mut buf_b = buf_a.clone();
+    while remaining > 0 {
+        let len = remaining.min(buf_a.len());
+        a.read_exact(&mut buf_a[..len])?;
+        b.read_exact(&mut buf_b[..len])?;
+        if buf_a[..len] != buf_b[..len] {
+            return Ok(false);
+        }
+        remaining -= len;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcBuilder;
+
+    fn archive(entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let inputs = entries
+            .iter()
+            .map(|&(name, mode, data)| (NewcBuilder::new(name).mode(mode), data));
+        crate::write_cpio_bytes(inputs, vec![]).unwrap().0
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = archive(&[
+            ("bin", 0o040755, b""),
+            ("bin/sh", 0o100755, b"shell"),
+            ("etc", 0o040755, b""),
+            ("etc/hosts", 0o100644, b"127.0.0.1"),
+            ("init", 0o100755, b"#!/bin/sh"),
+        ]);
+        let b = archive(&[
+            ("bin", 0o040755, b""),
+            ("bin/sh", 0o100755, b"shelL"),
+            ("dev", 0o040755, b""),
+            ("etc", 0o040700, b""),
+            ("init", 0o100755, b"#!/bin/sh -e"),
+        ]);
+
+        let changes: Vec<Change> = diff(a.as_slice(), b.as_slice())
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let name = |name: &str| name.to_string();
+        assert_eq!(
+            changes,
+            vec![
+                Change::Changed {
+                    name: name("bin/sh"),
+                    fields: vec![],
+                    data: true,
+                },
+                Change::Added { name: name("dev") },
+                Change::Changed {
+                    name: name("etc"),
+                    fields: vec!["mode"],
+                    data: false,
+                },
+                Change::Removed {
+                    name: name("etc/hosts")
+                },
+                Change::Changed {
+                    name: name("init"),
+                    fields: vec!["file_size"],
+                    data: true,
+                },
+            ]
+        );
+        assert_eq!(changes[4].to_string(), "~ init (file_size, data)");
+
+        assert_eq!(diff(a.as_slice(), a.as_slice()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_diff_unsorted() {
+        // Contents of a directory sort before its siblings.
+        let a = archive(&[
+            ("a", 0o040755, b""),
+            ("a/b", 0o100644, b""),
+            ("a-b", 0o100644, b""),
+        ]);
+        assert_eq!(diff(a.as_slice(), a.as_slice()).unwrap().count(), 0);
+
+        let a = archive(&[("b", 0o100644, b""), ("a", 0o100644, b"")]);
+        let b = archive(&[("b", 0o100644, b"")]);
+        let mut changes = diff(a.as_slice(), b.as_slice()).unwrap();
+        let err = changes.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Archive is not sorted by name: a follows b"
+        );
+        assert!(changes.next().is_none());
+    }
+}
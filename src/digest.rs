@@ -0,0 +1,99 @@
+//! Content digests of entry data, computed as the data streams past.
+//!
+//! This module is only available with the `digest` feature. It works with any
+//! [`Digest`](digest::Digest) implementation; with the `sha2` feature, [`Sha256`] is
+//! re-exported for convenience.
+
+use std::io::{self, Read, Write};
+
+use digest::{Digest, Output};
+
+use crate::newc::Reader;
+
+#[cfg(feature = "sha2")]
+pub use sha2::Sha256;
+
+/// A reader that feeds everything read through it into a digest.
+pub struct DigestReader<R, D> {
+    inner: R,
+    digest: D,
+}
+
+impl<R: Read, D: Digest> DigestReader<R, D> {
+    /// Wraps `inner`, hashing its data with a fresh `D`.
+    pub fn new(inner: R) -> Self {
+        Self::with_digest(inner, D::new())
+    }
+
+    /// Wraps `inner`, continuing to hash its data into `digest`.
+    pub fn with_digest(inner: R, digest: D) -> Self {
+        Self { inner, digest }
+    }
+
+    /// Returns the digest of the data read so far, along with the underlying reader.
+    pub fn finalize(self) -> (R, Output<D>) {
+        (self.inner, self.digest.finalize())
+    }
+}
+
+impl<R: Read, D: Digest> Read for DigestReader<R, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<R: Read> Reader<R> {
+    /// Like [`to_writer`](Self::to_writer), but also returns the `D` digest of the data
+    /// copied, so content hashes can be taken without a second pass over the data.
+    ///
+    /// Only data not already read through the `Read` interface is hashed.
+    pub fn to_writer_digest<D: Digest, W: Write>(
+        mut self,
+        mut writer: W,
+    ) -> io::Result<(R, Output<D>)> {
+        let mut reader = DigestReader::<_, D>::new(&mut self);
+        io::copy(&mut reader, &mut writer)?;
+        let (_, digest) = reader.finalize();
+        Ok((self.finish()?, digest))
+    }
+
+    /// Finishes reading this entry like [`finish`](Self::finish), returning the `D` digest of
+    /// the remaining data.
+    pub fn finish_digest<D: Digest>(self) -> io::Result<(R, Output<D>)> {
+        self.to_writer_digest::<D, _>(io::sink())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newc::Builder;
+
+    #[test]
+    fn test_entry_digests() {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (Builder::new("./a"), &b"Hello, World"[..]),
+                (Builder::new("./b"), &b""[..]),
+            ],
+            vec![],
+        )
+        .unwrap();
+
+        let mut copied = vec![];
+        let reader = Reader::new(archive.as_slice()).unwrap();
+        let (rest, digest) = reader
+            .to_writer_digest::<sha2::Sha256, _>(&mut copied)
+            .unwrap();
+        assert_eq!(copied, b"Hello, World");
+        assert_eq!(digest, sha2::Sha256::digest(b"Hello, World"));
+
+        let reader = Reader::new(rest).unwrap();
+        assert_eq!(reader.entry().name(), "./b");
+        let (rest, digest) = reader.finish_digest::<sha2::Sha256>().unwrap();
+        assert_eq!(digest, sha2::Sha256::digest(b""));
+        assert!(Reader::new(rest).unwrap().entry().is_trailer());
+    }
+}
@@ -0,0 +1,297 @@
+//! Authenticated encryption of whole archive streams.
+//!
+//! This module is only available with the `encrypt` feature. [`EncryptWriter`] wraps any
+//! sink, such as the one an [`ArchiveWriter`](crate::ArchiveWriter) writes to, and
+//! [`DecryptReader`] any source, so that decrypted data feeds straight into a
+//! [`Reader`](crate::NewcReader):
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! use cpio::encrypt::{self, DecryptReader, EncryptWriter};
+//! use cpio::{ArchiveWriter, NewcBuilder, NewcReader};
+//!
+//! let key = encrypt::generate_key();
+//! let mut archive = ArchiveWriter::new(EncryptWriter::new(vec![], &key)?);
+//! archive.append_bytes(NewcBuilder::new("firmware.bin").mode(0o100644), b"\x7fELF")?;
+//! let (sink, _) = archive.finish()?;
+//! let encrypted = sink.finish()?;
+//!
+//! let reader = NewcReader::new(DecryptReader::new(encrypted.as_slice(), &key)?)?;
+//! assert_eq!(reader.entry().name(), "firmware.bin");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The envelope is the 8 byte magic `CPIOAES1`, a random 7 byte nonce prefix, and then the
+//! data in chunks of 64 KiB, each encrypted with AES-256-GCM under the STREAM construction
+//! and followed by its 16 byte tag. The last chunk may be shorter than the others, and is
+//! sealed as the last, so that truncating the stream, or reordering or dropping chunks, fails
+//! authentication rather than going unnoticed.
+
+use std::io::{self, Read, Write};
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{KeyInit, OsRng};
+use aes_gcm::Aes256Gcm;
+
+/// Identifies an encrypted archive stream, and the version of its envelope.
+const MAGIC: &[u8; 8] = b"CPIOAES1";
+
+/// Size of the nonce prefix, the 12 byte AES-GCM nonce less STREAM's counter and last flag.
+const NONCE_LEN: usize = 7;
+
+/// Size of the plaintext of every chunk but the last.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Size of the authentication tag following each chunk.
+const TAG_LEN: usize = 16;
+
+/// A 256 bit AES key.
+pub type Key = [u8; 32];
+
+/// Returns a new random key from the operating system's random number generator.
+pub fn generate_key() -> Key {
+    let mut key = [0; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// The error returned for data that fails authentication: the wrong key, or a stream that
+/// was corrupted, truncated, or tampered with.
+fn auth_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Encrypted archive failed authentication",
+    )
+}
+
+/// A writer that encrypts everything written through it.
+///
+/// Data is encrypted a chunk at a time as it is written, so only one chunk is ever held in
+/// memory. [`finish`](Self::finish) must be called to seal the last chunk; a stream dropped
+/// without it fails to decrypt.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: EncryptorBE32<Aes256Gcm>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Writes the envelope header into `inner` and returns a writer encrypting with `key`
+    /// under a fresh random nonce.
+    pub fn new(mut inner: W, key: &Key) -> io::Result<Self> {
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        inner.write_all(MAGIC)?;
+        inner.write_all(&nonce)?;
+        let aead = Aes256Gcm::new(key.into());
+        Ok(Self {
+            inner,
+            encryptor: EncryptorBE32::from_aead(aead, nonce.as_ref().into()),
+            buf: Vec::with_capacity(CHUNK_SIZE + TAG_LEN),
+        })
+    }
+
+    /// Seals the last chunk, writes it out, and returns the underlying sink.
+    pub fn finish(mut self) -> io::Result<W> {
+        let chunk = self
+            .encryptor
+            .encrypt_last(self.buf.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Encryption failed"))?;
+        self.inner.write_all(&chunk)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full chunk is only sealed once more data arrives, as it may turn out to be the last.
+        if self.buf.len() == CHUNK_SIZE && !buf.is_empty() {
+            self.encryptor
+                .encrypt_next_in_place(b"", &mut self.buf)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Encryption failed"))?;
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        let n = buf.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Only whole chunks can be written, so buffered data stays buffered.
+        self.inner.flush()
+    }
+}
+
+/// A reader that decrypts and authenticates a stream written by [`EncryptWriter`].
+///
+/// The stream runs to the end of the underlying reader. Each chunk is authenticated before
+/// any of it is returned, so no data that fails authentication is ever read; a stream that
+/// ends before its last chunk fails with an `InvalidData` error.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    /// The ciphertext already read of the chunk after the current one.
+    ahead: Vec<u8>,
+    /// The decrypted chunk being read out.
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// Reads the envelope header from `inner` and returns a reader decrypting with `key`.
+    ///
+    /// Fails with an `InvalidData` error if `inner` does not start with the header.
+    pub fn new(mut inner: R, key: &Key) -> io::Result<Self> {
+        let mut header = [0; MAGIC.len() + NONCE_LEN];
+        inner.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not an encrypted archive",
+            ));
+        }
+        let aead = Aes256Gcm::new(key.into());
+        let nonce = &header[MAGIC.len()..];
+        Ok(Self {
+            inner,
+            decryptor: Some(DecryptorBE32::from_aead(aead, nonce.into())),
+            ahead: vec![],
+            chunk: vec![],
+            pos: 0,
+        })
+    }
+
+    /// Returns the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads and decrypts the next chunk, returning false if the last has already been read.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        if self.decryptor.is_none() {
+            return Ok(false);
+        }
+        // Read one byte past a full chunk, to tell whether it is the last.
+        let full = CHUNK_SIZE + TAG_LEN;
+        let mut chunk = std::mem::take(&mut self.ahead);
+        let wanted = full + 1 - chunk.len();
+        (&mut self.inner)
+            .take(wanted as u64)
+            .read_to_end(&mut chunk)?;
+        if chunk.len() > full {
+            self.ahead = chunk.split_off(full);
+            let decryptor = self.decryptor.as_mut().expect("checked above");
+            decryptor
+                .decrypt_next_in_place(b"", &mut chunk)
+                .map_err(|_| auth_error())?;
+        } else {
+            let decryptor = self.decryptor.take().expect("checked above");
+            decryptor
+                .decrypt_last_in_place(b"", &mut chunk)
+                .map_err(|_| auth_error())?;
+        }
+        self.chunk = chunk;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if buf.is_empty() || !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(data: &[u8], key: &Key) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(vec![], key).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(encrypted: &[u8], key: &Key) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        DecryptReader::new(encrypted, key)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key = generate_key();
+        for &len in &[
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE,
+        ] {
+            let data: Vec<u8> = (0..len).map(|idx| idx as u8).collect();
+            let encrypted = encrypt(&data, &key);
+            let chunks = ((len + CHUNK_SIZE - 1) / CHUNK_SIZE).max(1);
+            assert_eq!(
+                encrypted.len(),
+                MAGIC.len() + NONCE_LEN + len + chunks * TAG_LEN
+            );
+            assert_eq!(decrypt(&encrypted, &key).unwrap(), data);
+        }
+        // Nonces are never reused.
+        assert_ne!(encrypt(b"x", &key), encrypt(b"x", &key));
+    }
+
+    #[test]
+    fn test_authentication() {
+        let key = generate_key();
+        let data = vec![7; 2 * CHUNK_SIZE + 10];
+        let encrypted = encrypt(&data, &key);
+
+        let err = decrypt(&encrypted, &generate_key()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut tampered = encrypted.clone();
+        tampered[100] ^= 1;
+        assert!(decrypt(&tampered, &key).is_err());
+
+        // Truncating at a chunk boundary drops the sealed last chunk.
+        let boundary = MAGIC.len() + NONCE_LEN + 2 * (CHUNK_SIZE + TAG_LEN);
+        assert!(decrypt(&encrypted[..boundary], &key).is_err());
+        assert!(decrypt(&encrypted[..encrypted.len() - 1], &key).is_err());
+
+        let err = decrypt(&data, &key).unwrap_err();
+        assert_eq!(err.to_string(), "Not an encrypted archive");
+    }
+
+    #[test]
+    fn test_archive() {
+        let key = generate_key();
+        let mut archive = crate::ArchiveWriter::new(EncryptWriter::new(vec![], &key).unwrap());
+        let builder = crate::NewcBuilder::new("big").mode(0o100644);
+        archive
+            .append_bytes(builder, &vec![1; CHUNK_SIZE * 2])
+            .unwrap();
+        let (sink, _) = archive.finish().unwrap();
+        let encrypted = sink.finish().unwrap();
+
+        let decrypted = DecryptReader::new(encrypted.as_slice(), &key).unwrap();
+        let reader = crate::NewcReader::new(decrypted).unwrap();
+        assert_eq!(reader.entry().file_size() as usize, CHUNK_SIZE * 2);
+        let decrypted = reader.finish().unwrap();
+        let reader = crate::NewcReader::new(decrypted).unwrap();
+        assert!(reader.entry().is_trailer());
+    }
+}
@@ -0,0 +1,260 @@
+//! Reading archives so that they can be written back byte for byte.
+//!
+//! The [`Reader`] keeps what an archive means and drops how it was written:
+//! the case of the hex digits in its headers, extra NULs after names, the contents of
+//! padding, the fields of the trailer, and anything after it, such as padding out to a block
+//! size. [`ExactArchive`] keeps all of it, so that an archive can be rewritten with one entry
+//! changed and every other byte as it was.
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! use cpio::exact::ExactArchive;
+//! use cpio::NewcBuilder;
+//!
+//! # let (original, _) = cpio::write_cpio_bytes(
+//! #     vec![(NewcBuilder::new("etc/hostname").mode(0o100644), &b"old"[..])],
+//! #     vec![],
+//! # )?;
+//! let mut archive = ExactArchive::read(original.as_slice())?;
+//! for entry in archive.entries_mut() {
+//!     if entry.entry().name() == "etc/hostname" {
+//!         let builder = NewcBuilder::from_entry(entry.entry());
+//!         entry.replace(builder, b"new")?;
+//!     }
+//! }
+//! let mut patched = vec![];
+//! archive.write_to(&mut patched)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{self, Read, Write};
+
+use crate::newc::{checksum, pad, Builder, Entry, Header, HeaderParsing, Reader, HEADER_LEN};
+
+/// One entry of an [`ExactArchive`], with the bytes it was read from.
+#[derive(Clone)]
+pub struct ExactEntry {
+    entry: Entry,
+    /// The fixed-size header, as written.
+    header: Vec<u8>,
+    /// The name, its NUL terminator and any extra NULs, and the padding after it.
+    name: Vec<u8>,
+    data: Vec<u8>,
+    /// The padding after the data.
+    padding: Vec<u8>,
+}
+
+impl ExactEntry {
+    /// Reads an entry from `reader`, keeping every byte of it.
+    fn read<R: Read>(reader: &mut R) -> io::Result<ExactEntry> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let name_size = Header::parse_with(&header, HeaderParsing::Tolerant)?.name_size as usize;
+        let mut name = vec![0; name_size + pad(HEADER_LEN + name_size)];
+        reader.read_exact(&mut name)?;
+
+        // Decode the metadata from the bytes already read, as the reader would have.
+        let raw = [&header[..], &name].concat();
+        let entry = Reader::with_parsing(raw.as_slice(), HeaderParsing::Tolerant)?
+            .entry()
+            .clone();
+        let file_size = entry.file_size() as usize;
+        let mut data = vec![0; file_size];
+        reader.read_exact(&mut data)?;
+        let mut padding = vec![0; pad(file_size)];
+        reader.read_exact(&mut padding)?;
+        Ok(ExactEntry {
+            entry,
+            header: header.to_vec(),
+            name,
+            data,
+            padding,
+        })
+    }
+
+    /// Returns the metadata of the entry.
+    pub fn entry(&self) -> &Entry {
+        &self.entry
+    }
+
+    /// Returns the data of the entry.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Replaces the entry with one written from `builder` and `data`, in the same format as
+    /// the original, with a fresh checksum if it is in the `crc` format.
+    ///
+    /// The replacement is written as [`Builder`] writes any entry, so none of the quirks of the
+    /// original entry are kept.
+    pub fn replace(&mut self, builder: Builder, data: &[u8]) -> io::Result<()> {
+        *self = Self::encode(builder, data, self.entry.checksum().is_some())?;
+        Ok(())
+    }
+
+    /// Creates a new entry from `builder` and `data`, in the `newc` format, for inserting into
+    /// an [`ExactArchive`].
+    pub fn new(builder: Builder, data: &[u8]) -> io::Result<ExactEntry> {
+        Self::encode(builder, data, false)
+    }
+
+    /// Writes an entry with `builder`, in the `crc` format if `crc` is set, and reads it back.
+    fn encode(builder: Builder, data: &[u8], crc: bool) -> io::Result<ExactEntry> {
+        let len = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "data exceeds 4 GiB"))?;
+        let mut bytes = vec![];
+        let mut fp = if crc {
+            builder.write_crc(&mut bytes, len, checksum(data))
+        } else {
+            builder.write(&mut bytes, len)
+        };
+        fp.write_all(data)?;
+        fp.finish()?;
+        ExactEntry::read(&mut bytes.as_slice())
+    }
+
+    /// Writes the entry out exactly as it was read.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.header)?;
+        writer.write_all(&self.name)?;
+        writer.write_all(&self.data)?;
+        writer.write_all(&self.padding)
+    }
+}
+
+/// A whole archive held in memory along with every byte it was read from.
+#[derive(Clone)]
+pub struct ExactArchive {
+    entries: Vec<ExactEntry>,
+    trailer: ExactEntry,
+    trailing: Vec<u8>,
+}
+
+impl ExactArchive {
+    /// Reads `archive` to its end, keeping every byte of every entry, of the trailer, and of
+    /// whatever follows the trailer.
+    ///
+    /// Header fields are parsed as by [`HeaderParsing::Tolerant`], so archives with quirks
+    /// that the default reader rejects can be read, and written back with the same quirks.
+    pub fn read<R: Read>(mut archive: R) -> io::Result<ExactArchive> {
+        let mut entries = vec![];
+        loop {
+            let entry = ExactEntry::read(&mut archive)?;
+            if entry.entry.is_trailer() {
+                let mut trailing = vec![];
+                archive.read_to_end(&mut trailing)?;
+                return Ok(ExactArchive {
+                    entries,
+                    trailer: entry,
+                    trailing,
+                });
+            }
+            entries.push(entry);
+        }
+    }
+
+    /// Returns the entries of the archive, not including the trailer.
+    pub fn entries(&self) -> &[ExactEntry] {
+        &self.entries
+    }
+
+    /// Returns the entries of the archive for changing, not including the trailer.
+    pub fn entries_mut(&mut self) -> &mut Vec<ExactEntry> {
+        &mut self.entries
+    }
+
+    /// Returns the trailer of the archive.
+    pub fn trailer(&self) -> &ExactEntry {
+        &self.trailer
+    }
+
+    /// Returns the bytes after the trailer, such as padding out to a block size.
+    pub fn trailing(&self) -> &[u8] {
+        &self.trailing
+    }
+
+    /// Writes the archive out as it was read, with any changes made to its entries.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for entry in &self.entries {
+            entry.write_to(&mut writer)?;
+        }
+        self.trailer.write_to(&mut writer)?;
+        writer.write_all(&self.trailing)?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcBuilder;
+
+    /// Returns an archive with every quirk the reader forgets.
+    fn quirky() -> Vec<u8> {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (NewcBuilder::new("a").mode(0o100644), &b"Hello"[..]),
+                (NewcBuilder::new("b").mode(0o100644), b"World!"),
+            ],
+            vec![],
+        )
+        .unwrap();
+        let mut quirky = archive;
+        // Uppercase hex in the first header, and nonzero padding after its data.
+        quirky[14..22].copy_from_slice(b"000081A4");
+        quirky[117..120].copy_from_slice(b"xyz");
+        // A trailer with a nonzero inode, and block padding after it.
+        let trailer = quirky.len() - 124;
+        quirky[trailer + 6..trailer + 14].copy_from_slice(b"0000002a");
+        quirky.resize(512, 0);
+        quirky
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let original = quirky();
+        let archive = ExactArchive::read(original.as_slice()).unwrap();
+        assert_eq!(archive.entries().len(), 2);
+        assert_eq!(archive.entries()[0].entry().mode(), 0o100644);
+        assert_eq!(archive.entries()[1].data(), b"World!");
+        assert_eq!(archive.trailer().entry().ino(), 42);
+        assert_eq!(archive.trailing().len(), 512 - 364);
+
+        let mut written = vec![];
+        archive.write_to(&mut written).unwrap();
+        assert_eq!(written, original);
+    }
+
+    #[test]
+    fn test_replace() {
+        let original = quirky();
+        let mut archive = ExactArchive::read(original.as_slice()).unwrap();
+        let entry = &mut archive.entries_mut()[1];
+        let builder = NewcBuilder::from_entry(entry.entry());
+        entry.replace(builder, b"Hi").unwrap();
+        let mut written = vec![];
+        archive.write_to(&mut written).unwrap();
+
+        // The first entry, the trailer, and the padding after it are untouched.
+        let first = 120;
+        let tail = 512 - 240;
+        assert_eq!(written[..first], original[..first]);
+        assert_eq!(written.len(), original.len() - 4);
+        assert_eq!(
+            written[written.len() - tail..],
+            original[original.len() - tail..]
+        );
+        let reader = Reader::new(&written[first..]).unwrap();
+        assert_eq!(reader.entry().name(), "b");
+        assert_eq!(reader.entry().file_size(), 2);
+
+        let entry = ExactEntry::new(NewcBuilder::new("c").mode(0o100644), b"!").unwrap();
+        archive.entries_mut().push(entry);
+        let mut written = vec![];
+        archive.write_to(&mut written).unwrap();
+        assert_eq!(written[..first], original[..first]);
+        let archive = ExactArchive::read(written.as_slice()).unwrap();
+        assert_eq!(archive.entries()[2].entry().name(), "c");
+    }
+}
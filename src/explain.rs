@@ -0,0 +1,382 @@
+//! Annotated dumps of entry headers, for finding out why a tool rejects an archive.
+//!
+//! [`HeaderDump`] breaks the bytes of one entry's header down into its fields, with the
+//! offset, raw text, and decoded value of each, and notes anything wrong with them.
+//! [`explain`] does the same for every entry of an archive, up to the first header that can't
+//! be followed. Both print as a table:
+//!
+//! ```text
+//! 00000000  magic      070701               "new ascii" format
+//! 00000006  ino        00000000             0
+//! 0000000e  mode       000081a4             0o100644 regular file
+//! ...
+//! 0000006e  name       "hello\0"            name "hello"
+//! 00000074  data                            5 bytes
+//! ```
+
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::newc::{pad, read_hex_u32, ModeFileType, HEADER_LEN, TRAILER_NAME};
+
+/// The numeric fields of a header, in order, after the magic number.
+const FIELDS: [&str; 13] = [
+    "ino",
+    "mode",
+    "uid",
+    "gid",
+    "nlink",
+    "mtime",
+    "filesize",
+    "devmajor",
+    "devminor",
+    "rdevmajor",
+    "rdevminor",
+    "namesize",
+    "check",
+];
+
+/// One field of an entry, as it appears in the archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    name: &'static str,
+    offset: u64,
+    raw: Vec<u8>,
+    value: Option<u32>,
+    note: String,
+}
+
+impl Field {
+    /// Returns the name of the field, as in the format's `c_` struct members without the
+    /// prefix, or `name`, `padding`, or `data`.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the offset of the field's first byte in the archive.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the field's bytes, or nothing for the `data` field.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Returns the value of a numeric field, if it is valid hex.
+    pub fn value(&self) -> Option<u32> {
+        self.value
+    }
+
+    /// Returns a description of what the field holds, or of what is wrong with it.
+    pub fn note(&self) -> &str {
+        &self.note
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let raw = match self.name {
+            "name" => format!("{:?}", String::from_utf8_lossy(&self.raw)),
+            "padding" => self
+                .raw
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            _ => String::from_utf8_lossy(&self.raw).into_owned(),
+        };
+        write!(
+            f,
+            "{:08x}  {:<10} {:<20} {}",
+            self.offset, self.name, raw, self.note
+        )
+    }
+}
+
+/// The fields of one entry's header and name, with notes on each.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderDump {
+    fields: Vec<Field>,
+    problems: usize,
+}
+
+impl HeaderDump {
+    /// Breaks down the header starting at the start of `bytes`, which was found `offset`
+    /// bytes into its archive. `bytes` should hold the name after the header as well; as
+    /// much of the header and name as it holds is dumped.
+    pub fn parse(bytes: &[u8], offset: u64) -> Self {
+        let mut dump = Self {
+            fields: vec![],
+            problems: 0,
+        };
+        let magic = &bytes[..bytes.len().min(6)];
+        let note = match magic {
+            b"070701" => "\"new ascii\" format".to_string(),
+            b"070702" => "\"new crc\" format".to_string(),
+            b"070707" => "odc format, which is not supported".to_string(),
+            _ => "not a newc magic number (070701 or 070702)".to_string(),
+        };
+        let problem = !matches!(magic, b"070701" | b"070702");
+        dump.push("magic", offset, magic, None, note, problem);
+        if problem {
+            return dump;
+        }
+
+        let mut values = [0u32; 13];
+        for (i, &name) in FIELDS.iter().enumerate() {
+            let start = 6 + i * 8;
+            let raw = &bytes[start.min(bytes.len())..(start + 8).min(bytes.len())];
+            let field_offset = offset + start as u64;
+            if raw.len() < 8 {
+                dump.push(name, field_offset, raw, None, "truncated".to_string(), true);
+                return dump;
+            }
+            match read_hex_u32(raw) {
+                Ok(value) => {
+                    values[i] = value;
+                    let note = describe(name, value, magic == b"070702");
+                    dump.push(name, field_offset, raw, Some(value), note, false);
+                }
+                Err(_) => {
+                    let note = "not 8 hex digits".to_string();
+                    dump.push(name, field_offset, raw, None, note, true);
+                }
+            }
+        }
+        if dump.problems > 0 {
+            return dump;
+        }
+
+        let name_size = values[11] as usize;
+        let end = (HEADER_LEN + name_size).min(bytes.len());
+        let raw = &bytes[HEADER_LEN.min(end)..end];
+        let name_offset = offset + HEADER_LEN as u64;
+        let (note, problem) = if raw.len() < name_size {
+            ("truncated".to_string(), true)
+        } else if name_size == 0 {
+            ("namesize is zero, so there is no NUL".to_string(), true)
+        } else if raw[name_size - 1] != 0 {
+            ("not NUL-terminated at namesize".to_string(), true)
+        } else if let Some(nul) = raw[..name_size - 1].iter().position(|&byte| byte == 0) {
+            (format!("NUL at byte {} before namesize", nul), true)
+        } else {
+            let name = String::from_utf8_lossy(&raw[..name_size - 1]);
+            (format!("name {:?}", name), false)
+        };
+        dump.push("name", name_offset, raw, None, note, problem);
+        dump
+    }
+
+    fn push(
+        &mut self,
+        name: &'static str,
+        offset: u64,
+        raw: &[u8],
+        value: Option<u32>,
+        note: String,
+        problem: bool,
+    ) {
+        if problem {
+            self.problems += 1;
+        }
+        self.fields.push(Field {
+            name,
+            offset,
+            raw: raw.to_vec(),
+            value,
+            note,
+        });
+    }
+
+    /// Returns the fields of the header, in the order they appear.
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Returns the field called `name`, if the dump got as far as it.
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    /// Returns true if any field is malformed, so that the entry can't be read.
+    pub fn is_malformed(&self) -> bool {
+        self.problems > 0
+    }
+
+    /// Returns the entry's name, if its name field is intact.
+    fn entry_name(&self) -> Option<String> {
+        let raw = match self.field("name") {
+            Some(field) if !self.is_malformed() => &field.raw,
+            _ => return None,
+        };
+        Some(String::from_utf8_lossy(&raw[..raw.len() - 1]).into_owned())
+    }
+}
+
+impl fmt::Display for HeaderDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for field in &self.fields {
+            writeln!(f, "{}", field)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describes the value of the numeric field called `name`.
+fn describe(name: &str, value: u32, crc: bool) -> String {
+    match name {
+        "mode" => {
+            let file_type = match ModeFileType::from_mode(value) {
+                Some(ModeFileType::Regular) => "regular file",
+                Some(ModeFileType::Directory) => "directory",
+                Some(ModeFileType::Symlink) => "symlink",
+                Some(ModeFileType::Char) => "character device",
+                Some(ModeFileType::Block) => "block device",
+                Some(ModeFileType::Fifo) => "fifo",
+                Some(ModeFileType::Socket) => "socket",
+                Some(ModeFileType::NetworkSpecial) => "network special file",
+                None => "unknown file type",
+            };
+            format!("{:#o} {}", value, file_type)
+        }
+        "check" if !crc && value != 0 => {
+            format!("{:#010x}, but this format has no checksum", value)
+        }
+        "check" => format!("{:#010x}", value),
+        _ => value.to_string(),
+    }
+}
+
+/// Reads `len` bytes, or as many as there are before the end of `reader`.
+fn read_up_to<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Dumps the header of every entry in the archive read from `archive`, up to and including
+/// the trailer, with fields for the padding and data after each.
+///
+/// Stops after the first header that is malformed or cut short, since the entries after it
+/// can't be found; its dump shows what is wrong with it. Errors are only returned for
+/// failures to read.
+pub fn explain<R: Read>(mut archive: R) -> io::Result<Vec<HeaderDump>> {
+    let mut dumps: Vec<HeaderDump> = vec![];
+    let mut offset = 0u64;
+    loop {
+        let mut bytes = read_up_to(&mut archive, HEADER_LEN)?;
+        if bytes.is_empty() {
+            break;
+        }
+        if let Some(Ok(name_size)) = bytes.get(94..102).map(read_hex_u32) {
+            bytes.extend(read_up_to(&mut archive, name_size as usize)?);
+        }
+        let mut dump = HeaderDump::parse(&bytes, offset);
+        let name = match dump.entry_name() {
+            Some(name) => name,
+            None => {
+                dumps.push(dump);
+                break;
+            }
+        };
+        offset += bytes.len() as u64;
+
+        let file_size = dump.field("filesize").and_then(Field::value).unwrap_or(0);
+        let file_size = file_size as usize;
+        let parts = [
+            (pad(bytes.len()), false),
+            (file_size, true),
+            (pad(file_size), false),
+        ];
+        for &(len, is_data) in parts.iter() {
+            if len == 0 {
+                continue;
+            }
+            if is_data {
+                let copied = io::copy(&mut (&mut archive).take(len as u64), &mut io::sink())?;
+                let (note, problem) = if copied < len as u64 {
+                    (format!("truncated, {} of {} bytes", copied, len), true)
+                } else {
+                    (format!("{} bytes", len), false)
+                };
+                dump.push("data", offset, &[], None, note, problem);
+                offset += copied;
+            } else {
+                let raw = read_up_to(&mut archive, len)?;
+                let (note, problem) = if raw.len() < len {
+                    ("truncated".to_string(), true)
+                } else if raw.iter().any(|&byte| byte != 0) {
+                    ("not zero".to_string(), false)
+                } else {
+                    (String::new(), false)
+                };
+                dump.push("padding", offset, &raw, None, note, problem);
+                offset += raw.len() as u64;
+            }
+        }
+        let mode = dump.field("mode").and_then(Field::value);
+        let trailer = name == TRAILER_NAME && mode == Some(0) && file_size == 0;
+        let done = trailer || dump.is_malformed();
+        dumps.push(dump);
+        if done {
+            break;
+        }
+    }
+    Ok(dumps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcBuilder;
+
+    #[test]
+    fn test_explain() {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![(NewcBuilder::new("hello").mode(0o100644), "Hello")],
+            vec![],
+        )
+        .unwrap();
+        let dumps = explain(archive.as_slice()).unwrap();
+        assert_eq!(dumps.len(), 2);
+        let dump = &dumps[0];
+        assert!(!dump.is_malformed());
+        let mode = dump.field("mode").unwrap();
+        assert_eq!((mode.offset(), mode.value()), (14, Some(0o100644)));
+        assert_eq!(mode.raw(), b"000081a4");
+        assert_eq!(
+            mode.to_string(),
+            "0000000e  mode       000081a4             0o100644 regular file"
+        );
+        let data = dump.field("data").unwrap();
+        assert_eq!((data.offset(), data.note()), (116, "5 bytes"));
+        assert_eq!(
+            dumps[1].field("name").unwrap().note(),
+            "name \"TRAILER!!!\""
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        let (mut archive, _) =
+            crate::write_cpio_bytes(vec![(NewcBuilder::new("a"), "")], vec![]).unwrap();
+        archive[22..30].copy_from_slice(b"0000g000");
+        let dumps = explain(archive.as_slice()).unwrap();
+        assert_eq!(dumps.len(), 1);
+        assert!(dumps[0].is_malformed());
+        assert_eq!(dumps[0].field("uid").unwrap().note(), "not 8 hex digits");
+
+        // A name size one short of the name's NUL.
+        archive[22..30].copy_from_slice(b"00000000");
+        archive[94..102].copy_from_slice(b"00000001");
+        let dump = HeaderDump::parse(&archive, 0);
+        assert_eq!(
+            dump.field("name").unwrap().note(),
+            "not NUL-terminated at namesize"
+        );
+
+        let dump = HeaderDump::parse(b"070707", 0);
+        assert!(dump.is_malformed());
+        assert_eq!(dump.fields().len(), 1);
+    }
+}
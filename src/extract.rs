@@ -0,0 +1,1142 @@
+//! Unpacking archives onto the filesystem.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::fakeroot::{MetadataDb, Record};
+use crate::name::NamePolicy;
+use crate::newc::{ModeFileType, Reader};
+use crate::owner::OwnerMap;
+use crate::progress::{Observer, Progress, ProgressReader};
+
+mod journal;
+
+use journal::{Hasher, Journal};
+
+/// Caps on what an [`Extractor`] will create, for unpacking archives from untrusted sources.
+/// No quotas are set by default.
+///
+/// Quotas are checked against each entry's header before anything is created for it, so an
+/// archive that exceeds them leaves nothing past the quota on disk. Pair them with
+/// [`compress::Limits`](crate::compress::Limits) when the archive is also compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quotas {
+    max_files: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+impl Quotas {
+    /// Creates a set of quotas with none set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail rather than create more than `files` files, directories, and symlinks.
+    pub fn max_files(mut self, files: u64) -> Self {
+        self.max_files = Some(files);
+        self
+    }
+
+    /// Fail rather than write more than `bytes` bytes of file data in total.
+    pub fn max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Returns an error if creating one more entry of `len` bytes on top of `extracted`
+    /// would exceed these quotas.
+    fn check(&self, extracted: &Extracted, len: u64) -> io::Result<()> {
+        if self.max_files.map_or(false, |max| extracted.files >= max) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file count exceeds quota",
+            ));
+        }
+        if self
+            .max_bytes
+            .map_or(false, |max| extracted.bytes.saturating_add(len) > max)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "extracted size exceeds quota",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Statistics about an extracted archive.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Extracted {
+    files: u64,
+    bytes: u64,
+    skipped: u64,
+    resumed: u64,
+}
+
+impl Extracted {
+    /// Returns the number of files, directories, and symlinks created.
+    pub fn files(&self) -> u64 {
+        self.files
+    }
+
+    /// Returns the total size of the file data written, in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns the number of entries that were not extracted because they are device nodes,
+    /// FIFOs, sockets, or other special files, or symlinks on platforms without them.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Returns the number of files left as they were because the journal showed an earlier
+    /// run had already extracted them. These are included in [`files`](Self::files) and
+    /// [`bytes`](Self::bytes).
+    pub fn resumed(&self) -> u64 {
+        self.resumed
+    }
+}
+
+/// Unpacks the entries of an archive into a destination directory.
+///
+/// Entry names are resolved relative to the destination: leading `/` and `.` components are
+/// dropped, and names containing `..`, or leading through a symlink created by an earlier
+/// entry, are rejected with an `InvalidData` error, so that no entry is written outside the
+/// destination. An existing file in the way of an entry is replaced.
+///
+/// Regular files, directories, and (on Unix) symlinks are created, with the permission bits
+/// of their entries on Unix. Other file types are skipped (unless
+/// [`special_files`](Self::special_files) or a [`metadata_db`](Self::metadata_db) is set),
+/// and modification times are not restored (unless [`mtimes`](Self::mtimes) is set); nor is
+/// ownership, unless an [`owner_map`](Self::owner_map) is set.
+///
+/// Regular files that share device and inode numbers and have a link count above one are
+/// hard links to one file, and are extracted as such: the first of them is created and the
+/// rest linked to it, and the data is written through whichever of them carries it, so that
+/// archives storing the data with the first link or with the last both extract fully. A link
+/// is only made to a file this extraction created and no later entry has replaced, and is
+/// never written through if it turns out not to be a regular file.
+///
+/// Archives need not list directories before their contents, or at all: missing parent
+/// directories are created as they are needed, and the permissions and times of directories
+/// are applied only once everything else has been extracted, deepest first, so that a
+/// read-only directory can still be filled and its time is not changed by filling it.
+pub struct Extractor {
+    dest: PathBuf,
+    quotas: Quotas,
+    name_policy: NamePolicy,
+    observer: Option<Box<dyn Observer>>,
+    chunk_size: usize,
+    atomic: bool,
+    journal: Option<PathBuf>,
+    owner_map: Option<OwnerMap>,
+    metadata_db: Option<PathBuf>,
+    special_files: bool,
+    mtimes: bool,
+}
+
+impl Extractor {
+    /// Creates an extractor that unpacks into `dest`, which is created if it does not exist.
+    pub fn new<P: AsRef<Path>>(dest: P) -> Self {
+        Self {
+            dest: dest.as_ref().to_path_buf(),
+            quotas: Quotas::default(),
+            name_policy: NamePolicy::default(),
+            observer: None,
+            chunk_size: 0,
+            atomic: false,
+            journal: None,
+            owner_map: None,
+            metadata_db: None,
+            special_files: false,
+            mtimes: false,
+        }
+    }
+
+    /// Fail with an `InvalidData` error rather than extract past `quotas`.
+    pub fn quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Apply `policy` to the name of every entry, before resolving it against the destination.
+    /// Names the policy rejects fail the extraction with an `InvalidData` error.
+    pub fn name_policy(mut self, policy: NamePolicy) -> Self {
+        self.name_policy = policy;
+        self
+    }
+
+    /// Report progress to `observer` as entries are extracted.
+    pub fn progress<O: Observer + 'static>(mut self, observer: O) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Copy file data in chunks of `size` bytes, instead of the 8 KiB chunks of `io::copy`. A
+    /// `size` of 0 restores the default.
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = size;
+        self
+    }
+
+    /// Unpack into a temporary sibling of the destination, and rename it into place only once
+    /// the whole archive has been extracted, so that an interrupted or failed extraction never
+    /// leaves a partial tree at the destination. The temporary directory is removed on
+    /// failure.
+    ///
+    /// An existing destination is moved aside while the new tree is renamed into place and
+    /// then removed, so it is replaced rather than extracted over. Only the rename of the new
+    /// tree is atomic: there is a moment between the two renames when the destination does
+    /// not exist.
+    pub fn atomic(mut self, atomic: bool) -> Self {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Record each regular file in a journal at `path` once it has been written, so that if
+    /// the extraction is interrupted, running it again from the start skips the files that
+    /// are already complete. A file is only skipped if it still has the size and content hash
+    /// recorded for it; anything else is extracted again.
+    ///
+    /// The journal is removed once the extraction succeeds. With [`atomic`](Self::atomic)
+    /// extraction, the temporary directory is kept after a failure so that the next run can
+    /// resume in it.
+    pub fn journal<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.journal = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Restore the ownership of each file created (on Unix), passing the owner of its entry
+    /// through `map`; `OwnerMap::default()` restores owners as they are in the archive.
+    /// Giving files away usually takes privileges, and failing to is an error.
+    pub fn owner_map(mut self, map: OwnerMap) -> Self {
+        self.owner_map = Some(map);
+        self
+    }
+
+    /// Record the metadata that can't be applied to extracted files in the
+    /// [`MetadataDb`] at `path`, instead of failing on it or leaving it out, so that an
+    /// unprivileged extraction can be archived again as it was.
+    ///
+    /// Files are not given away; each file not owned by its entry's owner (passed through any
+    /// [`owner_map`](Self::owner_map)) has its mode, owner, and device numbers recorded.
+    /// Device nodes, fifos, and sockets are created as empty regular files and recorded, rather
+    /// than skipped. Records already in the database are kept, except for files extracted
+    /// afresh, and the database is saved once the extraction succeeds.
+    pub fn metadata_db<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.metadata_db = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Create device nodes and fifos (on Unix), with the device numbers of their entries,
+    /// instead of skipping them. Creating device nodes usually takes privileges, and failing
+    /// to is an error. A [`metadata_db`](Self::metadata_db) takes precedence: with one set,
+    /// they are still created as placeholders.
+    pub fn special_files(mut self, create: bool) -> Self {
+        self.special_files = create;
+        self
+    }
+
+    /// Restore the modification time of each file, directory, and (on Unix) symlink created,
+    /// as its entry gives it. Times are only restored on Unix.
+    pub fn mtimes(mut self, restore: bool) -> Self {
+        self.mtimes = restore;
+        self
+    }
+
+    /// Extracts every entry of the archive in `archive`, returning the reader positioned just
+    /// past the trailer along with statistics about what was extracted.
+    pub fn extract<R: Read>(mut self, archive: R) -> io::Result<(R, Extracted)> {
+        let dest = self.dest.clone();
+        let result = if self.atomic {
+            self.extract_atomic(&dest, archive)?
+        } else {
+            self.extract_into(&dest, archive)?
+        };
+        if let Some(ref journal) = self.journal {
+            fs::remove_file(journal)?;
+        }
+        Ok(result)
+    }
+
+    fn extract_atomic<R: Read>(&mut self, dest: &Path, archive: R) -> io::Result<(R, Extracted)> {
+        let staging = sibling(dest, "tmp")?;
+        let resuming = self.journal.is_some();
+        if !resuming {
+            let _ = fs::remove_dir_all(&staging);
+        }
+        match self.extract_into(&staging, archive) {
+            Ok(result) => {
+                replace_dir(&staging, dest).map_err(|err| {
+                    let _ = fs::remove_dir_all(&staging);
+                    err
+                })?;
+                trace_event!(dest = ?dest, "renamed extracted tree into place");
+                Ok(result)
+            }
+            Err(err) => {
+                if !resuming {
+                    let _ = fs::remove_dir_all(&staging);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn extract_into<R: Read>(&mut self, dest: &Path, mut archive: R) -> io::Result<(R, Extracted)> {
+        fs::create_dir_all(dest)?;
+        let mut journal = match self.journal {
+            Some(ref path) => Some(Journal::open(path)?),
+            None => None,
+        };
+        let mut db = match self.metadata_db {
+            Some(ref path) => Some(MetadataDb::open(path)?),
+            None => None,
+        };
+        let mut extracted = Extracted::default();
+        let mut buf = vec![0u8; self.chunk_size];
+        // Directory permissions and times are applied last, so that read-only directories can
+        // still be filled, and filling them doesn't change their times.
+        #[cfg(unix)]
+        let mut dirs = vec![];
+        // The path extracted for the first link to each file with hard links, and the other
+        // way around, to forget the links whose first path a later entry replaces.
+        let mut links: HashMap<(u32, u32, u32), (PathBuf, FileId)> = HashMap::new();
+        let mut firsts: HashMap<PathBuf, (u32, u32, u32)> = HashMap::new();
+        loop {
+            let reader = Reader::with_name_policy(archive, &self.name_policy)?;
+            if reader.entry().is_trailer() {
+                archive = reader.finish()?;
+                break;
+            }
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!(
+                "extract",
+                name = reader.entry().name(),
+                size = reader.entry().file_size()
+            )
+            .entered();
+            let path = match entry_path(reader.entry().name())? {
+                Some(path) => dest.join(path),
+                // The entry for the destination itself.
+                None => {
+                    archive = reader.finish()?;
+                    continue;
+                }
+            };
+            let mode = reader.entry().mode();
+            let mtime = if self.mtimes {
+                Some(reader.entry().mtime())
+            } else {
+                None
+            };
+            let wanted = match self.owner_map {
+                Some(ref map) => (
+                    map.map_uid(reader.entry().uid()),
+                    map.map_gid(reader.entry().gid()),
+                ),
+                None => (reader.entry().uid(), reader.entry().gid()),
+            };
+            // With a database, ownership is recorded rather than applied.
+            let owner = if db.is_none() && self.owner_map.is_some() {
+                Some(wanted)
+            } else {
+                None
+            };
+            let len = u64::from(reader.entry().file_size());
+            let file_type = ModeFileType::from_mode(mode);
+            let placeholder = db.is_some()
+                && matches!(
+                    file_type,
+                    Some(
+                        ModeFileType::Char
+                            | ModeFileType::Block
+                            | ModeFileType::Fifo
+                            | ModeFileType::Socket
+                            | ModeFileType::NetworkSpecial
+                    )
+                );
+            let record = Record {
+                mode,
+                uid: wanted.0,
+                gid: wanted.1,
+                rdev_major: reader.entry().rdev_major(),
+                rdev_minor: reader.entry().rdev_minor(),
+            };
+            let name = reader.entry().name().to_string();
+            let node = cfg!(unix)
+                && self.special_files
+                && !placeholder
+                && matches!(
+                    file_type,
+                    Some(ModeFileType::Char | ModeFileType::Block | ModeFileType::Fifo)
+                );
+            let creates = placeholder
+                || node
+                || matches!(
+                    file_type,
+                    Some(ModeFileType::Regular | ModeFileType::Directory)
+                )
+                || (cfg!(unix) && file_type == Some(ModeFileType::Symlink));
+            if !creates {
+                trace_event!(mode, "skipped entry");
+                extracted.skipped += 1;
+                archive = reader.finish()?;
+                continue;
+            }
+            let data_len = if file_type == Some(ModeFileType::Regular) || placeholder {
+                len
+            } else {
+                0
+            };
+            self.quotas.check(&extracted, data_len)?;
+            check_parents(dest, &path)?;
+            // Archives need not contain entries for every parent directory.
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // Links are always extracted afresh, as the journal records files by name.
+            let link = if file_type == Some(ModeFileType::Regular) && reader.entry().nlink() > 1 {
+                let entry = reader.entry();
+                Some((entry.dev_major(), entry.dev_minor(), entry.ino()))
+            } else {
+                None
+            };
+            if file_type == Some(ModeFileType::Regular) && link.is_none() {
+                if let Some(ref journal) = journal {
+                    if journal.is_done(reader.entry().name(), len, &path)? {
+                        trace_event!("already extracted");
+                        extracted.resumed += 1;
+                        extracted.files += 1;
+                        extracted.bytes += len;
+                        archive = reader.finish()?;
+                        continue;
+                    }
+                }
+            }
+            if let Some(key) = firsts.remove(&path) {
+                links.remove(&key);
+            }
+
+            archive = match file_type {
+                Some(ModeFileType::Directory) => {
+                    if !fs::symlink_metadata(&path).map_or(false, |meta| meta.is_dir()) {
+                        remove_existing(&path)?;
+                        fs::create_dir(&path)?;
+                    }
+                    set_owner(&path, owner)?;
+                    #[cfg(unix)]
+                    dirs.push((path.clone(), mode, mtime));
+                    reader.finish()?
+                }
+                #[cfg(unix)]
+                Some(ModeFileType::Symlink) => {
+                    let mut reader = reader;
+                    let target = reader.read_symlink_target()?;
+                    remove_existing(&path)?;
+                    std::os::unix::fs::symlink(target, &path)?;
+                    set_owner(&path, owner)?;
+                    set_mtime(&path, mtime)?;
+                    reader.finish()?
+                }
+                #[cfg(unix)]
+                _ if node => {
+                    remove_existing(&path)?;
+                    let rdev = (reader.entry().rdev_major(), reader.entry().rdev_minor());
+                    make_node(&path, mode, rdev)?;
+                    set_owner(&path, owner)?;
+                    set_mode(&path, mode)?;
+                    set_mtime(&path, mtime)?;
+                    reader.finish()?
+                }
+                _ => {
+                    remove_existing(&path)?;
+                    let file = match link.and_then(|key| links.get(&key)) {
+                        Some((first, id)) => {
+                            if file_id(&fs::symlink_metadata(first)?) != Some(*id) {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!(
+                                        "{}: {} is no longer the file it links to",
+                                        name,
+                                        first.display()
+                                    ),
+                                ));
+                            }
+                            fs::hard_link(first, &path)?;
+                            // Only replace the data if this link carries it.
+                            let file = open_nofollow(&path)?;
+                            if len > 0 {
+                                file.set_len(0)?;
+                            }
+                            file
+                        }
+                        None => {
+                            let file = File::create(&path)?;
+                            if let Some(key) = link {
+                                let id = file_id(&file.metadata()?).ok_or_else(|| {
+                                    io::Error::new(
+                                        io::ErrorKind::Other,
+                                        format!("{}: not created as a regular file", name),
+                                    )
+                                })?;
+                                links.insert(key, (path.clone(), id));
+                                firsts.insert(path.clone(), key);
+                            }
+                            file
+                        }
+                    };
+                    let mut file = Hasher::new(file, journal.is_some() && link.is_none());
+                    let progress = Progress {
+                        entries: extracted.files,
+                        bytes: extracted.bytes,
+                        name: &name,
+                    };
+                    let archive = match self.observer {
+                        Some(ref mut observer) => {
+                            let mut data = ProgressReader {
+                                inner: reader,
+                                observer: observer.as_mut(),
+                                progress,
+                            };
+                            copy_data(&mut data, &mut file, &mut buf)?;
+                            data.inner.finish()?
+                        }
+                        None => {
+                            let mut reader = reader;
+                            copy_data(&mut reader, &mut file, &mut buf)?;
+                            reader.finish()?
+                        }
+                    };
+                    // Changing the owner can clear setuid bits, so it comes first.
+                    set_owner(&path, owner)?;
+                    set_mode(&path, mode)?;
+                    set_mtime(&path, mtime)?;
+                    if let (Some(journal), Some(hash)) = (journal.as_mut(), file.hash()) {
+                        journal.record(&name, len, hash)?;
+                    }
+                    extracted.bytes += len;
+                    if let Some(ref mut observer) = self.observer {
+                        observer.update(&Progress {
+                            entries: extracted.files + 1,
+                            bytes: extracted.bytes,
+                            name: &name,
+                        });
+                    }
+                    archive
+                }
+            };
+            if let Some(ref mut db) = db {
+                if placeholder || !owned_by(&path, wanted)? {
+                    db.insert(&name, record);
+                } else {
+                    db.remove(&name);
+                }
+            }
+            extracted.files += 1;
+        }
+        // Contents come before their directories, whatever order the archive lists them in;
+        // among directories at the same depth, later entries are applied after earlier ones.
+        #[cfg(unix)]
+        {
+            dirs.sort_by_key(|(path, _, _)| std::cmp::Reverse(path.components().count()));
+            for (path, mode, mtime) in dirs {
+                set_mode(&path, mode)?;
+                set_mtime(&path, mtime)?;
+            }
+        }
+        if let (Some(db), Some(path)) = (db, self.metadata_db.as_ref()) {
+            db.save(path)?;
+        }
+        Ok((archive, extracted))
+    }
+}
+
+/// Returns a hidden path next to `dest` for temporary use, distinguished by `purpose`.
+fn sibling(dest: &Path, purpose: &str) -> io::Result<PathBuf> {
+    let name = dest.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "extraction destination has no file name",
+        )
+    })?;
+    let mut sibling = std::ffi::OsString::from(".");
+    sibling.push(name);
+    sibling.push(format!(".cpio-{}", purpose));
+    Ok(dest.with_file_name(sibling))
+}
+
+/// Renames the directory `from` to `to`, replacing anything already at `to`.
+fn replace_dir(from: &Path, to: &Path) -> io::Result<()> {
+    let old = match fs::symlink_metadata(to) {
+        Ok(_) => {
+            let old = sibling(to, "old")?;
+            fs::rename(to, &old)?;
+            Some(old)
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    if let Err(err) = fs::rename(from, to) {
+        if let Some(ref old) = old {
+            let _ = fs::rename(old, to);
+        }
+        return Err(err);
+    }
+    if let Some(old) = old {
+        if fs::symlink_metadata(&old)?.is_dir() {
+            fs::remove_dir_all(&old)?;
+        } else {
+            fs::remove_file(&old)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies all of `data` into `file`, through `buf` unless it is empty.
+fn copy_data<R: Read, W: Write>(data: &mut R, file: &mut W, buf: &mut [u8]) -> io::Result<()> {
+    if buf.is_empty() {
+        io::copy(data, file)?;
+        return Ok(());
+    }
+    loop {
+        let n = match data.read(buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        file.write_all(&buf[..n])?;
+    }
+}
+
+/// Returns the path of the entry called `name` relative to the destination, or `None` for
+/// the destination itself.
+fn entry_path(name: &str) -> io::Result<Option<PathBuf>> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Entry name {:?} leads outside the destination", name),
+                ))
+            }
+        }
+    }
+    Ok(if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    })
+}
+
+/// Returns an error if any directory between `dest` and `path` is a symlink, which could
+/// lead outside the destination.
+fn check_parents(dest: &Path, path: &Path) -> io::Result<()> {
+    let mut parent = path.parent();
+    while let Some(dir) = parent {
+        if dir == dest {
+            break;
+        }
+        if fs::symlink_metadata(dir).map_or(false, |meta| meta.file_type().is_symlink()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Entry path {:?} leads through a symlink", path),
+            ));
+        }
+        parent = dir.parent();
+    }
+    Ok(())
+}
+
+/// Removes whatever is at `path`, unless it is a directory. Succeeds if nothing is there.
+/// Identifies a regular file, to tell whether a path still names the file it did.
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = ();
+
+/// Returns the identity of the file described by `meta`, if it is a regular file.
+fn file_id(meta: &fs::Metadata) -> Option<FileId> {
+    if !meta.is_file() {
+        return None;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some((meta.dev(), meta.ino()))
+    }
+    #[cfg(not(unix))]
+    Some(())
+}
+
+/// Opens `path` for writing, failing rather than following it if it is a symlink.
+fn open_nofollow(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    options.open(path)
+}
+
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => Ok(()),
+        Ok(_) => fs::remove_file(path),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Applies the permission bits of `mode` to `path`.
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+}
+
+/// Changes the owner of `path`, not following symlinks, if `owner` is given.
+#[cfg(unix)]
+fn set_owner(path: &Path, owner: Option<(u32, u32)>) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let (uid, gid) = match owner {
+        Some(owner) => owner,
+        None => return Ok(()),
+    };
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // SAFETY: `path` is a valid NUL-terminated string for the duration of the call.
+    if unsafe { libc::lchown(path.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Creates a device node or fifo at `path` with the file type of `mode` and the major and
+/// minor device numbers `rdev`.
+#[cfg(unix)]
+fn make_node(path: &Path, mode: u32, rdev: (u32, u32)) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let dev = crate::newc::join_dev(rdev.0, rdev.1);
+    // Permissions are applied afterwards, unaffected by the umask.
+    let mode = (mode & 0o170000) | 0o600;
+    // SAFETY: `path` is a valid NUL-terminated string for the duration of the call.
+    if unsafe { libc::mknod(path.as_ptr(), mode as libc::mode_t, dev as libc::dev_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the modification time of `path`, not following symlinks, to `mtime` seconds since
+/// the epoch if it is given, leaving the access time as it is.
+#[cfg(unix)]
+fn set_mtime(path: &Path, mtime: Option<u32>) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mtime = match mtime {
+        Some(mtime) => mtime,
+        None => return Ok(()),
+    };
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: mtime as libc::time_t,
+            tv_nsec: 0,
+        },
+    ];
+    // SAFETY: `path` is a valid NUL-terminated string and `times` holds two timespecs, both
+    // for the duration of the call.
+    let flags = libc::AT_SYMLINK_NOFOLLOW;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns true if `path` is owned by `owner`, not following symlinks.
+#[cfg(unix)]
+fn owned_by(path: &Path, owner: (u32, u32)) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = fs::symlink_metadata(path)?;
+    Ok((meta.uid(), meta.gid()) == owner)
+}
+
+/// Files have no owners outside Unix, so none is owned as an entry says.
+#[cfg(not(unix))]
+fn owned_by(_path: &Path, _owner: (u32, u32)) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(not(unix))]
+fn set_owner(_path: &Path, _owner: Option<(u32, u32)>) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mtime(_path: &Path, _mtime: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcBuilder;
+
+    /// Returns a fresh, empty directory for the test called `name`.
+    fn tempdir(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("cpio-extract-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn archive() -> Vec<u8> {
+        let file = |name| NewcBuilder::new(name).mode(0o100644);
+        let mut archive = crate::ArchiveWriter::new(vec![]);
+        archive
+            .append_bytes(NewcBuilder::new(".").mode(0o040755), b"")
+            .unwrap();
+        archive
+            .append_bytes(NewcBuilder::new("./dir").mode(0o040755), b"")
+            .unwrap();
+        archive
+            .append_bytes(file("./dir/hello"), b"Hello, World")
+            .unwrap();
+        archive
+            .append_bytes(NewcBuilder::new("./link").mode(0o120777), b"dir/hello")
+            .unwrap();
+        archive
+            .append_bytes(NewcBuilder::new("./dev/null").mode(0o020666), b"")
+            .unwrap();
+        archive.append_bytes(file("/abs/file"), b"abs").unwrap();
+        archive.finish().unwrap().0
+    }
+
+    #[test]
+    fn test_extract() {
+        let dest = tempdir("basic");
+        let archive = archive();
+        let (rest, extracted) = Extractor::new(&dest)
+            .chunk_size(4)
+            .extract(archive.as_slice())
+            .unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(extracted.bytes(), 15);
+        assert_eq!(extracted.files(), if cfg!(unix) { 4 } else { 3 });
+        assert_eq!(extracted.skipped(), if cfg!(unix) { 1 } else { 2 });
+        assert_eq!(fs::read(dest.join("dir/hello")).unwrap(), b"Hello, World");
+        assert_eq!(fs::read(dest.join("abs/file")).unwrap(), b"abs");
+        #[cfg(unix)]
+        assert_eq!(fs::read(dest.join("link")).unwrap(), b"Hello, World");
+
+        // Extracting again replaces what is there.
+        Extractor::new(&dest).extract(archive.as_slice()).unwrap();
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_hard_links() {
+        let link = |name, ino| NewcBuilder::new(name).mode(0o100644).ino(ino).nlink(2);
+        let mut archive = crate::ArchiveWriter::new(vec![]);
+        let entries: Vec<(NewcBuilder, &[u8])> = vec![
+            // The data with the last link, as GNU cpio writes it.
+            (link("a", 1), b""),
+            (link("b", 1), b"last"),
+            // The data with the first link.
+            (link("c", 2), b"first"),
+            (link("d", 2), b""),
+        ];
+        for (builder, data) in entries {
+            archive.append_bytes(builder, data).unwrap();
+        }
+        let archive = archive.finish().unwrap().0;
+
+        let dest = tempdir("links");
+        Extractor::new(&dest).extract(archive.as_slice()).unwrap();
+        for (name, data) in [("a", "last"), ("b", "last"), ("c", "first"), ("d", "first")] {
+            assert_eq!(fs::read_to_string(dest.join(name)).unwrap(), data);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let ino = |name| fs::metadata(dest.join(name)).unwrap().ino();
+            assert_eq!(ino("a"), ino("b"));
+            assert_eq!(ino("c"), ino("d"));
+            assert_ne!(ino("a"), ino("c"));
+            assert_eq!(fs::metadata(dest.join("a")).unwrap().nlink(), 2);
+        }
+
+        // Extracting again relinks the files rather than failing on them.
+        Extractor::new(&dest).extract(archive.as_slice()).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("a")).unwrap(), "last");
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hard_link_to_replaced_file() {
+        let outside_dir = tempdir("links-outside");
+        fs::create_dir(&outside_dir).unwrap();
+        let outside = outside_dir.join("outside.txt");
+        fs::write(&outside, "safe").unwrap();
+        let link = |name| NewcBuilder::new(name).mode(0o100644).ino(7).nlink(2);
+        let mut archive = crate::ArchiveWriter::new(vec![]);
+        archive.append_bytes(link("a"), b"").unwrap();
+        // A symlink replacing the first link must not be linked to and written through.
+        let target = outside.to_str().unwrap().as_bytes();
+        let symlink = NewcBuilder::new("a").mode(0o120777);
+        archive.append_bytes(symlink, target).unwrap();
+        archive.append_bytes(link("b"), b"pwned").unwrap();
+        let archive = archive.finish().unwrap().0;
+
+        let dest = tempdir("links-replaced");
+        Extractor::new(&dest).extract(archive.as_slice()).unwrap();
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "safe");
+        assert!(fs::symlink_metadata(dest.join("a")).unwrap().is_symlink());
+        let b = fs::symlink_metadata(dest.join("b")).unwrap();
+        assert!(b.is_file());
+        assert_eq!(fs::read_to_string(dest.join("b")).unwrap(), "pwned");
+        fs::remove_dir_all(&dest).unwrap();
+        fs::remove_dir_all(&outside_dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_out_of_order() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dest = tempdir("order");
+        let dir = |name| NewcBuilder::new(name).mode(0o040500).mtime(1_000);
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (
+                    NewcBuilder::new("a/b/file").mode(0o100644).mtime(3_000),
+                    &b"data"[..],
+                ),
+                (dir("a"), b""),
+                (dir("a/b").mtime(2_000), b""),
+            ],
+            vec![],
+        )
+        .unwrap();
+        Extractor::new(&dest)
+            .mtimes(true)
+            .extract(archive.as_slice())
+            .unwrap();
+        let meta = |name| fs::symlink_metadata(dest.join(name)).unwrap();
+        assert_eq!(meta("a/b/file").mtime(), 3_000);
+        assert_eq!(meta("a/b").mtime(), 2_000);
+        assert_eq!(meta("a").mtime(), 1_000);
+        assert_eq!(meta("a").permissions().mode() & 0o7777, 0o500);
+
+        for name in ["a", "a/b"] {
+            fs::set_permissions(dest.join(name), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_unsafe_names() {
+        let dest = tempdir("unsafe");
+        let (archive, _) =
+            crate::write_cpio_bytes(vec![(NewcBuilder::new("../escape"), &b""[..])], vec![])
+                .unwrap();
+        let err = Extractor::new(&dest)
+            .extract(archive.as_slice())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        #[cfg(unix)]
+        {
+            let mut archive = crate::ArchiveWriter::new(vec![]);
+            archive
+                .append_bytes(NewcBuilder::new("out").mode(0o120777), b"/tmp")
+                .unwrap();
+            archive
+                .append_bytes(NewcBuilder::new("out/file").mode(0o100644), b"")
+                .unwrap();
+            let (archive, _) = archive.finish().unwrap();
+            let err = Extractor::new(&dest)
+                .extract(archive.as_slice())
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_atomic() {
+        let dest = tempdir("atomic");
+        fs::create_dir_all(dest.join("stale")).unwrap();
+        let (_, extracted) = Extractor::new(&dest)
+            .atomic(true)
+            .extract(archive().as_slice())
+            .unwrap();
+        assert_eq!(extracted.bytes(), 15);
+        assert_eq!(fs::read(dest.join("dir/hello")).unwrap(), b"Hello, World");
+        assert!(!dest.join("stale").exists());
+
+        // A failed extraction leaves the destination as it was, and nothing beside it.
+        let mut truncated = archive();
+        truncated.truncate(truncated.len() - 200);
+        Extractor::new(&dest)
+            .atomic(true)
+            .extract(truncated.as_slice())
+            .unwrap_err();
+        assert_eq!(fs::read(dest.join("dir/hello")).unwrap(), b"Hello, World");
+        assert!(!sibling(&dest, "tmp").unwrap().exists());
+        assert!(!sibling(&dest, "old").unwrap().exists());
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_journal() {
+        let dest = tempdir("journal");
+        let journal = dest.with_extension("journal");
+        let archive = archive();
+        let mut truncated = archive.clone();
+        truncated.truncate(truncated.len() - 200);
+        let extract = |archive: &[u8]| {
+            Extractor::new(&dest)
+                .atomic(true)
+                .journal(&journal)
+                .extract(archive)
+                .map(|(_, extracted)| extracted)
+        };
+
+        extract(&truncated).unwrap_err();
+        let extracted = extract(&archive).unwrap();
+        assert_eq!(extracted.resumed(), 1);
+        assert_eq!(extracted.bytes(), 15);
+        assert!(!journal.exists());
+
+        // Files changed since they were journaled are extracted again.
+        extract(&truncated).unwrap_err();
+        fs::write(
+            sibling(&dest, "tmp").unwrap().join("dir/hello"),
+            "Hello, Moon!",
+        )
+        .unwrap();
+        let extracted = extract(&archive).unwrap();
+        assert_eq!(extracted.resumed(), 0);
+        assert_eq!(fs::read(dest.join("dir/hello")).unwrap(), b"Hello, World");
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_name_policy() {
+        let dest = tempdir("policy");
+        let err = Extractor::new(&dest)
+            .name_policy(NamePolicy::strict())
+            .extract(archive().as_slice())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Entry name \"/abs/file\" is absolute");
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_owner_map() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dest = tempdir("owner");
+        fs::create_dir_all(&dest).unwrap();
+        // Giving files to ourselves works unprivileged.
+        let meta = fs::metadata(&dest).unwrap();
+        let map = OwnerMap::new().uid(Some(meta.uid())).gid(Some(meta.gid()));
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (NewcBuilder::new("dir").mode(0o40755).uid(4321), &b""[..]),
+                (NewcBuilder::new("dir/file").mode(0o100644).gid(4321), b"x"),
+                (NewcBuilder::new("link").mode(0o120777).uid(4321), b"dir"),
+            ],
+            vec![],
+        )
+        .unwrap();
+        Extractor::new(&dest)
+            .owner_map(map)
+            .extract(archive.as_slice())
+            .unwrap();
+        for name in ["dir", "dir/file", "link"].iter() {
+            let extracted = fs::symlink_metadata(dest.join(name)).unwrap();
+            assert_eq!((extracted.uid(), extracted.gid()), (meta.uid(), meta.gid()));
+        }
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_files() {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let dest = tempdir("special");
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![(NewcBuilder::new("fifo").mode(0o010640), &b""[..])],
+            vec![],
+        )
+        .unwrap();
+        let (_, extracted) = Extractor::new(&dest).extract(archive.as_slice()).unwrap();
+        assert_eq!(extracted.skipped(), 1);
+        assert!(!dest.join("fifo").exists());
+
+        let (_, extracted) = Extractor::new(&dest)
+            .special_files(true)
+            .extract(archive.as_slice())
+            .unwrap();
+        assert_eq!(extracted.files(), 1);
+        let meta = fs::symlink_metadata(dest.join("fifo")).unwrap();
+        assert!(meta.file_type().is_fifo());
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o640);
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_quotas() {
+        let dest = tempdir("quotas");
+        let archive = archive();
+        let err = Extractor::new(&dest)
+            .quotas(Quotas::new().max_files(2))
+            .extract(archive.as_slice())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "file count exceeds quota");
+
+        let err = Extractor::new(&dest)
+            .quotas(Quotas::new().max_bytes(14))
+            .extract(archive.as_slice())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "extracted size exceeds quota");
+        // The file over the quota was never created.
+        assert!(!dest.join("abs/file").exists());
+
+        Extractor::new(&dest)
+            .quotas(Quotas::new().max_files(4).max_bytes(15))
+            .extract(archive.as_slice())
+            .unwrap();
+        fs::remove_dir_all(&dest).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+//! The journal that lets an interrupted extraction resume.
+//!
+//! Each line records a regular file that was completely written, as its hash, size, and name:
+//!
+//! ```text
+//! 9bf6e0f5d2e2c4b1 12 ./dir/hello
+//! ```
+//!
+//! Lines are only ever appended, and a line cut short by a crash is ignored.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use crate::{fnv1a, FNV_OFFSET};
+
+/// Hashes the data written through it, if enabled.
+pub(super) struct Hasher<W> {
+    inner: W,
+    hash: Option<u64>,
+}
+
+impl<W: Write> Hasher<W> {
+    pub(super) fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            hash: if enabled { Some(FNV_OFFSET) } else { None },
+        }
+    }
+
+    pub(super) fn hash(&self) -> Option<u64> {
+        self.hash
+    }
+}
+
+impl<W: Write> Write for Hasher<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(ref mut hash) = self.hash {
+            *hash = fnv1a(*hash, &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The files recorded as complete by earlier runs, and the journal to record more in.
+pub(super) struct Journal {
+    file: File,
+    done: HashMap<String, (u64, u64)>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if it does not exist.
+    pub(super) fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut done = HashMap::new();
+        let mut lines = BufReader::new(&mut file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if lines.read_line(&mut line)? == 0 {
+                break;
+            }
+            if let Some((name, record)) = parse_line(&line) {
+                done.insert(name.to_string(), record);
+            }
+        }
+        Ok(Self { file, done })
+    }
+
+    /// Returns true if an earlier run recorded the file called `name` as complete with `len`
+    /// bytes, and the file at `path` still has that size and hash.
+    pub(super) fn is_done(&self, name: &str, len: u64, path: &Path) -> io::Result<bool> {
+        let &(size, hash) = match self.done.get(name) {
+            Some(record) if record.0 == len => record,
+            _ => return Ok(false),
+        };
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        if file.metadata()?.len() != size {
+            return Ok(false);
+        }
+        let mut actual = FNV_OFFSET;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            actual = fnv1a(actual, &buf[..n]);
+        }
+        Ok(actual == hash)
+    }
+
+    /// Records the file called `name` as complete.
+    pub(super) fn record(&mut self, name: &str, len: u64, hash: u64) -> io::Result<()> {
+        writeln!(self.file, "{:016x} {} {}", hash, len, name)?;
+        self.file.flush()
+    }
+}
+
+/// Parses a complete journal line into the name it records and its size and hash.
+fn parse_line(line: &str) -> Option<(&str, (u64, u64))> {
+    let line = line.strip_suffix('\n')?;
+    let mut fields = line.splitn(3, ' ');
+    let hash = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let size = fields.next()?.parse().ok()?;
+    Some((fields.next()?, (size, hash)))
+}
@@ -0,0 +1,224 @@
+//! Recording metadata that an unprivileged process can't apply to files.
+//!
+//! Building a root filesystem as an ordinary user runs into files that can't exist on disk as
+//! the archive needs them: files owned by root, device nodes, and the like. Like `fakeroot`
+//! and `pseudo`, a [`MetadataDb`] keeps the metadata such files should have, keyed by their
+//! path within the tree. [`Extractor::metadata_db`](crate::extract::Extractor::metadata_db)
+//! records it for anything it can't create as the archive describes, and
+//! [`TreeArchiver::metadata_db`](crate::tree::TreeArchiver::metadata_db) honors it, so that
+//! unpacking and repacking a tree unprivileged gives back the entries it started with.
+//!
+//! The database is a text file with a line for each file, holding its mode in octal, owner,
+//! group, and device numbers, then its path:
+//!
+//! ```text
+//! 020620 0 5 4 1 dev/tty1
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path};
+
+use crate::newc::Builder;
+
+/// The metadata recorded for one file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// The whole mode, including the file type.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+}
+
+impl Record {
+    /// Replaces the mode, owner, and device numbers in `builder` with the recorded ones.
+    pub fn apply(&self, builder: Builder) -> Builder {
+        builder
+            .mode(self.mode)
+            .uid(self.uid)
+            .gid(self.gid)
+            .rdev_major(self.rdev_major)
+            .rdev_minor(self.rdev_minor)
+    }
+}
+
+/// The metadata recorded for the files of a tree.
+///
+/// Paths are compared by their components, so `./etc/passwd`, `/etc/passwd`, and
+/// `etc/passwd` all name the same file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetadataDb {
+    records: BTreeMap<String, Record>,
+}
+
+/// Returns the key for the file at `path`: its normal components, joined by `/`.
+fn key(path: &str) -> String {
+    Path::new(path)
+        .components()
+        .filter_map(|part| match part {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn invalid_line(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid metadata database line {:?}", line),
+    )
+}
+
+impl MetadataDb {
+    /// Creates an empty database.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the database saved at `path`, or returns an empty one if there is no file there.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match File::open(path) {
+            Ok(file) => Self::read_from(file),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads a database written by [`write_to`](Self::write_to).
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Self> {
+        let mut db = Self::new();
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(6, ' ');
+            let mut number = |radix| {
+                fields
+                    .next()
+                    .and_then(|field| u32::from_str_radix(field, radix).ok())
+                    .ok_or_else(|| invalid_line(&line))
+            };
+            let record = Record {
+                mode: number(8)?,
+                uid: number(10)?,
+                gid: number(10)?,
+                rdev_major: number(10)?,
+                rdev_minor: number(10)?,
+            };
+            let path = fields.next().ok_or_else(|| invalid_line(&line))?;
+            db.insert(path, record);
+        }
+        Ok(db)
+    }
+
+    /// Writes the database to `path`, replacing anything there.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_to(&mut file)?;
+        file.flush()
+    }
+
+    /// Writes the database in its text form.
+    ///
+    /// Fails with an `InvalidInput` error if a path holds a newline, which the format can't
+    /// represent.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for (path, record) in &self.records {
+            if path.contains('\n') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Path {:?} holds a newline", path),
+                ));
+            }
+            writeln!(
+                writer,
+                "{:06o} {} {} {} {} {}",
+                record.mode, record.uid, record.gid, record.rdev_major, record.rdev_minor, path
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of files with recorded metadata.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Returns true if no metadata is recorded.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Returns the metadata recorded for the file at `path`, if any.
+    pub fn get(&self, path: &str) -> Option<&Record> {
+        self.records.get(&key(path))
+    }
+
+    /// Records the metadata of the file at `path`, replacing any recorded before.
+    pub fn insert(&mut self, path: &str, record: Record) {
+        self.records.insert(key(path), record);
+    }
+
+    /// Forgets the metadata of the file at `path`, returning it if there was any.
+    pub fn remove(&mut self, path: &str) -> Option<Record> {
+        self.records.remove(&key(path))
+    }
+
+    /// Replaces the metadata in `builder` with the metadata recorded for its name, if any.
+    pub fn apply(&self, builder: Builder) -> Builder {
+        match self.get(builder.name()) {
+            Some(record) => record.apply(builder),
+            None => builder,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut db = MetadataDb::new();
+        let tty = Record {
+            mode: 0o020620,
+            uid: 0,
+            gid: 5,
+            rdev_major: 4,
+            rdev_minor: 1,
+        };
+        db.insert("./dev/tty1", tty);
+        db.insert(
+            "/bin/with space",
+            Record {
+                mode: 0o104755,
+                ..tty
+            },
+        );
+        assert_eq!(db.get("dev/tty1"), Some(&tty));
+
+        let mut text = vec![];
+        db.write_to(&mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text.clone()).unwrap(),
+            "104755 0 5 4 1 bin/with space\n020620 0 5 4 1 dev/tty1\n"
+        );
+        assert_eq!(MetadataDb::read_from(text.as_slice()).unwrap(), db);
+
+        let entry = db.apply(Builder::new("dev/tty1")).to_entry(0, None);
+        assert_eq!(
+            (entry.mode(), entry.gid(), entry.rdev_major()),
+            (0o020620, 5, 4)
+        );
+        assert_eq!(db.remove("dev/tty1"), Some(tty));
+        assert_eq!(db.len(), 1);
+
+        let err = MetadataDb::read_from(&b"0644 0 0 x 0 a\n"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,430 @@
+//! C ABI for reading and writing `newc` archives.
+//!
+//! This module is only available with the `ffi` feature. All exported types are `#[repr(C)]`
+//! and all functions use the C calling convention with unmangled `cpio_` prefixed names, so a
+//! header can be generated with [cbindgen](https://github.com/mozilla/cbindgen). To build a
+//! shared library:
+//!
+//! ```text
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! Functions that fail return a null pointer or a negative value; a description of the most
+//! recent failure on the calling thread is available from [`cpio_last_error`].
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::slice;
+
+use crate::newc::{self, Builder};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error<E: ToString>(err: E) {
+    let msg = CString::new(err.to_string().replace('\0', ""))
+        .unwrap_or_else(|_| CString::new("unknown error").unwrap());
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// Metadata about one archive entry, as exchanged across the C ABI.
+///
+/// When returned from [`cpio_reader_next`], `name` points into memory owned by the reader and
+/// stays valid until the next call on that reader. When passed to [`cpio_writer_write_entry`],
+/// `name` must be a NUL-terminated UTF-8 string and `file_size` is ignored in favor of the
+/// length of the supplied data.
+#[repr(C)]
+pub struct CpioEntry {
+    pub name: *const c_char,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub mtime: u32,
+    pub file_size: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    /// Whether the entry uses the "new crc" format, in which case `checksum` is meaningful.
+    pub has_checksum: bool,
+    pub checksum: u32,
+}
+
+enum ReadState {
+    Header(Box<dyn Read>),
+    Data(newc::Reader<Box<dyn Read>>),
+    Done,
+    Failed,
+}
+
+/// An open archive being read. Created by [`cpio_reader_open`] or
+/// [`cpio_reader_open_memory`] and released with [`cpio_reader_free`].
+pub struct CpioReader {
+    state: ReadState,
+    name: CString,
+}
+
+/// An archive being written. Created by [`cpio_writer_create`] and released with
+/// [`cpio_writer_finish`].
+pub struct CpioWriter {
+    inner: BufWriter<File>,
+}
+
+fn path_arg<'a>(path: *const c_char) -> io::Result<&'a str> {
+    if path.is_null() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "null path"));
+    }
+    // SAFETY: the caller guarantees `path` is a valid NUL-terminated string.
+    unsafe { CStr::from_ptr(path) }
+        .to_str()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path was not valid UTF-8"))
+}
+
+impl CpioReader {
+    fn new(inner: Box<dyn Read>) -> *mut CpioReader {
+        Box::into_raw(Box::new(CpioReader {
+            state: ReadState::Header(inner),
+            name: CString::default(),
+        }))
+    }
+
+    fn next(&mut self, out: &mut CpioEntry) -> io::Result<bool> {
+        let inner = match std::mem::replace(&mut self.state, ReadState::Failed) {
+            ReadState::Header(inner) => inner,
+            ReadState::Data(reader) => reader.finish()?,
+            ReadState::Done => {
+                self.state = ReadState::Done;
+                return Ok(false);
+            }
+            ReadState::Failed => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "reader is unusable after a previous error",
+                ))
+            }
+        };
+
+        let reader = newc::Reader::new(inner)?;
+        let entry = reader.entry();
+        if entry.is_trailer() {
+            self.state = ReadState::Done;
+            return Ok(false);
+        }
+
+        self.name = CString::new(entry.name()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Entry name contains a NUL byte")
+        })?;
+        *out = CpioEntry {
+            name: self.name.as_ptr(),
+            ino: entry.ino(),
+            mode: entry.mode(),
+            uid: entry.uid(),
+            gid: entry.gid(),
+            nlink: entry.nlink(),
+            mtime: entry.mtime(),
+            file_size: entry.file_size(),
+            dev_major: entry.dev_major(),
+            dev_minor: entry.dev_minor(),
+            rdev_major: entry.rdev_major(),
+            rdev_minor: entry.rdev_minor(),
+            has_checksum: entry.checksum().is_some(),
+            checksum: entry.checksum().unwrap_or(0),
+        };
+        self.state = ReadState::Data(reader);
+        Ok(true)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.state {
+            ReadState::Data(ref mut reader) => reader.read(buf),
+            _ => Ok(0),
+        }
+    }
+}
+
+/// Opens the archive at `path` for reading.
+///
+/// Returns null on failure.
+///
+/// # Safety
+///
+/// `path` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cpio_reader_open(path: *const c_char) -> *mut CpioReader {
+    match path_arg(path).and_then(File::open) {
+        Ok(file) => CpioReader::new(Box::new(BufReader::new(file))),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Opens an archive held in memory for reading.
+///
+/// Returns null on failure.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes that remain valid and unmodified until the
+/// reader is released with [`cpio_reader_free`].
+#[no_mangle]
+pub unsafe extern "C" fn cpio_reader_open_memory(data: *const u8, len: usize) -> *mut CpioReader {
+    if data.is_null() && len != 0 {
+        set_last_error("null data pointer");
+        return ptr::null_mut();
+    }
+    let data: &'static [u8] = if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    };
+    CpioReader::new(Box::new(data))
+}
+
+/// Advances to the next entry in the archive, skipping any unread data of the current one, and
+/// fills in `entry` with its metadata.
+///
+/// Returns 1 if an entry was read, 0 once the trailer has been reached, and -1 on failure.
+///
+/// # Safety
+///
+/// `reader` must come from [`cpio_reader_open`] or [`cpio_reader_open_memory`] and `entry`
+/// must point to writable memory for one [`CpioEntry`].
+#[no_mangle]
+pub unsafe extern "C" fn cpio_reader_next(reader: *mut CpioReader, entry: *mut CpioEntry) -> c_int {
+    if reader.is_null() || entry.is_null() {
+        set_last_error("null argument");
+        return -1;
+    }
+    match (*reader).next(&mut *entry) {
+        Ok(true) => 1,
+        Ok(false) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Reads up to `len` bytes of the current entry's data into `buf`.
+///
+/// Returns the number of bytes read, 0 once the entry's data is exhausted, and -1 on failure.
+///
+/// # Safety
+///
+/// `reader` must come from [`cpio_reader_open`] or [`cpio_reader_open_memory`] and `buf` must
+/// point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cpio_reader_read(
+    reader: *mut CpioReader,
+    buf: *mut u8,
+    len: usize,
+) -> isize {
+    if reader.is_null() || (buf.is_null() && len != 0) {
+        set_last_error("null argument");
+        return -1;
+    }
+    if len == 0 {
+        return 0;
+    }
+    let buf = slice::from_raw_parts_mut(buf, len.min(isize::MAX as usize));
+    match (*reader).read(buf) {
+        Ok(n) => n as isize,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Releases a reader. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `reader` must be null or come from [`cpio_reader_open`] or [`cpio_reader_open_memory`], and
+/// must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cpio_reader_free(reader: *mut CpioReader) {
+    if !reader.is_null() {
+        drop(Box::from_raw(reader));
+    }
+}
+
+/// Creates (or truncates) the file at `path` and returns a writer for a new archive.
+///
+/// Returns null on failure.
+///
+/// # Safety
+///
+/// `path` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cpio_writer_create(path: *const c_char) -> *mut CpioWriter {
+    match path_arg(path).and_then(File::create) {
+        Ok(file) => Box::into_raw(Box::new(CpioWriter {
+            inner: BufWriter::new(file),
+        })),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+fn write_entry(writer: &mut CpioWriter, entry: &CpioEntry, data: &[u8]) -> io::Result<()> {
+    let name = path_arg(entry.name)?;
+    let file_size = u32::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "entry data exceeds 4 GiB"))?;
+    let builder = Builder::new(name)
+        .ino(entry.ino)
+        .mode(entry.mode)
+        .uid(entry.uid)
+        .gid(entry.gid)
+        .nlink(entry.nlink)
+        .mtime(entry.mtime)
+        .dev_major(entry.dev_major)
+        .dev_minor(entry.dev_minor)
+        .rdev_major(entry.rdev_major)
+        .rdev_minor(entry.rdev_minor);
+    let mut fp = if entry.has_checksum {
+        builder.write_crc(&mut writer.inner, file_size, entry.checksum)
+    } else {
+        builder.write(&mut writer.inner, file_size)
+    };
+    fp.write_all(data)?;
+    fp.finish()?;
+    Ok(())
+}
+
+/// Appends one entry with the metadata in `entry` and the `len` bytes at `data`.
+///
+/// Returns 0 on success and -1 on failure.
+///
+/// # Safety
+///
+/// `writer` must come from [`cpio_writer_create`], `entry` must point to a valid
+/// [`CpioEntry`] whose `name` is a NUL-terminated string, and `data` must point to `len`
+/// readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cpio_writer_write_entry(
+    writer: *mut CpioWriter,
+    entry: *const CpioEntry,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    if writer.is_null() || entry.is_null() || (data.is_null() && len != 0) {
+        set_last_error("null argument");
+        return -1;
+    }
+    let data = if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    };
+    match write_entry(&mut *writer, &*entry, data) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Writes the archive trailer, flushes the output, and releases the writer.
+///
+/// The writer is released even if this fails. Returns 0 on success and -1 on failure.
+///
+/// # Safety
+///
+/// `writer` must come from [`cpio_writer_create`] and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cpio_writer_finish(writer: *mut CpioWriter) -> c_int {
+    if writer.is_null() {
+        set_last_error("null argument");
+        return -1;
+    }
+    let writer = Box::from_raw(writer);
+    match newc::trailer(writer.inner).and_then(|mut w| w.flush()) {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Returns a description of the most recent failure on the calling thread, or null if nothing
+/// has failed yet. The string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn cpio_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map(|msg| msg.as_ptr())
+            .unwrap_or(ptr::null())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let path = std::env::temp_dir().join(format!("cpio-ffi-{}.cpio", std::process::id()));
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let name = CString::new("./hello_world").unwrap();
+        let data: &[u8] = b"Hello, World";
+
+        unsafe {
+            let writer = cpio_writer_create(c_path.as_ptr());
+            assert!(!writer.is_null());
+            let entry = CpioEntry {
+                name: name.as_ptr(),
+                ino: 1,
+                mode: 0o100644,
+                uid: 1000,
+                gid: 1000,
+                nlink: 1,
+                mtime: 0,
+                file_size: 0,
+                dev_major: 0,
+                dev_minor: 0,
+                rdev_major: 0,
+                rdev_minor: 0,
+                has_checksum: false,
+                checksum: 0,
+            };
+            assert_eq!(
+                cpio_writer_write_entry(writer, &entry, data.as_ptr(), data.len()),
+                0
+            );
+            assert_eq!(cpio_writer_finish(writer), 0);
+
+            let reader = cpio_reader_open(c_path.as_ptr());
+            assert!(!reader.is_null());
+            let mut entry = std::mem::zeroed::<CpioEntry>();
+            assert_eq!(cpio_reader_next(reader, &mut entry), 1);
+            assert_eq!(
+                CStr::from_ptr(entry.name).to_str().unwrap(),
+                "./hello_world"
+            );
+            assert_eq!(entry.file_size, data.len() as u32);
+            assert_eq!(entry.uid, 1000);
+            let mut buf = [0u8; 64];
+            let n = cpio_reader_read(reader, buf.as_mut_ptr(), buf.len());
+            assert_eq!(&buf[..n as usize], data);
+            assert_eq!(cpio_reader_next(reader, &mut entry), 0);
+            cpio_reader_free(reader);
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! Random entries and archives for property tests and fuzzing.
+//!
+//! [`Builder`] and [`Entry`] implement [`Arbitrary`], and [`ArbitraryArchive`] generates
+//! structurally valid archives, so that code handling cpio archives can be fuzzed with
+//! realistic inputs rather than just random bytes, which almost never get past the first
+//! header.
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::newc::{checksum, Builder, Entry, ModeFileType, TRAILER_NAME};
+use crate::ArchiveWriter;
+
+/// File types to pick from: mostly the ones found in real archives.
+const FILE_TYPES: [ModeFileType; 8] = [
+    ModeFileType::Regular,
+    ModeFileType::Directory,
+    ModeFileType::Symlink,
+    ModeFileType::Char,
+    ModeFileType::Block,
+    ModeFileType::Fifo,
+    ModeFileType::Socket,
+    ModeFileType::NetworkSpecial,
+];
+
+/// Picks a name that can be written and read back: no NUL bytes, and not the trailer's name.
+fn arbitrary_name(u: &mut Unstructured<'_>) -> Result<String> {
+    let name = String::arbitrary(u)?.replace('\0', "");
+    Ok(if name == TRAILER_NAME {
+        String::from("TRAILER")
+    } else {
+        name
+    })
+}
+
+impl<'a> Arbitrary<'a> for Builder {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Usually pick a real file type, but sometimes leave whatever the mode holds.
+        let mut mode = u32::arbitrary(u)?;
+        if u.ratio(7, 8)? {
+            mode = u32::from(*u.choose(&FILE_TYPES)?) | mode & 0o7777;
+        }
+        Ok(Builder::new(&arbitrary_name(u)?)
+            .ino(u.arbitrary()?)
+            .mode(mode)
+            .uid(u.arbitrary()?)
+            .gid(u.arbitrary()?)
+            .nlink(u.arbitrary()?)
+            .mtime(u.arbitrary()?)
+            .dev_major(u.arbitrary()?)
+            .dev_minor(u.arbitrary()?)
+            .rdev_major(u.arbitrary()?)
+            .rdev_minor(u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Entry {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let builder = Builder::arbitrary(u)?;
+        Ok(builder.to_entry(u.arbitrary()?, u.arbitrary()?))
+    }
+}
+
+/// A structurally valid archive of arbitrary entries, in either format, ending in a trailer.
+#[derive(Clone, Debug)]
+pub struct ArbitraryArchive {
+    bytes: Vec<u8>,
+    entries: usize,
+}
+
+impl ArbitraryArchive {
+    /// Returns the archive's bytes.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the archive's bytes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Returns the number of entries in the archive, not counting the trailer.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+}
+
+impl<'a> Arbitrary<'a> for ArbitraryArchive {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut archive = ArchiveWriter::new(vec![]);
+        let mut entries = 0;
+        u.arbitrary_loop(None, Some(64), |u| {
+            let builder = Builder::arbitrary(u)?;
+            let data = <&[u8]>::arbitrary(u)?;
+            // Writing to memory can't fail, and the data is far smaller than 4 GiB.
+            let written = if bool::arbitrary(u)? {
+                archive.append_crc(builder, data.len() as u32, checksum(data), data)
+            } else {
+                archive.append_bytes(builder, data)
+            };
+            written.expect("writing to memory failed");
+            entries += 1;
+            Ok(std::ops::ControlFlow::Continue(()))
+        })?;
+        let (bytes, _) = archive.finish().expect("writing to memory failed");
+        Ok(Self { bytes, entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+
+    #[test]
+    fn test_arbitrary_archive() {
+        // A fixed stream of pseudo-random bytes.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let random: Vec<u8> = (0..64 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+
+        let mut u = Unstructured::new(&random);
+        let mut total = 0;
+        while !u.is_empty() {
+            let archive = ArbitraryArchive::arbitrary(&mut u).unwrap();
+            let parsed = Archive::from_bytes(archive.bytes()).unwrap();
+            assert_eq!(parsed.len(), archive.entries());
+            total += archive.entries();
+        }
+        assert!(total > 0);
+
+        let mut u = Unstructured::new(&random);
+        Entry::arbitrary(&mut u).unwrap();
+    }
+}
@@ -0,0 +1,358 @@
+//! Reading archives over HTTP with range requests.
+//!
+//! A [`RangeReader`] makes a remote file readable and seekable by fetching only the byte
+//! ranges that are read, through any [`RangeTransport`], such as one issuing HTTP requests
+//! with a `Range` header. A [`RemoteArchive`] builds on it to list an archive and fetch single
+//! entries from it without downloading the rest: listing reads only the headers, seeking over
+//! entry data, and each entry's data is then fetched with one request.
+//!
+//! The crate has no HTTP client of its own; implement [`RangeTransport`] with whichever one
+//! the application already uses:
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! use std::io;
+//! use std::ops::Range;
+//! use cpio::http::{RangeTransport, RemoteArchive};
+//!
+//! # fn http_head(_url: &str) -> io::Result<u64> { unimplemented!() }
+//! # fn http_get(_url: &str, _range: &str) -> io::Result<Vec<u8>> { unimplemented!() }
+//! struct Cdn(String);
+//!
+//! impl RangeTransport for Cdn {
+//!     fn content_length(&mut self) -> io::Result<u64> {
+//!         http_head(&self.0)
+//!     }
+//!
+//!     fn get_range(&mut self, range: Range<u64>) -> io::Result<Vec<u8>> {
+//!         http_get(&self.0, &format!("bytes={}-{}", range.start, range.end - 1))
+//!     }
+//! }
+//!
+//! let mut archive = RemoteArchive::open(Cdn("https://cdn.example.com/update.cpio".into()))?;
+//! let firmware = archive.fetch("lib/firmware/modem.bin")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::index::{Index, IndexEntry};
+use crate::newc::checksum;
+
+/// Default size of the blocks a [`RangeReader`] fetches for small reads.
+const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Fetches byte ranges of one remote file.
+pub trait RangeTransport {
+    /// Returns the length of the file in bytes, as given by the `Content-Length` of a `HEAD`
+    /// request.
+    fn content_length(&mut self) -> io::Result<u64>;
+
+    /// Returns the bytes of the file in `range`, as given by a `GET` request with a
+    /// `Range: bytes={start}-{end - 1}` header.
+    ///
+    /// The range is never empty and never extends past the length of the file; the bytes
+    /// returned must be exactly those in it.
+    fn get_range(&mut self, range: Range<u64>) -> io::Result<Vec<u8>>;
+}
+
+impl<T: RangeTransport + ?Sized> RangeTransport for &mut T {
+    fn content_length(&mut self) -> io::Result<u64> {
+        (**self).content_length()
+    }
+
+    fn get_range(&mut self, range: Range<u64>) -> io::Result<Vec<u8>> {
+        (**self).get_range(range)
+    }
+}
+
+/// A seekable reader over a remote file, fetching the ranges read through a
+/// [`RangeTransport`].
+///
+/// Reads smaller than the block size fetch a whole block and serve later reads from it, so
+/// that reading a header and name costs one request; larger reads are fetched as they are.
+pub struct RangeReader<T> {
+    transport: T,
+    len: u64,
+    pos: u64,
+    block_size: usize,
+    /// The last block fetched.
+    block: Vec<u8>,
+    /// The position of `block` in the file.
+    block_start: u64,
+}
+
+impl<T: RangeTransport> RangeReader<T> {
+    /// Creates a reader positioned at the start of the file, asking `transport` for its
+    /// length.
+    pub fn new(mut transport: T) -> io::Result<Self> {
+        let len = transport.content_length()?;
+        Ok(RangeReader {
+            transport,
+            len,
+            pos: 0,
+            block_size: DEFAULT_BLOCK_SIZE,
+            block: vec![],
+            block_start: 0,
+        })
+    }
+
+    /// Fetch `size` bytes at a time for reads smaller than that, instead of 64 KiB. A `size`
+    /// of 0 fetches each read as it is.
+    pub fn block_size(mut self, size: usize) -> Self {
+        self.block_size = size;
+        self
+    }
+
+    /// Returns the length of the file in bytes.
+    pub fn content_length(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns a reference to the transport.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    /// Returns the transport, dropping any block held.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Fetches the bytes of the file in `range` with a single request, without moving the
+    /// position of the reader.
+    ///
+    /// Returns an `UnexpectedEof` error if the range extends past the end of the file, and an
+    /// `InvalidData` error if the transport returns a different number of bytes.
+    pub fn read_range(&mut self, range: Range<u64>) -> io::Result<Vec<u8>> {
+        if range.end > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "range extends past the end of the file",
+            ));
+        }
+        if range.start >= range.end {
+            return Ok(vec![]);
+        }
+        let expected = range.end - range.start;
+        let data = self.transport.get_range(range)?;
+        if data.len() as u64 != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "transport returned {} bytes for a range of {}",
+                    data.len(),
+                    expected
+                ),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+impl<T: RangeTransport> Read for RangeReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let in_block =
+            self.pos >= self.block_start && self.pos < self.block_start + self.block.len() as u64;
+        if !in_block {
+            if buf.len() >= self.block_size {
+                let end = self.len.min(self.pos + buf.len() as u64);
+                let data = self.read_range(self.pos..end)?;
+                buf[..data.len()].copy_from_slice(&data);
+                self.pos = end;
+                return Ok(data.len());
+            }
+            let end = self.len.min(self.pos + self.block_size as u64);
+            self.block = self.read_range(self.pos..end)?;
+            self.block_start = self.pos;
+        }
+        let available = &self.block[(self.pos - self.block_start) as usize..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: RangeTransport> Seek for RangeReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => offset_by(self.len, offset),
+            SeekFrom::Current(offset) => offset_by(self.pos, offset),
+        };
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Returns `base` moved by `offset`, if that is a valid position.
+fn offset_by(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        base.checked_sub(offset.unsigned_abs())
+    } else {
+        base.checked_add(offset as u64)
+    }
+}
+
+/// An archive on a remote server, listed once and then read from entry by entry.
+pub struct RemoteArchive<T> {
+    reader: RangeReader<T>,
+    index: Index,
+}
+
+impl<T: RangeTransport> RemoteArchive<T> {
+    /// Lists the archive that `transport` fetches from, reading its headers.
+    pub fn open(transport: T) -> io::Result<Self> {
+        let mut reader = RangeReader::new(transport)?;
+        let index = Index::build(&mut reader)?;
+        Ok(Self::with_index(reader, index))
+    }
+
+    /// Reads from an archive whose index has already been built, such as one stored
+    /// alongside it, without reading its headers again.
+    pub fn with_index(reader: RangeReader<T>, index: Index) -> Self {
+        RemoteArchive { reader, index }
+    }
+
+    /// Returns the index of the archive.
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Returns the reader fetching from the archive, for reading the entries of the index
+    /// with [`Reader::new_at`](crate::NewcReader::new_at).
+    pub fn reader(&mut self) -> &mut RangeReader<T> {
+        &mut self.reader
+    }
+
+    /// Fetches the data of the entry named `name`, or the last of them if there are several,
+    /// with a single request.
+    ///
+    /// Returns a `NotFound` error if the archive holds no such entry, and an `InvalidData`
+    /// error if the entry is in the `crc` format and the data fetched does not match its
+    /// checksum.
+    pub fn fetch(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        let entry = self.index.get(name).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No entry named {}", name))
+        })?;
+        self.fetch_entry(&entry)
+    }
+
+    /// Fetches the data of `entry`, one of the entries of the index, with a single request.
+    ///
+    /// Fails as [`fetch`](Self::fetch) does.
+    pub fn fetch_entry(&mut self, entry: &IndexEntry) -> io::Result<Vec<u8>> {
+        let start = entry.data_offset();
+        let data = self
+            .reader
+            .read_range(start..start + u64::from(entry.entry().file_size()))?;
+        if let Some(sum) = entry.entry().checksum() {
+            if checksum(&data) != sum {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: data does not match its checksum", entry.name()),
+                ));
+            }
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ArchiveWriter, NewcBuilder, NewcReader};
+
+    /// Serves ranges of a file held in memory, recording the requests made.
+    struct Memory {
+        file: Vec<u8>,
+        requests: Vec<Range<u64>>,
+    }
+
+    impl RangeTransport for Memory {
+        fn content_length(&mut self) -> io::Result<u64> {
+            Ok(self.file.len() as u64)
+        }
+
+        fn get_range(&mut self, range: Range<u64>) -> io::Result<Vec<u8>> {
+            self.requests.push(range.clone());
+            Ok(self.file[range.start as usize..range.end as usize].to_vec())
+        }
+    }
+
+    fn archive() -> Memory {
+        let mut archive = ArchiveWriter::new(vec![]);
+        let builder = |name| NewcBuilder::new(name).mode(0o100644);
+        archive
+            .append_bytes(builder("big.bin"), &[7; 100_000])
+            .unwrap();
+        let data = b"firmware";
+        archive
+            .append_crc(builder("fw.bin"), 8, checksum(data), &data[..])
+            .unwrap();
+        Memory {
+            file: archive.finish().unwrap().0,
+            requests: vec![],
+        }
+    }
+
+    #[test]
+    fn test_remote_archive() {
+        let mut transport = archive();
+        let mut archive = RemoteArchive::open(&mut transport).unwrap();
+        let names: Vec<&str> = archive.index().entries().iter().map(|e| e.name()).collect();
+        assert_eq!(names, ["big.bin", "fw.bin"]);
+        assert_eq!(archive.fetch("fw.bin").unwrap(), b"firmware");
+        let err = archive.fetch("missing").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let offset = archive.index().get("fw.bin").unwrap().offset();
+        let mut data = vec![];
+        NewcReader::new_at(archive.reader(), offset)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"firmware");
+
+        // Listing fetched headers only: the first block, and the block at the second entry.
+        let fetched: u64 = transport.requests.iter().map(|r| r.end - r.start).sum();
+        assert!(fetched < 100_000, "fetched {} bytes", fetched);
+    }
+
+    #[test]
+    fn test_range_reader() {
+        let mut reader = RangeReader::new(archive()).unwrap().block_size(16);
+        let mut buf = [0; 6];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"070701");
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(reader.get_ref().requests.len(), 1);
+        assert_eq!(reader.get_ref().requests[0], 0..16);
+
+        // A read of a block or more is fetched directly.
+        let mut big = [0; 32];
+        reader.seek(SeekFrom::Start(200)).unwrap();
+        reader.read_exact(&mut big).unwrap();
+        assert_eq!(big, [7; 32]);
+        assert_eq!(reader.get_ref().requests[1], 200..232);
+
+        let len = reader.content_length();
+        assert_eq!(reader.seek(SeekFrom::End(-1)).unwrap(), len - 1);
+        assert_eq!(reader.read(&mut big).unwrap(), 1);
+        assert_eq!(reader.read(&mut big).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-(len as i64) - 1)).is_err());
+        let err = reader.read_range(len - 1..len + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,985 @@
+//! Indexes of where the entries of archives lie, for finding entries without reading through
+//! whole archives.
+//!
+//! An [`Index`] records the metadata and offset of every entry of one seekable archive, read
+//! in a single pass that seeks over entry data rather than reading it. A [`Catalog`] holds
+//! the indexes of many archives, such as a directory of initrds, and answers which of them
+//! contain entries matching a pattern:
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! use cpio::index::Catalog;
+//!
+//! let catalog = Catalog::from_dir("/srv/initrds")?;
+//! for (archive, entry) in catalog.find("./lib/firmware/iwlwifi-*.ucode") {
+//!     println!("{}: {} at {}", archive.display(), entry.name(), entry.offset());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! An index can also be saved next to its archive, as a `.cpioidx` sidecar file, so that a
+//! process opening a huge archive again and again reads the sidecar rather than scanning the
+//! archive each time; see [`Index::load_or_build`]. A long-running service instead keeps
+//! the indexes it has built in an [`IndexCache`].
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::newc::{
+    pad, Entry, Header, HeaderParsing, ModeFileType, Reader, HEADER_LEN, MAGIC_NUMBER_NEWASCII,
+    MAGIC_NUMBER_NEWCRC,
+};
+
+/// The first word of a sidecar index file, followed by the format version.
+const SIDECAR_MAGIC: &str = "cpioidx 1";
+
+/// The length and modification time of an indexed archive, recorded in its sidecar.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    /// Time since the epoch.
+    mtime: Duration,
+}
+
+impl Stamp {
+    /// Returns the stamp of a file with `metadata`, or `None` if its modification time is not
+    /// available or is before the epoch.
+    fn of(metadata: &fs::Metadata) -> Option<Stamp> {
+        let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Stamp {
+            len: metadata.len(),
+            mtime,
+        })
+    }
+
+    /// Parses the length and `seconds.nanoseconds` modification time of a sidecar's first
+    /// line.
+    fn parse(len: &str, mtime: &str) -> Option<Stamp> {
+        let (secs, nanos) = mtime.split_once('.')?;
+        let nanos: u32 = nanos.parse().ok()?;
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        Some(Stamp {
+            len: len.parse().ok()?,
+            mtime: Duration::new(secs.parse().ok()?, nanos),
+        })
+    }
+}
+
+/// Returns the hash a sidecar records for `entry`: of its header as this crate encodes it,
+/// and its name.
+fn entry_hash(entry: &Entry) -> u64 {
+    let hash = crate::fnv1a(crate::FNV_OFFSET, &entry.header().emit());
+    crate::fnv1a(hash, entry.name().as_bytes())
+}
+
+/// Where one entry lies in an archive, along with its metadata.
+#[derive(Clone)]
+pub struct IndexEntry {
+    entry: Entry,
+    offset: u64,
+    data_offset: u64,
+}
+
+impl IndexEntry {
+    /// Returns the name of the entry.
+    pub fn name(&self) -> &str {
+        self.entry.name()
+    }
+
+    /// Returns the metadata of the entry.
+    pub fn entry(&self) -> &Entry {
+        &self.entry
+    }
+
+    /// Returns the position of the entry's header in the archive.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the position of the entry's data in the archive.
+    pub fn data_offset(&self) -> u64 {
+        self.data_offset
+    }
+}
+
+/// Entries of an archive that are hard links to one file, as found by [`Index::hard_links`].
+#[derive(Clone)]
+pub struct HardLinkGroup<'a> {
+    links: Vec<&'a IndexEntry>,
+}
+
+impl<'a> HardLinkGroup<'a> {
+    /// Returns the links, in archive order.
+    pub fn links(&self) -> &[&'a IndexEntry] {
+        &self.links
+    }
+
+    /// Returns the device and inode numbers the links share, as `(dev_major, dev_minor, ino)`.
+    pub fn inode(&self) -> (u32, u32, u32) {
+        let entry = self.links[0].entry();
+        (entry.dev_major(), entry.dev_minor(), entry.ino())
+    }
+
+    /// Returns the link that carries the file's data, or the last of them if more than one
+    /// does, as extracting them would leave that data in place. Returns `None` if the file is
+    /// empty.
+    pub fn data(&self) -> Option<&'a IndexEntry> {
+        self.links
+            .iter()
+            .rev()
+            .find(|link| link.entry().file_size() != 0)
+            .copied()
+    }
+
+    /// Returns the size of the file's data.
+    pub fn file_size(&self) -> u32 {
+        self.data().map_or(0, |link| link.entry().file_size())
+    }
+
+    /// Returns how many bytes of data the archive saves by storing the file's data once, rather
+    /// than with every link.
+    pub fn saved_bytes(&self) -> u64 {
+        let copies = self
+            .links
+            .iter()
+            .filter(|link| link.entry().file_size() == 0)
+            .count();
+        copies as u64 * u64::from(self.file_size())
+    }
+}
+
+/// The entries of one archive and where they lie in it.
+#[derive(Clone, Default)]
+pub struct Index {
+    entries: Vec<IndexEntry>,
+    /// The position in `entries` of the last entry with each name.
+    by_name: HashMap<String, usize>,
+    /// The position just past the trailer.
+    end: u64,
+}
+
+impl Index {
+    /// Indexes the archive that starts at the current position of `archive`, up to its
+    /// trailer.
+    ///
+    /// Entry data is seeked over rather than read. Offsets are positions in `archive`, as
+    /// returned by [`Seek::stream_position`], so that seeking `archive` to one finds the
+    /// entry there.
+    pub fn build<R: Read + Seek>(mut archive: R) -> io::Result<Index> {
+        let mut index = Index::default();
+        loop {
+            let offset = archive.stream_position()?;
+            let mut reader = Reader::new(archive)?;
+            if reader.entry().is_trailer() {
+                index.end = reader.skip()?.stream_position()?;
+                return Ok(index);
+            }
+            let data_offset = reader.offset()?;
+            index.push(IndexEntry {
+                entry: reader.entry().clone(),
+                offset,
+                data_offset,
+            });
+            archive = reader.skip()?;
+        }
+    }
+
+    fn push(&mut self, entry: IndexEntry) {
+        self.by_name
+            .insert(entry.name().to_string(), self.entries.len());
+        self.entries.push(entry);
+    }
+
+    /// Returns the entries of the archive, in archive order, not including the trailer.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Returns the position just past the archive's trailer, where anything appended to the
+    /// archive would start.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Writes the index to `output` in the sidecar format read by [`Index::read_from`].
+    ///
+    /// The format is text: a `cpioidx 1` line giving the end of the archive and, for sidecars
+    /// written by [`write_sidecar`](Self::write_sidecar), the length and modification time of
+    /// the archive indexed. Then comes a line for each entry, sorted by name, holding its
+    /// offset, its data offset, a hash of its header and name, its header as it is encoded in
+    /// an archive, and its name. Sizes and `crc` checksums are part of the header. Names
+    /// containing a newline can't be written, and give an `InvalidInput` error.
+    pub fn write_to<W: Write>(&self, output: W) -> io::Result<()> {
+        self.write_stamped(output, None)
+    }
+
+    /// Writes the index of the archive at `archive` to its sidecar at
+    /// [`sidecar_path`](Self::sidecar_path), stamped with the archive's length and
+    /// modification time so that [`load_or_build`](Self::load_or_build) can tell whether the
+    /// archive has changed since.
+    pub fn write_sidecar<P: AsRef<Path>>(&self, archive: P) -> io::Result<()> {
+        let stamp = Stamp::of(&fs::metadata(archive.as_ref())?);
+        let file = File::create(Index::sidecar_path(archive))?;
+        self.write_stamped(BufWriter::new(file), stamp)
+    }
+
+    fn write_stamped<W: Write>(&self, mut output: W, stamp: Option<Stamp>) -> io::Result<()> {
+        let mut sorted: Vec<&IndexEntry> = self.entries.iter().collect();
+        sorted.sort_by(|a, b| a.name().cmp(b.name()).then(a.offset.cmp(&b.offset)));
+
+        match stamp {
+            Some(stamp) => writeln!(
+                output,
+                "{} {} {} {}.{:09}",
+                SIDECAR_MAGIC,
+                self.end,
+                stamp.len,
+                stamp.mtime.as_secs(),
+                stamp.mtime.subsec_nanos()
+            )?,
+            None => writeln!(output, "{} {} - -", SIDECAR_MAGIC, self.end)?,
+        }
+        for entry in sorted {
+            if entry.name().contains('\n') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?}: name contains a newline", entry.name()),
+                ));
+            }
+            let header = entry.entry.header().emit();
+            writeln!(
+                output,
+                "{} {} {:016x} {} {}",
+                entry.offset,
+                entry.data_offset,
+                entry_hash(&entry.entry),
+                std::str::from_utf8(&header).expect("headers are ASCII"),
+                entry.name()
+            )?;
+        }
+        output.flush()
+    }
+
+    /// Reads an index written by [`Index::write_to`] or [`Index::write_sidecar`].
+    ///
+    /// Lines that can't be parsed or whose hash doesn't match their header and name, and a
+    /// first line that isn't a `cpioidx 1` line, give an `InvalidData` error. Nothing is
+    /// checked against the archive itself.
+    pub fn read_from<R: BufRead>(input: R) -> io::Result<Index> {
+        Index::read_stamped(input).map(|(index, _)| index)
+    }
+
+    fn read_stamped<R: BufRead>(input: R) -> io::Result<(Index, Option<Stamp>)> {
+        let mut lines = input.lines();
+        let invalid = |line: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid index line {}", line),
+            )
+        };
+        let first = lines.next().transpose()?.unwrap_or_default();
+        let mut fields = first
+            .strip_prefix(SIDECAR_MAGIC)
+            .and_then(|rest| rest.strip_prefix(' '))
+            .ok_or_else(|| invalid(1))?
+            .split(' ');
+        let (end, len, mtime) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(end), Some(len), Some(mtime), None) => (end, len, mtime),
+            _ => return Err(invalid(1)),
+        };
+        let end = end.parse().map_err(|_| invalid(1))?;
+        let stamp = match (len, mtime) {
+            ("-", "-") => None,
+            (len, mtime) => Some(Stamp::parse(len, mtime).ok_or_else(|| invalid(1))?),
+        };
+
+        let mut entries = vec![];
+        for (idx, line) in lines.enumerate() {
+            let line = line?;
+            let invalid = || invalid(idx + 2);
+            let mut fields = line.splitn(5, ' ');
+            let (offset, data_offset, hash, header, name) = match (
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+            ) {
+                (Some(offset), Some(data_offset), Some(hash), Some(header), Some(name)) => {
+                    (offset, data_offset, hash, header, name)
+                }
+                _ => return Err(invalid()),
+            };
+            let offset: u64 = offset.parse().map_err(|_| invalid())?;
+            let data_offset: u64 = data_offset.parse().map_err(|_| invalid())?;
+            let hash = u64::from_str_radix(hash, 16).map_err(|_| invalid())?;
+            if header.len() != HEADER_LEN {
+                return Err(invalid());
+            }
+            let mut buf = [0; HEADER_LEN];
+            buf.copy_from_slice(header.as_bytes());
+            let parsed =
+                Header::parse_with(&buf, HeaderParsing::Tolerant).map_err(|_| invalid())?;
+            if parsed.name_size as usize != name.len() + 1 {
+                return Err(invalid());
+            }
+
+            // Decode the metadata as the reader would from the entry in the archive.
+            let name_size = name.len() + 1;
+            let mut raw = Vec::with_capacity(HEADER_LEN + name_size + 3);
+            raw.extend_from_slice(&buf);
+            raw.extend_from_slice(name.as_bytes());
+            raw.resize(raw.len() + 1 + pad(HEADER_LEN + name_size), 0);
+            let entry = Reader::with_parsing(raw.as_slice(), HeaderParsing::Tolerant)
+                .map_err(|_| invalid())?
+                .entry()
+                .clone();
+            if entry_hash(&entry) != hash {
+                return Err(invalid());
+            }
+            entries.push(IndexEntry {
+                entry,
+                offset,
+                data_offset,
+            });
+        }
+
+        entries.sort_by_key(|entry| entry.offset);
+        let mut index = Index {
+            end,
+            ..Index::default()
+        };
+        for entry in entries {
+            index.push(entry);
+        }
+        Ok((index, stamp))
+    }
+
+    /// Returns the path of the sidecar index of the archive at `archive`: the archive's path
+    /// with `.cpioidx` appended.
+    pub fn sidecar_path<P: AsRef<Path>>(archive: P) -> PathBuf {
+        let mut path = archive.as_ref().as_os_str().to_os_string();
+        path.push(".cpioidx");
+        PathBuf::from(path)
+    }
+
+    /// Returns the index of the archive at `path`, read from its sidecar if it has an
+    /// up-to-date one, and otherwise built by scanning the archive.
+    ///
+    /// A sidecar is used only if it was written by [`write_sidecar`](Self::write_sidecar)
+    /// for an archive of the same length and modification time, and the headers of the first
+    /// and last entries it lists are found at their offsets in the archive with the hashes it
+    /// records for them. Otherwise the archive is indexed, and the sidecar written for next
+    /// time; failing to write it, such as in a read-only directory, is not an error.
+    pub fn load_or_build<P: AsRef<Path>>(path: P) -> io::Result<Index> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let stamp = Stamp::of(&file.metadata()?);
+
+        let saved = File::open(Index::sidecar_path(path))
+            .and_then(|sidecar| Index::read_stamped(BufReader::new(sidecar)));
+        if let (Some(stamp), Ok((index, Some(saved)))) = (stamp, saved) {
+            if saved == stamp && index.matches(&mut file).unwrap_or(false) {
+                return Ok(index);
+            }
+        }
+
+        file.rewind()?;
+        let index = Index::build(BufReader::new(file))?;
+        let _ = index.write_sidecar(path);
+        Ok(index)
+    }
+
+    /// Returns true if the first and last entries of the index are found in `archive` where
+    /// the index has them, with the same headers and names.
+    fn matches<R: Read + Seek>(&self, archive: &mut R) -> io::Result<bool> {
+        for entry in self.entries.first().into_iter().chain(self.entries.last()) {
+            let mut reader = Reader::new_at(&mut *archive, entry.offset)?;
+            if entry_hash(reader.entry()) != entry_hash(&entry.entry)
+                || reader.offset()? != entry.data_offset
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the entry named `name`, or the last of them if the archive holds several, as
+    /// extracting it would leave that one in place.
+    pub fn get(&self, name: &str) -> Option<&IndexEntry> {
+        self.by_name.get(name).map(|&idx| &self.entries[idx])
+    }
+
+    /// Returns the groups of entries that are hard links to one file, in order of the first
+    /// link of each.
+    ///
+    /// Entries are hard links to one file if they share device and inode numbers and have a
+    /// link count above one. Directories, whose link counts come from their subdirectories,
+    /// and files with only one link in the archive are left out.
+    pub fn hard_links(&self) -> impl Iterator<Item = HardLinkGroup<'_>> {
+        let mut groups: Vec<HardLinkGroup<'_>> = vec![];
+        let mut inodes = HashMap::new();
+        for entry in &self.entries {
+            let meta = entry.entry();
+            if meta.nlink() < 2
+                || ModeFileType::from_mode(meta.mode()) == Some(ModeFileType::Directory)
+            {
+                continue;
+            }
+            let key = (meta.dev_major(), meta.dev_minor(), meta.ino());
+            let idx = *inodes.entry(key).or_insert_with(|| {
+                groups.push(HardLinkGroup { links: vec![] });
+                groups.len() - 1
+            });
+            groups[idx].links.push(entry);
+        }
+        groups.into_iter().filter(|group| group.links.len() > 1)
+    }
+
+    /// Returns the entries whose names match the shell-style `pattern`, in archive order.
+    ///
+    /// In the pattern, `*` matches any run of characters but `/`, `?` matches any one
+    /// character but `/`, and `[...]` matches any one of the characters in the brackets,
+    /// such as `[abc]` or `[a-z]`, or any but them if the first is `!` or `^`. Every other
+    /// character matches itself.
+    pub fn find<'a>(&'a self, pattern: &'a str) -> impl Iterator<Item = &'a IndexEntry> + 'a {
+        let pattern: Vec<char> = pattern.chars().collect();
+        self.entries
+            .iter()
+            .filter(move |entry| glob_matches(&pattern, entry.name()))
+    }
+}
+
+/// The indexes of many archives, searchable together.
+#[derive(Clone, Default)]
+pub struct Catalog {
+    archives: Vec<(PathBuf, Index)>,
+}
+
+impl Catalog {
+    /// Returns an empty catalog.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Indexes every archive in the directory `dir`, in order of file name.
+    ///
+    /// Subdirectories are not searched, and files that don't start with a `newc` or `crc`
+    /// header, such as compressed archives, are left out.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> io::Result<Catalog> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+
+        let mut catalog = Catalog::new();
+        for path in paths {
+            let mut file = BufReader::new(File::open(&path)?);
+            let mut magic = [0; 6];
+            let is_archive = match file.read_exact(&mut magic) {
+                Ok(()) => magic == MAGIC_NUMBER_NEWASCII || magic == MAGIC_NUMBER_NEWCRC,
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+                Err(err) => return Err(err),
+            };
+            if !is_archive {
+                continue;
+            }
+            file.rewind()?;
+            let index = Index::build(file).map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+            })?;
+            catalog.insert(path, index);
+        }
+        Ok(catalog)
+    }
+
+    /// Adds the index of the archive at `path`.
+    pub fn insert(&mut self, path: PathBuf, index: Index) {
+        self.archives.push((path, index));
+    }
+
+    /// Returns the archives in the catalog, in the order they were added, with their indexes.
+    pub fn archives(&self) -> impl Iterator<Item = (&Path, &Index)> {
+        self.archives
+            .iter()
+            .map(|(path, index)| (path.as_path(), index))
+    }
+
+    /// Returns every entry, in every archive, whose name matches the shell-style `pattern`,
+    /// as for [`Index::find`], along with the path of the archive holding it.
+    pub fn find<'a>(
+        &'a self,
+        pattern: &'a str,
+    ) -> impl Iterator<Item = (&'a Path, &'a IndexEntry)> + 'a {
+        self.archives.iter().flat_map(move |(path, index)| {
+            index
+                .find(pattern)
+                .map(move |entry| (path.as_path(), entry))
+        })
+    }
+}
+
+/// Counts of how an [`IndexCache`] has answered lookups, for sizing it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    stale: u64,
+    evictions: u64,
+}
+
+impl CacheStats {
+    /// Returns the number of lookups answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Returns the number of lookups that had to index the archive, stale ones included.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Returns the number of misses caused by an archive having changed since it was cached.
+    pub fn stale(&self) -> u64 {
+        self.stale
+    }
+
+    /// Returns the number of indexes dropped to make room for others.
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+}
+
+struct CacheSlot {
+    modified: SystemTime,
+    len: u64,
+    index: Arc<Index>,
+    /// The value of the cache's clock when the slot was last looked up.
+    last_used: u64,
+}
+
+/// A cache of the indexes of recently used archives, keyed by path and modification time.
+///
+/// Looking an archive up returns its cached index if the archive's modification time and
+/// length are unchanged since it was indexed, and otherwise indexes it again. Once the cache
+/// holds `capacity` indexes, the least recently used one is dropped to make room for a new
+/// one. Indexes are handed out as [`Arc`]s, so they stay usable after being dropped from the
+/// cache; share a cache between threads by putting it behind a `Mutex`.
+pub struct IndexCache {
+    capacity: usize,
+    slots: HashMap<PathBuf, CacheSlot>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl IndexCache {
+    /// Returns an empty cache holding at most `capacity` indexes. A capacity of zero caches
+    /// nothing.
+    pub fn new(capacity: usize) -> Self {
+        IndexCache {
+            capacity,
+            slots: HashMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Returns the index of the archive at `path`, from the cache if it is up to date, and
+    /// otherwise by indexing the archive with [`Index::build`] and caching the result.
+    pub fn get<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Arc<Index>> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata.modified()?;
+        let len = metadata.len();
+        self.clock += 1;
+
+        if let Some(slot) = self.slots.get_mut(path) {
+            if slot.modified == modified && slot.len == len {
+                slot.last_used = self.clock;
+                self.stats.hits += 1;
+                return Ok(slot.index.clone());
+            }
+            self.stats.stale += 1;
+        }
+        self.stats.misses += 1;
+
+        let index = Index::build(BufReader::new(file))
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+        let index = Arc::new(index);
+        if self.capacity == 0 {
+            return Ok(index);
+        }
+        if !self.slots.contains_key(path) && self.slots.len() >= self.capacity {
+            let oldest = self
+                .slots
+                .iter()
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.slots.remove(&oldest);
+                self.stats.evictions += 1;
+            }
+        }
+        self.slots.insert(
+            path.to_path_buf(),
+            CacheSlot {
+                modified,
+                len,
+                index: index.clone(),
+                last_used: self.clock,
+            },
+        );
+        Ok(index)
+    }
+
+    /// Drops the cached index of the archive at `path`, returning whether there was one.
+    pub fn invalidate<P: AsRef<Path>>(&mut self, path: P) -> bool {
+        self.slots.remove(path.as_ref()).is_some()
+    }
+
+    /// Drops every cached index. The statistics are kept.
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    /// Returns the number of indexes in the cache.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Returns true if the cache holds no indexes.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Returns the most indexes the cache holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns how the cache has answered lookups so far.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+/// Returns true if `name` matches the shell-style `pattern`, as described on [`Index::find`].
+fn glob_matches(pattern: &[char], name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    // Where to resume after the last `*`: the position in the pattern just after it, and the
+    // position in the name that it has matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+                continue;
+            }
+            Some('?') if name[n] != '/' => Some(p + 1),
+            Some('[') => match_class(pattern, p, name[n]).filter(|_| name[n] != '/'),
+            Some(&c) if c == name[n] => Some(p + 1),
+            _ => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                p = next;
+                n += 1;
+            }
+            // Let the last `*` match one more character, unless that would cross a `/`.
+            (None, Some((after, matched))) if name[matched] != '/' => {
+                star = Some((after, matched + 1));
+                p = after;
+                n = matched + 1;
+            }
+            _ => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the bracketed class starting at `pattern[start]`, returning the
+/// position after the class if it matches. An unterminated `[` matches itself.
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<usize> {
+    let mut p = start + 1;
+    let negated = matches!(pattern.get(p), Some('!') | Some('^'));
+    if negated {
+        p += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match pattern.get(p) {
+            None => return if c == '[' { Some(start + 1) } else { None },
+            Some(']') if !first => break,
+            Some(&lo) => {
+                if pattern.get(p + 1) == Some(&'-')
+                    && pattern.get(p + 2).map_or(false, |&hi| hi != ']')
+                {
+                    matched |= lo <= c && c <= pattern[p + 2];
+                    p += 3;
+                } else {
+                    matched |= lo == c;
+                    p += 1;
+                }
+            }
+        }
+        first = false;
+    }
+    if matched != negated {
+        Some(p + 1)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcBuilder;
+    use std::io::Cursor;
+
+    fn archive(names: &[&str]) -> Vec<u8> {
+        let inputs = names
+            .iter()
+            .map(|&name| (NewcBuilder::new(name).mode(0o100644), name.as_bytes()));
+        crate::write_cpio_bytes(inputs, vec![]).unwrap().0
+    }
+
+    #[test]
+    fn test_index() {
+        let data = archive(&["a", "dir/b", "a"]);
+        let mut cursor = Cursor::new(data);
+        let index = Index::build(&mut cursor).unwrap();
+        assert_eq!(index.entries().len(), 3);
+        assert_eq!(index.entries()[0].offset(), 0);
+        assert_eq!(index.entries()[0].data_offset(), 112);
+
+        let entry = index.get("dir/b").unwrap();
+        let mut data = String::new();
+        Reader::new_at(&mut cursor, entry.offset())
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "dir/b");
+        assert_eq!(
+            index.get("a").unwrap().offset(),
+            index.entries()[2].offset()
+        );
+        assert!(index.get("c").is_none());
+        assert_eq!(index.end(), cursor.get_ref().len() as u64);
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let mut writer = crate::ArchiveWriter::new(vec![]);
+        writer
+            .append_bytes(NewcBuilder::new("b").mode(0o100644).uid(7), b"bee")
+            .unwrap();
+        writer
+            .append_bytes(NewcBuilder::new("a name").mode(0o100644), b"a")
+            .unwrap();
+        writer
+            .append_bytes(NewcBuilder::new("dir").mode(0o040755), b"")
+            .unwrap();
+        let index = Index::build(Cursor::new(writer.finish().unwrap().0)).unwrap();
+
+        let mut sidecar = vec![];
+        index.write_to(&mut sidecar).unwrap();
+        let text = String::from_utf8(sidecar.clone()).unwrap();
+        let names: Vec<&str> = text
+            .lines()
+            .skip(1)
+            .map(|line| line.splitn(5, ' ').nth(4).unwrap())
+            .collect();
+        assert_eq!(names, ["a name", "b", "dir"]);
+        assert!(text.lines().next().unwrap().ends_with(" - -"));
+
+        let read = Index::read_from(sidecar.as_slice()).unwrap();
+        assert_eq!(read.end(), index.end());
+        assert_eq!(read.entries().len(), 3);
+        for (read, built) in read.entries().iter().zip(index.entries()) {
+            assert_eq!(read.name(), built.name());
+            assert_eq!(read.offset(), built.offset());
+            assert_eq!(read.data_offset(), built.data_offset());
+            assert_eq!(read.entry().header(), built.entry().header());
+        }
+        assert_eq!(read.get("b").unwrap().entry().uid(), 7);
+
+        let mut bad = sidecar.clone();
+        bad.extend_from_slice(b"12 nonsense\n");
+        let err = Index::read_from(bad.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(Index::read_from(&b"not an index\n"[..]).is_err());
+        // A line whose header doesn't match its hash is refused.
+        let tampered = text.replacen("00000007", "00000008", 1);
+        assert_ne!(tampered, text);
+        assert!(Index::read_from(tampered.as_bytes()).is_err());
+
+        // The first load writes the sidecar, and the second reads it.
+        let path = std::env::temp_dir().join(format!("cpio-sidecar-{}.cpio", std::process::id()));
+        fs::write(&path, archive(&["x", "y"])).unwrap();
+        let sidecar = Index::sidecar_path(&path);
+        let _ = fs::remove_file(&sidecar);
+        let built = Index::load_or_build(&path).unwrap();
+        assert!(sidecar.exists());
+        let loaded = Index::load_or_build(&path).unwrap();
+        assert_eq!(loaded.entries().len(), 2);
+        assert_eq!(
+            loaded.get("y").unwrap().offset(),
+            built.get("y").unwrap().offset()
+        );
+
+        // A sidecar stamped like the archive, but for other contents, is not used: here the
+        // archive is rewritten at the same length, as if its modification time were kept.
+        fs::write(&path, archive(&["p", "q"])).unwrap();
+        built.write_sidecar(&path).unwrap();
+        let loaded = Index::load_or_build(&path).unwrap();
+        assert!(loaded.get("p").is_some());
+        assert!(loaded.get("x").is_none());
+        // A sidecar for an archive of another length isn't either.
+        fs::write(&path, archive(&["longer", "q"])).unwrap();
+        assert!(Index::load_or_build(&path).unwrap().get("longer").is_some());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&sidecar).unwrap();
+    }
+
+    #[test]
+    fn test_hard_links() {
+        let link = |name, ino| NewcBuilder::new(name).mode(0o100644).ino(ino).nlink(3);
+        let mut archive = crate::ArchiveWriter::new(vec![]);
+        let entries: Vec<(NewcBuilder, &[u8])> = vec![
+            (link("a", 1), b""),
+            (NewcBuilder::new("dir").mode(0o040755).ino(2).nlink(2), b""),
+            (link("b", 1), b"shared"),
+            (link("c", 1), b""),
+            (link("lonely", 5), b"data"),
+            (
+                NewcBuilder::new("dir/sub").mode(0o040755).ino(2).nlink(2),
+                b"",
+            ),
+        ];
+        for (builder, data) in entries {
+            archive.append_bytes(builder, data).unwrap();
+        }
+        let archive = archive.finish().unwrap().0;
+        let index = Index::build(Cursor::new(archive)).unwrap();
+
+        let groups: Vec<HardLinkGroup<'_>> = index.hard_links().collect();
+        assert_eq!(groups.len(), 1);
+        let names: Vec<&str> = groups[0].links().iter().map(|link| link.name()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(groups[0].inode(), (0, 0, 1));
+        assert_eq!(groups[0].data().unwrap().name(), "b");
+        assert_eq!(groups[0].file_size(), 6);
+        assert_eq!(groups[0].saved_bytes(), 12);
+    }
+
+    #[test]
+    fn test_glob() {
+        let matches =
+            |pattern: &str, name| glob_matches(&pattern.chars().collect::<Vec<_>>(), name);
+        assert!(matches(
+            "./lib/firmware/iwlwifi-*.ucode",
+            "./lib/firmware/iwlwifi-7265-17.ucode"
+        ));
+        assert!(!matches(
+            "./lib/firmware/iwlwifi-*.ucode",
+            "./lib/firmware/iwlwifi-a/b.ucode"
+        ));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXbYbc"));
+        assert!(!matches("a*b", "a/b/b"));
+        assert!(matches("*/*", "a/b"));
+        assert!(matches("?x", "ax"));
+        assert!(!matches("?", "/"));
+        assert!(!matches("?", ""));
+        assert!(matches("[a-c]1", "b1"));
+        assert!(!matches("[!a-c]1", "b1"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[", "["));
+        assert!(!matches("abc", "ab"));
+    }
+
+    #[test]
+    fn test_catalog() {
+        let dir = std::env::temp_dir().join(format!("cpio-catalog-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("one.cpio"),
+            archive(&["lib/fw-a.bin", "etc/hosts"]),
+        )
+        .unwrap();
+        fs::write(dir.join("two.cpio"), archive(&["lib/fw-b.bin"])).unwrap();
+        fs::write(dir.join("notes.txt"), b"not an archive").unwrap();
+        fs::write(dir.join("empty"), b"").unwrap();
+
+        let catalog = Catalog::from_dir(&dir).unwrap();
+        assert_eq!(catalog.archives().count(), 2);
+        let hits: Vec<(String, &str)> = catalog
+            .find("lib/fw-*.bin")
+            .map(|(path, entry)| {
+                let file = path.file_name().unwrap().to_str().unwrap().to_string();
+                (file, entry.name())
+            })
+            .collect();
+        assert_eq!(
+            hits,
+            vec![
+                ("one.cpio".to_string(), "lib/fw-a.bin"),
+                ("two.cpio".to_string(), "lib/fw-b.bin"),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = std::env::temp_dir().join(format!("cpio-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b, c) = (dir.join("a.cpio"), dir.join("b.cpio"), dir.join("c.cpio"));
+        fs::write(&a, archive(&["one"])).unwrap();
+        fs::write(&b, archive(&["two"])).unwrap();
+        fs::write(&c, archive(&["three"])).unwrap();
+
+        let mut cache = IndexCache::new(2);
+        let first = cache.get(&a).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.get(&a).unwrap()));
+        cache.get(&b).unwrap();
+        cache.get(&a).unwrap();
+        // b is the least recently used, so it makes room for c.
+        cache.get(&c).unwrap();
+        assert_eq!(cache.len(), 2);
+        cache.get(&a).unwrap();
+        cache.get(&b).unwrap();
+
+        // A changed archive is indexed again.
+        fs::write(&a, archive(&["one", "more"])).unwrap();
+        assert_eq!(cache.get(&a).unwrap().entries().len(), 2);
+        assert_eq!(first.entries().len(), 1);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits(), 3);
+        assert_eq!(stats.misses(), 5);
+        assert_eq!(stats.stale(), 1);
+        assert_eq!(stats.evictions(), 2);
+        assert!(cache.invalidate(&a));
+        assert!(!cache.invalidate(&a));
+        assert!(cache.get(dir.join("missing.cpio")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
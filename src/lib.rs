@@ -6,38 +6,380 @@
 //! now, this library only supports the `newc` (SVR4) format.
 
 use std::io;
-use std::iter::Iterator;
 
+/// Emits a `tracing` debug event when the `tracing` feature is enabled, and compiles to
+/// nothing otherwise.
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+pub mod archive;
+#[cfg(feature = "digest")]
+pub mod blobs;
+#[cfg(feature = "bytes")]
+pub mod buf;
+pub mod compress;
+pub mod diagnostics;
+pub mod diff;
+#[cfg(feature = "digest")]
+pub mod digest;
+#[cfg(feature = "encrypt")]
+pub mod encrypt;
+pub mod exact;
+pub mod explain;
+pub mod extract;
+pub mod fakeroot;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "arbitrary")]
+pub mod fuzzing;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod index;
+pub mod lint;
+#[cfg(feature = "macros")]
+mod macros;
+#[cfg(feature = "digest")]
+pub mod manifest;
+pub mod name;
 pub mod newc;
+pub mod owner;
+#[cfg(feature = "rayon")]
+pub mod parallel;
+pub mod patch;
+pub mod prefetch;
+pub mod progress;
+pub mod repair;
+pub mod rewrite;
+#[cfg(feature = "sign")]
+pub mod sign;
+pub mod source;
+#[cfg(feature = "tar")]
+mod tar_header;
+pub mod tree;
+pub mod writer;
 pub use newc::Builder as NewcBuilder;
 pub use newc::Reader as NewcReader;
+use writer::{entry_len, InputErrors, InputFailure, ZeroFill};
+pub use writer::{ArchiveWriter, Summary};
 
 /// Creates a new CPIO archive.
-pub fn write_cpio<I, RS, W>(inputs: I, output: W) -> io::Result<W>
+///
+/// Each entry's inode number is set to its index in `inputs`. Returns the output along with
+/// statistics about the archive written.
+pub fn write_cpio<I, RS, W>(inputs: I, output: W) -> io::Result<(W, Summary)>
+where
+    I: IntoIterator<Item = (NewcBuilder, RS)>,
+    RS: io::Read + io::Seek,
+    W: io::Write,
+{
+    write_seekable(inputs, ArchiveWriter::new(output))
+}
+
+/// Creates a new CPIO archive like [`write_cpio`], reporting progress to `observer` as each
+/// chunk of input is copied and as each entry completes.
+pub fn write_cpio_with_progress<I, RS, W, O>(
+    inputs: I,
+    output: W,
+    observer: O,
+) -> io::Result<(W, Summary)>
+where
+    I: IntoIterator<Item = (NewcBuilder, RS)>,
+    RS: io::Read + io::Seek,
+    W: io::Write,
+    O: progress::Observer + 'static,
+{
+    write_seekable(inputs, ArchiveWriter::new(output).progress(observer))
+}
+
+fn write_seekable<I, RS, W>(inputs: I, mut archive: ArchiveWriter<W>) -> io::Result<(W, Summary)>
+where
+    I: IntoIterator<Item = (NewcBuilder, RS)>,
+    RS: io::Read + io::Seek,
+    W: io::Write,
+{
+    for (idx, (builder, input)) in inputs.into_iter().enumerate() {
+        // Write out the file with a unique inode number
+        append_seekable(&mut archive, builder.ino(idx as u32), input)?;
+    }
+
+    archive.finish()
+}
+
+/// Appends an input whose length is found by seeking to its end.
+fn append_seekable<W, RS>(
+    archive: &mut ArchiveWriter<W>,
+    builder: NewcBuilder,
+    mut input: RS,
+) -> io::Result<()>
+where
+    RS: io::Read + io::Seek,
+    W: io::Write,
+{
+    // Grab the length of the input file
+    let len = input.seek(io::SeekFrom::End(0))?;
+    input.seek(io::SeekFrom::Start(0))?;
+
+    archive.append(builder, entry_len(len)?, input)
+}
+
+/// Creates a new CPIO archive from inputs that may have failed to open.
+///
+/// This works like [`write_cpio`], but the iterator yields `io::Result`s, so errors from
+/// opening input files propagate without unwrapping inside the iterator. Any error, whether
+/// yielded by the iterator or hit while writing an input, is returned with the index (and,
+/// once known, the name) of the input it belongs to prepended to its message.
+pub fn try_write_cpio<I, RS, W>(inputs: I, output: W) -> io::Result<(W, Summary)>
+where
+    I: IntoIterator<Item = io::Result<(NewcBuilder, RS)>>,
+    RS: io::Read + io::Seek,
+    W: io::Write,
+{
+    let mut archive = ArchiveWriter::new(output);
+    for (idx, input) in inputs.into_iter().enumerate() {
+        let (builder, input) = input.map_err(|err| input_error(idx, None, err))?;
+        let name = builder.name().to_string();
+        append_seekable(&mut archive, builder.ino(idx as u32), input)
+            .map_err(|err| input_error(idx, Some(&name), err))?;
+    }
+
+    archive.finish()
+}
+
+/// Adds context about which input failed to an error.
+fn input_error(idx: usize, name: Option<&str>, err: io::Error) -> io::Error {
+    let msg = match name {
+        Some(name) => format!("input {} ({}): {}", idx, name, err),
+        None => format!("input {}: {}", idx, err),
+    };
+    io::Error::new(err.kind(), msg)
+}
+
+/// Creates a new CPIO archive from inputs whose lengths are known up front.
+///
+/// This works like [`write_cpio`], but each input is given together with its length, so it
+/// does not need to be seekable; pipes, sockets, and process output can be archived directly.
+/// Each input must yield at least `len` bytes.
+pub fn write_cpio_sized<I, R, W>(inputs: I, output: W) -> io::Result<(W, Summary)>
 where
-    I: Iterator<Item = (NewcBuilder, RS)> + Sized,
+    I: IntoIterator<Item = (NewcBuilder, u64, R)>,
+    R: io::Read,
+    W: io::Write,
+{
+    let mut archive = ArchiveWriter::new(output);
+    for (idx, (builder, len, input)) in inputs.into_iter().enumerate() {
+        archive.append(builder.ino(idx as u32), entry_len(len)?, input)?;
+    }
+
+    archive.finish()
+}
+
+/// Creates a new CPIO archive from inputs that are already held in memory.
+///
+/// This works like [`write_cpio`], but each input is any byte container (`&[u8]`, `Vec<u8>`,
+/// `bytes::Bytes`, ...) and is written with a single `write_all` rather than copied through a
+/// reader.
+pub fn write_cpio_bytes<I, D, W>(inputs: I, output: W) -> io::Result<(W, Summary)>
+where
+    I: IntoIterator<Item = (NewcBuilder, D)>,
+    D: AsRef<[u8]>,
+    W: io::Write,
+{
+    let mut archive = ArchiveWriter::new(output);
+    for (idx, (builder, data)) in inputs.into_iter().enumerate() {
+        archive.append_bytes(builder.ino(idx as u32), data.as_ref())?;
+    }
+
+    archive.finish()
+}
+
+/// The data of one input to [`write_cpio_inputs`].
+pub enum Input<R> {
+    /// The entry has no data, as for directories and device nodes.
+    NoData,
+    /// The entry's data is held in memory, as for the target of a symlink.
+    Inline(Vec<u8>),
+    /// The entry's data is read from a reader whose length is found by seeking to its end.
+    Reader(R),
+}
+
+/// Creates a new CPIO archive from inputs that may have no data, data held in memory, or data
+/// to be read.
+///
+/// This works like [`write_cpio`], but each input is an [`Input`], so that archives mixing
+/// directories, symlinks, and files can be written in one call.
+pub fn write_cpio_inputs<I, RS, W>(inputs: I, output: W) -> io::Result<(W, Summary)>
+where
+    I: IntoIterator<Item = (NewcBuilder, Input<RS>)>,
     RS: io::Read + io::Seek,
     W: io::Write,
 {
-    let output = inputs
-        .enumerate()
-        .try_fold(output, |output, (idx, (builder, mut input))| {
-            // If the output is valid, try to write the next input file
-            // Grab the length of the input file
+    let mut archive = ArchiveWriter::new(output);
+    for (idx, (builder, input)) in inputs.into_iter().enumerate() {
+        let builder = builder.ino(idx as u32);
+        match input {
+            Input::NoData => archive.append_bytes(builder, &[])?,
+            Input::Inline(data) => archive.append_bytes(builder, &data)?,
+            Input::Reader(input) => append_seekable(&mut archive, builder, input)?,
+        }
+    }
+
+    archive.finish()
+}
+
+/// Creates a new CPIO archive from inputs that are only opened as they are written.
+///
+/// This works like [`write_cpio`], but each input is given as a function that opens it, which
+/// is called just before its entry is written, and the input dropped straight after, so only
+/// one input is open at a time however many there are, even if `inputs` is collected first.
+/// An error from opening or writing an input is returned with the index and name of the input
+/// prepended to its message, as by [`try_write_cpio`].
+pub fn write_cpio_lazy<I, F, RS, W>(inputs: I, output: W) -> io::Result<(W, Summary)>
+where
+    I: IntoIterator<Item = (NewcBuilder, F)>,
+    F: FnOnce() -> io::Result<RS>,
+    RS: io::Read + io::Seek,
+    W: io::Write,
+{
+    let mut archive = ArchiveWriter::new(output);
+    for (idx, (builder, open)) in inputs.into_iter().enumerate() {
+        let name = builder.name().to_string();
+        open()
+            .and_then(|input| append_seekable(&mut archive, builder.ino(idx as u32), input))
+            .map_err(|err| input_error(idx, Some(&name), err))?;
+    }
+
+    archive.finish()
+}
+
+/// Creates a new CPIO archive from inputs that are only opened as they are written, like
+/// [`write_cpio_lazy`], handling inputs that fail to open or to be read as `policy` says.
+///
+/// Every input that fails and is carried on past is recorded in [`Summary::failures`]. Errors
+/// in writing the output always stop the archive, and so do inputs that fail partway through
+/// being read, unless they are substituted.
+pub fn write_cpio_lazy_with_policy<I, F, RS, W>(
+    inputs: I,
+    output: W,
+    mut policy: InputErrors<'_>,
+) -> io::Result<(W, Summary)>
+where
+    I: IntoIterator<Item = (NewcBuilder, F)>,
+    F: FnOnce() -> io::Result<RS>,
+    RS: io::Read + io::Seek,
+    W: io::Write,
+{
+    if let InputErrors::FailFast = policy {
+        return write_cpio_lazy(inputs, output);
+    }
+    let mut archive = ArchiveWriter::new(output);
+    for (idx, (builder, open)) in inputs.into_iter().enumerate() {
+        let name = builder.name().to_string();
+        let builder = builder.ino(idx as u32);
+        let opened = open().and_then(|mut input| {
             let len = input.seek(io::SeekFrom::End(0))?;
             input.seek(io::SeekFrom::Start(0))?;
+            Ok((input, entry_len(len)?))
+        });
+        let (err, partial) = match opened {
+            Ok((input, len)) if matches!(policy, InputErrors::Skip(_)) => {
+                archive
+                    .append(builder, len, input)
+                    .map_err(|err| input_error(idx, Some(&name), err))?;
+                continue;
+            }
+            Ok((input, len)) => {
+                let mut error = None;
+                let data = ZeroFill {
+                    inner: input,
+                    remaining: u64::from(len),
+                    error: &mut error,
+                };
+                archive
+                    .append(builder, len, data)
+                    .map_err(|err| input_error(idx, Some(&name), err))?;
+                match error {
+                    Some(err) => (err, true),
+                    None => continue,
+                }
+            }
+            Err(err) => {
+                if let InputErrors::SubstituteEmpty = policy {
+                    archive
+                        .append_bytes(builder, &[])
+                        .map_err(|err| input_error(idx, Some(&name), err))?;
+                }
+                (err, false)
+            }
+        };
+        let failure = InputFailure {
+            index: idx,
+            name,
+            kind: err.kind(),
+            message: err.to_string(),
+            partial,
+        };
+        if let InputErrors::Skip(ref mut report) = policy {
+            report(&failure);
+        }
+        archive.record_failure(failure);
+    }
 
-            // Create our writer fp with a unique inode number
-            let mut fp = builder.ino(idx as u32).write(output, len as u32);
+    archive.finish()
+}
 
-            // Write out the file
-            io::copy(&mut input, &mut fp)?;
+pub(crate) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
 
-            // And finish off the input file
-            fp.finish()
-        })?;
+/// Updates the 64-bit FNV-1a hash `hash` with `data`. The hash only needs to tell intact data
+/// from data that was cut short or altered, not to resist tampering.
+pub(crate) fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
 
-    newc::trailer(output)
+/// Returns the exact size in bytes of the archive that the high-level writers produce from
+/// entries with the given names and file sizes, including padding and the trailer.
+///
+/// The size depends on nothing else, so it can be found before any data is read, to set a
+/// `Content-Length` or to preallocate the output. Names are counted as they will be written,
+/// after any [`NamePolicy`](name::NamePolicy); an archive written with an
+/// [alignment](ArchiveWriter::align) is then padded out to a multiple of it.
+pub fn predicted_len<I, N>(entries: I) -> u64
+where
+    I: IntoIterator<Item = (N, u32)>,
+    N: AsRef<str>,
+{
+    let entries: u64 = entries
+        .into_iter()
+        .map(|(name, file_size)| newc::encoded_len(name.as_ref().len(), file_size))
+        .sum();
+    entries + newc::encoded_len(newc::TRAILER_NAME.len(), 0)
+}
+
+/// Writes an archive with no entries, only a trailer, to `output`, and returns `output`.
+///
+/// The result is what the high-level writers produce from no inputs, and what the kernel
+/// accepts as an empty initramfs segment.
+pub fn write_empty_cpio<W: io::Write>(output: W) -> io::Result<W> {
+    ArchiveWriter::new(output)
+        .finish()
+        .map(|(output, _)| output)
+}
+
+/// Returns true if the archive in `input` has no entries, that is, if its first entry is a
+/// trailer.
+///
+/// Only the first entry is read, up to the end of its name. Input that doesn't start with an
+/// entry gives an `InvalidData` error, and input that ends first an `UnexpectedEof` one.
+pub fn is_empty_archive<R: io::Read>(input: R) -> io::Result<bool> {
+    Ok(NewcReader::new(input)?.entry().is_trailer())
 }
 
 #[cfg(test)]
@@ -69,6 +411,287 @@ mod tests {
         let output = Cursor::new(vec![]);
 
         // Write out the CPIO archive
-        let _ = write_cpio(input.drain(..), output).unwrap();
+        let (output, summary) = write_cpio(input.drain(..), output).unwrap();
+        assert_eq!(summary.entries(), 2);
+        assert_eq!(summary.data_bytes(), 26);
+        assert_eq!(summary.archive_bytes(), output.into_inner().len() as u64);
+    }
+
+    #[test]
+    fn test_write_cpio_variants() {
+        let inputs = || {
+            vec![
+                (NewcBuilder::new("./hello_world"), "Hello, World"),
+                (NewcBuilder::new("./hello_world2"), "Hello, World 2"),
+            ]
+        };
+        let expected = write_cpio(
+            inputs()
+                .into_iter()
+                .map(|(builder, data)| (builder, Cursor::new(data))),
+            vec![],
+        )
+        .unwrap();
+
+        let output = write_cpio_bytes(inputs(), vec![]).unwrap();
+        assert_eq!(output, expected);
+
+        let output = try_write_cpio(
+            inputs()
+                .into_iter()
+                .map(|(builder, data)| Ok((builder, Cursor::new(data)))),
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(output, expected);
+
+        let output = write_cpio_sized(
+            inputs()
+                .into_iter()
+                .map(|(builder, data)| (builder, data.len() as u64, data.as_bytes())),
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_write_cpio_inputs() {
+        let (output, summary) = write_cpio_inputs(
+            vec![
+                (NewcBuilder::new("./dir").mode(0o040755), Input::NoData),
+                (
+                    NewcBuilder::new("./link").mode(0o120777),
+                    Input::Inline(b"file".to_vec()),
+                ),
+                (
+                    NewcBuilder::new("./file").mode(0o100644),
+                    Input::Reader(Cursor::new("Hello, World")),
+                ),
+            ],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(summary.entries(), 3);
+        assert_eq!(summary.data_bytes(), 16);
+
+        let expected = write_cpio_bytes(
+            vec![
+                (NewcBuilder::new("./dir").mode(0o040755), &b""[..]),
+                (NewcBuilder::new("./link").mode(0o120777), b"file"),
+                (NewcBuilder::new("./file").mode(0o100644), b"Hello, World"),
+            ],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(output, expected.0);
+    }
+
+    #[test]
+    fn test_predicted_len() {
+        assert_eq!(predicted_len(Vec::<(&str, u32)>::new()), 124);
+        let entries: Vec<(&str, &[u8])> = vec![
+            ("./dir", b""),
+            ("./a", b"Hello, World"),
+            ("./longer_name", b"odd"),
+        ];
+        let predicted = predicted_len(
+            entries
+                .iter()
+                .map(|&(name, data)| (name, data.len() as u32)),
+        );
+        let inputs = entries
+            .iter()
+            .map(|&(name, data)| (NewcBuilder::new(name).mode(0o100644), data));
+        let (output, summary) = write_cpio_bytes(inputs, vec![]).unwrap();
+        assert_eq!(predicted, output.len() as u64);
+        assert_eq!(predicted, summary.archive_bytes());
+    }
+
+    #[test]
+    fn test_empty_archive() {
+        let empty = write_empty_cpio(vec![]).unwrap();
+        assert_eq!(empty.len() as u64, predicted_len(Vec::<(&str, u32)>::new()));
+        assert_eq!(
+            empty,
+            write_cpio_bytes(Vec::<(NewcBuilder, &[u8])>::new(), vec![])
+                .unwrap()
+                .0
+        );
+        assert!(is_empty_archive(empty.as_slice()).unwrap());
+
+        let (archive, _) =
+            write_cpio_bytes(vec![(NewcBuilder::new("a"), &b"data"[..])], vec![]).unwrap();
+        assert!(!is_empty_archive(archive.as_slice()).unwrap());
+        let err = is_empty_archive(&b"not an archive at all"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = is_empty_archive(&[0u8; 200][..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = is_empty_archive(&b""[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_try_write_cpio_error() {
+        let inputs = vec![
+            Ok((
+                NewcBuilder::new("./hello_world"),
+                Cursor::new("Hello, World"),
+            )),
+            Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+        ];
+        let err = try_write_cpio(inputs, vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "input 1: no such file");
+    }
+
+    #[test]
+    fn test_write_cpio_lazy() {
+        use std::cell::Cell;
+        let open = Cell::new(0);
+        let opened = Cell::new(0);
+        let inputs: Vec<_> = ["./a", "./b"]
+            .iter()
+            .map(|&name| {
+                let open = &open;
+                let opened = &opened;
+                (NewcBuilder::new(name).mode(0o100644), move || {
+                    // Each input is opened once the one before it has been dropped.
+                    assert_eq!(open.get(), 0);
+                    open.set(1);
+                    opened.set(opened.get() + 1);
+                    Ok(Tracked {
+                        data: Cursor::new(name),
+                        open,
+                    })
+                })
+            })
+            .collect();
+        assert_eq!(opened.get(), 0);
+        let (output, _) = write_cpio_lazy(inputs, vec![]).unwrap();
+        assert_eq!(opened.get(), 2);
+        let expected = write_cpio(
+            vec![
+                (NewcBuilder::new("./a").mode(0o100644), Cursor::new("./a")),
+                (NewcBuilder::new("./b").mode(0o100644), Cursor::new("./b")),
+            ],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(output, expected.0);
+
+        let inputs = vec![(NewcBuilder::new("./gone"), || {
+            Err::<Cursor<Vec<u8>>, _>(io::Error::new(io::ErrorKind::NotFound, "no such file"))
+        })];
+        let err = write_cpio_lazy(inputs, vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "input 0 (./gone): no such file");
+    }
+
+    #[test]
+    fn test_write_cpio_lazy_with_policy() {
+        /// Fails after yielding `good` bytes.
+        struct Failing(Cursor<Vec<u8>>, u64);
+        impl io::Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.position() >= self.1 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "read failed"));
+                }
+                let len = buf.len().min((self.1 - self.0.position()) as usize);
+                self.0.read(&mut buf[..len])
+            }
+        }
+        impl io::Seek for Failing {
+            fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+
+        type Open = Box<dyn FnOnce() -> io::Result<Failing>>;
+        let inputs = || -> Vec<(NewcBuilder, Open)> {
+            vec![
+                (
+                    NewcBuilder::new("./good"),
+                    Box::new(|| Ok(Failing(Cursor::new(b"good".to_vec()), 4))),
+                ),
+                (
+                    NewcBuilder::new("./gone"),
+                    Box::new(|| Err(io::Error::new(io::ErrorKind::NotFound, "no such file"))),
+                ),
+                (
+                    NewcBuilder::new("./bad"),
+                    Box::new(|| Ok(Failing(Cursor::new(b"bad sector".to_vec()), 3))),
+                ),
+            ]
+        };
+        let entries = |archive: &[u8]| {
+            let mut entries = vec![];
+            let mut archive = archive;
+            loop {
+                let mut reader = NewcReader::new(archive).unwrap();
+                if reader.entry().is_trailer() {
+                    return entries;
+                }
+                let mut data = vec![];
+                io::Read::read_to_end(&mut reader, &mut data).unwrap();
+                entries.push((reader.entry().name().to_string(), data));
+                archive = reader.finish().unwrap();
+            }
+        };
+
+        let err = write_cpio_lazy_with_policy(inputs(), vec![], InputErrors::FailFast).unwrap_err();
+        assert_eq!(err.to_string(), "input 1 (./gone): no such file");
+
+        let mut reported = vec![];
+        let policy = InputErrors::Skip(Box::new(|failure| reported.push(failure.to_string())));
+        let (output, summary) =
+            write_cpio_lazy_with_policy(inputs().into_iter().take(2), vec![], policy).unwrap();
+        assert_eq!(reported, ["input 1 (./gone): no such file"]);
+        assert_eq!(entries(&output), [("./good".to_string(), b"good".to_vec())]);
+        assert_eq!(summary.failures().len(), 1);
+        assert_eq!(summary.failures()[0].kind(), io::ErrorKind::NotFound);
+        assert!(!summary.failures()[0].partial());
+        // An input that fails once its entry is started can't be skipped.
+        let policy = InputErrors::Skip(Box::new(|_| {}));
+        let err = write_cpio_lazy_with_policy(inputs(), vec![], policy).unwrap_err();
+        assert_eq!(err.to_string(), "input 2 (./bad): read failed");
+
+        let policy = InputErrors::SubstituteEmpty;
+        let (output, summary) = write_cpio_lazy_with_policy(inputs(), vec![], policy).unwrap();
+        assert_eq!(
+            entries(&output),
+            [
+                ("./good".to_string(), b"good".to_vec()),
+                ("./gone".to_string(), vec![]),
+                ("./bad".to_string(), b"bad\0\0\0\0\0\0\0".to_vec()),
+            ]
+        );
+        assert_eq!(summary.entries(), 3);
+        assert_eq!(summary.failures().len(), 2);
+        assert!(summary.failures()[1].partial());
+        assert_eq!(summary.failures()[1].index(), 2);
+    }
+
+    /// An input that marks itself closed when dropped.
+    struct Tracked<'a> {
+        data: Cursor<&'static str>,
+        open: &'a std::cell::Cell<u32>,
+    }
+
+    impl io::Read for Tracked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.data.read(buf)
+        }
+    }
+
+    impl io::Seek for Tracked<'_> {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    impl Drop for Tracked<'_> {
+        fn drop(&mut self) {
+            self.open.set(0);
+        }
     }
 }
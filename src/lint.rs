@@ -0,0 +1,580 @@
+//! Flagging risky content in archives.
+//!
+//! [`lint`] reads a whole archive and reports entries that deserve a second look before the
+//! archive is shipped: setuid and setgid files, world-writable files, device nodes, files
+//! owned by unexpected users, and symlinks with absolute targets. [`check_initramfs`] checks
+//! that an archive has what the kernel needs to boot from it, and [`check_hardlinks`] that its
+//! hard links hang together.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::newc::{ModeFileType, Permissions, Reader};
+
+/// The longest symlink target read in full; anything longer is truncated for the finding.
+const MAX_TARGET_LEN: u64 = 4096;
+
+/// Risky content found by [`lint`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Finding {
+    /// The file runs as its owner.
+    Setuid { name: String },
+    /// The file runs as its group.
+    Setgid { name: String },
+    /// Anyone may write to the file or directory. Directories with the sticky bit set, such
+    /// as `/tmp`, are not flagged.
+    WorldWritable { name: String },
+    /// The entry is a character or block device node.
+    DeviceNode {
+        name: String,
+        file_type: ModeFileType,
+        major: u32,
+        minor: u32,
+    },
+    /// The entry's owner is not one of the allowed UIDs.
+    UnexpectedOwner { name: String, uid: u32 },
+    /// The symlink's target is an absolute path, which resolves differently depending on
+    /// where the archive is unpacked.
+    AbsoluteSymlink { name: String, target: String },
+}
+
+impl Finding {
+    /// Returns the name of the entry the finding is about.
+    pub fn name(&self) -> &str {
+        match *self {
+            Finding::Setuid { ref name }
+            | Finding::Setgid { ref name }
+            | Finding::WorldWritable { ref name }
+            | Finding::DeviceNode { ref name, .. }
+            | Finding::UnexpectedOwner { ref name, .. }
+            | Finding::AbsoluteSymlink { ref name, .. } => name,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Finding::Setuid { ref name } => write!(f, "{}: setuid", name),
+            Finding::Setgid { ref name } => write!(f, "{}: setgid", name),
+            Finding::WorldWritable { ref name } => write!(f, "{}: world-writable", name),
+            Finding::DeviceNode {
+                ref name,
+                file_type,
+                major,
+                minor,
+            } => {
+                let kind = match file_type {
+                    ModeFileType::Block => "block",
+                    _ => "character",
+                };
+                write!(f, "{}: {} device {}:{}", name, kind, major, minor)
+            }
+            Finding::UnexpectedOwner { ref name, uid } => {
+                write!(f, "{}: owned by unexpected uid {}", name, uid)
+            }
+            Finding::AbsoluteSymlink {
+                ref name,
+                ref target,
+            } => write!(f, "{}: symlink to absolute path {}", name, target),
+        }
+    }
+}
+
+/// Settings for [`lint_with`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LintOptions {
+    allowed_uids: Option<BTreeSet<u32>>,
+}
+
+impl LintOptions {
+    /// Creates the default options, which do not check owners.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flag entries owned by any UID not in `uids`.
+    pub fn allowed_uids<I: IntoIterator<Item = u32>>(mut self, uids: I) -> Self {
+        self.allowed_uids = Some(uids.into_iter().collect());
+        self
+    }
+}
+
+/// Reads every entry of `archive` and returns any risky content found, in archive order.
+///
+/// This uses the default [`LintOptions`]; see [`lint_with`] to also check owners.
+pub fn lint<R: Read>(archive: R) -> io::Result<Vec<Finding>> {
+    lint_with(archive, &LintOptions::default())
+}
+
+/// Like [`lint`], but with the checks configured by `options`.
+pub fn lint_with<R: Read>(mut archive: R, options: &LintOptions) -> io::Result<Vec<Finding>> {
+    let mut findings = vec![];
+    loop {
+        let mut reader = Reader::new(archive)?;
+        let entry = reader.entry();
+        if entry.is_trailer() {
+            reader.finish()?;
+            return Ok(findings);
+        }
+        let name = || entry.name().to_string();
+        let permissions = entry.permissions();
+        let file_type = ModeFileType::from_mode(entry.mode());
+        if permissions.contains(Permissions::SETUID) {
+            findings.push(Finding::Setuid { name: name() });
+        }
+        if permissions.contains(Permissions::SETGID) {
+            findings.push(Finding::Setgid { name: name() });
+        }
+        let sticky_dir =
+            file_type == Some(ModeFileType::Directory) && permissions.contains(Permissions::STICKY);
+        if permissions.contains(Permissions::OTHER_WRITE)
+            && file_type != Some(ModeFileType::Symlink)
+            && !sticky_dir
+        {
+            findings.push(Finding::WorldWritable { name: name() });
+        }
+        if let Some(file_type @ (ModeFileType::Char | ModeFileType::Block)) = file_type {
+            findings.push(Finding::DeviceNode {
+                name: name(),
+                file_type,
+                major: entry.rdev_major(),
+                minor: entry.rdev_minor(),
+            });
+        }
+        if let Some(ref uids) = options.allowed_uids {
+            if !uids.contains(&entry.uid()) {
+                findings.push(Finding::UnexpectedOwner {
+                    name: name(),
+                    uid: entry.uid(),
+                });
+            }
+        }
+        if file_type == Some(ModeFileType::Symlink) {
+            let name = name();
+            let mut target = vec![];
+            reader
+                .by_ref()
+                .take(MAX_TARGET_LEN)
+                .read_to_end(&mut target)?;
+            if target.first() == Some(&b'/') {
+                findings.push(Finding::AbsoluteSymlink {
+                    name,
+                    target: String::from_utf8_lossy(&target).into_owned(),
+                });
+            }
+        }
+        archive = reader.finish()?;
+    }
+}
+
+/// A reason an archive may not boot as an initramfs, found by [`check_initramfs`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BootProblem {
+    /// The archive has no entries.
+    Empty,
+    /// Neither `/init` nor `/sbin/init` is a regular file or symlink.
+    MissingInit,
+    /// There is no `/dev` directory.
+    MissingDev,
+    /// There is no `/dev/console` character device, so init starts without a console.
+    MissingConsole,
+    /// The entry's parent directory is not an earlier entry. The kernel does not create
+    /// missing parents, so the entry cannot be unpacked.
+    ParentNotBefore { name: String, parent: String },
+}
+
+impl fmt::Display for BootProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            BootProblem::Empty => write!(f, "archive is empty"),
+            BootProblem::MissingInit => write!(f, "no /init or /sbin/init"),
+            BootProblem::MissingDev => write!(f, "no /dev directory"),
+            BootProblem::MissingConsole => write!(f, "no /dev/console character device"),
+            BootProblem::ParentNotBefore {
+                ref name,
+                ref parent,
+            } => write!(
+                f,
+                "{}: parent directory {} does not precede it",
+                name, parent
+            ),
+        }
+    }
+}
+
+/// Strips the leading `/` and `./` components from `name`, as the kernel resolves names
+/// relative to the root it unpacks into.
+fn root_relative(name: &str) -> &str {
+    let mut name = name;
+    loop {
+        let stripped = name.trim_start_matches('/');
+        let stripped = stripped.strip_prefix("./").unwrap_or(stripped);
+        if stripped == name {
+            return if name == "." { "" } else { name };
+        }
+        name = stripped;
+    }
+}
+
+/// Reads every entry of `archive` and returns the reasons it may not boot as an initramfs.
+///
+/// Later entries replace earlier entries of the same name, as when the kernel unpacks them.
+/// An archive that is only one segment of a larger initramfs, such as an early microcode
+/// archive, is not expected to pass.
+pub fn check_initramfs<R: Read>(mut archive: R) -> io::Result<Vec<BootProblem>> {
+    let mut problems = vec![];
+    let mut types = HashMap::new();
+    loop {
+        let reader = Reader::new(archive)?;
+        let entry = reader.entry();
+        if entry.is_trailer() {
+            reader.finish()?;
+            break;
+        }
+        let name = root_relative(entry.name());
+        if let Some(idx) = name.rfind('/') {
+            let parent = &name[..idx];
+            if types.get(parent) != Some(&Some(ModeFileType::Directory)) {
+                problems.push(BootProblem::ParentNotBefore {
+                    name: entry.name().to_string(),
+                    parent: parent.to_string(),
+                });
+            }
+        }
+        types.insert(name.to_string(), ModeFileType::from_mode(entry.mode()));
+        archive = reader.finish()?;
+    }
+
+    if types.is_empty() {
+        problems.insert(0, BootProblem::Empty);
+        return Ok(problems);
+    }
+    let is = |name: &str, file_types: &[ModeFileType]| {
+        types.get(name).map_or(false, |file_type| {
+            file_type.map_or(false, |t| file_types.contains(&t))
+        })
+    };
+    let mut missing = vec![];
+    let init = [ModeFileType::Regular, ModeFileType::Symlink];
+    if !is("init", &init) && !is("sbin/init", &init) {
+        missing.push(BootProblem::MissingInit);
+    }
+    if !is("dev", &[ModeFileType::Directory]) {
+        missing.push(BootProblem::MissingDev);
+    }
+    if !is("dev/console", &[ModeFileType::Char]) {
+        missing.push(BootProblem::MissingConsole);
+    }
+    missing.extend(problems);
+    Ok(missing)
+}
+
+/// An inconsistency in the hard links of an archive, found by [`check_hardlinks`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LinkProblem {
+    /// The entry's link count is `nlink`, but `links` names every entry with its device and
+    /// inode numbers, which is a different number of them.
+    ///
+    /// Archives of part of a filesystem have this for files with links outside of that part.
+    WrongCount {
+        name: String,
+        nlink: u32,
+        links: Vec<String>,
+    },
+    /// More than one of the entries sharing the inode of the entry called `name` carries
+    /// data. GNU cpio stores the data of a file with several links once, so extractors differ
+    /// in which copy ends up on disk.
+    DuplicateData {
+        name: String,
+        with_data: Vec<String>,
+    },
+}
+
+impl LinkProblem {
+    /// Returns the name of the entry the problem is about.
+    pub fn name(&self) -> &str {
+        match *self {
+            LinkProblem::WrongCount { ref name, .. }
+            | LinkProblem::DuplicateData { ref name, .. } => name,
+        }
+    }
+}
+
+impl fmt::Display for LinkProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            LinkProblem::WrongCount {
+                ref name,
+                nlink,
+                ref links,
+            } => write!(
+                f,
+                "{}: link count is {}, but the archive holds {}",
+                name,
+                nlink,
+                links.len()
+            ),
+            LinkProblem::DuplicateData {
+                ref name,
+                ref with_data,
+            } => write!(
+                f,
+                "{}: data is stored with more than one link: {}",
+                name,
+                with_data.join(", ")
+            ),
+        }
+    }
+}
+
+/// The entries of an archive sharing one inode.
+struct LinkGroup {
+    /// The name and link count of each entry.
+    links: Vec<(String, u32)>,
+    /// The names of the entries with data.
+    with_data: Vec<String>,
+}
+
+/// Reads every entry of `archive` and returns the inconsistencies among the entries that
+/// share device and inode numbers, which extractors turn into hard links.
+///
+/// Each entry with a link count above one (other than directories, whose link counts come
+/// from their subdirectories) should have a link count equal to the number of such entries
+/// sharing its inode, and at most one of those entries should carry the file's data. Entries
+/// with a link count of one are not links, so archives that number every inode 0 lint clean.
+/// Problems are returned in the order of the first entry of each inode.
+pub fn check_hardlinks<R: Read>(mut archive: R) -> io::Result<Vec<LinkProblem>> {
+    let mut groups: Vec<LinkGroup> = vec![];
+    let mut inodes = HashMap::new();
+    loop {
+        let reader = Reader::new(archive)?;
+        let entry = reader.entry();
+        if entry.is_trailer() {
+            reader.finish()?;
+            break;
+        }
+        if entry.nlink() > 1
+            && ModeFileType::from_mode(entry.mode()) != Some(ModeFileType::Directory)
+        {
+            let key = (entry.dev_major(), entry.dev_minor(), entry.ino());
+            let idx = *inodes.entry(key).or_insert_with(|| {
+                groups.push(LinkGroup {
+                    links: vec![],
+                    with_data: vec![],
+                });
+                groups.len() - 1
+            });
+            let group = &mut groups[idx];
+            group.links.push((entry.name().to_string(), entry.nlink()));
+            if entry.file_size() != 0 {
+                group.with_data.push(entry.name().to_string());
+            }
+        }
+        archive = reader.finish()?;
+    }
+
+    let mut problems = vec![];
+    for group in groups {
+        let names: Vec<String> = group.links.iter().map(|(name, _)| name.clone()).collect();
+        for (name, nlink) in &group.links {
+            if *nlink as usize != group.links.len() {
+                problems.push(LinkProblem::WrongCount {
+                    name: name.clone(),
+                    nlink: *nlink,
+                    links: names.clone(),
+                });
+            }
+        }
+        if group.with_data.len() > 1 {
+            problems.push(LinkProblem::DuplicateData {
+                name: names[0].clone(),
+                with_data: group.with_data,
+            });
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcBuilder;
+
+    #[test]
+    fn test_lint() {
+        let mut archive = crate::ArchiveWriter::new(vec![]);
+        let entries: &[(NewcBuilder, &[u8])] = &[
+            (NewcBuilder::new("bin").mode(0o040755), b""),
+            (NewcBuilder::new("bin/su").mode(0o106755), b"su"),
+            (NewcBuilder::new("tmp").mode(0o041777), b""),
+            (NewcBuilder::new("log").mode(0o100666).uid(1000), b""),
+            (
+                NewcBuilder::new("dev/console")
+                    .mode(0o020600)
+                    .rdev_major(5)
+                    .rdev_minor(1),
+                b"",
+            ),
+            (NewcBuilder::new("sh").mode(0o120777), b"/bin/busybox"),
+            (NewcBuilder::new("ls").mode(0o120777), b"busybox"),
+        ];
+        for (builder, data) in entries.iter() {
+            archive.append_bytes(builder.clone(), data).unwrap();
+        }
+        let (archive, _) = archive.finish().unwrap();
+
+        let name = |name: &str| name.to_string();
+        let findings = lint(archive.as_slice()).unwrap();
+        assert_eq!(
+            findings,
+            vec![
+                Finding::Setuid {
+                    name: name("bin/su")
+                },
+                Finding::Setgid {
+                    name: name("bin/su")
+                },
+                Finding::WorldWritable { name: name("log") },
+                Finding::DeviceNode {
+                    name: name("dev/console"),
+                    file_type: ModeFileType::Char,
+                    major: 5,
+                    minor: 1,
+                },
+                Finding::AbsoluteSymlink {
+                    name: name("sh"),
+                    target: name("/bin/busybox"),
+                },
+            ]
+        );
+        assert_eq!(findings[3].to_string(), "dev/console: character device 5:1");
+
+        let options = LintOptions::new().allowed_uids(vec![0]);
+        let findings = lint_with(archive.as_slice(), &options).unwrap();
+        assert!(findings.contains(&Finding::UnexpectedOwner {
+            name: name("log"),
+            uid: 1000,
+        }));
+        assert_eq!(findings.len(), 6);
+    }
+
+    #[test]
+    fn test_check_initramfs() {
+        let dir = |name| (NewcBuilder::new(name).mode(0o040755), &b""[..]);
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                dir("."),
+                dir("./dev"),
+                (
+                    NewcBuilder::new("./dev/console")
+                        .mode(0o020600)
+                        .rdev_major(5)
+                        .rdev_minor(1),
+                    &b""[..],
+                ),
+                dir("./sbin"),
+                (NewcBuilder::new("./sbin/init").mode(0o100755), b"#!/bin/sh"),
+            ],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(check_initramfs(archive.as_slice()).unwrap(), vec![]);
+
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (NewcBuilder::new("/init").mode(0o040755), &b""[..]),
+                (NewcBuilder::new("/dev/null").mode(0o020666), b""),
+                dir("/dev"),
+            ],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(
+            check_initramfs(archive.as_slice()).unwrap(),
+            vec![
+                BootProblem::MissingInit,
+                BootProblem::MissingConsole,
+                BootProblem::ParentNotBefore {
+                    name: "/dev/null".to_string(),
+                    parent: "dev".to_string(),
+                },
+            ]
+        );
+
+        let (archive, _) = crate::write_cpio_bytes(Vec::<(_, &[u8])>::new(), vec![]).unwrap();
+        assert_eq!(
+            check_initramfs(archive.as_slice()).unwrap(),
+            vec![BootProblem::Empty]
+        );
+    }
+
+    #[test]
+    fn test_check_hardlinks() {
+        let file = |name, ino, nlink| NewcBuilder::new(name).ino(ino).nlink(nlink).mode(0o100644);
+        let mut archive = crate::ArchiveWriter::new(vec![]);
+        let entries: &[(NewcBuilder, &[u8])] = &[
+            // A consistent set of links, with the data on the last.
+            (file("a", 1, 2), b""),
+            (file("b", 1, 2), b"Hello"),
+            (NewcBuilder::new("d").ino(2).nlink(3).mode(0o040755), b""),
+            (NewcBuilder::new("d/e").ino(2).nlink(3).mode(0o040755), b""),
+            // A link outside the archive.
+            (file("f", 3, 2), b"Hello"),
+            (file("g", 4, 2), b"Hello"),
+            (file("h", 4, 2), b"World"),
+        ];
+        for (builder, data) in entries.iter() {
+            archive.append_bytes(builder.clone(), data).unwrap();
+        }
+        let (archive, _) = archive.finish().unwrap();
+
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let problems = check_hardlinks(archive.as_slice()).unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                LinkProblem::WrongCount {
+                    name: "f".to_string(),
+                    nlink: 2,
+                    links: names(&["f"]),
+                },
+                LinkProblem::DuplicateData {
+                    name: "g".to_string(),
+                    with_data: names(&["g", "h"]),
+                },
+            ]
+        );
+        assert_eq!(
+            problems[0].to_string(),
+            "f: link count is 2, but the archive holds 1"
+        );
+        assert_eq!(
+            problems[1].to_string(),
+            "g: data is stored with more than one link: g, h"
+        );
+
+        // Entries that aren't links may share an inode number.
+        let inputs = vec![
+            (
+                NewcBuilder::new("a").mode(0o100644),
+                io::Cursor::new("Hello"),
+            ),
+            (
+                NewcBuilder::new("b").mode(0o100644),
+                io::Cursor::new("World"),
+            ),
+        ];
+        let (archive, _) = crate::write_cpio(inputs, vec![]).unwrap();
+        assert!(check_hardlinks(archive.as_slice()).unwrap().is_empty());
+        let mut archive = crate::ArchiveWriter::new(vec![]);
+        archive.append_bytes(file("x", 0, 1), b"Hello").unwrap();
+        archive.append_bytes(file("y", 0, 1), b"World").unwrap();
+        let (archive, _) = archive.finish().unwrap();
+        assert!(check_hardlinks(archive.as_slice()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+//! The `cpio!` macro.
+
+/// Builds an archive in memory from a literal description of its entries, returning its
+/// bytes.
+///
+/// Each entry is a directory, regular file, or symlink, ended by a semicolon. Directories and
+/// files take their permissions after the name, defaulting to `0o755` and `0o644`:
+///
+/// ```
+/// let archive: Vec<u8> = cpio::cpio! {
+///     dir "./etc";
+///     file "./etc/hostname" => b"box\n";
+///     dir "./sbin" 0o700;
+///     file "./sbin/init" 0o755 => "#!/bin/sh\nexec /bin/sh\n";
+///     symlink "./init" => "/sbin/init";
+/// };
+/// ```
+///
+/// Entries are written in order, numbered as by [`ArchiveSpec`](crate::archive::ArchiveSpec),
+/// which the macro expands to. File data can be any expression of a type that implements
+/// `AsRef<[u8]>`. The macro panics if the archive cannot be written, which only happens for a
+/// file of 4 GiB or more.
+#[macro_export]
+macro_rules! cpio {
+    ($($body:tt)*) => {
+        $crate::__cpio_entries!($crate::archive::ArchiveSpec::new(); $($body)*)
+            .write_to(::std::vec::Vec::new())
+            .expect("cpio! archive could not be written")
+            .0
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __cpio_entries {
+    ($spec:expr;) => {
+        $spec
+    };
+    ($spec:expr; dir $name:literal; $($rest:tt)*) => {
+        $crate::__cpio_entries!($spec.dir($name, 0o755); $($rest)*)
+    };
+    ($spec:expr; dir $name:literal $mode:literal; $($rest:tt)*) => {
+        $crate::__cpio_entries!($spec.dir($name, $mode); $($rest)*)
+    };
+    ($spec:expr; file $name:literal => $data:expr; $($rest:tt)*) => {
+        $crate::__cpio_entries!($spec.file($name, $data, 0o644); $($rest)*)
+    };
+    ($spec:expr; file $name:literal $mode:literal => $data:expr; $($rest:tt)*) => {
+        $crate::__cpio_entries!($spec.file($name, $data, $mode); $($rest)*)
+    };
+    ($spec:expr; symlink $name:literal => $target:expr; $($rest:tt)*) => {
+        $crate::__cpio_entries!($spec.symlink($name, $target); $($rest)*)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::{Archive, ArchiveSpec};
+
+    #[test]
+    fn test_cpio_macro() {
+        let contents = String::from("#!/bin/sh\n");
+        let archive = cpio! {
+            dir "./etc";
+            file "./etc/hostname" => b"box\n";
+            dir "./sbin" 0o700;
+            file "./sbin/init" 0o755 => &contents;
+            symlink "./init" => "/sbin/init";
+        };
+        let (expected, _) = ArchiveSpec::new()
+            .dir("./etc", 0o755)
+            .file("./etc/hostname", b"box\n", 0o644)
+            .dir("./sbin", 0o700)
+            .file("./sbin/init", &contents, 0o755)
+            .symlink("./init", "/sbin/init")
+            .write_to(vec![])
+            .unwrap();
+        assert_eq!(archive, expected);
+
+        let empty = cpio! {};
+        assert!(Archive::from_bytes(&empty).unwrap().is_empty());
+    }
+}
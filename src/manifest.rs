@@ -0,0 +1,386 @@
+//! `SHA256SUMS`-style checksum manifests of the regular files in an archive.
+//!
+//! [`write_manifest`] produces a manifest for an archive, and [`verify_manifest`] checks an
+//! archive against one. This module is only available with the `digest` feature. Each line
+//! of a manifest holds the hex digest, the size, and the path of one regular file, separated
+//! by two spaces:
+//!
+//! ```text
+//! 8663bab6d124806b9727f89bb4ab9db4cbcc3862f6bbf22024dfa7212aa4ab7d  13  ./hello
+//! ```
+//!
+//! Hard links are listed with the size and digest of the file they link to, whichever of
+//! them the archive stores its data with, as that is what each unpacks to. A name may be
+//! listed more than once, as an archive may hold it more than once; its entries are matched
+//! with its lines in order.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Read, Write};
+
+use digest::Digest;
+
+use crate::newc::{ModeFileType, Reader};
+
+/// Writes a manifest of every regular file in `archive` to `manifest`, hashing each with `D`.
+///
+/// The archive is read in a single pass; entries other than regular files are skipped.
+/// Returns `manifest` once the trailer has been reached. A regular file whose name contains a
+/// newline can't be listed, and gives an `InvalidInput` error.
+pub fn write_manifest<D, R, W>(archive: R, mut manifest: W) -> io::Result<W>
+where
+    D: Digest,
+    R: Read,
+    W: Write,
+{
+    let mut line = String::new();
+    for_each_file::<D, _, _>(archive, |name, size, digest| {
+        if name.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?}: name contains a newline", name),
+            ));
+        }
+        line.clear();
+        writeln!(line, "{}  {}  {}", digest, size, name).unwrap();
+        manifest.write_all(line.as_bytes())
+    })?;
+    Ok(manifest)
+}
+
+/// A file with hard links, as seen so far by [`for_each_file`].
+enum Links {
+    /// The names of the links seen before the one with the data.
+    Waiting(Vec<String>),
+    /// The size and hex digest of the data.
+    Found(u64, String),
+}
+
+/// Reads every entry of `archive`, passing the name, size, and hex digest of each regular
+/// file to `f`, in archive order, save that hard links stored without data are passed with
+/// the link that has it.
+fn for_each_file<D, R, F>(mut archive: R, mut f: F) -> io::Result<()>
+where
+    D: Digest,
+    R: Read,
+    F: FnMut(String, u64, &str) -> io::Result<()>,
+{
+    // The files with hard links, in order of their first link.
+    let mut groups = vec![];
+    let mut index = HashMap::new();
+    let mut hex = String::new();
+    loop {
+        let reader = Reader::new(archive)?;
+        let entry = reader.entry();
+        if entry.is_trailer() {
+            reader.finish()?;
+            break;
+        }
+        if ModeFileType::from_mode(entry.mode()) != Some(ModeFileType::Regular) {
+            archive = reader.finish()?;
+            continue;
+        }
+
+        let name = entry.name().to_string();
+        let size = u64::from(entry.file_size());
+        let key = if entry.nlink() > 1 {
+            Some((entry.dev_major(), entry.dev_minor(), entry.ino()))
+        } else {
+            None
+        };
+        let (rest, digest) = reader.finish_digest::<D>()?;
+        archive = rest;
+        hex.clear();
+        push_hex(&mut hex, &digest);
+
+        let key = match key {
+            Some(key) => key,
+            None => {
+                f(name, size, &hex)?;
+                continue;
+            }
+        };
+        let idx = *index.entry(key).or_insert_with(|| {
+            groups.push(Links::Waiting(vec![]));
+            groups.len() - 1
+        });
+        let links = &mut groups[idx];
+        match *links {
+            Links::Waiting(ref mut waiting) if size == 0 => waiting.push(name),
+            Links::Found(found, ref digest) if size == 0 => f(name, found, digest)?,
+            _ => {
+                if let Links::Waiting(ref mut waiting) = *links {
+                    for link in waiting.drain(..) {
+                        f(link, size, &hex)?;
+                    }
+                }
+                f(name, size, &hex)?;
+                *links = Links::Found(size, hex.clone());
+            }
+        }
+    }
+
+    // Links to a file stored without data anywhere are to an empty file.
+    let empty = hex_digest::<D>(b"");
+    for links in groups {
+        if let Links::Waiting(waiting) = links {
+            for name in waiting {
+                f(name, 0, &empty)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn hex_digest<D: Digest>(data: &[u8]) -> String {
+    let mut hex = String::new();
+    push_hex(&mut hex, &D::digest(data));
+    hex
+}
+
+fn push_hex(out: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        write!(out, "{:02x}", byte).unwrap();
+    }
+}
+
+/// A difference between an archive and its manifest, found by [`verify_manifest`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The file's data does not match the digest in the manifest.
+    Digest(String),
+    /// The file's size does not match the size in the manifest.
+    Size {
+        name: String,
+        expected: u64,
+        actual: u64,
+    },
+    /// The manifest lists a file that is not in the archive.
+    MissingFromArchive(String),
+    /// The archive holds a regular file that is not in the manifest.
+    MissingFromManifest(String),
+}
+
+/// One parsed manifest line.
+struct Expected {
+    name: String,
+    digest: String,
+    size: u64,
+    seen: bool,
+}
+
+fn parse_manifest<M: BufRead>(manifest: M) -> io::Result<Vec<Expected>> {
+    let mut expected = vec![];
+    for (idx, line) in manifest.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.splitn(3, "  ");
+        let (digest, size, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(digest), Some(size), Some(name)) => (digest, size, name),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid manifest line {}", idx + 1),
+                ))
+            }
+        };
+        let size = size.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid size on manifest line {}", idx + 1),
+            )
+        })?;
+        expected.push(Expected {
+            name: name.to_string(),
+            digest: digest.to_ascii_lowercase(),
+            size,
+            seen: false,
+        });
+    }
+    Ok(expected)
+}
+
+/// Checks every regular file in `archive` against `manifest`, hashing each with `D`.
+///
+/// The archive is read in a single pass. Returns the differences found, in archive order,
+/// followed by the files listed in the manifest but missing from the archive, in manifest
+/// order; an empty list means the archive matches. A file whose size differs is reported as
+/// a size mismatch only.
+pub fn verify_manifest<D, R, M>(archive: R, manifest: M) -> io::Result<Vec<Mismatch>>
+where
+    D: Digest,
+    R: Read,
+    M: BufRead,
+{
+    let mut expected = parse_manifest(manifest)?;
+    // The lines listing each name, last first, to be matched in order.
+    let mut by_name: HashMap<String, Vec<usize>> = HashMap::new();
+    for (idx, file) in expected.iter().enumerate().rev() {
+        by_name.entry(file.name.clone()).or_default().push(idx);
+    }
+
+    let mut mismatches = vec![];
+    for_each_file::<D, _, _>(archive, |name, size, actual| {
+        let file = match by_name.get_mut(&name).and_then(Vec::pop) {
+            Some(idx) => &mut expected[idx],
+            None => {
+                mismatches.push(Mismatch::MissingFromManifest(name));
+                return Ok(());
+            }
+        };
+        file.seen = true;
+        if file.size != size {
+            mismatches.push(Mismatch::Size {
+                name,
+                expected: file.size,
+                actual: size,
+            });
+        } else if file.digest != actual {
+            mismatches.push(Mismatch::Digest(name));
+        }
+        Ok(())
+    })?;
+
+    mismatches.extend(
+        expected
+            .into_iter()
+            .filter(|file| !file.seen)
+            .map(|file| Mismatch::MissingFromArchive(file.name)),
+    );
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newc::Builder;
+
+    #[test]
+    fn test_write_manifest() {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (
+                    Builder::new("./dir").set_mode_file_type(ModeFileType::Directory),
+                    &b""[..],
+                ),
+                (
+                    Builder::new("./hello").set_mode_file_type(ModeFileType::Regular),
+                    &b"Hello, World\n"[..],
+                ),
+            ],
+            vec![],
+        )
+        .unwrap();
+
+        let manifest = write_manifest::<sha2::Sha256, _, _>(archive.as_slice(), vec![]).unwrap();
+        assert_eq!(
+            String::from_utf8(manifest).unwrap(),
+            "8663bab6d124806b9727f89bb4ab9db4cbcc3862f6bbf22024dfa7212aa4ab7d  13  ./hello\n"
+        );
+
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![(Builder::new("./two\nlines").mode(0o100644), &b"data"[..])],
+            vec![],
+        )
+        .unwrap();
+        let err = write_manifest::<sha2::Sha256, _, _>(archive.as_slice(), vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_verify_manifest() {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (Builder::new("./same"), &b"same"[..]),
+                (Builder::new("./changed"), &b"new!"[..]),
+                (Builder::new("./resized"), &b"longer"[..]),
+                (Builder::new("./added"), &b""[..]),
+            ]
+            .into_iter()
+            .map(|(builder, data)| (builder.set_mode_file_type(ModeFileType::Regular), data)),
+            vec![],
+        )
+        .unwrap();
+
+        let manifest = write_manifest::<sha2::Sha256, _, _>(archive.as_slice(), vec![]).unwrap();
+        let mismatches =
+            verify_manifest::<sha2::Sha256, _, _>(archive.as_slice(), manifest.as_slice()).unwrap();
+        assert!(mismatches.is_empty());
+
+        let digest = |data: &[u8]| {
+            let mut hex = String::new();
+            push_hex(&mut hex, &sha2::Sha256::digest(data));
+            hex
+        };
+        let manifest = format!(
+            "{}  4  ./same\n{}  4  ./changed\n{}  5  ./resized\n{}  0  ./removed\n",
+            digest(b"same"),
+            digest(b"old!"),
+            digest(b"short"),
+            digest(b""),
+        );
+        let mismatches =
+            verify_manifest::<sha2::Sha256, _, _>(archive.as_slice(), manifest.as_bytes()).unwrap();
+        assert_eq!(
+            mismatches,
+            vec![
+                Mismatch::Digest("./changed".to_string()),
+                Mismatch::Size {
+                    name: "./resized".to_string(),
+                    expected: 5,
+                    actual: 6,
+                },
+                Mismatch::MissingFromManifest("./added".to_string()),
+                Mismatch::MissingFromArchive("./removed".to_string()),
+            ]
+        );
+
+        let err =
+            verify_manifest::<sha2::Sha256, _, _>(archive.as_slice(), &b"bogus\n"[..]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid manifest line 1");
+    }
+
+    #[test]
+    fn test_links_and_duplicates() {
+        let file = |name| Builder::new(name).set_mode_file_type(ModeFileType::Regular);
+        let link = |name| file(name).ino(1).nlink(2);
+        let mut archive = crate::ArchiveWriter::new(vec![]);
+        // The data with the last link, as GNU cpio writes it.
+        archive.append_bytes(link("./a"), b"").unwrap();
+        archive.append_bytes(link("./b"), b"shared").unwrap();
+        archive.append_bytes(file("./dup"), b"one").unwrap();
+        archive.append_bytes(file("./dup"), b"two").unwrap();
+        let archive = archive.finish().unwrap().0;
+
+        let manifest = write_manifest::<sha2::Sha256, _, _>(archive.as_slice(), vec![]).unwrap();
+        let shared = hex_digest::<sha2::Sha256>(b"shared");
+        let (one, two) = (
+            hex_digest::<sha2::Sha256>(b"one"),
+            hex_digest::<sha2::Sha256>(b"two"),
+        );
+        assert_eq!(
+            String::from_utf8(manifest.clone()).unwrap(),
+            format!(
+                "{0}  6  ./a\n{0}  6  ./b\n{1}  3  ./dup\n{2}  3  ./dup\n",
+                shared, one, two
+            )
+        );
+        let mismatches =
+            verify_manifest::<sha2::Sha256, _, _>(archive.as_slice(), manifest.as_slice()).unwrap();
+        assert!(mismatches.is_empty());
+
+        // Each listing of a name is matched with one of its entries, in order.
+        let manifest = format!(
+            "{}  6  ./a\n{}  6  ./b\n{}  3  ./dup\n",
+            shared, shared, one
+        );
+        let mismatches =
+            verify_manifest::<sha2::Sha256, _, _>(archive.as_slice(), manifest.as_bytes()).unwrap();
+        assert_eq!(
+            mismatches,
+            vec![Mismatch::MissingFromManifest("./dup".to_string())]
+        );
+    }
+}
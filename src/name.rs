@@ -0,0 +1,475 @@
+//! Policies for which entry names to accept.
+//!
+//! The format itself puts no restrictions on entry names, and by default neither does this
+//! crate. Consumers differ in what they can safely accept: the kernel resolves names relative
+//! to the root it unpacks into, package managers expect relative paths, and forensic tools
+//! want names exactly as stored. A [`NamePolicy`] makes that choice explicit, and is applied
+//! by [`Reader::with_name_policy`](crate::NewcReader::with_name_policy),
+//! [`ArchiveWriter::name_policy`](crate::ArchiveWriter::name_policy), and
+//! [`Extractor::name_policy`](crate::extract::Extractor::name_policy).
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::newc::TRAILER_NAME;
+
+/// What to do with a name that has a particular property.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Accept the name as it is.
+    Allow,
+    /// Reject the entry with an error.
+    Reject,
+    /// Rewrite the name so that it no longer has the property.
+    Normalize,
+}
+
+/// A property of a name rejected by a [`NamePolicy`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The name starts with `/`.
+    Absolute(String),
+    /// The name has a `..` component.
+    ParentDir(String),
+    /// The name is empty.
+    Empty,
+    /// The name ends with `/`.
+    TrailingSlash(String),
+    /// The name is `TRAILER!!!`, which other tools take for the end of the archive.
+    Trailer,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Violation::Absolute(ref name) => write!(f, "Entry name {:?} is absolute", name),
+            Violation::ParentDir(ref name) => {
+                write!(f, "Entry name {:?} contains a `..` component", name)
+            }
+            Violation::Empty => write!(f, "Entry name is empty"),
+            Violation::TrailingSlash(ref name) => {
+                write!(f, "Entry name {:?} ends with a slash", name)
+            }
+            Violation::Trailer => write!(f, "Entry name is the trailer's name"),
+        }
+    }
+}
+
+impl Error for Violation {}
+
+/// Decides, for each kind of questionable name, whether to allow, reject, or normalize it.
+///
+/// The default policy allows everything, which is how names were always treated.
+/// Normalizing strips leading or trailing slashes, resolves `..` components against the
+/// components before them (dropping any that would lead above the root), turns an empty
+/// name into `.`, and escapes a regular file called `TRAILER!!!` as `./TRAILER!!!`, which
+/// names the same file without being mistaken for the trailer by other tools.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NamePolicy {
+    absolute: Action,
+    parent_dir: Action,
+    empty: Action,
+    trailing_slash: Action,
+    trailer: Action,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self::permissive()
+    }
+}
+
+impl NamePolicy {
+    /// Creates a policy that allows every name.
+    pub fn permissive() -> Self {
+        Self {
+            absolute: Action::Allow,
+            parent_dir: Action::Allow,
+            empty: Action::Allow,
+            trailing_slash: Action::Allow,
+            trailer: Action::Allow,
+        }
+    }
+
+    /// Creates a policy that rejects every kind of questionable name.
+    pub fn strict() -> Self {
+        Self {
+            absolute: Action::Reject,
+            parent_dir: Action::Reject,
+            empty: Action::Reject,
+            trailing_slash: Action::Reject,
+            trailer: Action::Reject,
+        }
+    }
+
+    /// Creates a policy that normalizes every kind of questionable name.
+    pub fn normalizing() -> Self {
+        Self {
+            absolute: Action::Normalize,
+            parent_dir: Action::Normalize,
+            empty: Action::Normalize,
+            trailing_slash: Action::Normalize,
+            trailer: Action::Normalize,
+        }
+    }
+
+    /// Set what to do with names starting with `/`.
+    pub fn absolute(mut self, action: Action) -> Self {
+        self.absolute = action;
+        self
+    }
+
+    /// Set what to do with names containing a `..` component.
+    pub fn parent_dir(mut self, action: Action) -> Self {
+        self.parent_dir = action;
+        self
+    }
+
+    /// Set what to do with empty names.
+    pub fn empty(mut self, action: Action) -> Self {
+        self.empty = action;
+        self
+    }
+
+    /// Set what to do with names ending with `/`.
+    pub fn trailing_slash(mut self, action: Action) -> Self {
+        self.trailing_slash = action;
+        self
+    }
+
+    /// Set what to do with entries named `TRAILER!!!`, other than the trailer itself.
+    pub fn trailer(mut self, action: Action) -> Self {
+        self.trailer = action;
+        self
+    }
+
+    /// Applies the policy to `name`, returning the name to use or the first property of it
+    /// that the policy rejects.
+    pub fn apply<'a>(&self, name: &'a str) -> Result<Cow<'a, str>, Violation> {
+        if name == TRAILER_NAME {
+            match self.trailer {
+                Action::Allow => {}
+                Action::Reject => return Err(Violation::Trailer),
+                Action::Normalize => return Ok(Cow::Owned(format!("./{}", TRAILER_NAME))),
+            }
+        }
+        let mut name = Cow::Borrowed(name);
+        if name.len() > 1 && name.ends_with('/') {
+            match self.trailing_slash {
+                Action::Allow => {}
+                Action::Reject => return Err(Violation::TrailingSlash(name.into_owned())),
+                Action::Normalize => {
+                    name = Cow::Owned(name.trim_end_matches('/').to_string());
+                    if name.is_empty() {
+                        name = Cow::Borrowed("/");
+                    }
+                }
+            }
+        }
+        if name.starts_with('/') {
+            match self.absolute {
+                Action::Allow => {}
+                Action::Reject => return Err(Violation::Absolute(name.into_owned())),
+                Action::Normalize => {
+                    name = Cow::Owned(match name.trim_start_matches('/') {
+                        "" => ".".to_string(),
+                        rest => rest.to_string(),
+                    })
+                }
+            }
+        }
+        if name.split('/').any(|part| part == "..") {
+            match self.parent_dir {
+                Action::Allow => {}
+                Action::Reject => return Err(Violation::ParentDir(name.into_owned())),
+                Action::Normalize => name = Cow::Owned(resolve_parents(&name)),
+            }
+        }
+        if name.is_empty() {
+            match self.empty {
+                Action::Allow => {}
+                Action::Reject => return Err(Violation::Empty),
+                Action::Normalize => name = Cow::Borrowed("."),
+            }
+        }
+        Ok(name)
+    }
+}
+
+/// The longest component [`PortableNames`] accepts by default: `NAME_MAX` on Linux, the BSDs,
+/// and macOS.
+const DEFAULT_MAX_COMPONENT: usize = 255;
+
+/// A way a name may fail to unpack as it is on other systems, found by [`PortableNames`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Portability {
+    /// The name holds a character outside the POSIX portable filename character set
+    /// (`A`-`Z`, `a`-`z`, `0`-`9`, `.`, `_`, and `-`), other than the `/` between components.
+    Character { name: String, character: char },
+    /// A component of the name is more than `max` bytes long.
+    LongComponent {
+        name: String,
+        component: String,
+        max: usize,
+    },
+    /// The name differs from the earlier name `other` only in case, so the two would be one
+    /// file on a case-insensitive filesystem.
+    CaseCollision { name: String, other: String },
+}
+
+impl Portability {
+    /// Returns the name that isn't portable.
+    pub fn name(&self) -> &str {
+        match *self {
+            Portability::Character { ref name, .. }
+            | Portability::LongComponent { ref name, .. }
+            | Portability::CaseCollision { ref name, .. } => name,
+        }
+    }
+}
+
+impl fmt::Display for Portability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Portability::Character {
+                ref name,
+                character,
+            } => write!(
+                f,
+                "Entry name {:?} contains {:?}, which is not a portable filename character",
+                name, character
+            ),
+            Portability::LongComponent {
+                ref name,
+                ref component,
+                max,
+            } => write!(
+                f,
+                "Entry name {:?} has a component {} bytes long, more than {}",
+                name,
+                component.len(),
+                max
+            ),
+            Portability::CaseCollision {
+                ref name,
+                ref other,
+            } => write!(
+                f,
+                "Entry name {:?} differs only in case from {:?}",
+                name, other
+            ),
+        }
+    }
+}
+
+impl Error for Portability {}
+
+/// Checks that names will unpack cleanly on systems and unpackers other than the one they
+/// were made on.
+///
+/// Names are held to the POSIX portable filename character set, components to a length limit
+/// (255 bytes by default), and each name is remembered so that later names differing from it
+/// only in case are caught. A leading `/` or `./` is ignored, as are the `.` and empty
+/// components it leaves.
+#[derive(Clone, Debug)]
+pub struct PortableNames {
+    max_component: usize,
+    /// The names seen so far, normalized and lowercased, mapped to the first name with each and
+    /// its normalized form.
+    seen: HashMap<String, (String, String)>,
+}
+
+impl Default for PortableNames {
+    fn default() -> Self {
+        Self {
+            max_component: DEFAULT_MAX_COMPONENT,
+            seen: HashMap::new(),
+        }
+    }
+}
+
+impl PortableNames {
+    /// Creates a checker that has seen no names.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the longest component to accept, in bytes. POSIX only guarantees 14, which some
+    /// old unpackers still enforce.
+    pub fn max_component(mut self, max: usize) -> Self {
+        self.max_component = max;
+        self
+    }
+
+    /// Checks `name`, returning every way in which it is not portable, and remembers it to
+    /// check later names against.
+    pub fn check(&mut self, name: &str) -> Vec<Portability> {
+        let mut problems = vec![];
+        let components = || {
+            name.split('/')
+                .filter(|&component| component != "." && !component.is_empty())
+        };
+        let bad = name
+            .chars()
+            .find(|&c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/')));
+        if let Some(character) = bad {
+            problems.push(Portability::Character {
+                name: name.to_string(),
+                character,
+            });
+        }
+        let max = self.max_component;
+        if let Some(component) = components().find(|component| component.len() > max) {
+            problems.push(Portability::LongComponent {
+                name: name.to_string(),
+                component: component.to_string(),
+                max,
+            });
+        }
+
+        let normalized = components().collect::<Vec<_>>().join("/");
+        match self.seen.get(&normalized.to_lowercase()) {
+            Some((other, other_normalized)) => {
+                if *other_normalized != normalized {
+                    problems.push(Portability::CaseCollision {
+                        name: name.to_string(),
+                        other: other.clone(),
+                    });
+                }
+            }
+            None => {
+                self.seen
+                    .insert(normalized.to_lowercase(), (name.to_string(), normalized));
+            }
+        }
+        problems
+    }
+}
+
+/// Resolves the `..` components of `name` against the components before them, keeping any
+/// leading `/` or `./` and trailing `/`.
+fn resolve_parents(name: &str) -> String {
+    let (prefix, rest) = if let Some(rest) = name.strip_prefix('/') {
+        ("/", rest)
+    } else if let Some(rest) = name.strip_prefix("./") {
+        ("./", rest)
+    } else {
+        ("", name)
+    };
+    let mut parts = vec![];
+    for part in rest.split('/') {
+        match part {
+            ".." => {
+                parts.pop();
+            }
+            "" | "." => {}
+            part => parts.push(part),
+        }
+    }
+    if parts.is_empty() {
+        return if prefix == "/" { "/" } else { "." }.to_string();
+    }
+    let mut resolved = prefix.to_string();
+    resolved.push_str(&parts.join("/"));
+    if name.ends_with('/') {
+        resolved.push('/');
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permissive() {
+        let policy = NamePolicy::default();
+        for name in ["", "/etc/passwd", "../../x", "dir/"].iter() {
+            assert_eq!(policy.apply(name).unwrap(), *name);
+        }
+    }
+
+    #[test]
+    fn test_strict() {
+        let policy = NamePolicy::strict();
+        assert_eq!(policy.apply("./usr/bin").unwrap(), "./usr/bin");
+        assert_eq!(policy.apply("a..b").unwrap(), "a..b");
+        assert_eq!(policy.apply("").unwrap_err(), Violation::Empty);
+        assert_eq!(policy.apply("TRAILER!!!").unwrap_err(), Violation::Trailer);
+        assert_eq!(policy.apply("./TRAILER!!!").unwrap(), "./TRAILER!!!");
+        assert_eq!(
+            policy.apply("/etc").unwrap_err(),
+            Violation::Absolute("/etc".to_string())
+        );
+        assert_eq!(
+            policy.apply("a/../b").unwrap_err(),
+            Violation::ParentDir("a/../b".to_string())
+        );
+        assert_eq!(
+            policy.apply("dir/").unwrap_err().to_string(),
+            "Entry name \"dir/\" ends with a slash"
+        );
+    }
+
+    #[test]
+    fn test_normalizing() {
+        let policy = NamePolicy::normalizing();
+        let cases = [
+            ("/etc/passwd", "etc/passwd"),
+            ("/", "."),
+            ("", "."),
+            ("dir//", "dir"),
+            ("./a/../b", "./b"),
+            ("../../etc", "etc"),
+            ("a/..", "."),
+            ("//../x/", "x"),
+            ("TRAILER!!!", "./TRAILER!!!"),
+        ];
+        for &(name, normalized) in cases.iter() {
+            assert_eq!(policy.apply(name).unwrap(), normalized, "{}", name);
+        }
+
+        let policy = NamePolicy::permissive().parent_dir(Action::Normalize);
+        assert_eq!(policy.apply("/a/../../b/").unwrap(), "/b/");
+    }
+
+    #[test]
+    fn test_portable_names() {
+        let mut names = PortableNames::new();
+        assert!(names.check("./usr/lib/libc.so.6").is_empty());
+        assert!(names.check("usr/lib/libc.so.6").is_empty());
+        assert_eq!(
+            names.check("./usr/lib/LIBC.so.6"),
+            vec![Portability::CaseCollision {
+                name: "./usr/lib/LIBC.so.6".to_string(),
+                other: "./usr/lib/libc.so.6".to_string(),
+            }]
+        );
+
+        let problems = names.check("./my file:1");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            problems[0].to_string(),
+            "Entry name \"./my file:1\" contains ' ', which is not a portable filename character"
+        );
+        assert_eq!(
+            names.check("caf\u{e9}")[0],
+            Portability::Character {
+                name: "caf\u{e9}".to_string(),
+                character: '\u{e9}',
+            }
+        );
+
+        let mut names = PortableNames::new().max_component(14);
+        assert!(names.check("abcdefghijklmn/x").is_empty());
+        let problems = names.check("abcdefghijklmno/x");
+        assert_eq!(problems[0].name(), "abcdefghijklmno/x");
+        assert_eq!(
+            problems[0].to_string(),
+            "Entry name \"abcdefghijklmno/x\" has a component 15 bytes long, more than 14"
+        );
+    }
+}
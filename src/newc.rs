@@ -1,22 +1,88 @@
 //! Read/write `newc` (SVR4) format archives.
 
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fmt;
+use std::fs;
+use std::io::{self, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::ops::{BitAnd, BitOr, BitOrAssign, Not};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
 
-const HEADER_LEN: usize = 110; // 6 byte magic number + 104 bytes of metadata
+use crate::diagnostics::{Diagnostics, Warning};
+use crate::name::NamePolicy;
+use crate::owner::OwnerMap;
 
-const MAGIC_NUMBER_NEWASCII: &[u8] = b"070701";
-const MAGIC_NUMBER_NEWCRC: &[u8] = b"070702";
+/// The length of the fixed-size part of an entry header: a 6 byte magic number followed by
+/// thirteen 8 digit hex fields.
+pub const HEADER_LEN: usize = 110;
 
-const TRAILER_NAME: &str = "TRAILER!!!";
+/// The magic number that starts each header of a `newc` ("new ascii") archive.
+pub const MAGIC_NUMBER_NEWASCII: &[u8] = b"070701";
+/// The magic number that starts each header of a `crc` archive.
+pub const MAGIC_NUMBER_NEWCRC: &[u8] = b"070702";
+
+/// The name of the entry that ends an archive.
+pub const TRAILER_NAME: &str = "TRAILER!!!";
+
+/// The longest symlink target [`Reader::read_symlink_target`] accepts: Linux's `PATH_MAX`,
+/// which includes a terminating NUL that archives don't store.
+const MAX_SYMLINK_TARGET: u32 = 4095;
+
+/// Size of the chunks handed to each worker thread by [`checksum_parallel`].
+const CHECKSUM_CHUNK_SIZE: usize = 1024 * 1024;
 
 /// Whether this header is of the "new ascii" form (without checksum) or the "crc" form which
 /// is structurally identical but includes a checksum, depending on the magic number present.
-#[derive(Clone)]
-enum EntryType {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryType {
     Crc,
     Newc,
 }
 
+/// The fixed-size part of an entry header, as it appears in the archive before the entry
+/// name.
+///
+/// [`parse`](Self::parse) and [`emit`](Self::emit) convert between this and the on-disk bytes
+/// without doing any I/O, for callers who manage their own buffers. The fields are those of
+/// the header in order, with the magic number as `entry_type`. A header is [`HEADER_LEN`]
+/// bytes long and is followed by `name_size` bytes of name, then by padding that brings the
+/// header and name to a multiple of four bytes, then by `file_size` bytes of data, padded
+/// the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// Which magic number starts the header: [`MAGIC_NUMBER_NEWASCII`] or
+    /// [`MAGIC_NUMBER_NEWCRC`].
+    pub entry_type: EntryType,
+    /// Inode number of the file. Entries sharing device and inode numbers, with a link
+    /// count above one, are hard links to one file.
+    pub ino: u32,
+    /// File type and permission bits, as in `st_mode`.
+    pub mode: u32,
+    /// Numeric user ID of the owner.
+    pub uid: u32,
+    /// Numeric group ID of the owner.
+    pub gid: u32,
+    /// Number of links to the file.
+    pub nlink: u32,
+    /// Modification time, in seconds since the epoch.
+    pub mtime: u32,
+    /// Length of the data that follows the name, in bytes.
+    pub file_size: u32,
+    /// Major number of the device holding the file.
+    pub dev_major: u32,
+    /// Minor number of the device holding the file.
+    pub dev_minor: u32,
+    /// Major number of the device a character or block device node refers to.
+    pub rdev_major: u32,
+    /// Minor number of the device a character or block device node refers to.
+    pub rdev_minor: u32,
+    /// Length of the name that follows the header, including its NUL terminator.
+    pub name_size: u32,
+    /// Sum of the bytes of the data, truncated to 32 bits, in `crc` headers; zero in `newc`
+    /// ones.
+    pub checksum: u32,
+}
+
 /// Metadata about one entry from an archive.
 #[derive(Clone)]
 pub struct Entry {
@@ -41,6 +107,7 @@ pub struct Reader<R: Read> {
     inner: R,
     entry: Entry,
     bytes_read: u32,
+    diagnostics: Option<Diagnostics>,
 }
 
 /// Builds metadata for one entry to be written into an archive.
@@ -59,26 +126,237 @@ pub struct Builder {
     rdev_minor: u32,
 }
 
+/// The metadata given to files by [`Builder::from_portable_metadata`], for the fields that
+/// not every platform can provide.
+///
+/// By default files are owned by root, directories have mode `0755`, regular files `0644`,
+/// and symlinks `0777`, and modification times come from the files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MetadataDefaults {
+    uid: u32,
+    gid: u32,
+    file_mode: u32,
+    dir_mode: u32,
+    symlink_mode: u32,
+    mtime: Option<u32>,
+}
+
+impl Default for MetadataDefaults {
+    fn default() -> Self {
+        Self {
+            uid: 0,
+            gid: 0,
+            file_mode: 0o644,
+            dir_mode: 0o755,
+            symlink_mode: 0o777,
+            mtime: None,
+        }
+    }
+}
+
+impl MetadataDefaults {
+    /// Creates the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the user id every file is owned by.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = uid;
+        self
+    }
+
+    /// Set the group id every file is owned by.
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = gid;
+        self
+    }
+
+    /// Set the permissions of regular files. Write permission is removed from read-only ones.
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.file_mode = mode;
+        self
+    }
+
+    /// Set the permissions of directories. Write permission is removed from read-only ones.
+    pub fn dir_mode(mut self, mode: u32) -> Self {
+        self.dir_mode = mode;
+        self
+    }
+
+    /// Set the permissions of symlinks.
+    pub fn symlink_mode(mut self, mode: u32) -> Self {
+        self.symlink_mode = mode;
+        self
+    }
+
+    /// Set a modification time to give every file, in place of the file's own.
+    pub fn mtime(mut self, mtime: Option<u32>) -> Self {
+        self.mtime = mtime;
+        self
+    }
+}
+
+/// What to do with a modification time that the 32-bit unsigned header field can't hold: one
+/// before 1970 or after early 2106.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MtimePolicy {
+    /// Fail with an `InvalidInput` error.
+    Error,
+    /// Use the nearest time that can be represented.
+    #[default]
+    Clamp,
+    /// Use a modification time of zero, the start of 1970.
+    Zero,
+}
+
+impl MtimePolicy {
+    /// Converts `seconds` since the Unix epoch into a header modification time according to
+    /// this policy, naming the entry called `name` in any error.
+    pub fn apply(self, name: &str, seconds: i64) -> io::Result<u32> {
+        if let Ok(mtime) = u32::try_from(seconds) {
+            return Ok(mtime);
+        }
+        match self {
+            MtimePolicy::Error => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{}: modification time {} is outside the range of the format",
+                    name, seconds
+                ),
+            )),
+            MtimePolicy::Clamp => Ok(seconds.clamp(0, i64::from(u32::MAX)) as u32),
+            MtimePolicy::Zero => Ok(0),
+        }
+    }
+}
+
+/// Returns the whole seconds from the Unix epoch to `time`, rounding down, saturating at the
+/// bounds of `i64`.
+fn system_time_seconds(time: std::time::SystemTime) -> i64 {
+    match time.duration_since(std::time::UNIX_EPOCH) {
+        Ok(since) => i64::try_from(since.as_secs()).unwrap_or(i64::MAX),
+        Err(err) => {
+            let before = err.duration();
+            let seconds = i64::try_from(before.as_secs()).unwrap_or(i64::MAX);
+            -seconds - i64::from(before.subsec_nanos() > 0)
+        }
+    }
+}
+
 /// Writes one entry header/data into an archive.
 pub struct Writer<W: Write> {
     inner: W,
-    written: u32,
+    written: u64,
     file_size: u32,
     header_size: usize,
     header: Vec<u8>,
 }
 
-fn pad(len: usize) -> Option<Vec<u8>> {
-    // pad out to a multiple of 4 bytes
-    let overhang = len % 4;
-    if overhang != 0 {
-        let repeat = 4 - overhang;
-        Some(vec![0u8; repeat])
-    } else {
-        None
+/// Default size of the copy buffer held by [`Buffers`].
+const DEFAULT_COPY_BUF_SIZE: usize = 64 * 1024;
+
+/// Reusable scratch buffers for repeated archive operations.
+///
+/// Long-running code that reads or writes many entries or archives can hold one of these and
+/// pass it to [`Builder::write_with_buffers`]/[`Writer::finish_with_buffers`], and its
+/// [`copy_buffer`](Self::copy_buffer) to [`Reader::finish_with_buffer`] and
+/// [`Reader::to_writer_with_buffer`], instead of allocating fresh buffers for each operation.
+pub struct Buffers {
+    header: Vec<u8>,
+    copy: Vec<u8>,
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Buffers {
+    /// Creates a set of buffers with a 64 KiB copy buffer.
+    pub fn new() -> Self {
+        Self::with_copy_size(DEFAULT_COPY_BUF_SIZE)
+    }
+
+    /// Creates a set of buffers with a copy buffer of `size` bytes.
+    pub fn with_copy_size(size: usize) -> Self {
+        Self {
+            header: Vec::with_capacity(HEADER_LEN),
+            copy: vec![0u8; size],
+        }
+    }
+
+    /// Returns the scratch buffer used for copying or discarding file data.
+    pub fn copy_buffer(&mut self) -> &mut [u8] {
+        &mut self.copy
+    }
+}
+
+/// Zero bytes used to pad headers and file data.
+const PADDING: [u8; 4] = [0u8; 4];
+
+/// Returns the number of bytes needed to pad `len` out to a multiple of 4 bytes.
+pub(crate) fn pad(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// Returns the number of bytes an entry with a name of `name_len` bytes and `file_size` bytes
+/// of data occupies in an archive: the header, the name and its NUL terminator, the data, and
+/// the padding after each.
+pub(crate) fn encoded_len(name_len: usize, file_size: u32) -> u64 {
+    let header = HEADER_LEN + name_len + 1;
+    (header + pad(header)) as u64 + u64::from(file_size) + pad(file_size as usize) as u64
+}
+
+/// Copies exactly `len` bytes from `reader` to `writer` through the caller's scratch `buf`.
+fn copy_with_buffer<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    len: u64,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    if copy_up_to(reader, writer, len, buf)? != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "archive ended before the end of the entry data",
+        ));
+    }
+    Ok(())
+}
+
+/// Copies up to `len` bytes from `reader` to `writer` through the caller's scratch `buf`,
+/// returning the number copied before `reader` ended.
+pub(crate) fn copy_up_to<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    len: u64,
+    buf: &mut [u8],
+) -> io::Result<u64> {
+    if buf.is_empty() && len > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "scratch buffer must not be empty",
+        ));
+    }
+    let mut copied = 0;
+    while copied < len {
+        let limit = buf
+            .len()
+            .min((len - copied).try_into().unwrap_or(usize::MAX));
+        let n = match reader.read(&mut buf[..limit]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
     }
+    Ok(copied)
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ModeFileType {
     Symlink,
     Fifo,
@@ -92,6 +370,21 @@ pub enum ModeFileType {
 
 impl ModeFileType {
     const MASK: u32 = 0o170000;
+
+    /// Returns the file type encoded in `mode`, if it is one we know.
+    pub fn from_mode(mode: u32) -> Option<Self> {
+        match mode & Self::MASK {
+            0o010000 => Some(ModeFileType::Fifo),
+            0o020000 => Some(ModeFileType::Char),
+            0o040000 => Some(ModeFileType::Directory),
+            0o060000 => Some(ModeFileType::Block),
+            0o100000 => Some(ModeFileType::Regular),
+            0o110000 => Some(ModeFileType::NetworkSpecial),
+            0o120000 => Some(ModeFileType::Symlink),
+            0o140000 => Some(ModeFileType::Socket),
+            _ => None,
+        }
+    }
 }
 
 impl From<ModeFileType> for u32 {
@@ -109,16 +402,342 @@ impl From<ModeFileType> for u32 {
     }
 }
 
-fn read_hex_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
-    let mut bytes = [0u8; 8];
-    reader.read_exact(&mut bytes)?;
-    ::std::str::from_utf8(&bytes)
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf-8 header field"))
-        .and_then(|string| {
-            u32::from_str_radix(string, 16).map_err(|_| {
-                io::Error::new(io::ErrorKind::InvalidData, "Invalid hex u32 header field")
-            })
+/// The permission bits of a mode: read, write, and execute for the owner, the group, and
+/// everyone else, along with the setuid, setgid, and sticky bits.
+///
+/// Flags combine with `|`, so that `Permissions::USER_RWX | Permissions::GROUP_READ` is mode
+/// `0740`. They format with `{:?}` as an octal mode, and with `{}` as `ls` shows them, such
+/// as `rwxr-x---`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Permissions(u32);
+
+impl Permissions {
+    pub const SETUID: Self = Self(0o4000);
+    pub const SETGID: Self = Self(0o2000);
+    pub const STICKY: Self = Self(0o1000);
+    pub const USER_READ: Self = Self(0o400);
+    pub const USER_WRITE: Self = Self(0o200);
+    pub const USER_EXEC: Self = Self(0o100);
+    pub const USER_RWX: Self = Self(0o700);
+    pub const GROUP_READ: Self = Self(0o040);
+    pub const GROUP_WRITE: Self = Self(0o020);
+    pub const GROUP_EXEC: Self = Self(0o010);
+    pub const GROUP_RWX: Self = Self(0o070);
+    pub const OTHER_READ: Self = Self(0o004);
+    pub const OTHER_WRITE: Self = Self(0o002);
+    pub const OTHER_EXEC: Self = Self(0o001);
+    pub const OTHER_RWX: Self = Self(0o007);
+
+    const MASK: u32 = 0o7777;
+
+    /// Returns no permissions at all.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns every permission bit.
+    pub const fn all() -> Self {
+        Self(Self::MASK)
+    }
+
+    /// Returns the permissions with the bits `bits`, or `None` if any other bit is set.
+    pub const fn from_bits(bits: u32) -> Option<Self> {
+        if bits & !Self::MASK == 0 {
+            Some(Self(bits))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the permission bits of `mode`, ignoring its file type.
+    pub const fn from_mode(mode: u32) -> Self {
+        Self(mode & Self::MASK)
+    }
+
+    /// Returns the permissions as mode bits.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if every bit set in `other` is also set in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Sets the bits set in `other`.
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    /// Clears the bits set in `other`.
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Permissions {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for Permissions {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl Not for Permissions {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(!self.0 & Self::MASK)
+    }
+}
+
+impl From<Permissions> for u32 {
+    fn from(permissions: Permissions) -> u32 {
+        permissions.0
+    }
+}
+
+#[cfg(unix)]
+impl From<fs::Permissions> for Permissions {
+    fn from(permissions: fs::Permissions) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+        Self::from_mode(permissions.mode())
+    }
+}
+
+#[cfg(unix)]
+impl From<Permissions> for fs::Permissions {
+    fn from(permissions: Permissions) -> Self {
+        use std::os::unix::fs::PermissionsExt;
+        fs::Permissions::from_mode(permissions.0)
+    }
+}
+
+impl fmt::Debug for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Permissions({:#05o})", self.0)
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Each class as `ls` shows it, with the special bit that replaces its execute bit.
+        let classes = [
+            (6, Self::SETUID, 's'),
+            (3, Self::SETGID, 's'),
+            (0, Self::STICKY, 't'),
+        ];
+        for &(shift, special, letter) in classes.iter() {
+            let bits = self.0 >> shift;
+            let exec = bits & 1 != 0;
+            let exec = match (self.contains(special), exec) {
+                (true, true) => letter,
+                (true, false) => letter.to_ascii_uppercase(),
+                (false, true) => 'x',
+                (false, false) => '-',
+            };
+            let read = if bits & 4 != 0 { 'r' } else { '-' };
+            let write = if bits & 2 != 0 { 'w' } else { '-' };
+            write!(f, "{}{}{}", read, write, exec)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a Linux `dev_t` into the major and minor numbers stored in a header.
+///
+/// This is the encoding glibc and musl use, in which the low 8 bits of the minor number sit
+/// below the low 12 bits of the major number and the rest of each above them, so that the old
+/// 8:8 bit split of small device numbers still decodes correctly. Shifting by 8 bits instead
+/// mangles minor numbers above 255.
+pub fn linux_dev_split(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+/// Joins the major and minor numbers stored in a header into a Linux `dev_t`, the inverse of
+/// [`linux_dev_split`].
+pub fn linux_dev_join(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (u64::from(major), u64::from(minor));
+    ((major & !0xfff) << 32) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12) | (minor & 0xff)
+}
+
+/// Splits a device number from `stat` into its major and minor numbers.
+#[cfg(all(unix, not(target_vendor = "apple")))]
+fn split_dev(dev: u64) -> (u32, u32) {
+    linux_dev_split(dev)
+}
+
+/// Splits a device number from `stat` into its major and minor numbers.
+#[cfg(target_vendor = "apple")]
+fn split_dev(dev: u64) -> (u32, u32) {
+    (((dev >> 24) & 0xff) as u32, (dev & 0xff_ffff) as u32)
+}
+
+/// Joins major and minor numbers into a device number to create a node with, the inverse of
+/// `split_dev`.
+#[cfg(all(unix, not(target_vendor = "apple")))]
+pub(crate) fn join_dev(major: u32, minor: u32) -> u64 {
+    linux_dev_join(major, minor)
+}
+
+/// Joins major and minor numbers into a device number to create a node with, the inverse of
+/// `split_dev`.
+#[cfg(target_vendor = "apple")]
+pub(crate) fn join_dev(major: u32, minor: u32) -> u64 {
+    (u64::from(major & 0xff) << 24) | u64::from(minor & 0xff_ffff)
+}
+
+/// Encodes `value` as an 8 byte hex header field into `field`.
+fn write_hex_u32(field: &mut [u8], value: u32) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for (idx, digit) in field.iter_mut().enumerate() {
+        *digit = DIGITS[((value >> ((7 - idx) * 4)) & 0xf) as usize];
+    }
+}
+
+/// How strictly the hex fields of a header are parsed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeaderParsing {
+    /// Accept only the lowercase hex digits that GNU cpio and the kernel's `gen_init_cpio`
+    /// write.
+    Strict,
+    /// Also accept uppercase hex digits. This is the default.
+    #[default]
+    Standard,
+    /// Also accept fields padded with spaces on either side, as some vendor tools write them;
+    /// a field of only spaces reads as zero.
+    Tolerant,
+}
+
+/// Decodes an 8 byte hex header field directly from its bytes.
+pub(crate) fn read_hex_u32(bytes: &[u8]) -> io::Result<u32> {
+    read_hex_u32_with(bytes, HeaderParsing::Standard)
+}
+
+/// Decodes an 8 byte hex header field directly from its bytes, as strictly as `parsing` says.
+fn read_hex_u32_with(mut bytes: &[u8], parsing: HeaderParsing) -> io::Result<u32> {
+    if parsing == HeaderParsing::Tolerant {
+        while let Some((b' ', rest)) = bytes.split_first() {
+            bytes = rest;
+        }
+        while let Some((b' ', rest)) = bytes.split_last() {
+            bytes = rest;
+        }
+    }
+    bytes.iter().try_fold(0u32, |value, &byte| {
+        let digit = match byte {
+            b'0'..=b'9' => byte - b'0',
+            b'a'..=b'f' => byte - b'a' + 10,
+            b'A'..=b'F' if parsing != HeaderParsing::Strict => byte - b'A' + 10,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid hex u32 header field",
+                ))
+            }
+        };
+        Ok(value << 4 | u32::from(digit))
+    })
+}
+
+impl Header {
+    /// Decodes a header from its on-disk bytes.
+    pub fn parse(buf: &[u8; HEADER_LEN]) -> io::Result<Header> {
+        Self::parse_with(buf, HeaderParsing::default())
+    }
+
+    /// Like [`parse`](Self::parse), but parses the hex fields as strictly as `parsing` says.
+    pub fn parse_with(buf: &[u8; HEADER_LEN], parsing: HeaderParsing) -> io::Result<Header> {
+        let read_hex_u32 = |field| read_hex_u32_with(field, parsing);
+        // char    c_magic[6];
+        let entry_type = match &buf[0..6] {
+            MAGIC_NUMBER_NEWASCII => EntryType::Newc,
+            MAGIC_NUMBER_NEWCRC => EntryType::Crc,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid magic number",
+                ))
+            }
+        };
+
+        Ok(Header {
+            entry_type,
+            // char    c_ino[8];
+            ino: read_hex_u32(&buf[6..14])?,
+            // char    c_mode[8];
+            mode: read_hex_u32(&buf[14..22])?,
+            // char    c_uid[8];
+            uid: read_hex_u32(&buf[22..30])?,
+            // char    c_gid[8];
+            gid: read_hex_u32(&buf[30..38])?,
+            // char    c_nlink[8];
+            nlink: read_hex_u32(&buf[38..46])?,
+            // char    c_mtime[8];
+            mtime: read_hex_u32(&buf[46..54])?,
+            // char    c_filesize[8];
+            file_size: read_hex_u32(&buf[54..62])?,
+            // char    c_devmajor[8];
+            dev_major: read_hex_u32(&buf[62..70])?,
+            // char    c_devminor[8];
+            dev_minor: read_hex_u32(&buf[70..78])?,
+            // char    c_rdevmajor[8];
+            rdev_major: read_hex_u32(&buf[78..86])?,
+            // char    c_rdevminor[8];
+            rdev_minor: read_hex_u32(&buf[86..94])?,
+            // char    c_namesize[8];
+            name_size: read_hex_u32(&buf[94..102])?,
+            // char    c_checksum[8];
+            checksum: read_hex_u32(&buf[102..110])?,
         })
+    }
+
+    /// Encodes this header into its on-disk bytes.
+    pub fn emit(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+
+        // char    c_magic[6];
+        buf[0..6].copy_from_slice(match self.entry_type {
+            EntryType::Newc => MAGIC_NUMBER_NEWASCII,
+            EntryType::Crc => MAGIC_NUMBER_NEWCRC,
+        });
+        let fields = [
+            self.ino,        // char    c_ino[8];
+            self.mode,       // char    c_mode[8];
+            self.uid,        // char    c_uid[8];
+            self.gid,        // char    c_gid[8];
+            self.nlink,      // char    c_nlink[8];
+            self.mtime,      // char    c_mtime[8];
+            self.file_size,  // char    c_filesize[8];
+            self.dev_major,  // char    c_devmajor[8];
+            self.dev_minor,  // char    c_devminor[8];
+            self.rdev_major, // char    c_rdevmajor[8];
+            self.rdev_minor, // char    c_rdevminor[8];
+            self.name_size,  // char    c_namesize[8];
+            self.checksum,   // char    c_check[8];
+        ];
+        for (field, value) in buf[6..].chunks_exact_mut(8).zip(fields.iter()) {
+            write_hex_u32(field, *value);
+        }
+
+        buf
+    }
 }
 
 impl Entry {
@@ -138,6 +757,11 @@ impl Entry {
         self.mode
     }
 
+    /// Returns the permission bits of the file's mode.
+    pub fn permissions(&self) -> Permissions {
+        Permissions::from_mode(self.mode)
+    }
+
     /// Returns the UID for this file's owner.
     pub fn uid(&self) -> u32 {
         self.uid
@@ -158,6 +782,14 @@ impl Entry {
         self.mtime
     }
 
+    /// Returns the modification time of this file as a [`SystemTime`], taking the field as
+    /// seconds since the Unix epoch.
+    ///
+    /// [`SystemTime`]: std::time::SystemTime
+    pub fn mtime_systemtime(&self) -> std::time::SystemTime {
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(self.mtime.into())
+    }
+
     /// Returns the size of this file, in bytes.
     pub fn file_size(&self) -> u32 {
         self.file_size
@@ -199,9 +831,48 @@ impl Entry {
         self.rdev_minor
     }
 
+    /// Records warnings about this entry's header in `diagnostics`.
+    fn check(&self, diagnostics: &Diagnostics, nonzero_padding: bool) {
+        let name = || self.name.clone();
+        if nonzero_padding {
+            diagnostics.push(Warning::NonzeroPadding { name: name() });
+        }
+        if self.is_trailer() {
+            return;
+        }
+        if self.mode == 0 {
+            diagnostics.push(Warning::ZeroMode { name: name() });
+        } else if ModeFileType::from_mode(self.mode).is_none() {
+            diagnostics.push(Warning::MissingFileType {
+                name: name(),
+                mode: self.mode,
+            });
+        }
+        if self.entry_type == EntryType::Newc && self.checksum != 0 {
+            diagnostics.push(Warning::UnexpectedChecksum {
+                name: name(),
+                checksum: self.checksum,
+            });
+        }
+        if self.nlink == 0 {
+            diagnostics.push(Warning::ZeroNlink { name: name() });
+        }
+        let now = system_time_seconds(std::time::SystemTime::now());
+        if i64::from(self.mtime) > now {
+            diagnostics.push(Warning::FutureMtime {
+                name: name(),
+                mtime: self.mtime,
+            });
+        }
+    }
+
     /// Returns true if this is a trailer entry.
+    ///
+    /// The trailer is recognized by its name together with a mode and file size of zero, so
+    /// that a real file that happens to be called `TRAILER!!!`, which has a file type in its
+    /// mode, does not end the archive early.
     pub fn is_trailer(&self) -> bool {
-        self.name == TRAILER_NAME
+        self.name == TRAILER_NAME && self.mode == 0 && self.file_size == 0
     }
 
     /// Return the checksum of this entry.
@@ -214,56 +885,68 @@ impl Entry {
             EntryType::Newc => None,
         }
     }
+
+    /// Returns the fixed-size header this entry is written with, which
+    /// [`Header::emit`] encodes as this crate would write it.
+    pub fn header(&self) -> Header {
+        Header {
+            entry_type: self.entry_type,
+            ino: self.ino,
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            nlink: self.nlink,
+            mtime: self.mtime,
+            file_size: self.file_size,
+            dev_major: self.dev_major,
+            dev_minor: self.dev_minor,
+            rdev_major: self.rdev_major,
+            rdev_minor: self.rdev_minor,
+            name_size: self.name.len() as u32 + 1,
+            checksum: self.checksum,
+        }
+    }
 }
 
 impl<R: Read> Reader<R> {
     /// Parses metadata for the next entry in an archive, and returns a reader
     /// that will yield the entry data.
-    pub fn new(mut inner: R) -> io::Result<Reader<R>> {
-        // char    c_magic[6];
-        let mut magic = [0u8; 6];
-        inner.read_exact(&mut magic)?;
-        let entry_type = match magic.as_slice() {
-            MAGIC_NUMBER_NEWASCII => EntryType::Newc,
-            MAGIC_NUMBER_NEWCRC => EntryType::Crc,
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Invalid magic number",
-                ))
-            }
-        };
+    pub fn new(inner: R) -> io::Result<Reader<R>> {
+        Self::read_entry(inner, None, HeaderParsing::default())
+    }
+
+    /// Like [`new`](Self::new), but records any [`Warning`]s about the entry in `diagnostics`,
+    /// including about its padding as the entry is finished (other than by
+    /// [`skip`](Self::skip), which seeks past it).
+    pub fn with_diagnostics(inner: R, diagnostics: &Diagnostics) -> io::Result<Reader<R>> {
+        Self::read_entry(inner, Some(diagnostics.clone()), HeaderParsing::default())
+    }
+
+    /// Like [`new`](Self::new), but parses the hex fields of the header as strictly as
+    /// `parsing` says.
+    pub fn with_parsing(inner: R, parsing: HeaderParsing) -> io::Result<Reader<R>> {
+        Self::read_entry(inner, None, parsing)
+    }
 
-        // char    c_ino[8];
-        let ino = read_hex_u32(&mut inner)?;
-        // char    c_mode[8];
-        let mode = read_hex_u32(&mut inner)?;
-        // char    c_uid[8];
-        let uid = read_hex_u32(&mut inner)?;
-        // char    c_gid[8];
-        let gid = read_hex_u32(&mut inner)?;
-        // char    c_nlink[8];
-        let nlink = read_hex_u32(&mut inner)?;
-        // char    c_mtime[8];
-        let mtime = read_hex_u32(&mut inner)?;
-        // char    c_filesize[8];
-        let file_size = read_hex_u32(&mut inner)?;
-        // char    c_devmajor[8];
-        let dev_major = read_hex_u32(&mut inner)?;
-        // char    c_devminor[8];
-        let dev_minor = read_hex_u32(&mut inner)?;
-        // char    c_rdevmajor[8];
-        let rdev_major = read_hex_u32(&mut inner)?;
-        // char    c_rdevminor[8];
-        let rdev_minor = read_hex_u32(&mut inner)?;
-        // char    c_namesize[8];
-        let name_len = read_hex_u32(&mut inner)? as usize;
-        // char    c_checksum[8];
-        let checksum = read_hex_u32(&mut inner)?;
-
-        // NUL-terminated name with length `name_len` (including NUL byte).
-        let mut name_bytes = vec![0u8; name_len];
+    fn read_entry(
+        mut inner: R,
+        diagnostics: Option<Diagnostics>,
+        parsing: HeaderParsing,
+    ) -> io::Result<Reader<R>> {
+        // Read the whole fixed-size header at once and decode the fields from it.
+        let mut buf = [0u8; HEADER_LEN];
+        inner.read_exact(&mut buf)?;
+        let header = Header::parse_with(&buf, parsing)?;
+        let name_len = header.name_size as usize;
+
+        // NUL-terminated name with length `name_len` (including NUL byte), followed by padding
+        // out to a multiple of 4 bytes. Both are read at once.
+        let mut name_bytes = vec![0u8; name_len + pad(HEADER_LEN + name_len)];
         inner.read_exact(&mut name_bytes)?;
+        let nonzero_padding = name_bytes[name_len.min(name_bytes.len())..]
+            .iter()
+            .any(|&byte| byte != 0);
+        name_bytes.truncate(name_len);
         if name_bytes.last() != Some(&0) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -280,39 +963,114 @@ impl<R: Read> Reader<R> {
             io::Error::new(io::ErrorKind::InvalidData, "Entry name was not valid UTF-8")
         })?;
 
-        // Pad out to a multiple of 4 bytes.
-        if let Some(mut padding) = pad(HEADER_LEN + name_len) {
-            inner.read_exact(&mut padding)?;
-        }
-
         let entry = Entry {
-            entry_type,
+            entry_type: header.entry_type,
             name,
-            ino,
-            mode,
-            uid,
-            gid,
-            nlink,
-            mtime,
-            file_size,
-            dev_major,
-            dev_minor,
-            rdev_major,
-            rdev_minor,
-            checksum,
+            ino: header.ino,
+            mode: header.mode,
+            uid: header.uid,
+            gid: header.gid,
+            nlink: header.nlink,
+            mtime: header.mtime,
+            file_size: header.file_size,
+            dev_major: header.dev_major,
+            dev_minor: header.dev_minor,
+            rdev_major: header.rdev_major,
+            rdev_minor: header.rdev_minor,
+            checksum: header.checksum,
         };
+        trace_event!(
+            name = entry.name.as_str(),
+            size = entry.file_size,
+            mode = entry.mode,
+            "parsed entry header"
+        );
+        if let Some(ref diagnostics) = diagnostics {
+            entry.check(diagnostics, nonzero_padding);
+        }
         Ok(Reader {
             inner,
             entry,
             bytes_read: 0,
+            diagnostics,
         })
     }
 
+    /// Like [`new`](Self::new), but applies `policy` to the entry's name, failing with an
+    /// `InvalidData` error if it rejects the name. The trailer's name is never rejected.
+    pub fn with_name_policy(inner: R, policy: &NamePolicy) -> io::Result<Reader<R>> {
+        let mut reader = Self::new(inner)?;
+        if !reader.entry.is_trailer() {
+            let name = policy
+                .apply(&reader.entry.name)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+                .into_owned();
+            reader.entry.name = name;
+        }
+        Ok(reader)
+    }
+
     /// Returns the metadata for this entry.
     pub fn entry(&self) -> &Entry {
         &self.entry
     }
 
+    /// Reads the data of a symlink entry as the path it points to.
+    ///
+    /// The data of a symlink is its target, without a NUL terminator. Fails with an
+    /// `InvalidInput` error if the entry is not a symlink or some of its data has already been
+    /// read, and with an `InvalidData` error if the target is empty, longer than 4095 bytes,
+    /// or contains a NUL byte, or, on platforms other than Unix, is not UTF-8.
+    pub fn read_symlink_target(&mut self) -> io::Result<PathBuf> {
+        let name = self.entry.name.clone();
+        let invalid = |kind, problem: &str| io::Error::new(kind, format!("{}: {}", name, problem));
+        if ModeFileType::from_mode(self.entry.mode) != Some(ModeFileType::Symlink) {
+            return Err(invalid(io::ErrorKind::InvalidInput, "not a symlink"));
+        }
+        if self.bytes_read > 0 {
+            return Err(invalid(
+                io::ErrorKind::InvalidInput,
+                "symlink target has already been partly read",
+            ));
+        }
+        match self.entry.file_size {
+            0 => return Err(invalid(io::ErrorKind::InvalidData, "symlink has no target")),
+            len if len > MAX_SYMLINK_TARGET => {
+                return Err(invalid(
+                    io::ErrorKind::InvalidData,
+                    "symlink target is too long",
+                ))
+            }
+            _ => {}
+        }
+        let mut target = vec![0; self.entry.file_size as usize];
+        self.read_exact(&mut target)?;
+        if target.contains(&0) {
+            return Err(invalid(
+                io::ErrorKind::InvalidData,
+                "symlink target contains a NUL byte",
+            ));
+        }
+        bytes_to_path(target)
+            .ok_or_else(|| invalid(io::ErrorKind::InvalidData, "symlink target is not UTF-8"))
+    }
+
+    /// Reads the padding after the file data, recording a warning if it is not zero and
+    /// diagnostics are enabled.
+    fn read_data_padding(&mut self) -> io::Result<()> {
+        let mut padding = PADDING;
+        let padding = &mut padding[..pad(self.entry.file_size as usize)];
+        self.inner.read_exact(padding)?;
+        if let Some(ref diagnostics) = self.diagnostics {
+            if padding.iter().any(|&byte| byte != 0) {
+                diagnostics.push(Warning::NonzeroPadding {
+                    name: self.entry.name.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
     /// Finishes reading this entry and returns the underlying reader in a
     /// position ready to read the next entry (if any).
     pub fn finish(mut self) -> io::Result<R> {
@@ -323,29 +1081,285 @@ impl<R: Read> Reader<R> {
                 &mut io::sink(),
             )?;
         }
-        if let Some(mut padding) = pad(self.entry.file_size as usize) {
-            self.inner.read_exact(&mut padding)?;
+        self.read_data_padding()?;
+        trace_event!(
+            name = self.entry.name.as_str(),
+            skipped = remaining,
+            path = "io::copy",
+            "finished entry"
+        );
+        Ok(self.inner)
+    }
+
+    /// Write the contents of the entry out to the writer using `io::copy`, taking advantage of any
+    /// platform-specific behavior to effeciently copy data that `io::copy` can use. If any of the
+    /// file data has already been read through the `Read` interface, this will copy the
+    /// _remaining_ data in the entry.
+    pub fn to_writer<W: Write>(mut self, mut writer: W) -> io::Result<R> {
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+        let remaining = self.entry.file_size - self.bytes_read;
+        if remaining > 0 {
+            io::copy(&mut self.inner.by_ref().take(remaining as u64), &mut writer)?;
+        }
+        self.read_data_padding()?;
+        trace_event!(
+            name = self.entry.name.as_str(),
+            copied = remaining,
+            path = "io::copy",
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "copied entry data"
+        );
+        Ok(self.inner)
+    }
+
+    /// Like [`finish`](Self::finish), but discards the remaining file data through the
+    /// caller-supplied scratch `buf` instead of a buffer allocated per call.
+    pub fn finish_with_buffer(mut self, buf: &mut [u8]) -> io::Result<R> {
+        let remaining = self.entry.file_size - self.bytes_read;
+        copy_with_buffer(&mut self.inner, &mut io::sink(), remaining.into(), buf)?;
+        self.read_data_padding()?;
+        trace_event!(
+            name = self.entry.name.as_str(),
+            skipped = remaining,
+            path = "buffer",
+            "finished entry"
+        );
+        Ok(self.inner)
+    }
+
+    /// Like [`finish`](Self::finish), but discards the remaining file data in chunks of
+    /// `chunk_size` bytes.
+    pub fn finish_chunked(self, chunk_size: usize) -> io::Result<R> {
+        self.finish_with_buffer(&mut vec![0u8; chunk_size])
+    }
+
+    /// Like [`to_writer`](Self::to_writer), but copies the remaining file data in chunks of
+    /// `chunk_size` bytes instead of the 8 KiB chunks of `io::copy`.
+    pub fn to_writer_chunked<W: Write>(self, writer: W, chunk_size: usize) -> io::Result<R> {
+        self.to_writer_with_buffer(writer, &mut vec![0u8; chunk_size])
+    }
+
+    /// Like [`to_writer`](Self::to_writer), but copies the remaining file data through the
+    /// caller-supplied scratch `buf` instead of using `io::copy`.
+    pub fn to_writer_with_buffer<W: Write>(
+        mut self,
+        mut writer: W,
+        buf: &mut [u8],
+    ) -> io::Result<R> {
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+        let remaining = self.entry.file_size - self.bytes_read;
+        copy_with_buffer(&mut self.inner, &mut writer, remaining.into(), buf)?;
+        self.read_data_padding()?;
+        trace_event!(
+            name = self.entry.name.as_str(),
+            copied = remaining,
+            path = "buffer",
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "copied entry data"
+        );
+        Ok(self.inner)
+    }
+
+    /// Abandons this entry and returns the underlying reader as it is, without reading the
+    /// rest of the file data or its padding.
+    ///
+    /// The reader is left partway through the entry, so no further entries can be read from
+    /// it. Use this when done with the archive entirely, to avoid draining a large entry from
+    /// a source that cannot seek past it just to drop the stream.
+    pub fn into_inner(self) -> R {
+        trace_event!(
+            name = self.entry.name.as_str(),
+            abandoned = self.entry.file_size - self.bytes_read,
+            "abandoned entry"
+        );
+        self.inner
+    }
+
+    /// Splits the reader into a reader bounded to the remaining file data of this entry, which
+    /// can be handed to code that wants to own a `Read`, and a [`Continuation`] that recovers
+    /// the archive stream from it afterwards.
+    ///
+    /// ```
+    /// # fn main() -> std::io::Result<()> {
+    /// # let (archive, _) = cpio::write_cpio_bytes(vec![(cpio::NewcBuilder::new("a"), b"Hi")], vec![])?;
+    /// # fn consume<R: std::io::Read>(mut data: R) -> std::io::Result<R> {
+    /// #     std::io::copy(&mut data, &mut std::io::sink())?;
+    /// #     Ok(data)
+    /// # }
+    /// let reader = cpio::NewcReader::new(archive.as_slice())?;
+    /// let (data, continuation) = reader.into_limited_reader();
+    /// let data = consume(data)?;
+    /// let next = cpio::NewcReader::new(continuation.resume(data)?)?;
+    /// assert!(next.entry().is_trailer());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_limited_reader(self) -> (io::Take<R>, Continuation) {
+        let remaining = self.entry.file_size - self.bytes_read;
+        let continuation = Continuation {
+            padding: pad(self.entry.file_size as usize),
+            name: self.entry.name,
+            diagnostics: self.diagnostics,
+        };
+        (self.inner.take(remaining.into()), continuation)
+    }
+}
+
+/// A reader that can also seek, for use as a trait object: `Box<dyn ReadSeek>` can be read
+/// from and seeked, so a [`Reader`] over one supports [`Reader::skip`] and
+/// [`Reader::offset`].
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+
+/// A [`Reader`] over a boxed trait object, for sources whose concrete type varies at runtime,
+/// such as the output of compression detection or of a plugin.
+pub type DynReader<'a> = Reader<Box<dyn Read + 'a>>;
+
+impl<'a> Reader<Box<dyn Read + 'a>> {
+    /// Boxes `inner` and parses the metadata for the next entry from it, like
+    /// [`new`](Self::new).
+    ///
+    /// The resulting reader has the same type whatever the type of `inner`, so readers over
+    /// different kinds of source can be stored and passed around together.
+    pub fn boxed<T: Read + 'a>(inner: T) -> io::Result<Self> {
+        Self::new(Box::new(inner))
+    }
+}
+
+/// Walks the entries of an archive, lending out a reader for each in turn.
+///
+/// Each call to [`next`](Self::next) finishes the previous entry, reading past whatever of its
+/// data was left unread, so entries can be read in place without threading the underlying
+/// reader through [`Reader::new`] and [`Reader::finish`] by hand:
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// # let (archive, _) = cpio::write_cpio_bytes(vec![(cpio::NewcBuilder::new("a"), b"Hi")], vec![])?;
+/// use std::io::Read;
+/// use cpio::newc::EntryCursor;
+///
+/// let mut cursor = EntryCursor::new(archive.as_slice());
+/// while let Some(reader) = cursor.next()? {
+///     let mut data = String::new();
+///     reader.read_to_string(&mut data)?;
+///     println!("{}: {}", reader.entry().name(), data);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The borrowed reader is tied to the cursor by a plain method lifetime rather than a
+/// lending-iterator trait, which would need generic associated types.
+pub struct EntryCursor<R: Read> {
+    inner: Option<R>,
+    current: Option<Reader<R>>,
+    done: bool,
+}
+
+impl<R: Read> EntryCursor<R> {
+    /// Starts walking the archive at the start of `inner`.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner: Some(inner),
+            current: None,
+            done: false,
+        }
+    }
+
+    /// Finishes the current entry, and returns a reader for the next one, or `None` once the
+    /// trailer has been read.
+    ///
+    /// After an error, the cursor yields no more entries.
+    // Named like `Iterator::next`, which it can't implement because the reader it returns
+    // borrows the cursor.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> io::Result<Option<&mut Reader<R>>> {
+        // An error while finishing or reading an entry loses the underlying reader, which
+        // ends the walk.
+        if let Some(reader) = self.current.take() {
+            self.inner = Some(reader.finish()?);
+        }
+        let inner = match self.inner.take() {
+            Some(inner) if !self.done => inner,
+            inner => {
+                self.inner = inner;
+                return Ok(None);
+            }
+        };
+        let reader = Reader::new(inner)?;
+        if reader.entry().is_trailer() {
+            self.done = true;
+            self.inner = Some(reader.finish()?);
+            return Ok(None);
         }
-        Ok(self.inner)
+        self.current = Some(reader);
+        Ok(self.current.as_mut())
     }
 
-    /// Write the contents of the entry out to the writer using `io::copy`, taking advantage of any
-    /// platform-specific behavior to effeciently copy data that `io::copy` can use. If any of the
-    /// file data has already been read through the `Read` interface, this will copy the
-    /// _remaining_ data in the entry.
-    pub fn to_writer<W: Write>(mut self, mut writer: W) -> io::Result<R> {
-        let remaining = self.entry.file_size - self.bytes_read;
-        if remaining > 0 {
-            io::copy(&mut self.inner.by_ref().take(remaining as u64), &mut writer)?;
+    /// Finishes the current entry, if any, and returns the underlying reader: just past the
+    /// trailer if [`next`](Self::next) returned `None`, or else ready to read the next entry.
+    ///
+    /// Fails if an earlier error left the underlying reader partway through an entry.
+    pub fn finish(self) -> io::Result<R> {
+        if let Some(reader) = self.current {
+            return reader.finish();
+        }
+        self.inner.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "archive reader was lost to an earlier error",
+            )
+        })
+    }
+}
+
+/// What's needed to recover the archive stream from the bounded reader returned by
+/// [`Reader::into_limited_reader`].
+pub struct Continuation {
+    padding: usize,
+    name: String,
+    diagnostics: Option<Diagnostics>,
+}
+
+impl Continuation {
+    /// Skips whatever file data is left unread in `data`, and the padding after it, and
+    /// returns the underlying reader in a position ready to read the next entry (if any).
+    pub fn resume<R: Read>(self, mut data: io::Take<R>) -> io::Result<R> {
+        let remaining = data.limit();
+        if remaining > 0 && io::copy(&mut data, &mut io::sink())? != remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "archive ended before the end of the entry data",
+            ));
         }
-        if let Some(mut padding) = pad(self.entry.file_size as usize) {
-            self.inner.read_exact(&mut padding)?;
+        let mut inner = data.into_inner();
+        let mut padding = PADDING;
+        let padding = &mut padding[..self.padding];
+        inner.read_exact(padding)?;
+        if let Some(ref diagnostics) = self.diagnostics {
+            if padding.iter().any(|&byte| byte != 0) {
+                diagnostics.push(Warning::NonzeroPadding { name: self.name });
+            }
         }
-        Ok(self.inner)
+        Ok(inner)
     }
 }
 
 impl<R: Read + Seek> Reader<R> {
+    /// Seeks `inner` to `offset` and parses the metadata of the entry whose header starts
+    /// there, like [`new`](Self::new).
+    ///
+    /// `offset` is a position of a header, such as one from
+    /// [`IndexEntry::offset`](crate::index::IndexEntry::offset) or
+    /// [`Summary::offsets`](crate::Summary::offsets).
+    pub fn new_at(mut inner: R, offset: u64) -> io::Result<Reader<R>> {
+        inner.seek(SeekFrom::Start(offset))?;
+        Self::new(inner)
+    }
+
     /// Returns the offset within inner, which can be useful for efficient
     /// io::copy()/copy_file_range() of file data.
     pub fn offset(&mut self) -> io::Result<u64> {
@@ -356,17 +1370,31 @@ impl<R: Read + Seek> Reader<R> {
     /// underlying reader in a position ready to read the next entry (if any).
     pub fn skip(mut self) -> io::Result<R> {
         let mut remaining: i64 = (self.entry.file_size - self.bytes_read).into();
-        match pad(self.entry.file_size as usize) {
-            Some(p) => remaining += p.len() as i64,
-            None {} => {}
-        };
+        remaining += pad(self.entry.file_size as usize) as i64;
         if remaining > 0 {
             self.inner.seek(SeekFrom::Current(remaining))?;
         }
+        trace_event!(
+            name = self.entry.name.as_str(),
+            skipped = remaining,
+            path = "seek",
+            "finished entry"
+        );
         Ok(self.inner)
     }
 }
 
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(std::ffi::OsString::from_vec(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
 impl<R: Read> Read for Reader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let remaining = self.entry.file_size - self.bytes_read;
@@ -379,6 +1407,29 @@ impl<R: Read> Read for Reader<R> {
             Ok(0)
         }
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let remaining = (self.entry.file_size - self.bytes_read) as usize;
+        // Forward as many whole buffers as fit within the remaining file data. A buffer that
+        // straddles the end of the entry is only read into on its own, clamped by `read`.
+        let mut total = 0;
+        let fit = bufs
+            .iter()
+            .take_while(|buf| {
+                total += buf.len();
+                total <= remaining
+            })
+            .count();
+        if fit == 0 {
+            return match bufs.first_mut() {
+                Some(buf) => self.read(buf),
+                None => Ok(0),
+            };
+        }
+        let num_bytes = self.inner.read_vectored(&mut bufs[..fit])?;
+        self.bytes_read += num_bytes as u32;
+        Ok(num_bytes)
+    }
 }
 
 impl Builder {
@@ -399,6 +1450,172 @@ impl Builder {
         }
     }
 
+    /// Create metadata matching that of an entry read from an archive, for copying the entry
+    /// into another archive.
+    pub fn from_entry(entry: &Entry) -> Self {
+        Self {
+            name: entry.name.clone(),
+            ino: entry.ino,
+            mode: entry.mode,
+            uid: entry.uid,
+            gid: entry.gid,
+            nlink: entry.nlink,
+            mtime: entry.mtime,
+            dev_major: entry.dev_major,
+            dev_minor: entry.dev_minor,
+            rdev_major: entry.rdev_major,
+            rdev_minor: entry.rdev_minor,
+        }
+    }
+
+    /// Create metadata describing a file on disk, as returned by [`fs::symlink_metadata`] for
+    /// symlinks to be archived as links.
+    ///
+    /// On Unix, the inode number, owner, mode, link count, modification time, and device
+    /// numbers are all taken from `metadata`; fields wider than the format's 32 bits are
+    /// truncated, except the modification time, which is clamped as by
+    /// [`MtimePolicy::Clamp`]. Elsewhere, files have no mode, owner, or device numbers, so the
+    /// metadata is synthesized as by [`from_portable_metadata`](Self::from_portable_metadata)
+    /// with the default [`MetadataDefaults`].
+    ///
+    /// [`fs::symlink_metadata`]: std::fs::symlink_metadata
+    pub fn from_metadata(name: &str, metadata: &fs::Metadata) -> Self {
+        Self::from_metadata_with_policy(name, metadata, MtimePolicy::Clamp)
+            .expect("clamping a modification time can't fail")
+    }
+
+    /// Like [`from_metadata`](Self::from_metadata), but handles a modification time the format
+    /// can't represent according to `policy`.
+    #[cfg(unix)]
+    pub fn from_metadata_with_policy(
+        name: &str,
+        metadata: &fs::Metadata,
+        policy: MtimePolicy,
+    ) -> io::Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        let (dev_major, dev_minor) = split_dev(metadata.dev());
+        let (rdev_major, rdev_minor) = split_dev(metadata.rdev());
+        Ok(Self {
+            name: name.to_string(),
+            ino: metadata.ino() as u32,
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            nlink: metadata.nlink() as u32,
+            mtime: policy.apply(name, metadata.mtime())?,
+            dev_major,
+            dev_minor,
+            rdev_major,
+            rdev_minor,
+        })
+    }
+
+    /// Like [`from_metadata`](Self::from_metadata), but handles a modification time the format
+    /// can't represent according to `policy`.
+    #[cfg(not(unix))]
+    pub fn from_metadata_with_policy(
+        name: &str,
+        metadata: &fs::Metadata,
+        policy: MtimePolicy,
+    ) -> io::Result<Self> {
+        let builder = Self::from_portable_metadata(name, metadata, &MetadataDefaults::default());
+        match metadata.modified() {
+            Ok(time) => builder.mtime_systemtime(time, policy),
+            Err(_) => Ok(builder),
+        }
+    }
+
+    /// Create metadata describing a file on disk from only the parts of its metadata that
+    /// every platform has: its type, whether it is read-only, and its modification time.
+    ///
+    /// The permissions and owner come from `defaults`, so the result is the same on every
+    /// platform, whoever owns the file; device numbers are zero, and inode numbers are left
+    /// for the writer to assign.
+    pub fn from_portable_metadata(
+        name: &str,
+        metadata: &fs::Metadata,
+        defaults: &MetadataDefaults,
+    ) -> Self {
+        let file_type = metadata.file_type();
+        let (file_type, mut mode) = if file_type.is_dir() {
+            (ModeFileType::Directory, defaults.dir_mode)
+        } else if file_type.is_symlink() {
+            (ModeFileType::Symlink, defaults.symlink_mode)
+        } else {
+            (ModeFileType::Regular, defaults.file_mode)
+        };
+        if metadata.permissions().readonly() && file_type != ModeFileType::Symlink {
+            mode &= !0o222;
+        }
+        let mtime = defaults.mtime.unwrap_or_else(|| {
+            metadata.modified().map_or(0, |time| {
+                let seconds = system_time_seconds(time);
+                seconds.clamp(0, i64::from(u32::MAX)) as u32
+            })
+        });
+        Builder::new(name)
+            .mode(mode & 0o7777)
+            .set_mode_file_type(file_type)
+            .uid(defaults.uid)
+            .gid(defaults.gid)
+            .mtime(mtime)
+    }
+
+    /// Returns the metadata of an entry written with this builder, holding `file_size` bytes
+    /// of data with the given checksum, if it is in the "new crc" format.
+    pub(crate) fn to_entry(&self, file_size: u32, checksum: Option<u32>) -> Entry {
+        Entry {
+            entry_type: if checksum.is_some() {
+                EntryType::Crc
+            } else {
+                EntryType::Newc
+            },
+            name: self.name.clone(),
+            ino: self.ino,
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            nlink: self.nlink,
+            mtime: self.mtime,
+            file_size,
+            dev_major: self.dev_major,
+            dev_minor: self.dev_minor,
+            rdev_major: self.rdev_major,
+            rdev_minor: self.rdev_minor,
+            checksum: checksum.unwrap_or(0),
+        }
+    }
+
+    /// Returns the name of the file.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Changes the name of the file.
+    pub(crate) fn rename(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Applies `policy` to the name of the file, failing with an `InvalidInput` error if it
+    /// rejects the name.
+    pub fn name_policy(mut self, policy: &NamePolicy) -> io::Result<Self> {
+        let name = policy
+            .apply(&self.name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+            .into_owned();
+        self.name = name;
+        Ok(self)
+    }
+
+    /// Passes the owner of the file through `map`.
+    pub fn owner_map(mut self, map: &OwnerMap) -> Self {
+        self.uid = map.map_uid(self.uid);
+        self.gid = map.map_gid(self.gid);
+        self
+    }
+
     /// Set the inode number for this file. In modern times however, typically this is just a
     /// a unique index ID for the file, rather than the actual inode number.
     pub fn ino(mut self, ino: u32) -> Self {
@@ -413,6 +1630,12 @@ impl Builder {
         self
     }
 
+    /// Set the permission bits of the file's mode, keeping its file type.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.mode = self.mode & !Permissions::MASK | permissions.bits();
+        self
+    }
+
     /// Set this file's UID.
     pub fn uid(mut self, uid: u32) -> Self {
         self.uid = uid;
@@ -437,6 +1660,21 @@ impl Builder {
         self
     }
 
+    /// Set the modification time of this file from a [`SystemTime`], handling a time the
+    /// format can't represent according to `policy`.
+    ///
+    /// Fractions of a second are discarded.
+    ///
+    /// [`SystemTime`]: std::time::SystemTime
+    pub fn mtime_systemtime(
+        mut self,
+        time: std::time::SystemTime,
+        policy: MtimePolicy,
+    ) -> io::Result<Self> {
+        self.mtime = policy.apply(&self.name, system_time_seconds(time))?;
+        Ok(self)
+    }
+
     /// Set the major component of the device ID, describing the device on which this file
     /// resides.
     ///
@@ -477,6 +1715,27 @@ impl Builder {
         self
     }
 
+    /// Gives the file a type, and permissions if it has none, if its mode lacks a file type,
+    /// as archives made by naive tools often do.
+    ///
+    /// A name ending in `/`, or an entry known to have children, makes a directory with mode
+    /// `0755`; anything else makes a regular file with mode `0644`. Modes that already have a
+    /// file type are left alone.
+    pub fn infer_mode(mut self, has_children: bool) -> Self {
+        if ModeFileType::from_mode(self.mode).is_some() {
+            return self;
+        }
+        let (file_type, permissions) = if has_children || self.name.ends_with('/') {
+            (ModeFileType::Directory, 0o755)
+        } else {
+            (ModeFileType::Regular, 0o644)
+        };
+        if self.mode & 0o7777 == 0 {
+            self.mode = permissions;
+        }
+        self.set_mode_file_type(file_type)
+    }
+
     /// Set the mode file type of the entry
     pub fn set_mode_file_type(mut self, file_type: ModeFileType) -> Self {
         self.mode &= !ModeFileType::MASK;
@@ -486,7 +1745,8 @@ impl Builder {
 
     /// Write out an entry to the provided writer in SVR4 "new ascii" CPIO format.
     pub fn write<W: Write>(self, w: W, file_size: u32) -> Writer<W> {
-        let header = self.into_header(file_size, None);
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        self.fill_header(&mut header, file_size, None);
 
         Writer {
             inner: w,
@@ -499,7 +1759,8 @@ impl Builder {
 
     /// Write out an entry to the provided writer in SVR4 "new crc" CPIO format.
     pub fn write_crc<W: Write>(self, w: W, file_size: u32, file_checksum: u32) -> Writer<W> {
-        let header = self.into_header(file_size, Some(file_checksum));
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        self.fill_header(&mut header, file_size, Some(file_checksum));
 
         Writer {
             inner: w,
@@ -510,54 +1771,67 @@ impl Builder {
         }
     }
 
-    /// Build a newc header from the entry metadata.
-    fn into_header(self, file_size: u32, file_checksum: Option<u32>) -> Vec<u8> {
-        let mut header = Vec::with_capacity(HEADER_LEN);
+    /// Like [`write`](Self::write) or, when `file_checksum` is given,
+    /// [`write_crc`](Self::write_crc), but builds the header in the reusable header buffer held
+    /// by `buffers`. Finish the entry with [`Writer::finish_with_buffers`] to hand the buffer
+    /// back for the next entry.
+    pub fn write_with_buffers<W: Write>(
+        self,
+        w: W,
+        file_size: u32,
+        file_checksum: Option<u32>,
+        buffers: &mut Buffers,
+    ) -> Writer<W> {
+        let mut header = std::mem::take(&mut buffers.header);
+        self.fill_header(&mut header, file_size, file_checksum);
+
+        Writer {
+            inner: w,
+            written: 0,
+            file_size,
+            header_size: header.len(),
+            header,
+        }
+    }
+
+    /// Build a newc header from the entry metadata into `header`, replacing its contents.
+    pub(crate) fn fill_header(
+        &self,
+        header: &mut Vec<u8>,
+        file_size: u32,
+        file_checksum: Option<u32>,
+    ) {
+        header.clear();
 
-        // char    c_magic[6];
-        if file_checksum.is_some() {
-            header.extend(MAGIC_NUMBER_NEWCRC);
-        } else {
-            header.extend(MAGIC_NUMBER_NEWASCII);
-        }
-        // char    c_ino[8];
-        header.extend(format!("{:08x}", self.ino).as_bytes());
-        // char    c_mode[8];
-        header.extend(format!("{:08x}", self.mode).as_bytes());
-        // char    c_uid[8];
-        header.extend(format!("{:08x}", self.uid).as_bytes());
-        // char    c_gid[8];
-        header.extend(format!("{:08x}", self.gid).as_bytes());
-        // char    c_nlink[8];
-        header.extend(format!("{:08x}", self.nlink).as_bytes());
-        // char    c_mtime[8];
-        header.extend(format!("{:08x}", self.mtime).as_bytes());
-        // char    c_filesize[8];
-        header.extend(format!("{:08x}", file_size).as_bytes());
-        // char    c_devmajor[8];
-        header.extend(format!("{:08x}", self.dev_major).as_bytes());
-        // char    c_devminor[8];
-        header.extend(format!("{:08x}", self.dev_minor).as_bytes());
-        // char    c_rdevmajor[8];
-        header.extend(format!("{:08x}", self.rdev_major).as_bytes());
-        // char    c_rdevminor[8];
-        header.extend(format!("{:08x}", self.rdev_minor).as_bytes());
-        // char    c_namesize[8];
         let name_len = self.name.len() + 1;
-        header.extend(format!("{:08x}", name_len).as_bytes());
-        // char    c_check[8];
-        header.extend(format!("{:08x}", file_checksum.unwrap_or(0)).as_bytes());
+        let fixed = Header {
+            entry_type: if file_checksum.is_some() {
+                EntryType::Crc
+            } else {
+                EntryType::Newc
+            },
+            ino: self.ino,
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            nlink: self.nlink,
+            mtime: self.mtime,
+            file_size,
+            dev_major: self.dev_major,
+            dev_minor: self.dev_minor,
+            rdev_major: self.rdev_major,
+            rdev_minor: self.rdev_minor,
+            name_size: name_len as u32,
+            checksum: file_checksum.unwrap_or(0),
+        };
+        header.extend_from_slice(&fixed.emit());
 
         // append the name to the end of the header
         header.extend(self.name.as_bytes());
         header.push(0u8);
 
         // pad out to a multiple of 4 bytes
-        if let Some(pad) = pad(HEADER_LEN + name_len) {
-            header.extend(pad);
-        }
-
-        header
+        header.extend(&PADDING[..pad(HEADER_LEN + name_len)]);
     }
 }
 
@@ -567,6 +1841,25 @@ impl<W: Write> Writer<W> {
         Ok(self.inner)
     }
 
+    /// Like [`finish`](Self::finish), but hands the header buffer back to `buffers` for reuse
+    /// by the next [`Builder::write_with_buffers`].
+    pub fn finish_with_buffers(mut self, buffers: &mut Buffers) -> io::Result<W> {
+        self.do_finish()?;
+        buffers.header = std::mem::take(&mut self.header);
+        Ok(self.inner)
+    }
+
+    /// Returns the number of bytes of file data written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Returns the total number of bytes this entry occupies in the archive once finished: the
+    /// header, name, file data, and padding.
+    pub fn entry_len(&self) -> u64 {
+        self.header_size as u64 + u64::from(self.file_size) + pad(self.file_size as usize) as u64
+    }
+
     fn try_write_header(&mut self) -> io::Result<()> {
         if !self.header.is_empty() {
             self.inner.write_all(&self.header)?;
@@ -578,11 +1871,10 @@ impl<W: Write> Writer<W> {
     fn do_finish(&mut self) -> io::Result<()> {
         self.try_write_header()?;
 
-        if self.written == self.file_size {
-            if let Some(pad) = pad(self.header_size + self.file_size as usize) {
-                self.inner.write_all(&pad)?;
-                self.inner.flush()?;
-            }
+        if self.written == u64::from(self.file_size) {
+            // The header is already padded, so only the file data needs to be.
+            self.inner
+                .write_all(&PADDING[..pad(self.file_size as usize)])?;
         }
 
         Ok(())
@@ -591,11 +1883,11 @@ impl<W: Write> Writer<W> {
 
 impl<W: Write> Write for Writer<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if self.written + buf.len() as u32 <= self.file_size {
+        if self.written + buf.len() as u64 <= u64::from(self.file_size) {
             self.try_write_header()?;
 
             let n = self.inner.write(buf)?;
-            self.written += n as u32;
+            self.written += n as u64;
             Ok(n)
         } else {
             Err(io::Error::new(
@@ -617,6 +1909,70 @@ pub fn trailer<W: Write>(w: W) -> io::Result<W> {
     writer.finish()
 }
 
+/// Writes a trailer entry with the `crc` magic into an archive, to end one whose entries were
+/// written with [`Builder::write_crc`].
+pub fn trailer_crc<W: Write>(w: W) -> io::Result<W> {
+    let b = Builder::new(TRAILER_NAME).nlink(1);
+    let writer = b.write_crc(w, 0, 0);
+    writer.finish()
+}
+
+/// Computes the "new crc" checksum of `data`: the sum of its bytes, truncated to the least
+/// significant 32 bits.
+pub fn checksum(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0u32, |sum, &byte| sum.wrapping_add(u32::from(byte)))
+}
+
+/// Computes the "new crc" checksum of everything `reader` yields, splitting the summing across
+/// `threads` worker threads while the calling thread performs the reads.
+///
+/// This is useful for very large entries, where the checksum has to be known before the
+/// header is written and summing on a single thread dominates archive creation time.
+pub fn checksum_parallel<R: Read>(mut reader: R, threads: usize) -> io::Result<u32> {
+    let threads = threads.max(1);
+    let mut senders = Vec::with_capacity(threads);
+    let mut workers = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        // Allow each worker a little backlog so reads aren't stalled on a busy worker.
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(2);
+        senders.push(tx);
+        workers.push(thread::spawn(move || {
+            rx.iter()
+                .fold(0u32, |sum, chunk| sum.wrapping_add(checksum(&chunk)))
+        }));
+    }
+
+    let mut result = Ok(());
+    for tx in senders.iter().cycle() {
+        let mut chunk = vec![0u8; CHECKSUM_CHUNK_SIZE];
+        let n = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        chunk.truncate(n);
+        if tx.send(chunk).is_err() {
+            // The worker is gone, which means it panicked; that is reported on join below.
+            break;
+        }
+    }
+    drop(senders);
+
+    let mut sum = 0u32;
+    for worker in workers {
+        let partial = worker
+            .join()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "checksum worker thread panicked"))?;
+        sum = sum.wrapping_add(partial);
+    }
+    result.map(|_| sum)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -788,4 +2144,599 @@ mod tests {
         let reader = Reader::new(handle).unwrap();
         assert!(reader.entry().is_trailer());
     }
+
+    #[test]
+    fn test_header_read_count() {
+        /// Counts the number of `read` calls made against it.
+        struct CountingReader<'a> {
+            inner: &'a [u8],
+            reads: usize,
+        }
+
+        impl<'a> Read for CountingReader<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.reads += 1;
+                self.inner.read(buf)
+            }
+        }
+
+        let output = Builder::new("./hello_world")
+            .write(vec![], 0)
+            .finish()
+            .unwrap();
+        let mut input = CountingReader {
+            inner: &output,
+            reads: 0,
+        };
+        let reader = Reader::new(&mut input).unwrap();
+        assert_eq!(reader.entry().name(), "./hello_world");
+        assert_eq!(input.reads, 2);
+    }
+
+    #[test]
+    fn test_multi_file_with_buffer() {
+        let mut output = vec![];
+        for (idx, data) in [&b"Hello, World"[..], &b"Hello, World 2"[..]]
+            .iter()
+            .enumerate()
+        {
+            let name = format!("./hello_world{}", idx);
+            let mut writer = Builder::new(&name).write(output, data.len() as u32);
+            writer.write_all(data).unwrap();
+            output = writer.finish().unwrap();
+        }
+        let output = trailer(output).unwrap();
+
+        let mut buf = [0u8; 5];
+        let reader = Reader::new(output.as_slice()).unwrap();
+        let mut contents = vec![];
+        let handle = reader
+            .to_writer_with_buffer(&mut contents, &mut buf)
+            .unwrap();
+        assert_eq!(contents, b"Hello, World");
+
+        let mut reader = Reader::new(handle).unwrap();
+        let mut partial = [0u8; 3];
+        reader.read_exact(&mut partial).unwrap();
+        let handle = reader.finish_with_buffer(&mut buf).unwrap();
+
+        let reader = Reader::new(handle).unwrap();
+        assert!(reader.entry().is_trailer());
+    }
+
+    #[test]
+    fn test_checksum_parallel() {
+        let data: Vec<u8> = (0..3 * CHECKSUM_CHUNK_SIZE + 17)
+            .map(|idx| (idx % 251) as u8)
+            .collect();
+        let expected = checksum(&data);
+        for threads in 0..4 {
+            assert_eq!(
+                checksum_parallel(data.as_slice(), threads).unwrap(),
+                expected
+            );
+        }
+        assert_eq!(checksum_parallel(io::empty(), 2).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_read_vectored() {
+        let data: &[u8] = b"Hello, World";
+        let mut writer = Builder::new("./hello_world").write(vec![], data.len() as u32);
+        writer.write_all(data).unwrap();
+        let output = trailer(writer.finish().unwrap()).unwrap();
+
+        let mut reader = Reader::new(output.as_slice()).unwrap();
+        let (mut first, mut second, mut third) = ([0u8; 5], [0u8; 5], [0u8; 5]);
+        let mut bufs = [
+            IoSliceMut::new(&mut first),
+            IoSliceMut::new(&mut second),
+            IoSliceMut::new(&mut third),
+        ];
+        assert_eq!(reader.read_vectored(&mut bufs).unwrap(), 10);
+        assert_eq!(reader.read_vectored(&mut bufs[2..]).unwrap(), 2);
+        assert_eq!(reader.read_vectored(&mut bufs[2..]).unwrap(), 0);
+        assert_eq!(&first, b"Hello");
+        assert_eq!(&second, b", Wor");
+        assert_eq!(&third[..2], b"ld");
+
+        let reader = Reader::new(reader.finish().unwrap()).unwrap();
+        assert!(reader.entry().is_trailer());
+    }
+
+    #[test]
+    fn test_buffers() {
+        let mut buffers = Buffers::with_copy_size(4);
+        let mut output = vec![];
+        for name in ["./a", "./b"].iter() {
+            let mut writer = Builder::new(name).write_with_buffers(output, 5, None, &mut buffers);
+            writer.write_all(b"Hello").unwrap();
+            output = writer.finish_with_buffers(&mut buffers).unwrap();
+        }
+        assert!(buffers.header.capacity() >= HEADER_LEN);
+        let output = trailer(output).unwrap();
+
+        let mut handle = output.as_slice();
+        for name in ["./a", "./b"].iter() {
+            let reader = Reader::new(handle).unwrap();
+            assert_eq!(reader.entry().name(), *name);
+            let mut contents = vec![];
+            handle = reader
+                .to_writer_with_buffer(&mut contents, buffers.copy_buffer())
+                .unwrap();
+            assert_eq!(contents, b"Hello");
+        }
+        assert!(Reader::new(handle).unwrap().entry().is_trailer());
+    }
+
+    #[test]
+    fn test_write_size_accounting() {
+        let mut writer = Builder::new("./big").write(io::sink(), u32::MAX);
+        assert_eq!(writer.entry_len(), 116 + u64::from(u32::MAX) + 1);
+
+        // Pretend nearly all of the data has been written already; going past the declared
+        // size must fail rather than wrap around.
+        writer.written = u64::from(u32::MAX) - 1;
+        assert_eq!(writer.write(b"x").unwrap(), 1);
+        assert_eq!(writer.written(), u64::from(u32::MAX));
+        let err = writer.write(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_read_hex_u32() {
+        assert_eq!(read_hex_u32(b"00000000").unwrap(), 0);
+        assert_eq!(read_hex_u32(b"000081a4").unwrap(), 0o100644);
+        assert_eq!(read_hex_u32(b"DEADbeef").unwrap(), 0xdeadbeef);
+        assert_eq!(read_hex_u32(b"ffffffff").unwrap(), u32::MAX);
+        for field in [&b"0000000g"[..], b"+0000001", b"0000 001", b"\xff0000000"].iter() {
+            let err = read_hex_u32(field).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_header_parse_emit() {
+        let header = Header {
+            entry_type: EntryType::Crc,
+            ino: 1,
+            mode: 0o100644,
+            uid: 1000,
+            gid: 1000,
+            nlink: 1,
+            mtime: 0x5f5e1000,
+            file_size: 12,
+            dev_major: 8,
+            dev_minor: 1,
+            rdev_major: 0,
+            rdev_minor: 0,
+            name_size: 14,
+            checksum: 0xdeadbeef,
+        };
+        let buf = header.emit();
+        assert_eq!(&buf[..14], b"07070200000001");
+        assert_eq!(&buf[..6], MAGIC_NUMBER_NEWCRC);
+        assert_eq!(Header::parse(&buf).unwrap(), header);
+
+        let mut bad = buf;
+        bad[5] = b'7';
+        assert!(Header::parse(&bad).is_err());
+    }
+
+    #[test]
+    fn test_header_parsing() {
+        let (archive, _) =
+            crate::write_cpio_bytes(vec![(Builder::new("a").mode(0o100644), b"x")], vec![])
+                .unwrap();
+        // Uppercase the mode, and space-pad the file size.
+        let mut quirky = archive.clone();
+        quirky[14..22].copy_from_slice(b"000081A4");
+        quirky[54..62].copy_from_slice(b"   1    ");
+
+        let err = Reader::with_parsing(quirky.as_slice(), HeaderParsing::Strict)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(Reader::new(quirky.as_slice()).is_err());
+        let reader = Reader::with_parsing(quirky.as_slice(), HeaderParsing::Tolerant).unwrap();
+        assert_eq!(reader.entry().mode(), 0o100644);
+        assert_eq!(reader.entry().file_size(), 1);
+
+        quirky[54..62].copy_from_slice(b"00000001");
+        let reader = Reader::new(quirky.as_slice()).unwrap();
+        assert_eq!(reader.entry().mode(), 0o100644);
+        assert!(Reader::with_parsing(archive.as_slice(), HeaderParsing::Strict).is_ok());
+
+        // Spaces within a field are never accepted, and blank fields read as zero.
+        let mut buf = [0u8; HEADER_LEN];
+        buf.copy_from_slice(&archive[..HEADER_LEN]);
+        buf[6..14].copy_from_slice(b"  1 2   ");
+        assert!(Header::parse_with(&buf, HeaderParsing::Tolerant).is_err());
+        buf[6..14].copy_from_slice(b"        ");
+        let header = Header::parse_with(&buf, HeaderParsing::Tolerant).unwrap();
+        assert_eq!(header.ino, 0);
+    }
+
+    #[test]
+    fn test_linux_dev() {
+        assert_eq!(linux_dev_split(0x0103), (1, 3));
+        assert_eq!(linux_dev_join(1, 3), 0x0103);
+        // Minor numbers above 255 move above the major number.
+        assert_eq!(linux_dev_split(0x0010_3500), (0x35, 0x100));
+        for &(major, minor) in &[(0, 0), (259, 300_000), (0xfff, 0xff), (u32::MAX, u32::MAX)] {
+            assert_eq!(
+                linux_dev_split(linux_dev_join(major, minor)),
+                (major, minor)
+            );
+            #[cfg(target_os = "linux")]
+            assert_eq!(
+                linux_dev_join(major, minor),
+                libc::makedev(major, minor) as u64
+            );
+        }
+    }
+
+    #[test]
+    fn test_mtime_policy() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let late = 1i64 << 33;
+        assert_eq!(MtimePolicy::Clamp.apply("a", late).unwrap(), u32::MAX);
+        assert_eq!(MtimePolicy::Clamp.apply("a", -5).unwrap(), 0);
+        assert_eq!(MtimePolicy::Zero.apply("a", late).unwrap(), 0);
+        assert_eq!(MtimePolicy::Error.apply("a", 1234).unwrap(), 1234);
+        let err = MtimePolicy::Error.apply("a", -1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "a: modification time -1 is outside the range of the format"
+        );
+
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        let builder = Builder::new("a")
+            .mtime_systemtime(time, MtimePolicy::Error)
+            .unwrap();
+        assert_eq!(builder.to_entry(0, None).mtime(), 1);
+        let time = UNIX_EPOCH - Duration::from_millis(500);
+        assert!(Builder::new("a")
+            .mtime_systemtime(time, MtimePolicy::Error)
+            .is_err());
+        let time = UNIX_EPOCH + Duration::from_secs(1 << 33);
+        let builder = Builder::new("a")
+            .mtime_systemtime(time, MtimePolicy::Zero)
+            .unwrap();
+        assert_eq!(builder.to_entry(0, None).mtime(), 0);
+
+        let entry = Builder::new("a").mtime(u32::MAX).to_entry(0, None);
+        assert_eq!(
+            entry.mtime_systemtime(),
+            UNIX_EPOCH + Duration::from_secs(u32::MAX.into())
+        );
+        let time = entry.mtime_systemtime();
+        let builder = Builder::new("a")
+            .mtime_systemtime(time, MtimePolicy::Error)
+            .unwrap();
+        assert_eq!(builder.to_entry(0, None).mtime(), u32::MAX);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let header = Header {
+            entry_type: EntryType::Newc,
+            ino: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            nlink: 0,
+            mtime: 0,
+            file_size: 1,
+            dev_major: 0,
+            dev_minor: 0,
+            rdev_major: 0,
+            rdev_minor: 0,
+            name_size: 3,
+            checksum: 5,
+        };
+        let mut archive = header.emit().to_vec();
+        archive.extend_from_slice(b"ab\0\x01\0\0x\0\0\x02");
+        let archive = trailer(archive).unwrap();
+
+        let diagnostics = Diagnostics::new();
+        let reader = Reader::with_diagnostics(archive.as_slice(), &diagnostics).unwrap();
+        let rest = reader.finish().unwrap();
+        let reader = Reader::with_diagnostics(rest, &diagnostics).unwrap();
+        assert!(reader.entry().is_trailer());
+        reader.finish().unwrap();
+
+        let name = || "ab".to_string();
+        assert_eq!(
+            diagnostics.take(),
+            vec![
+                Warning::NonzeroPadding { name: name() },
+                Warning::ZeroMode { name: name() },
+                Warning::UnexpectedChecksum {
+                    name: name(),
+                    checksum: 5
+                },
+                Warning::ZeroNlink { name: name() },
+                Warning::NonzeroPadding { name: name() },
+            ]
+        );
+        assert!(diagnostics.is_empty());
+
+        let (archive, _) =
+            crate::write_cpio_bytes(vec![(Builder::new("ab").mode(0o644), &b"x"[..])], vec![])
+                .unwrap();
+        let reader = Reader::with_diagnostics(archive.as_slice(), &diagnostics).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(
+            diagnostics.take(),
+            vec![Warning::MissingFileType {
+                name: name(),
+                mode: 0o644
+            }]
+        );
+
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![(Builder::new("ab").mode(0o100644).mtime(u32::MAX), &b"x"[..])],
+            vec![],
+        )
+        .unwrap();
+        let reader = Reader::with_diagnostics(archive.as_slice(), &diagnostics).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(
+            diagnostics.take(),
+            vec![Warning::FutureMtime {
+                name: name(),
+                mtime: u32::MAX
+            }]
+        );
+
+        // Well-formed archives produce no warnings.
+        let (archive, _) =
+            crate::write_cpio_bytes(vec![(Builder::new("ab").mode(0o100644), &b"x"[..])], vec![])
+                .unwrap();
+        let reader = Reader::with_diagnostics(archive.as_slice(), &diagnostics).unwrap();
+        reader.finish().unwrap();
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_into_limited_reader() {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (Builder::new("./a"), &b"Hello"[..]),
+                (Builder::new("./b"), b"World"),
+            ],
+            vec![],
+        )
+        .unwrap();
+
+        let mut reader = Reader::new(archive.as_slice()).unwrap();
+        let mut first = [0u8; 1];
+        reader.read_exact(&mut first).unwrap();
+        let (mut data, continuation) = reader.into_limited_reader();
+        let mut rest = vec![];
+        data.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"ello");
+        let reader = Reader::new(continuation.resume(data).unwrap()).unwrap();
+        assert_eq!(reader.entry().name(), "./b");
+
+        // Data left unread is skipped.
+        let (data, continuation) = reader.into_limited_reader();
+        let reader = Reader::new(continuation.resume(data).unwrap()).unwrap();
+        assert!(reader.entry().is_trailer());
+    }
+
+    #[test]
+    fn test_file_named_trailer() {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (
+                    Builder::new(TRAILER_NAME).mode(0o100644),
+                    &b"not the end"[..],
+                ),
+                (Builder::new(TRAILER_NAME).mode(0o100644), b""),
+                (Builder::new("after"), b""),
+            ],
+            vec![],
+        )
+        .unwrap();
+        let mut names = vec![];
+        let mut rest = archive.as_slice();
+        loop {
+            let reader = Reader::new(rest).unwrap();
+            if reader.entry().is_trailer() {
+                break;
+            }
+            names.push(reader.entry().name().to_string());
+            rest = reader.finish().unwrap();
+        }
+        assert_eq!(names, [TRAILER_NAME, TRAILER_NAME, "after"]);
+
+        let policy = NamePolicy::permissive().trailer(crate::name::Action::Reject);
+        let err = Builder::new(TRAILER_NAME)
+            .name_policy(&policy)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let (archive, _) =
+            crate::write_cpio_bytes(vec![(Builder::new("./a"), &b"Hello"[..])], vec![]).unwrap();
+        let mut reader = Reader::new(archive.as_slice()).unwrap();
+        reader.read_exact(&mut [0u8; 2]).unwrap();
+        let rest = reader.into_inner();
+        assert!(rest.starts_with(b"llo"));
+    }
+
+    #[test]
+    fn test_entry_cursor() {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (Builder::new("./a"), &b"Hello"[..]),
+                (Builder::new("./b"), b"World"),
+                (Builder::new("./c"), b"!"),
+            ],
+            vec![],
+        )
+        .unwrap();
+        let mut extra = archive.clone();
+        extra.extend_from_slice(b"after");
+
+        let mut cursor = EntryCursor::new(extra.as_slice());
+        let mut seen = vec![];
+        while let Some(reader) = cursor.next().unwrap() {
+            // Only read part of some entries; the cursor skips the rest.
+            let mut first = [0u8; 1];
+            reader.read_exact(&mut first).unwrap();
+            seen.push((reader.entry().name().to_string(), first[0]));
+        }
+        assert!(cursor.next().unwrap().is_none());
+        assert_eq!(
+            seen,
+            vec![
+                ("./a".to_string(), b'H'),
+                ("./b".to_string(), b'W'),
+                ("./c".to_string(), b'!'),
+            ]
+        );
+        assert_eq!(cursor.finish().unwrap(), b"after");
+
+        let mut cursor = EntryCursor::new(&archive[..archive.len() - 130]);
+        assert!(cursor.next().unwrap().is_some());
+        assert!(cursor.next().unwrap().is_some());
+        assert!(cursor.next().is_err());
+        assert!(cursor.next().unwrap().is_none());
+        assert!(cursor.finish().is_err());
+    }
+
+    #[test]
+    fn test_dyn_readers() {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (Builder::new("./a"), &b"Hello"[..]),
+                (Builder::new("./b"), b"World"),
+            ],
+            vec![],
+        )
+        .unwrap();
+
+        let sources: Vec<Box<dyn Read>> = vec![
+            Box::new(archive.as_slice()),
+            Box::new(Cursor::new(archive.clone())),
+            Box::new(crate::compress::Decoder::detect(archive.as_slice()).unwrap()),
+        ];
+        for source in sources {
+            let reader: DynReader = Reader::new(source).unwrap();
+            let reader = Reader::boxed(reader.finish().unwrap()).unwrap();
+            assert_eq!(reader.entry().name(), "./b");
+            let mut cursor = EntryCursor::new(reader.finish().unwrap());
+            assert!(cursor.next().unwrap().is_none());
+        }
+
+        let source: Box<dyn ReadSeek> = Box::new(Cursor::new(archive));
+        let mut reader = Reader::new(source).unwrap();
+        assert_eq!(reader.offset().unwrap(), 116);
+        let reader = Reader::new(reader.skip().unwrap()).unwrap();
+        assert_eq!(reader.entry().name(), "./b");
+    }
+
+    #[test]
+    fn test_read_symlink_target() {
+        let symlink = |target: &[u8]| {
+            Builder::new("./link")
+                .mode(0o120777)
+                .write(vec![], target.len() as u32)
+        };
+        let read = |target: &[u8]| {
+            let mut fp = symlink(target);
+            fp.write_all(target).unwrap();
+            let archive = fp.finish().unwrap();
+            Reader::new(archive.as_slice())
+                .unwrap()
+                .read_symlink_target()
+        };
+        assert_eq!(
+            read(b"../sbin/init").unwrap(),
+            PathBuf::from("../sbin/init")
+        );
+        let err = read(b"").unwrap_err();
+        assert_eq!(err.to_string(), "./link: symlink has no target");
+        let err = read(b"a\0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(read(&[b'a'; 4095]).is_ok());
+        let err = read(&[b'a'; 4096]).unwrap_err();
+        assert_eq!(err.to_string(), "./link: symlink target is too long");
+
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![(Builder::new("./file").mode(0o100644), &b"data"[..])],
+            vec![],
+        )
+        .unwrap();
+        let err = Reader::new(archive.as_slice())
+            .unwrap()
+            .read_symlink_target()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_permissions() {
+        let permissions = Permissions::USER_RWX | Permissions::GROUP_READ | Permissions::GROUP_EXEC;
+        assert_eq!(permissions.bits(), 0o750);
+        assert_eq!(format!("{:?}", permissions), "Permissions(0o750)");
+        assert_eq!(permissions.to_string(), "rwxr-x---");
+        assert!(permissions.contains(Permissions::USER_EXEC));
+        assert!(!permissions.contains(Permissions::OTHER_READ));
+        assert_eq!((!permissions).bits(), 0o7027);
+        assert_eq!((permissions & Permissions::GROUP_RWX).bits(), 0o050);
+
+        let mut special = Permissions::from_mode(0o104755);
+        assert_eq!(special.to_string(), "rwsr-xr-x");
+        special.remove(Permissions::USER_EXEC | Permissions::OTHER_EXEC);
+        special.insert(Permissions::STICKY);
+        assert_eq!(special.to_string(), "rwSr-xr-T");
+        assert_eq!(Permissions::from_bits(0o1777), Some(Permissions(0o1777)));
+        assert_eq!(Permissions::from_bits(0o10777), None);
+
+        let builder = Builder::new("./a")
+            .set_mode_file_type(ModeFileType::Directory)
+            .permissions(Permissions::from_mode(0o755));
+        assert_eq!(builder.to_entry(0, None).mode(), 0o040755);
+        let entry = builder.permissions(Permissions::USER_RWX).to_entry(0, None);
+        assert_eq!(entry.mode(), 0o040700);
+        assert_eq!(entry.permissions(), Permissions::USER_RWX);
+
+        #[cfg(unix)]
+        {
+            let permissions: fs::Permissions = Permissions::from_mode(0o640).into();
+            assert_eq!(Permissions::from(permissions).bits(), 0o640);
+        }
+    }
+
+    #[test]
+    fn test_new_at() {
+        let mut archive = crate::ArchiveWriter::new(vec![]).track_offsets(true);
+        archive.append_bytes(Builder::new("./a"), b"first").unwrap();
+        archive
+            .append_bytes(Builder::new("./b"), b"second")
+            .unwrap();
+        let (archive, summary) = archive.finish().unwrap();
+        let offsets = summary.offsets().unwrap();
+
+        let mut cursor = Cursor::new(archive);
+        let mut data = String::new();
+        let mut reader = Reader::new_at(&mut cursor, offsets[1]).unwrap();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "second");
+        // Seeking is from the start, wherever the source was left.
+        let reader = Reader::new_at(&mut cursor, offsets[0]).unwrap();
+        assert_eq!(reader.entry().name(), "./a");
+
+        let err = Reader::new_at(&mut cursor, offsets[0] + 1).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
 }
@@ -0,0 +1,145 @@
+//! Rewriting the ownership of entries.
+//!
+//! Archives built from a tree owned by an unprivileged user usually need to claim their
+//! files are owned by someone else, most often root, as `cpio -R` and `tar --owner` allow.
+//! Trees from containers with user namespaces are owned by ids shifted into a subordinate
+//! range, and need shifting back the way the container runtime maps them. An [`OwnerMap`]
+//! decides the owner to give each entry, and is applied when writing by
+//! [`ArchiveWriter::owner_map`](crate::ArchiveWriter::owner_map) and when extracting by
+//! [`Extractor::owner_map`](crate::extract::Extractor::owner_map).
+
+/// A range of `count` ids starting at `from`, mapped to the range of ids starting at `to`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct IdRange {
+    from: u32,
+    to: u32,
+    count: u32,
+}
+
+impl IdRange {
+    fn map(&self, id: u32) -> Option<u32> {
+        let offset = id.checked_sub(self.from)?;
+        if offset < self.count {
+            self.to.checked_add(offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// Maps `id` through the first of `ranges` containing it, if any.
+fn map_ranges(ranges: &[IdRange], id: u32) -> u32 {
+    ranges.iter().find_map(|range| range.map(id)).unwrap_or(id)
+}
+
+/// Decides the user and group ids to give each entry, in place of the ones it has.
+///
+/// An id can be forced, giving every entry the same one, or shifted through ranges like the
+/// `uid_map` and `gid_map` of a user namespace. A forced id takes precedence over the ranges,
+/// and ids outside every range are left as they are.
+///
+/// The default map leaves ids unchanged.
+///
+/// ```
+/// use cpio::owner::OwnerMap;
+///
+/// // Shift a container's ids back from the subordinate range they were stored under.
+/// let map = OwnerMap::new()
+///     .uid_range(100_000, 0, 65_536)
+///     .gid_range(100_000, 0, 65_536);
+/// assert_eq!(map.map_uid(101_000), 1000);
+/// assert_eq!(map.map_uid(7), 7);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OwnerMap {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    uid_ranges: Vec<IdRange>,
+    gid_ranges: Vec<IdRange>,
+}
+
+impl OwnerMap {
+    /// Creates a map that leaves ids unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a map that gives every entry to root, in the root group.
+    pub fn root() -> Self {
+        Self::new().uid(Some(0)).gid(Some(0))
+    }
+
+    /// Set the user id to give every entry, or `None` to leave user ids unchanged.
+    pub fn uid(mut self, uid: Option<u32>) -> Self {
+        self.uid = uid;
+        self
+    }
+
+    /// Set the group id to give every entry, or `None` to leave group ids unchanged.
+    pub fn gid(mut self, gid: Option<u32>) -> Self {
+        self.gid = gid;
+        self
+    }
+
+    /// Map the `count` user ids starting at `from` to those starting at `to`. Ranges are
+    /// tried in the order they were added, and ids that would map past `u32::MAX` are left
+    /// as they are.
+    pub fn uid_range(mut self, from: u32, to: u32, count: u32) -> Self {
+        self.uid_ranges.push(IdRange { from, to, count });
+        self
+    }
+
+    /// Map the `count` group ids starting at `from` to those starting at `to`. Ranges are
+    /// tried in the order they were added, and ids that would map past `u32::MAX` are left
+    /// as they are.
+    pub fn gid_range(mut self, from: u32, to: u32, count: u32) -> Self {
+        self.gid_ranges.push(IdRange { from, to, count });
+        self
+    }
+
+    /// Returns the user id to give an entry owned by `uid`.
+    pub fn map_uid(&self, uid: u32) -> u32 {
+        self.uid
+            .unwrap_or_else(|| map_ranges(&self.uid_ranges, uid))
+    }
+
+    /// Returns the group id to give an entry owned by `gid`.
+    pub fn map_gid(&self, gid: u32) -> u32 {
+        self.gid
+            .unwrap_or_else(|| map_ranges(&self.gid_ranges, gid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owner_map() {
+        let map = OwnerMap::default();
+        assert_eq!((map.map_uid(1000), map.map_gid(100)), (1000, 100));
+        let map = OwnerMap::root();
+        assert_eq!((map.map_uid(1000), map.map_gid(100)), (0, 0));
+        let map = OwnerMap::new().gid(Some(5));
+        assert_eq!((map.map_uid(1000), map.map_gid(100)), (1000, 5));
+    }
+
+    #[test]
+    fn test_ranges() {
+        let map = OwnerMap::new()
+            .uid_range(0, 100_000, 65_536)
+            .uid_range(65_536, 0, 1)
+            .gid_range(u32::MAX - 1, u32::MAX - 1, 10)
+            .gid_range(10, u32::MAX, 2);
+        assert_eq!(map.map_uid(0), 100_000);
+        assert_eq!(map.map_uid(65_535), 165_535);
+        assert_eq!(map.map_uid(65_536), 0);
+        assert_eq!(map.map_uid(65_537), 65_537);
+        assert_eq!(map.map_gid(u32::MAX), u32::MAX);
+        assert_eq!(map.map_gid(10), u32::MAX);
+        assert_eq!(map.map_gid(11), 11);
+
+        let map = map.uid(Some(0));
+        assert_eq!(map.map_uid(5), 0);
+    }
+}
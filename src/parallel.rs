@@ -0,0 +1,265 @@
+//! Parallel archive creation on the rayon thread pool.
+//!
+//! This module is only available with the `rayon` feature.
+
+#[cfg(any(unix, windows))]
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use rayon::prelude::*;
+
+use crate::newc::{self, Builder};
+#[cfg(any(unix, windows))]
+use crate::writer::copy_entry;
+use crate::writer::{entry_len, ArchiveWriter, Summary};
+
+/// Options controlling [`write_cpio_parallel`].
+#[derive(Clone, Debug)]
+pub struct ParallelOptions {
+    batch_size: usize,
+    crc: bool,
+}
+
+impl Default for ParallelOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 64,
+            crc: false,
+        }
+    }
+}
+
+impl ParallelOptions {
+    /// Creates the default options: batches of 64 inputs, written in "new ascii" format.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the number of inputs loaded concurrently. Each batch is held in memory in full
+    /// until it has been written, so this bounds memory use.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Write entries in "new crc" format, computing each checksum on the thread pool.
+    pub fn crc(mut self, crc: bool) -> Self {
+        self.crc = crc;
+        self
+    }
+}
+
+/// One input that has been read into memory, ready to be written.
+struct Loaded {
+    builder: Builder,
+    data: Vec<u8>,
+    checksum: Option<u32>,
+}
+
+fn load<R: Read>(idx: usize, builder: Builder, mut input: R, crc: bool) -> io::Result<Loaded> {
+    let mut data = vec![];
+    input.read_to_end(&mut data)?;
+    let checksum = if crc {
+        Some(newc::checksum(&data))
+    } else {
+        None
+    };
+    Ok(Loaded {
+        builder: builder.ino(idx as u32),
+        data,
+        checksum,
+    })
+}
+
+/// Creates a new CPIO archive like [`write_cpio`](crate::write_cpio), but reads (and
+/// optionally checksums) the inputs across the rayon thread pool.
+///
+/// Inputs are processed in batches; within a batch they are loaded concurrently, and then
+/// written out in input order, so the output is identical to a sequential run. As with
+/// `write_cpio`, each entry's inode number is its index in `inputs`.
+pub fn write_cpio_parallel<I, R, W>(
+    inputs: I,
+    output: W,
+    options: &ParallelOptions,
+) -> io::Result<(W, Summary)>
+where
+    I: IntoIterator<Item = (Builder, R)>,
+    R: Read + Send,
+    W: Write,
+{
+    let mut archive = ArchiveWriter::new(output);
+    let mut inputs = inputs.into_iter().enumerate();
+    loop {
+        let batch: Vec<_> = inputs.by_ref().take(options.batch_size).collect();
+        if batch.is_empty() {
+            break;
+        }
+
+        let loaded: Vec<io::Result<Loaded>> = batch
+            .into_par_iter()
+            .map(|(idx, (builder, input))| load(idx, builder, input, options.crc))
+            .collect();
+
+        for entry in loaded {
+            let entry = entry?;
+            let len = entry_len(entry.data.len() as u64)?;
+            match entry.checksum {
+                Some(checksum) => {
+                    archive.append_crc(entry.builder, len, checksum, entry.data.as_slice())?
+                }
+                None => archive.append(entry.builder, len, entry.data.as_slice())?,
+            }
+        }
+    }
+    archive.finish()
+}
+
+/// Writes sequentially into a file at a given offset with positioned writes, so the file can
+/// be shared between threads.
+#[cfg(any(unix, windows))]
+struct WriteAt<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+#[cfg(any(unix, windows))]
+impl<'a> Write for WriteAt<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::write_at(self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_write(self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Creates a new CPIO archive in `output` from inputs whose lengths are known up front, writing
+/// the entries concurrently across the rayon thread pool.
+///
+/// Because every length is known, the offset of each entry can be computed before any data is
+/// copied. `output` is sized to the whole archive, and then each entry is written into its own
+/// region with positioned writes (`pwrite` on Unix), so no input is held in memory and reads
+/// from fast sources are not serialized behind a single writer. The trailer is written last.
+/// As with [`write_cpio_sized`](crate::write_cpio_sized), each input must yield at least `len`
+/// bytes and each entry's inode number is its index in `inputs`; the output is identical.
+///
+/// Entry offsets are always recorded in the returned summary.
+#[cfg(any(unix, windows))]
+pub fn write_cpio_positioned<I, R>(inputs: I, output: &File) -> io::Result<Summary>
+where
+    I: IntoIterator<Item = (Builder, u64, R)>,
+    R: Read + Send,
+{
+    let mut entries = vec![];
+    let mut offsets = vec![];
+    let mut summary = Summary::default();
+    for (idx, (builder, len, input)) in inputs.into_iter().enumerate() {
+        let builder = builder.ino(idx as u32);
+        let len = entry_len(len)?;
+        let entry_size = builder.clone().write(io::sink(), len).entry_len();
+        offsets.push(summary.archive_bytes);
+        summary.entries += 1;
+        summary.data_bytes += u64::from(len);
+        summary.archive_bytes += entry_size;
+        entries.push((builder, len, input));
+    }
+    let trailer = newc::trailer(vec![])?;
+    let trailer_offset = summary.archive_bytes;
+    summary.archive_bytes += trailer.len() as u64;
+    output.set_len(summary.archive_bytes)?;
+
+    entries
+        .into_par_iter()
+        .zip(offsets.par_iter())
+        .try_for_each(|((builder, len, input), &pos)| {
+            let mut out = io::BufWriter::new(WriteAt { file: output, pos });
+            let fp = builder.write(&mut out, len);
+            copy_entry(fp, len, input, &mut newc::Buffers::with_copy_size(0))?;
+            out.flush()
+        })?;
+
+    WriteAt {
+        file: output,
+        pos: trailer_offset,
+    }
+    .write_all(&trailer)?;
+    summary.offsets = Some(offsets);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{write_cpio, NewcReader};
+    use std::io::Cursor;
+
+    fn inputs() -> Vec<(Builder, Cursor<Vec<u8>>)> {
+        (0..10)
+            .map(|idx| {
+                (
+                    Builder::new(&format!("./file{}", idx)).mode(0o100644),
+                    Cursor::new(format!("contents of file {}", idx).into_bytes()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_sequential() {
+        let sequential = write_cpio(inputs(), vec![]).unwrap();
+        let options = ParallelOptions::new().batch_size(3);
+        let parallel = write_cpio_parallel(inputs(), vec![], &options).unwrap();
+        assert_eq!(sequential, parallel);
+    }
+
+    #[test]
+    #[cfg(any(unix, windows))]
+    fn test_positioned_matches_sequential() {
+        let sized = || {
+            inputs().into_iter().map(|(builder, data)| {
+                let len = data.get_ref().len() as u64;
+                (builder, len, data)
+            })
+        };
+        let (sequential, _) = crate::write_cpio_sized(sized(), vec![]).unwrap();
+
+        let mut file = tempfile();
+        let summary = write_cpio_positioned(sized(), &file).unwrap();
+        let mut positioned = vec![];
+        io::Seek::rewind(&mut file).unwrap();
+        file.read_to_end(&mut positioned).unwrap();
+        assert_eq!(positioned, sequential);
+        assert_eq!(summary.archive_bytes(), sequential.len() as u64);
+        assert_eq!(summary.offsets().unwrap().len(), 10);
+    }
+
+    #[cfg(any(unix, windows))]
+    fn tempfile() -> File {
+        let path = std::env::temp_dir().join(format!("cpio-positioned-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_crc() {
+        let options = ParallelOptions::new().crc(true);
+        let (output, _) = write_cpio_parallel(inputs(), vec![], &options).unwrap();
+        let reader = NewcReader::new(output.as_slice()).unwrap();
+        assert_eq!(
+            reader.entry().checksum(),
+            Some(newc::checksum(b"contents of file 0"))
+        );
+    }
+}
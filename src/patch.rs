@@ -0,0 +1,151 @@
+//! Changing the metadata of entries in place.
+//!
+//! Every header field is a fixed-width hex number, so owners, modes, and times can be changed
+//! by overwriting their fields where they lie, without copying the rest of the archive. A
+//! [`Patch`] does that to one entry of a seekable archive, found for example through an
+//! [`Index`](crate::index::Index):
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! use std::fs::OpenOptions;
+//! use cpio::index::Index;
+//! use cpio::patch::Patch;
+//!
+//! let mut archive = OpenOptions::new().read(true).write(true).open("rootfs.cpio")?;
+//! let index = Index::build(&mut archive)?;
+//! let patch = Patch::new().uid(0).gid(0);
+//! for entry in index.find("./usr/bin/*") {
+//!     patch.apply(&mut archive, entry.offset())?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::newc::{Header, HeaderParsing, HEADER_LEN};
+
+/// Offsets of the patchable fields within a header, each eight hex digits wide.
+const MODE_FIELD: usize = 14;
+const UID_FIELD: usize = 22;
+const GID_FIELD: usize = 30;
+const MTIME_FIELD: usize = 46;
+
+/// New values for some of the header fields of an entry, to be written over the old ones in
+/// place. Fields that aren't set are left as they are, byte for byte.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Patch {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<u32>,
+}
+
+impl Patch {
+    /// Returns a patch that changes nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the mode, file type included. Changing the file type of an entry leaves its data
+    /// as it is, so only change the permission bits unless the data suits the new type.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Set the uid.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Set the gid.
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Set the modification time, in seconds since the epoch.
+    pub fn mtime(mut self, mtime: u32) -> Self {
+        self.mtime = Some(mtime);
+        self
+    }
+
+    /// Overwrites the fields set in the patch in the header of the entry at `offset` in
+    /// `archive`, such as the offset recorded for it in an index.
+    ///
+    /// The header there is read and checked first; if `offset` is not the start of an entry,
+    /// an `InvalidData` error is returned and nothing is written. Checksums cover only entry
+    /// data, so they stay valid. Any [`Index`](crate::index::Index) of the archive still holds
+    /// the old values.
+    pub fn apply<F: Read + Write + Seek>(&self, archive: &mut F, offset: u64) -> io::Result<()> {
+        archive.seek(SeekFrom::Start(offset))?;
+        let mut header = [0; HEADER_LEN];
+        archive.read_exact(&mut header)?;
+        Header::parse_with(&header, HeaderParsing::Tolerant)?;
+
+        let fields = [
+            (MODE_FIELD, self.mode),
+            (UID_FIELD, self.uid),
+            (GID_FIELD, self.gid),
+            (MTIME_FIELD, self.mtime),
+        ];
+        for &(field, value) in &fields {
+            if let Some(value) = value {
+                archive.seek(SeekFrom::Start(offset + field as u64))?;
+                write!(archive, "{:08x}", value)?;
+            }
+        }
+        archive.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::Index;
+    use crate::{NewcBuilder, NewcReader};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_patch() {
+        let builder = |name| NewcBuilder::new(name).mode(0o100644).uid(1000).gid(1000);
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![(builder("a"), &b"first"[..]), (builder("b"), b"second")],
+            vec![],
+        )
+        .unwrap();
+        let original = archive.clone();
+        let mut archive = Cursor::new(archive);
+        let index = Index::build(&mut archive).unwrap();
+        let offset = index.get("b").unwrap().offset();
+        Patch::new()
+            .uid(0)
+            .gid(0)
+            .mode(0o100600)
+            .mtime(1_700_000_000)
+            .apply(&mut archive, offset)
+            .unwrap();
+
+        let archive = archive.into_inner();
+        assert_eq!(archive.len(), original.len());
+        assert_eq!(archive[..offset as usize], original[..offset as usize]);
+        let mut reader = NewcReader::new_at(Cursor::new(&archive), offset).unwrap();
+        let entry = reader.entry();
+        assert_eq!((entry.uid(), entry.gid()), (0, 0));
+        assert_eq!(entry.mode(), 0o100600);
+        assert_eq!(entry.mtime(), 1_700_000_000);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"second");
+
+        // An offset that isn't the start of an entry is refused.
+        let mut archive = Cursor::new(archive);
+        let err = Patch::new()
+            .uid(0)
+            .apply(&mut archive, offset + 1)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
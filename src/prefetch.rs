@@ -0,0 +1,170 @@
+//! Read-ahead for high-latency sources.
+//!
+//! [`PrefetchReader`] reads its source on a background thread, staying up to a configurable
+//! window ahead of the consumer. Wrapping a network-backed source (HTTP, S3, NFS, ...) in one
+//! overlaps the round trips to the source with the parsing and processing of entries.
+
+use std::io::{self, BufRead, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+
+/// Size of each read made against the source.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Default read-ahead window, in bytes.
+const DEFAULT_WINDOW: usize = 1024 * 1024;
+
+/// A reader that reads ahead of its consumer on a background thread.
+///
+/// The source is read in 64 KiB chunks; up to the window's worth of chunks are buffered before
+/// the background thread waits for the consumer to catch up. Dropping the reader stops the
+/// background thread after its current read. If the background thread stops before the end of
+/// the source, such as by the source panicking, reading fails rather than ending early.
+pub struct PrefetchReader {
+    /// Chunks of the source, then `None` once its end has been reached.
+    chunks: Receiver<io::Result<Option<Vec<u8>>>>,
+    thread: Option<JoinHandle<()>>,
+    current: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl PrefetchReader {
+    /// Starts reading ahead of `inner` by the default window of 1 MiB.
+    pub fn new<R: Read + Send + 'static>(inner: R) -> Self {
+        Self::with_window(DEFAULT_WINDOW, inner)
+    }
+
+    /// Starts reading ahead of `inner` by up to `window` bytes, rounded up to a whole chunk.
+    pub fn with_window<R: Read + Send + 'static>(window: usize, mut inner: R) -> Self {
+        let depth = ((window + CHUNK_SIZE - 1) / CHUNK_SIZE).max(1);
+        let (tx, chunks) = mpsc::sync_channel(depth);
+        let thread = thread::spawn(move || loop {
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            let chunk = match inner.read(&mut chunk) {
+                Ok(0) => Ok(None),
+                Ok(n) => {
+                    chunk.truncate(n);
+                    Ok(Some(chunk))
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let last = !matches!(chunk, Ok(Some(_)));
+            if tx.send(chunk).is_err() || last {
+                break;
+            }
+        });
+        Self {
+            chunks,
+            thread: Some(thread),
+            current: vec![],
+            pos: 0,
+            done: false,
+        }
+    }
+}
+
+impl Read for PrefetchReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for PrefetchReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.current.len() && !self.done {
+            match self.chunks.recv() {
+                Ok(Ok(Some(chunk))) => {
+                    self.current = chunk;
+                    self.pos = 0;
+                }
+                Ok(Ok(None)) => self.done = true,
+                Ok(Err(err)) => return Err(err),
+                // The background thread stopped without reaching the end of the source.
+                Err(_) => {
+                    let panicked = self
+                        .thread
+                        .take()
+                        .map_or(false, |thread| thread.join().is_err());
+                    let message = if panicked {
+                        "prefetch thread panicked"
+                    } else {
+                        "prefetch thread stopped before the end of the source"
+                    };
+                    return Err(io::Error::new(io::ErrorKind::Other, message));
+                }
+            }
+        }
+        Ok(&self.current[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.current.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcReader;
+
+    #[test]
+    fn test_prefetch_archive() {
+        let data = vec![7u8; 3 * CHUNK_SIZE + 5];
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (crate::NewcBuilder::new("./a"), data.clone()),
+                (crate::NewcBuilder::new("./b"), b"b".to_vec()),
+            ],
+            vec![],
+        )
+        .unwrap();
+
+        let reader =
+            NewcReader::new(PrefetchReader::with_window(1, io::Cursor::new(archive))).unwrap();
+        let mut contents = vec![];
+        let rest = reader.to_writer(&mut contents).unwrap();
+        assert_eq!(contents, data);
+        let reader = NewcReader::new(rest).unwrap();
+        assert_eq!(reader.entry().name(), "./b");
+        let reader = NewcReader::new(reader.finish().unwrap()).unwrap();
+        assert!(reader.entry().is_trailer());
+    }
+
+    #[test]
+    fn test_prefetch_error() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+            }
+        }
+
+        let mut reader = PrefetchReader::new(Failing);
+        let err = reader.read(&mut [0u8; 8]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        // A source that panics fails the read, rather than looking like the end of the data.
+        struct Panicking(bool);
+        impl Read for Panicking {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0 {
+                    panic!("source failed");
+                }
+                self.0 = true;
+                buf[0] = 1;
+                Ok(1)
+            }
+        }
+        let mut reader = PrefetchReader::new(Panicking(false));
+        let mut data = vec![];
+        let err = reader.read_to_end(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(data, [1]);
+    }
+}
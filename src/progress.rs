@@ -0,0 +1,63 @@
+//! Progress reporting for long-running archive operations.
+
+use std::io::{self, Read};
+
+/// A snapshot of the progress of an archive operation.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress<'a> {
+    pub(crate) entries: u64,
+    pub(crate) bytes: u64,
+    pub(crate) name: &'a str,
+}
+
+impl<'a> Progress<'a> {
+    /// Returns the number of entries completed so far.
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Returns the number of bytes of file data processed so far, including the part of the
+    /// current entry processed so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns the name of the entry currently being processed, or the most recently
+    /// completed one.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+}
+
+/// Receives progress updates from an archive operation.
+///
+/// Updates are delivered as each chunk of file data is processed and once more as each entry
+/// completes. Any `FnMut(&Progress)` closure is an observer.
+pub trait Observer {
+    /// Called with the current progress of the operation.
+    fn update(&mut self, progress: &Progress<'_>);
+}
+
+impl<F: FnMut(&Progress<'_>)> Observer for F {
+    fn update(&mut self, progress: &Progress<'_>) {
+        self(progress)
+    }
+}
+
+/// Reports progress to an observer as data is read through it.
+pub(crate) struct ProgressReader<'a, R> {
+    pub(crate) inner: R,
+    pub(crate) observer: &'a mut dyn Observer,
+    pub(crate) progress: Progress<'a>,
+}
+
+impl<'a, R: Read> Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.progress.bytes += n as u64;
+            self.observer.update(&self.progress);
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,374 @@
+//! Rewriting damaged archives into valid ones.
+//!
+//! Archives cut short in transfer, or written by buggy tools, often hold every entry intact
+//! but for a few defects that stop strict readers. [`repair`] copies what can be recovered
+//! into a fresh, valid archive and reports what it had to change, so that an almost-valid
+//! archive can be extracted.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::newc::{self, Builder, EntryType, Header, HEADER_LEN, TRAILER_NAME};
+use crate::{ArchiveWriter, Summary};
+
+/// How far on either side of where an entry's header should start to look for it.
+const RESYNC_DISTANCE: usize = 3;
+
+/// Settings for [`repair`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RepairOptions {
+    recompute_checksums: bool,
+    resync: bool,
+}
+
+impl Default for RepairOptions {
+    fn default() -> Self {
+        Self {
+            recompute_checksums: true,
+            resync: true,
+        }
+    }
+}
+
+impl RepairOptions {
+    /// Creates the default options, with every repair enabled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set whether to replace wrong checksums in "new crc" entries with the sums of their
+    /// data. Otherwise the recorded checksums are kept, and only reported.
+    pub fn recompute_checksums(mut self, recompute: bool) -> Self {
+        self.recompute_checksums = recompute;
+        self
+    }
+
+    /// Set whether to look for a header that is a few bytes away from where the padding of the
+    /// entry before it says it should be. Otherwise everything from a misplaced header on is
+    /// dropped.
+    pub fn resync(mut self, resync: bool) -> Self {
+        self.resync = resync;
+        self
+    }
+}
+
+/// A defect fixed by [`repair`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Repair {
+    /// The entry's header was found `offset` bytes into the archive, rather than where the
+    /// padding of the entry before it put it.
+    Misplaced { name: String, offset: u64 },
+    /// The entry's name size did not match the length of its NUL-terminated name.
+    NameSize {
+        name: String,
+        recorded: u32,
+        actual: u32,
+    },
+    /// The entry's checksum did not match its data. It was recomputed unless that was
+    /// disabled.
+    Checksum {
+        name: String,
+        recorded: u32,
+        actual: u32,
+    },
+    /// The archive ended partway through the entry, which was dropped.
+    Truncated { name: String },
+    /// No valid header was found `offset` bytes into the archive, so everything from there on
+    /// was dropped.
+    Unreadable { offset: u64 },
+    /// The archive had no trailer, so one was added.
+    MissingTrailer,
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Repair::Misplaced { ref name, offset } => {
+                write!(f, "{}: header misplaced at offset {}", name, offset)
+            }
+            Repair::NameSize {
+                ref name,
+                recorded,
+                actual,
+            } => write!(f, "{}: name size {} should be {}", name, recorded, actual),
+            Repair::Checksum {
+                ref name,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "{}: checksum {:08x} should be {:08x}",
+                name, recorded, actual
+            ),
+            Repair::Truncated { ref name } => write!(f, "{}: truncated, dropped", name),
+            Repair::Unreadable { offset } => {
+                write!(f, "no header at offset {}, rest of archive dropped", offset)
+            }
+            Repair::MissingTrailer => write!(f, "trailer missing, added"),
+        }
+    }
+}
+
+/// What [`repair`] wrote, and what it had to fix.
+#[derive(Clone, Debug)]
+pub struct Repaired {
+    summary: Summary,
+    repairs: Vec<Repair>,
+}
+
+impl Repaired {
+    /// Returns the statistics of the archive written.
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// Returns the defects fixed, in the order they were found.
+    pub fn repairs(&self) -> &[Repair] {
+        &self.repairs
+    }
+}
+
+/// Returns the offset of the header nearest to `expected`, within `RESYNC_DISTANCE` and
+/// preferring later offsets, if `resync` is set.
+fn find_header(archive: &[u8], expected: usize, resync: bool) -> Option<usize> {
+    let is_header = |pos: usize| {
+        archive.len() >= pos + HEADER_LEN
+            && <&[u8; HEADER_LEN]>::try_from(&archive[pos..pos + HEADER_LEN])
+                .map_or(false, |header| Header::parse(header).is_ok())
+    };
+    if is_header(expected) {
+        return Some(expected);
+    }
+    if !resync {
+        return None;
+    }
+    (1..=RESYNC_DISTANCE)
+        .map(|distance| expected + distance)
+        .chain((1..=RESYNC_DISTANCE).filter_map(|distance| expected.checked_sub(distance)))
+        .find(|&pos| is_header(pos))
+}
+
+/// Copies the recoverable entries of the archive read from `reader` into a valid archive
+/// written to `writer`, returning the writer with what was written and repaired.
+///
+/// Every entry is rewritten with the correct name size and padding. An entry whose name size
+/// is wrong is read up to the NUL ending its name, and a header a few bytes away from where
+/// it should be is found by looking around for it. Wrong checksums are recomputed. The entry
+/// the archive ends partway through, if any, is dropped, a trailer is added if there was none,
+/// and reading stops at the first stretch of the archive that can't be read as a header.
+///
+/// The whole archive is read into memory first.
+pub fn repair<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+    options: &RepairOptions,
+) -> io::Result<(W, Repaired)> {
+    let mut archive = vec![];
+    reader.read_to_end(&mut archive)?;
+
+    let mut output = ArchiveWriter::new(writer);
+    let mut repairs = vec![];
+    let mut expected = 0;
+    let mut trailer = false;
+    while expected < archive.len() {
+        let pos = match find_header(&archive, expected, options.resync) {
+            Some(pos) => pos,
+            None => {
+                let truncated = archive.len() - expected < HEADER_LEN
+                    && archive[expected..].iter().all(|&byte| byte == 0);
+                if !truncated {
+                    repairs.push(Repair::Unreadable {
+                        offset: expected as u64,
+                    });
+                }
+                break;
+            }
+        };
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&archive[pos..pos + HEADER_LEN]);
+        let header = Header::parse(&header)?;
+
+        let name_start = pos + HEADER_LEN;
+        let nul = match archive[name_start..].iter().position(|&byte| byte == 0) {
+            Some(nul) => nul,
+            None => {
+                repairs.push(Repair::Truncated {
+                    name: String::from_utf8_lossy(&archive[name_start..]).into_owned(),
+                });
+                break;
+            }
+        };
+        let name = String::from_utf8_lossy(&archive[name_start..name_start + nul]).into_owned();
+        if pos != expected {
+            repairs.push(Repair::Misplaced {
+                name: name.clone(),
+                offset: pos as u64,
+            });
+        }
+        let name_size = nul as u32 + 1;
+        if name_size != header.name_size {
+            repairs.push(Repair::NameSize {
+                name: name.clone(),
+                recorded: header.name_size,
+                actual: name_size,
+            });
+        }
+        if name == TRAILER_NAME && header.mode == 0 && header.file_size == 0 {
+            trailer = true;
+            break;
+        }
+
+        let data_start = pos + newc::pad(HEADER_LEN + nul + 1) + HEADER_LEN + nul + 1;
+        let data_end = data_start + header.file_size as usize;
+        if data_end > archive.len() {
+            repairs.push(Repair::Truncated { name });
+            break;
+        }
+        let data = &archive[data_start..data_end];
+        let builder = Builder::new(&name)
+            .ino(header.ino)
+            .mode(header.mode)
+            .uid(header.uid)
+            .gid(header.gid)
+            .nlink(header.nlink)
+            .mtime(header.mtime)
+            .dev_major(header.dev_major)
+            .dev_minor(header.dev_minor)
+            .rdev_major(header.rdev_major)
+            .rdev_minor(header.rdev_minor);
+        match header.entry_type {
+            EntryType::Crc => {
+                let actual = newc::checksum(data);
+                let mut checksum = header.checksum;
+                if actual != header.checksum {
+                    repairs.push(Repair::Checksum {
+                        name,
+                        recorded: header.checksum,
+                        actual,
+                    });
+                    if options.recompute_checksums {
+                        checksum = actual;
+                    }
+                }
+                output.append_crc(builder, header.file_size, checksum, data)?;
+            }
+            EntryType::Newc => output.append_bytes(builder, data)?,
+        }
+        expected = data_end + newc::pad(data_end - pos);
+    }
+    if !trailer {
+        repairs.push(Repair::MissingTrailer);
+    }
+
+    let (writer, summary) = output.finish()?;
+    #[cfg(feature = "tracing")]
+    for repair in &repairs {
+        trace_event!(repair = %repair, "repaired archive");
+    }
+    Ok((writer, Repaired { summary, repairs }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+
+    fn archive() -> Vec<u8> {
+        let mut archive = ArchiveWriter::new(vec![]);
+        archive
+            .append_crc(
+                Builder::new("a"),
+                5,
+                newc::checksum(b"Hello"),
+                &b"Hello"[..],
+            )
+            .unwrap();
+        archive.append_bytes(Builder::new("bb"), b"World!").unwrap();
+        archive.finish().unwrap().0
+    }
+
+    fn repaired(damaged: &[u8]) -> (Vec<u8>, Vec<Repair>) {
+        let (output, repaired) = repair(damaged, vec![], &RepairOptions::default()).unwrap();
+        (output, repaired.repairs().to_vec())
+    }
+
+    #[test]
+    fn test_intact() {
+        let (output, repairs) = repaired(&archive());
+        assert_eq!(output, archive());
+        assert!(repairs.is_empty());
+    }
+
+    #[test]
+    fn test_checksum_and_name_size() {
+        let mut damaged = archive();
+        // The checksum of "a", then its name size.
+        damaged[102..110].copy_from_slice(b"00000001");
+        damaged[94..102].copy_from_slice(b"00000007");
+        let (output, repairs) = repaired(&damaged);
+        assert_eq!(output, archive());
+        assert_eq!(
+            repairs,
+            [
+                Repair::NameSize {
+                    name: "a".to_string(),
+                    recorded: 7,
+                    actual: 2
+                },
+                Repair::Checksum {
+                    name: "a".to_string(),
+                    recorded: 1,
+                    actual: newc::checksum(b"Hello")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_misplaced_header() {
+        // Drop one byte of the padding after the first entry's data.
+        let mut damaged = archive();
+        damaged.remove(HEADER_LEN + 2 + 5);
+        let (output, repairs) = repaired(&damaged);
+        assert_eq!(output, archive());
+        assert_eq!(
+            repairs,
+            [Repair::Misplaced {
+                name: "bb".to_string(),
+                offset: 119
+            }]
+        );
+
+        let (_, repaired) = repair(
+            damaged.as_slice(),
+            vec![],
+            &RepairOptions::new().resync(false),
+        )
+        .unwrap();
+        assert_eq!(repaired.summary().entries(), 1);
+        assert_eq!(
+            repaired.repairs(),
+            [Repair::Unreadable { offset: 120 }, Repair::MissingTrailer]
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let intact = archive();
+        let (output, repairs) = repaired(&intact[..intact.len() - 130]);
+        assert_eq!(
+            repairs,
+            [
+                Repair::Truncated {
+                    name: "bb".to_string()
+                },
+                Repair::MissingTrailer
+            ]
+        );
+        let archive = Archive::from_bytes(&output).unwrap();
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive.get("a").unwrap().data(), b"Hello");
+    }
+}
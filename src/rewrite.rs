@@ -0,0 +1,392 @@
+//! Rewriting archives entry by entry as they stream through.
+//!
+//! A [`Rewriter`] copies an archive into an [`ArchiveWriter`], passing the metadata of each
+//! entry through the metadata transforms given to it and the data of selected entries through
+//! the data transforms, so that an archive of any size can be rewritten without holding more
+//! than one entry's data in memory:
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! use cpio::rewrite::Rewriter;
+//! use cpio::{ArchiveWriter, NewcBuilder};
+//!
+//! # let (archive, _) = cpio::write_cpio_bytes(
+//! #     vec![(NewcBuilder::new("etc/motd").mode(0o100644), &b"Welcome to @HOST@\n"[..])],
+//! #     vec![],
+//! # )?;
+//! let (rewritten, _) = Rewriter::new()
+//!     .map_metadata(|builder| builder.uid(0).gid(0))
+//!     .map_data(
+//!         |entry| entry.name().starts_with("etc/"),
+//!         |_, data| {
+//!             let text = String::from_utf8_lossy(&data).replace("@HOST@", "gateway");
+//!             Ok(text.into_bytes())
+//!         },
+//!     )
+//!     .rewrite(archive.as_slice(), ArchiveWriter::new(vec![]))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Rewriter::scrub`] adds a metadata transform that clears the fields describing where and
+//! when an archive was made, for publishing it or comparing builds.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use crate::newc::{checksum, Builder, Entry, Reader};
+use crate::writer::entry_len;
+use crate::{ArchiveWriter, Summary};
+
+type MetadataTransform<'a> = Box<dyn FnMut(Builder) -> Builder + 'a>;
+type DataSelector<'a> = Box<dyn FnMut(&Entry) -> bool + 'a>;
+type DataTransform<'a> = Box<dyn FnMut(&Entry, Vec<u8>) -> io::Result<Vec<u8>> + 'a>;
+
+/// What to do with modification times when scrubbing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ScrubMtime {
+    Keep,
+    Zero,
+    Clamp(u32),
+}
+
+/// The metadata fields that [`Rewriter::scrub`] clears from every entry.
+///
+/// By default, owners, modification times, and the device numbers of the filesystem entries
+/// were archived from are all zeroed. Zeroing device numbers renumbers inodes from 1 in order
+/// of first appearance, so that entries from different filesystems with the same inode number
+/// aren't taken for hard links to one file, while those sharing an inode still do. The device
+/// numbers of device nodes themselves are always kept.
+#[derive(Clone, Debug)]
+pub struct Scrub {
+    owner: bool,
+    mtime: ScrubMtime,
+    devices: bool,
+}
+
+impl Default for Scrub {
+    fn default() -> Self {
+        Scrub {
+            owner: true,
+            mtime: ScrubMtime::Zero,
+            devices: true,
+        }
+    }
+}
+
+impl Scrub {
+    /// Returns the default scrub, zeroing every field it covers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set whether to zero the uid and gid of entries.
+    pub fn owner(mut self, zero: bool) -> Self {
+        self.owner = zero;
+        self
+    }
+
+    /// Set whether to zero the modification time of entries.
+    pub fn mtime(mut self, zero: bool) -> Self {
+        self.mtime = if zero {
+            ScrubMtime::Zero
+        } else {
+            ScrubMtime::Keep
+        };
+        self
+    }
+
+    /// Instead of zeroing modification times, set those later than `epoch` (such as the
+    /// `SOURCE_DATE_EPOCH` of a reproducible build) to it, keeping earlier ones.
+    pub fn clamp_mtime(mut self, epoch: u32) -> Self {
+        self.mtime = ScrubMtime::Clamp(epoch);
+        self
+    }
+
+    /// Set whether to zero the `dev_major` and `dev_minor` fields of entries, renumbering their
+    /// inodes.
+    pub fn devices(mut self, zero: bool) -> Self {
+        self.devices = zero;
+        self
+    }
+
+    /// Applies the scrub to the metadata in `builder`, numbering renumbered inodes by their
+    /// device and inode numbers in `inodes`.
+    fn apply(&self, mut builder: Builder, inodes: &mut HashMap<(u32, u32, u32), u32>) -> Builder {
+        if self.owner {
+            builder = builder.uid(0).gid(0);
+        }
+        match self.mtime {
+            ScrubMtime::Keep => {}
+            ScrubMtime::Zero => builder = builder.mtime(0),
+            ScrubMtime::Clamp(epoch) => {
+                let mtime = builder.to_entry(0, None).mtime();
+                builder = builder.mtime(mtime.min(epoch));
+            }
+        }
+        if self.devices {
+            let entry = builder.to_entry(0, None);
+            let next = inodes.len() as u32 + 1;
+            let ino = *inodes
+                .entry((entry.dev_major(), entry.dev_minor(), entry.ino()))
+                .or_insert(next);
+            builder = builder.ino(ino).dev_major(0).dev_minor(0);
+        }
+        builder
+    }
+}
+
+/// Copies archives, transforming the metadata and data of their entries on the way.
+#[derive(Default)]
+pub struct Rewriter<'a> {
+    metadata: Vec<MetadataTransform<'a>>,
+    data: Vec<(DataSelector<'a>, DataTransform<'a>)>,
+}
+
+impl<'a> Rewriter<'a> {
+    /// Returns a rewriter that copies archives unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Passes the metadata of every entry through `transform`, after any transforms added
+    /// before it.
+    ///
+    /// The file size and checksum set on the builder are replaced by those of the entry's data
+    /// as written.
+    pub fn map_metadata<F>(mut self, transform: F) -> Self
+    where
+        F: FnMut(Builder) -> Builder + 'a,
+    {
+        self.metadata.push(Box::new(transform));
+        self
+    }
+
+    /// Clears the metadata fields that `scrub` covers from every entry, after any metadata
+    /// transforms added before it.
+    pub fn scrub(self, scrub: Scrub) -> Self {
+        let mut inodes = HashMap::new();
+        self.map_metadata(move |builder| scrub.apply(builder, &mut inodes))
+    }
+
+    /// Passes the data of every entry for which `select` returns true through `transform`,
+    /// after any data transforms added before it.
+    ///
+    /// Both are given the entry's metadata as it was read. The data of a selected entry is read
+    /// into memory in full, and the transformed data may be of any size up to 4 GiB; entries
+    /// in the `crc` format get a fresh checksum. Unselected entries are copied without being
+    /// held in memory.
+    pub fn map_data<P, F>(mut self, select: P, transform: F) -> Self
+    where
+        P: FnMut(&Entry) -> bool + 'a,
+        F: FnMut(&Entry, Vec<u8>) -> io::Result<Vec<u8>> + 'a,
+    {
+        self.data.push((Box::new(select), Box::new(transform)));
+        self
+    }
+
+    /// Reads `archive` up to its trailer, writing each entry, transformed, to `output`, and
+    /// finishes `output`.
+    ///
+    /// Entries are written in the order they are read. The trailer, and anything after it, is
+    /// not copied; `output` writes its own.
+    pub fn rewrite<R: Read, W: Write>(
+        &mut self,
+        mut archive: R,
+        mut output: ArchiveWriter<W>,
+    ) -> io::Result<(W, Summary)> {
+        loop {
+            let mut reader = Reader::new(archive)?;
+            if reader.entry().is_trailer() {
+                reader.finish()?;
+                return output.finish();
+            }
+            let entry = reader.entry().clone();
+            let mut builder = Builder::from_entry(&entry);
+            for transform in &mut self.metadata {
+                builder = transform(builder);
+            }
+
+            let mut selected = self
+                .data
+                .iter_mut()
+                .filter_map(|(select, transform)| {
+                    if select(&entry) {
+                        Some(transform)
+                    } else {
+                        None
+                    }
+                })
+                .peekable();
+            if selected.peek().is_none() {
+                match entry.checksum() {
+                    Some(sum) => output.append_crc(builder, entry.file_size(), sum, &mut reader)?,
+                    None => output.append(builder, entry.file_size(), &mut reader)?,
+                }
+            } else {
+                let mut data = Vec::with_capacity(entry.file_size() as usize);
+                reader.read_to_end(&mut data)?;
+                for transform in selected {
+                    data = transform(&entry, data)?;
+                }
+                match entry.checksum() {
+                    Some(_) => {
+                        let len = entry_len(data.len() as u64)?;
+                        output.append_crc(builder, len, checksum(&data), data.as_slice())?
+                    }
+                    None => output.append_bytes(builder, &data)?,
+                }
+            }
+            archive = reader.finish()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcReader;
+
+    fn archive() -> Vec<u8> {
+        let mut archive = ArchiveWriter::new(vec![]);
+        let builder = |name| Builder::new(name).mode(0o100644).uid(1000);
+        archive
+            .append_bytes(builder("etc/app.conf"), b"# comment\nkey=value\n")
+            .unwrap();
+        let data = b"\x7fELF";
+        archive
+            .append_crc(builder("bin/app"), 4, checksum(data), &data[..])
+            .unwrap();
+        archive.finish().unwrap().0
+    }
+
+    fn read_all(archive: &[u8]) -> Vec<(Entry, Vec<u8>)> {
+        let mut entries = vec![];
+        let mut archive = archive;
+        loop {
+            let mut reader = NewcReader::new(archive).unwrap();
+            if reader.entry().is_trailer() {
+                return entries;
+            }
+            let mut data = vec![];
+            reader.read_to_end(&mut data).unwrap();
+            entries.push((reader.entry().clone(), data));
+            archive = reader.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_unchanged() {
+        let original = archive();
+        let (copy, summary) = Rewriter::new()
+            .rewrite(original.as_slice(), ArchiveWriter::new(vec![]))
+            .unwrap();
+        assert_eq!(copy, original);
+        assert_eq!(summary.entries(), 2);
+    }
+
+    #[test]
+    fn test_transforms() {
+        let mut seen = vec![];
+        let (rewritten, _) = Rewriter::new()
+            .map_metadata(|builder| builder.uid(0))
+            .map_data(
+                |entry| entry.name().ends_with(".conf"),
+                |_, data| {
+                    let text = String::from_utf8(data).unwrap();
+                    let kept: Vec<&str> =
+                        text.lines().filter(|line| !line.starts_with('#')).collect();
+                    Ok(format!("{}\n", kept.join("\n")).into_bytes())
+                },
+            )
+            .map_data(
+                |_| true,
+                |entry, mut data| {
+                    seen.push(entry.name().to_string());
+                    data.extend_from_slice(b"!");
+                    Ok(data)
+                },
+            )
+            .rewrite(archive().as_slice(), ArchiveWriter::new(vec![]))
+            .unwrap();
+        assert_eq!(seen, ["etc/app.conf", "bin/app"]);
+
+        let entries = read_all(&rewritten);
+        assert!(entries.iter().all(|(entry, _)| entry.uid() == 0));
+        assert_eq!(entries[0].1, b"key=value\n!");
+        assert_eq!(entries[0].0.file_size(), 11);
+        assert_eq!(entries[1].1, b"\x7fELF!");
+        assert_eq!(entries[1].0.checksum(), Some(checksum(b"\x7fELF!")));
+    }
+
+    #[test]
+    fn test_scrub() {
+        let mut archive = ArchiveWriter::new(vec![]);
+        let builder = |name, mtime| {
+            Builder::new(name)
+                .uid(1000)
+                .gid(100)
+                .mtime(mtime)
+                .dev_major(8)
+                .dev_minor(1)
+                .ino(5)
+        };
+        archive
+            .append_bytes(builder("old", 1_000).mode(0o100644), b"data")
+            .unwrap();
+        let console = builder("console", 2_000_000_000)
+            .mode(0o020600)
+            .rdev_major(5)
+            .rdev_minor(1);
+        archive.append_bytes(console, b"").unwrap();
+        // The same inode number on another filesystem is another file.
+        let other = builder("other", 0).dev_major(9).mode(0o100644);
+        archive.append_bytes(other, b"").unwrap();
+        let original = archive.finish().unwrap().0;
+
+        let (scrubbed, _) = Rewriter::new()
+            .scrub(Scrub::new())
+            .rewrite(original.as_slice(), ArchiveWriter::new(vec![]))
+            .unwrap();
+        for (entry, _) in read_all(&scrubbed) {
+            assert_eq!((entry.uid(), entry.gid(), entry.mtime()), (0, 0, 0));
+            assert_eq!((entry.dev_major(), entry.dev_minor()), (0, 0));
+        }
+        let entries = read_all(&scrubbed);
+        let inos: Vec<_> = entries.iter().map(|(entry, _)| entry.ino()).collect();
+        assert_eq!(inos, [1, 1, 2]);
+        assert_eq!(
+            (entries[1].0.rdev_major(), entries[1].0.rdev_minor()),
+            (5, 1)
+        );
+        assert_eq!(entries[0].1, b"data");
+
+        let (clamped, _) = Rewriter::new()
+            .scrub(
+                Scrub::new()
+                    .owner(false)
+                    .devices(false)
+                    .clamp_mtime(1_700_000_000),
+            )
+            .rewrite(original.as_slice(), ArchiveWriter::new(vec![]))
+            .unwrap();
+        let entries = read_all(&clamped);
+        assert_eq!(entries[0].0.mtime(), 1_000);
+        assert_eq!(entries[1].0.mtime(), 1_700_000_000);
+        assert_eq!(entries[1].0.uid(), 1000);
+        assert_eq!(entries[1].0.dev_major(), 8);
+        assert_eq!(entries[2].0.ino(), 5);
+    }
+
+    #[test]
+    fn test_transform_error() {
+        let err = Rewriter::new()
+            .map_data(
+                |_| true,
+                |_, _| Err(io::Error::new(io::ErrorKind::Other, "transform failed")),
+            )
+            .rewrite(archive().as_slice(), ArchiveWriter::new(vec![]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "transform failed");
+    }
+}
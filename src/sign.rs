@@ -0,0 +1,215 @@
+//! Detached ed25519 signatures over archives.
+//!
+//! This module is only available with the `sign` feature. Signatures cover the SHA-256
+//! [`canonical_digest`] of an archive rather than its raw bytes, so that an archive stays
+//! validly signed through changes that don't alter what it unpacks to: the magic of the
+//! headers, the padding, the inode and device numbers that happened to be read from the disk
+//! it was created on, and zeros padding it out after the trailer.
+//!
+//! Anything else after the trailer, such as a further archive appended to a signed initrd,
+//! would be unpacked by the kernel without being covered by the signature, so archives with
+//! it can neither be signed nor verified.
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! use cpio::sign::{self, SigningKey};
+//!
+//! let (archive, _) = cpio::write_cpio_bytes(
+//!     vec![(cpio::NewcBuilder::new("init").mode(0o100755), &b"#!/bin/sh"[..])],
+//!     vec![],
+//! )?;
+//! let key = SigningKey::from_bytes(&[7; 32]);
+//! let signature = sign::sign(archive.as_slice(), &key)?;
+//!
+//! // Verify the very bytes that are about to be extracted.
+//! sign::verify(archive.as_slice(), &key.verifying_key(), &signature)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use digest::Digest;
+use ed25519_dalek::{Signer, Verifier};
+use sha2::Sha256;
+
+use crate::newc::{self, Builder, Reader};
+
+pub use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+
+/// A sink that hashes everything written to it.
+struct HashWriter(Sha256);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads every entry of `archive` and returns the SHA-256 digest of its canonical form.
+///
+/// The canonical form of an archive is the one this crate would write for its entries, in
+/// the same order:
+///
+/// * every entry but the trailer is written with the `newc` magic and a checksum field of
+///   zero, whichever format it was read in;
+/// * the device numbers of the filesystem each entry came from are zero, and inode numbers
+///   are renumbered from 1 in order of first appearance, so that entries sharing an inode
+///   still do;
+/// * every other field, the name, and the data are as in the archive;
+/// * all padding is zeros, and the archive ends with a `newc` trailer and nothing after it.
+///
+/// Checksums of entries in the `crc` format are not verified. The archive may be followed by
+/// zeros, which are read to the end; anything else after the trailer is an `InvalidData`
+/// error.
+pub fn canonical_digest<R: Read>(mut archive: R) -> io::Result<[u8; 32]> {
+    let mut hasher = HashWriter(Sha256::new());
+    let mut inodes = HashMap::new();
+    loop {
+        let mut reader = Reader::new(archive)?;
+        let entry = reader.entry();
+        if entry.is_trailer() {
+            archive = reader.finish()?;
+            break;
+        }
+        let next = inodes.len() as u32 + 1;
+        let ino = *inodes
+            .entry((entry.dev_major(), entry.dev_minor(), entry.ino()))
+            .or_insert(next);
+        let builder = Builder::from_entry(entry)
+            .ino(ino)
+            .dev_major(0)
+            .dev_minor(0);
+        let mut fp = builder.write(&mut hasher, entry.file_size());
+        io::copy(&mut reader, &mut fp)?;
+        fp.finish()?;
+        archive = reader.finish()?;
+    }
+    let mut rest = [0u8; 4096];
+    loop {
+        let n = match archive.read(&mut rest) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if rest[..n].iter().any(|&byte| byte != 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data after the trailer would not be covered by the signature",
+            ));
+        }
+    }
+    newc::trailer(&mut hasher)?;
+    Ok(hasher.0.finalize().into())
+}
+
+/// Signs the [`canonical_digest`] of `archive` with `key`.
+pub fn sign<R: Read>(archive: R, key: &SigningKey) -> io::Result<Signature> {
+    Ok(key.sign(&canonical_digest(archive)?))
+}
+
+/// Checks that `signature` is `key`'s signature of the [`canonical_digest`] of `archive`,
+/// failing with an `InvalidData` error if it isn't.
+///
+/// The archive is read in full before the signature is checked, so when verifying before
+/// extracting, extract from the same bytes that were verified, such as a buffer, rather than
+/// reading the archive a second time from where it might have been changed in between.
+pub fn verify<R: Read>(archive: R, key: &VerifyingKey, signature: &Signature) -> io::Result<()> {
+    let digest = canonical_digest(archive)?;
+    key.verify(&digest, signature)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid archive signature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(crc: bool, ino: u32) -> Vec<u8> {
+        let mut archive = crate::ArchiveWriter::new(vec![]);
+        let data: &[u8] = b"#!/bin/sh";
+        let file = |name| {
+            Builder::new(name)
+                .mode(0o100755)
+                .ino(ino)
+                .nlink(2)
+                .dev_major(8)
+        };
+        archive.append_bytes(file("init"), b"").unwrap();
+        if crc {
+            let sum = newc::checksum(data);
+            archive
+                .append_crc(file("sbin/init"), data.len() as u32, sum, data)
+                .unwrap();
+        } else {
+            archive.append_bytes(file("sbin/init"), data).unwrap();
+        }
+        archive.finish().unwrap().0
+    }
+
+    #[test]
+    fn test_canonical_digest() {
+        let digest = canonical_digest(archive(false, 5).as_slice()).unwrap();
+        assert_eq!(
+            canonical_digest(archive(true, 9).as_slice()).unwrap(),
+            digest
+        );
+        let mut trailing = archive(false, 5);
+        trailing.extend_from_slice(&[0; 512]);
+        assert_eq!(canonical_digest(trailing.as_slice()).unwrap(), digest);
+
+        // Breaking up the hard link changes what the archive unpacks to.
+        let (split, _) = crate::write_cpio_bytes(
+            vec![
+                (Builder::new("init").mode(0o100755).nlink(2), &b""[..]),
+                (
+                    Builder::new("sbin/init").mode(0o100755).nlink(2),
+                    b"#!/bin/sh",
+                ),
+            ],
+            vec![],
+        )
+        .unwrap();
+        assert_ne!(canonical_digest(split.as_slice()).unwrap(), digest);
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let signature = sign(archive(false, 5).as_slice(), &key).unwrap();
+        verify(
+            archive(true, 1).as_slice(),
+            &key.verifying_key(),
+            &signature,
+        )
+        .unwrap();
+
+        let mut tampered = archive(false, 5);
+        // Flip a bit of the data of the last entry, which precedes the 124 byte trailer.
+        let len = tampered.len();
+        tampered[len - 136] ^= 1;
+        let err = verify(tampered.as_slice(), &key.verifying_key(), &signature).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A second archive appended after the trailer would also be unpacked.
+        let mut appended = archive(false, 5);
+        appended.extend_from_slice(&archive(false, 5));
+        let err = verify(appended.as_slice(), &key.verifying_key(), &signature).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let err = verify(
+            archive(false, 5).as_slice(),
+            &other.verifying_key(),
+            &signature,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
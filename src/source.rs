@@ -0,0 +1,245 @@
+//! Generating archives on demand, as they are read.
+//!
+//! A [`CpioSource`] turns a sequence of inputs into a reader of the archive holding them,
+//! producing each header as it is reached and pulling entry data from the inputs only as the
+//! archive is read, so that an archive can be handed straight to anything that consumes a
+//! reader, such as a hash function or an upload client, without being written out first:
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! use std::io::Read;
+//! use cpio::source::CpioSource;
+//! use cpio::NewcBuilder;
+//!
+//! let inputs = vec![
+//!     (NewcBuilder::new("etc").mode(0o040755), 0, &b""[..]),
+//!     (NewcBuilder::new("etc/hostname").mode(0o100644), 7, &b"router\n"[..]),
+//! ];
+//! let mut archive = vec![];
+//! CpioSource::new(inputs).read_to_end(&mut archive)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `bytes` feature, `CpioSource::chunks` hands the archive out instead as a
+//! sequence of `Bytes` of a fixed size, as HTTP response bodies and multipart uploads take
+//! it.
+
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+use std::io::{self, Read};
+use std::iter::Enumerate;
+
+use crate::newc::{self, pad, Builder};
+use crate::writer::entry_len;
+
+/// A reader of the archive holding a sequence of inputs, generated as it is read.
+///
+/// Each input is given with its length, as for [`write_cpio_sized`](crate::write_cpio_sized),
+/// and the archive read is identical to the one that writes: each entry's inode number is its
+/// index among the inputs, and the archive ends with a `newc` trailer. Each input is read
+/// only once the archive has been read up to its data, and must yield at least its length in
+/// bytes; reading fails with an `UnexpectedEof` error at an input that ends early.
+pub struct CpioSource<I, R> {
+    inputs: Enumerate<I>,
+    /// Bytes ready to be read: a header and name, the padding after data, or the trailer.
+    pending: Vec<u8>,
+    /// How much of `pending` has been read.
+    pos: usize,
+    /// The input whose data is being read, the number of its bytes still to be read, and the
+    /// length of the padding after them.
+    data: Option<(R, u64, usize)>,
+    done: bool,
+}
+
+impl<I, R> CpioSource<I, R>
+where
+    I: Iterator<Item = (Builder, u64, R)>,
+    R: Read,
+{
+    /// Creates a reader of the archive holding `inputs`, without reading any of them yet.
+    pub fn new<T: IntoIterator<IntoIter = I>>(inputs: T) -> Self {
+        CpioSource {
+            inputs: inputs.into_iter().enumerate(),
+            pending: vec![],
+            pos: 0,
+            data: None,
+            done: false,
+        }
+    }
+
+    /// Moves on to the next part of the archive once everything pending has been read,
+    /// returning false at the end of the archive.
+    fn advance(&mut self) -> io::Result<bool> {
+        self.pos = 0;
+        self.pending.clear();
+        if let Some((_, _, padding)) = self.data.take() {
+            self.pending.resize(padding, 0);
+            return Ok(true);
+        }
+        if self.done {
+            return Ok(false);
+        }
+        match self.inputs.next() {
+            Some((idx, (builder, len, input))) => {
+                let len = entry_len(len)?;
+                builder
+                    .ino(idx as u32)
+                    .fill_header(&mut self.pending, len, None);
+                self.data = Some((input, u64::from(len), pad(len as usize)));
+            }
+            None => {
+                self.pending = newc::trailer(vec![])?;
+                self.done = true;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns an iterator over the archive in chunks of `size` bytes, the last of which may be
+    /// shorter. Only one chunk is held in memory at a time.
+    ///
+    /// Inputs are read as the chunks are iterated over, blocking on them; to feed an
+    /// asynchronous body, iterate on a thread that is allowed to block. The iterator ends after
+    /// the first error.
+    #[cfg(feature = "bytes")]
+    pub fn chunks(self, size: usize) -> Chunks<I, R> {
+        Chunks {
+            source: self,
+            size: size.max(1),
+            failed: false,
+        }
+    }
+}
+
+/// An iterator over an archive in chunks of a fixed size, returned by [`CpioSource::chunks`].
+#[cfg(feature = "bytes")]
+pub struct Chunks<I, R> {
+    source: CpioSource<I, R>,
+    size: usize,
+    failed: bool,
+}
+
+#[cfg(feature = "bytes")]
+impl<I, R> Iterator for Chunks<I, R>
+where
+    I: Iterator<Item = (Builder, u64, R)>,
+    R: Read,
+{
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<io::Result<Bytes>> {
+        if self.failed {
+            return None;
+        }
+        let mut chunk = Vec::with_capacity(self.size);
+        match (&mut self.source)
+            .take(self.size as u64)
+            .read_to_end(&mut chunk)
+        {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(Bytes::from(chunk))),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<I, R> Read for CpioSource<I, R>
+where
+    I: Iterator<Item = (Builder, u64, R)>,
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.pos < self.pending.len() {
+                let available = &self.pending[self.pos..];
+                let n = available.len().min(buf.len());
+                buf[..n].copy_from_slice(&available[..n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if let Some((ref mut input, ref mut remaining, _)) = self.data {
+                if *remaining > 0 {
+                    let len = buf
+                        .len()
+                        .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                    let n = input.read(&mut buf[..len])?;
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "input ended before the specified file size",
+                        ));
+                    }
+                    *remaining -= n as u64;
+                    return Ok(n);
+                }
+            }
+            if !self.advance()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs() -> Vec<(Builder, u64, &'static [u8])> {
+        vec![
+            (Builder::new("./dir").mode(0o040755), 0, b""),
+            (Builder::new("./a").mode(0o100644), 5, b"Hello"),
+            (Builder::new("./b").mode(0o100644), 6, b"World!"),
+        ]
+    }
+
+    #[test]
+    fn test_matches_writer() {
+        let (expected, _) = crate::write_cpio_sized(inputs(), vec![]).unwrap();
+        let mut archive = vec![];
+        CpioSource::new(inputs()).read_to_end(&mut archive).unwrap();
+        assert_eq!(archive, expected);
+
+        // Reading a byte at a time gives the same archive.
+        let mut source = CpioSource::new(inputs());
+        let mut archive = vec![];
+        let mut byte = [0];
+        while source.read(&mut byte).unwrap() == 1 {
+            archive.push(byte[0]);
+        }
+        assert_eq!(archive, expected);
+    }
+
+    #[test]
+    fn test_short_input() {
+        let inputs = vec![(Builder::new("./a").mode(0o100644), 10, &b"Hello"[..])];
+        let err = CpioSource::new(inputs)
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    #[cfg(feature = "bytes")]
+    fn test_chunks() {
+        let (expected, _) = crate::write_cpio_sized(inputs(), vec![]).unwrap();
+        let chunks: Vec<Bytes> = CpioSource::new(inputs())
+            .chunks(100)
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(chunks.len(), (expected.len() + 99) / 100);
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.len() == 100));
+        assert_eq!(chunks.concat(), expected);
+
+        let inputs = vec![(Builder::new("./a").mode(0o100644), 10, &b"Hello"[..])];
+        let mut chunks = CpioSource::new(inputs).chunks(1000);
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+    }
+}
@@ -0,0 +1,186 @@
+//! Conversions between entry metadata and the `tar` crate's headers.
+//!
+//! Both formats describe files with the same stat-like fields, but differ in where they keep
+//! a few of them: tar stores the file type in its own field and a link's target in the header,
+//! while cpio folds the type into the mode and stores a symlink's target as the entry's data.
+//! Hard links are separate entries pointing at a name in tar, and entries sharing an inode
+//! number in cpio. Converting a header converts only what the header itself holds, so callers
+//! moving symlinks between the formats also move the target between the header and the data.
+
+use std::convert::TryFrom;
+use std::io;
+
+use ::tar::{EntryType, Header};
+
+use crate::newc::{Builder, Entry, ModeFileType};
+
+fn out_of_range(field: &str, name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} of {:?} does not fit in a cpio header", field, name),
+    )
+}
+
+impl TryFrom<&Header> for Builder {
+    type Error = io::Error;
+
+    /// Converts a tar header into the metadata for a cpio entry.
+    ///
+    /// Fails if the name is not UTF-8, if the header's file type has no cpio equivalent (such
+    /// as the GNU and pax extension headers), or if the owner or modification time don't fit
+    /// in the 32 bits a cpio header has for them. Hard links become regular files.
+    fn try_from(header: &Header) -> io::Result<Self> {
+        let path = header.path_bytes();
+        let name = std::str::from_utf8(&path).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "tar entry name is not UTF-8")
+        })?;
+
+        let entry_type = header.entry_type();
+        let file_type = match entry_type {
+            EntryType::Regular | EntryType::Continuous | EntryType::Link => ModeFileType::Regular,
+            EntryType::Directory => ModeFileType::Directory,
+            EntryType::Symlink => ModeFileType::Symlink,
+            EntryType::Char => ModeFileType::Char,
+            EntryType::Block => ModeFileType::Block,
+            EntryType::Fifo => ModeFileType::Fifo,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("tar entry {:?} has no cpio equivalent", name),
+                ))
+            }
+        };
+
+        let uid = u32::try_from(header.uid()?).map_err(|_| out_of_range("uid", name))?;
+        let gid = u32::try_from(header.gid()?).map_err(|_| out_of_range("gid", name))?;
+        let mtime = u32::try_from(header.mtime()?).map_err(|_| out_of_range("mtime", name))?;
+        let mut builder = Builder::new(name)
+            .mode(header.mode()? & 0o7777)
+            .set_mode_file_type(file_type)
+            .uid(uid)
+            .gid(gid)
+            .mtime(mtime);
+        if entry_type.is_character_special() || entry_type.is_block_special() {
+            builder = builder
+                .rdev_major(header.device_major()?.unwrap_or(0))
+                .rdev_minor(header.device_minor()?.unwrap_or(0));
+        }
+        Ok(builder)
+    }
+}
+
+impl TryFrom<&Builder> for Header {
+    type Error = io::Error;
+
+    /// Converts the metadata for a cpio entry into a GNU tar header with a size of zero.
+    ///
+    /// Fails if the name is too long for the header itself or has a `..` component, or if the
+    /// mode holds a file type tar can't represent, such as a socket.
+    fn try_from(builder: &Builder) -> io::Result<Self> {
+        // The builder's fields are only reachable through the entry it would write.
+        Header::try_from(&builder.to_entry(0, None))
+    }
+}
+
+impl TryFrom<&Entry> for Header {
+    type Error = io::Error;
+
+    /// Converts the metadata of an entry read from a cpio archive into a GNU tar header, with
+    /// the same size as the entry's data.
+    ///
+    /// Fails in the same cases as converting a [`Builder`].
+    fn try_from(entry: &Entry) -> io::Result<Self> {
+        let entry_type = match ModeFileType::from_mode(entry.mode()) {
+            Some(ModeFileType::Regular) => EntryType::Regular,
+            Some(ModeFileType::Directory) => EntryType::Directory,
+            Some(ModeFileType::Symlink) => EntryType::Symlink,
+            Some(ModeFileType::Char) => EntryType::Char,
+            Some(ModeFileType::Block) => EntryType::Block,
+            Some(ModeFileType::Fifo) => EntryType::Fifo,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cpio entry {:?} has no tar equivalent", entry.name()),
+                ))
+            }
+        };
+
+        let mut header = Header::new_gnu();
+        header.set_path_absolute(entry.name())?;
+        header.set_entry_type(entry_type);
+        header.set_mode(entry.mode() & 0o7777);
+        header.set_uid(entry.uid().into());
+        header.set_gid(entry.gid().into());
+        header.set_mtime(entry.mtime().into());
+        header.set_size(entry.file_size().into());
+        if entry_type.is_character_special() || entry_type.is_block_special() {
+            header.set_device_major(entry.rdev_major())?;
+            header.set_device_minor(entry.rdev_minor())?;
+        }
+        header.set_cksum();
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newc::Reader;
+
+    #[test]
+    fn test_round_trip() {
+        let builder = Builder::new("dev/console")
+            .mode(0o600)
+            .set_mode_file_type(ModeFileType::Char)
+            .uid(1000)
+            .gid(100)
+            .mtime(1_600_000_000)
+            .rdev_major(5)
+            .rdev_minor(1);
+        let header = Header::try_from(&builder).unwrap();
+        assert_eq!(header.entry_type(), EntryType::Char);
+        assert_eq!(header.mode().unwrap(), 0o600);
+        assert_eq!(header.device_major().unwrap(), Some(5));
+        assert_eq!(header.size().unwrap(), 0);
+
+        let converted = Builder::try_from(&header).unwrap();
+        let entry = converted.to_entry(0, None);
+        assert_eq!(entry.name(), "dev/console");
+        assert_eq!(entry.mode(), 0o020600);
+        assert_eq!((entry.uid(), entry.gid()), (1000, 100));
+        assert_eq!(entry.mtime(), 1_600_000_000);
+        assert_eq!((entry.rdev_major(), entry.rdev_minor()), (5, 1));
+    }
+
+    #[test]
+    fn test_entry_size() {
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![(Builder::new("./hello").mode(0o100644), "Hello")],
+            vec![],
+        )
+        .unwrap();
+        let reader = Reader::new(archive.as_slice()).unwrap();
+        let header = Header::try_from(reader.entry()).unwrap();
+        assert_eq!(header.path().unwrap().to_str(), Some("hello"));
+        assert_eq!(header.size().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let socket = Builder::new("sock").set_mode_file_type(ModeFileType::Socket);
+        assert!(Header::try_from(&socket).is_err());
+        assert!(Header::try_from(&Builder::new("../x").mode(0o100644)).is_err());
+
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::XHeader);
+        assert!(Builder::try_from(&header).is_err());
+        let mut header = Header::new_gnu();
+        header.set_path("big").unwrap();
+        header.set_uid(1 << 40);
+        let err = Builder::try_from(&header).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "uid of \"big\" does not fit in a cpio header"
+        );
+    }
+}
@@ -0,0 +1,525 @@
+//! Archiving directory trees.
+//!
+//! [`TreeArchiver`] writes every file under a root directory into an archive, named relative
+//! to the root, the way `find . | cpio -o -H newc` does. It can walk the tree itself, or take
+//! the files from a walk the caller already made, so that a tree need not be walked (and every
+//! file stat'ed) twice; any [`TreeEntry`] will do, and with the `walkdir` feature so will the
+//! entries `walkdir` yields. Metadata comes from the file system, or can be synthesized the
+//! same way on every platform with [`TreeArchiver::portable`].
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::fakeroot::MetadataDb;
+use crate::newc::{Builder, MetadataDefaults, ModeFileType};
+use crate::owner::OwnerMap;
+use crate::writer::{entry_len, HardLinkData};
+use crate::{ArchiveWriter, Summary};
+
+/// A file found while walking a tree.
+pub trait TreeEntry {
+    /// Returns the path of the file.
+    fn path(&self) -> &Path;
+
+    /// Returns the metadata of the file itself, not following symlinks.
+    fn metadata(&self) -> io::Result<fs::Metadata>;
+}
+
+impl TreeEntry for PathBuf {
+    fn path(&self) -> &Path {
+        self
+    }
+
+    fn metadata(&self) -> io::Result<fs::Metadata> {
+        fs::symlink_metadata(self)
+    }
+}
+
+#[cfg(feature = "walkdir")]
+impl TreeEntry for walkdir::DirEntry {
+    fn path(&self) -> &Path {
+        walkdir::DirEntry::path(self)
+    }
+
+    /// Returns the metadata `walkdir` holds for the file, which follows symlinks if the walk
+    /// was made with `follow_links` set.
+    fn metadata(&self) -> io::Result<fs::Metadata> {
+        walkdir::DirEntry::metadata(self).map_err(io::Error::from)
+    }
+}
+
+/// The data of a file from a tree: the file itself, or the target of a symlink.
+///
+/// It can be passed along with its [`Builder`] to [`write_cpio`](crate::write_cpio).
+#[derive(Debug)]
+pub enum Source {
+    File(File),
+    Bytes(Cursor<Vec<u8>>),
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Source::File(ref mut file) => file.read(buf),
+            Source::Bytes(ref mut bytes) => bytes.read(buf),
+        }
+    }
+}
+
+impl Seek for Source {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            Source::File(ref mut file) => file.seek(pos),
+            Source::Bytes(ref mut bytes) => bytes.seek(pos),
+        }
+    }
+}
+
+impl Source {
+    fn len(&self) -> io::Result<u64> {
+        match *self {
+            Source::File(ref file) => Ok(file.metadata()?.len()),
+            Source::Bytes(ref bytes) => Ok(bytes.get_ref().len() as u64),
+        }
+    }
+}
+
+/// Writes the files under a directory into an archive.
+///
+/// ```no_run
+/// use cpio::tree::TreeArchiver;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let output = std::fs::File::create("rootfs.cpio")?;
+/// let (_, summary) = TreeArchiver::new("rootfs").write(output)?;
+/// println!("archived {} files", summary.entries());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct TreeArchiver {
+    root: PathBuf,
+    portable: Option<MetadataDefaults>,
+    owner_map: OwnerMap,
+    metadata_db: Option<MetadataDb>,
+}
+
+impl TreeArchiver {
+    /// Creates an archiver for the files under `root`.
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            portable: None,
+            owner_map: OwnerMap::default(),
+            metadata_db: None,
+        }
+    }
+
+    /// Set whether to synthesize every file's metadata from `defaults` with
+    /// [`Builder::from_portable_metadata`], rather than taking it from the file system, so
+    /// that the archive is the same whatever platform it is built on and whoever owns the
+    /// files.
+    pub fn portable(mut self, defaults: Option<MetadataDefaults>) -> Self {
+        self.portable = defaults;
+        self
+    }
+
+    /// Pass the owner of every file through `map`, such as to archive a tree owned by an
+    /// unprivileged user as though root owned it.
+    pub fn owner_map(mut self, map: OwnerMap) -> Self {
+        self.owner_map = map;
+        self
+    }
+
+    /// Give files the metadata recorded for them in `db`, as an unprivileged
+    /// [`Extractor`](crate::extract::Extractor) records it, in place of their own. Files whose
+    /// recorded type has no data, such as device nodes, are archived without any. Recorded
+    /// owners are still passed through the [`owner_map`](Self::owner_map).
+    pub fn metadata_db(mut self, db: MetadataDb) -> Self {
+        self.metadata_db = Some(db);
+        self
+    }
+
+    /// Walks the tree and writes every file in it, except the root itself, into an archive.
+    ///
+    /// Entries within a directory are written in order of their names, with each directory
+    /// before its contents, and symlinks are archived as links rather than followed. As with
+    /// [`write_cpio`](crate::write_cpio), files are numbered in order from 0.
+    ///
+    /// Hard links among the files share their inode number, and have their link count set to
+    /// the number of them in the archive, whatever other links they have outside it; the data
+    /// is written with the first, as with [`HardLinkData::First`].
+    pub fn write<W: Write>(&self, output: W) -> io::Result<(W, Summary)> {
+        let entries = walk(&self.root)?;
+        self.write_entries(entries.into_iter().map(Ok::<_, io::Error>), output)
+    }
+
+    /// Writes the files yielded by a walk of the tree into an archive, in the walk's order.
+    ///
+    /// Every path must lie under the root; the root itself is skipped. The walk is taken in
+    /// full before anything is written, to count the links to each file, so the first error
+    /// it yields stops the archive before it is started. Files are opened one at a time, as
+    /// they are written.
+    ///
+    /// ```no_run
+    /// # #[cfg(feature = "walkdir")]
+    /// # fn main() -> std::io::Result<()> {
+    /// use cpio::tree::TreeArchiver;
+    ///
+    /// let walk = walkdir::WalkDir::new("rootfs").sort_by_file_name();
+    /// let (archive, _) = TreeArchiver::new("rootfs").write_entries(walk, vec![])?;
+    /// # Ok(())
+    /// # }
+    /// # #[cfg(not(feature = "walkdir"))]
+    /// # fn main() {}
+    /// ```
+    pub fn write_entries<I, T, E, W>(&self, entries: I, output: W) -> io::Result<(W, Summary)>
+    where
+        I: IntoIterator<Item = Result<T, E>>,
+        T: TreeEntry,
+        E: Into<io::Error>,
+        W: Write,
+    {
+        let mut files = vec![];
+        // The links to each file with more than one, by its own device and inode numbers,
+        // and the inode number it is given once its first link is written.
+        let mut links: HashMap<_, (u32, Option<u32>)> = HashMap::new();
+        for entry in entries {
+            let entry = entry.map_err(Into::into)?;
+            if entry.path() == self.root {
+                continue;
+            }
+            let (builder, metadata, has_data) = self.builder(&entry)?;
+            let key = link_key(&builder);
+            if let Some(key) = key {
+                links.entry(key).or_insert((0, None)).0 += 1;
+            }
+            files.push((entry, builder, metadata, has_data, key));
+        }
+
+        let mut archive = ArchiveWriter::new(output)
+            .owner_map(self.owner_map.clone())
+            .hard_link_data(HardLinkData::First);
+        let mut next_ino = 0;
+        for (entry, builder, metadata, has_data, key) in files {
+            let builder = match key.and_then(|key| links.get_mut(&key)) {
+                Some((count, ino)) => {
+                    let ino = *ino.get_or_insert_with(|| {
+                        next_ino += 1;
+                        next_ino - 1
+                    });
+                    builder.ino(ino).nlink(*count)
+                }
+                None => {
+                    next_ino += 1;
+                    builder.ino(next_ino - 1)
+                }
+            };
+            if archive.link_has_data(&builder) {
+                archive.append(builder, 0, io::empty())?;
+                continue;
+            }
+            let source = source(entry.path(), &metadata, has_data)?;
+            let len = source.len()?;
+            archive.append(builder, entry_len(len)?, source)?;
+        }
+        archive.finish()
+    }
+
+    /// Returns the metadata and data to archive for one file of the tree, named relative to
+    /// the root with `/` separating its components.
+    ///
+    /// Regular files are opened, and symlinks have their target read; every other kind of
+    /// file has no data.
+    pub fn input<T: TreeEntry>(&self, entry: &T) -> io::Result<(Builder, Source)> {
+        let (builder, metadata, has_data) = self.builder(entry)?;
+        Ok((builder, source(entry.path(), &metadata, has_data)?))
+    }
+
+    /// Returns the metadata to archive for one file of the tree, along with the file's own
+    /// metadata and whether it has data to archive.
+    fn builder<T: TreeEntry>(&self, entry: &T) -> io::Result<(Builder, fs::Metadata, bool)> {
+        let path = entry.path();
+        let relative = path.strip_prefix(&self.root).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not under {}", path.display(), self.root.display()),
+            )
+        })?;
+        let name = relative
+            .components()
+            .map(|part| match part {
+                Component::Normal(part) => part.to_str(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a UTF-8 relative path", path.display()),
+                )
+            })?
+            .join("/");
+
+        let metadata = entry.metadata()?;
+        let mut builder = match self.portable {
+            Some(ref defaults) => Builder::from_portable_metadata(&name, &metadata, defaults),
+            None => Builder::from_metadata(&name, &metadata),
+        };
+        // Files standing in for device nodes and the like have no data to archive.
+        let mut has_data = true;
+        if let Some(record) = self.metadata_db.as_ref().and_then(|db| db.get(&name)) {
+            builder = record.apply(builder);
+            has_data = matches!(
+                ModeFileType::from_mode(record.mode),
+                Some(ModeFileType::Regular | ModeFileType::Symlink)
+            );
+        }
+        Ok((builder, metadata, has_data))
+    }
+}
+
+/// Opens the data to archive for the file at `path`, if it has any.
+fn source(path: &Path, metadata: &fs::Metadata, has_data: bool) -> io::Result<Source> {
+    let file_type = metadata.file_type();
+    Ok(if !has_data {
+        Source::Bytes(Cursor::new(vec![]))
+    } else if file_type.is_file() {
+        Source::File(File::open(path)?)
+    } else if file_type.is_symlink() {
+        Source::Bytes(Cursor::new(link_target(&fs::read_link(path)?)?))
+    } else {
+        Source::Bytes(Cursor::new(vec![]))
+    })
+}
+
+/// Returns the device and inode numbers that `builder` takes from its file, if the file has
+/// other links that may be in the tree.
+fn link_key(builder: &Builder) -> Option<(u32, u32, u32)> {
+    let entry = builder.to_entry(0, None);
+    if entry.nlink() < 2 || ModeFileType::from_mode(entry.mode()) == Some(ModeFileType::Directory) {
+        return None;
+    }
+    Some((entry.dev_major(), entry.dev_minor(), entry.ino()))
+}
+
+/// Returns the bytes to store as the data of a symlink to `target`.
+#[cfg(unix)]
+fn link_target(target: &Path) -> io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(target.as_os_str().as_bytes().to_vec())
+}
+
+/// Returns the bytes to store as the data of a symlink to `target`, with `/` separating its
+/// components as on the systems that will read it.
+#[cfg(not(unix))]
+fn link_target(target: &Path) -> io::Result<Vec<u8>> {
+    let target = target.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("symlink target {} is not UTF-8", target.display()),
+        )
+    })?;
+    Ok(target.replace('\\', "/").into_bytes())
+}
+
+/// Lists the paths under `root`, including `root` itself, with each directory's entries in
+/// order of their names and before their own contents.
+fn walk(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        if fs::symlink_metadata(&path)?.is_dir() {
+            let mut children = fs::read_dir(&path)?
+                .map(|child| child.map(|child| child.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            // Sorted in reverse, so that popping them visits them in order.
+            children.sort_by(|a, b| b.file_name().cmp(&a.file_name()));
+            pending.extend(children);
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cpio-tree-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_tree() {
+        let root = tempdir("write");
+        fs::create_dir(root.join("etc")).unwrap();
+        fs::write(root.join("etc/hostname"), "box\n").unwrap();
+        fs::write(root.join("init"), "#!/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink("etc/hostname", root.join("hostname")).unwrap();
+
+        let (output, summary) = TreeArchiver::new(&root).write(vec![]).unwrap();
+        assert_eq!(summary.entries(), 4);
+        let archive = Archive::from_bytes(&output).unwrap();
+        let names: Vec<_> = archive.entries().iter().map(|e| e.name()).collect();
+        assert_eq!(names, ["etc", "etc/hostname", "hostname", "init"]);
+        assert_eq!(archive.get("etc/hostname").unwrap().data(), b"box\n");
+        let link = archive.get("hostname").unwrap();
+        assert_eq!(
+            ModeFileType::from_mode(link.entry().mode()),
+            Some(ModeFileType::Symlink)
+        );
+        assert_eq!(link.data(), b"etc/hostname");
+
+        // Feeding the same walk in gives the same archive.
+        let paths = walk(&root).unwrap().into_iter().map(Ok::<_, io::Error>);
+        let (again, _) = TreeArchiver::new(&root)
+            .write_entries(paths, vec![])
+            .unwrap();
+        assert_eq!(again, output);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links() {
+        let root = tempdir("links");
+        let outside = tempdir("links-outside");
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("a"), "shared").unwrap();
+        fs::hard_link(root.join("a"), root.join("dir/b")).unwrap();
+        // A third link outside the tree doesn't count.
+        fs::hard_link(root.join("a"), outside.join("c")).unwrap();
+        fs::write(root.join("d"), "alone").unwrap();
+        // Links to an empty file are written in place, not held back to the end.
+        fs::write(root.join("e"), "").unwrap();
+        fs::hard_link(root.join("e"), root.join("f")).unwrap();
+
+        let (output, _) = TreeArchiver::new(&root).write(vec![]).unwrap();
+        assert!(crate::lint::check_hardlinks(&output[..])
+            .unwrap()
+            .is_empty());
+        let archive = Archive::from_bytes(&output).unwrap();
+        let names: Vec<_> = archive.entries().iter().map(|e| e.name()).collect();
+        assert_eq!(names, ["a", "d", "dir", "dir/b", "e", "f"]);
+        let (a, b, d) = (
+            archive.get("a").unwrap(),
+            archive.get("dir/b").unwrap(),
+            archive.get("d").unwrap(),
+        );
+        assert_eq!(a.entry().ino(), b.entry().ino());
+        assert_ne!(a.entry().ino(), d.entry().ino());
+        assert_eq!((a.entry().nlink(), b.entry().nlink()), (2, 2));
+        assert_eq!(a.data(), b"shared");
+        assert_eq!(b.data(), b"");
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_portable() {
+        let root = tempdir("portable");
+        fs::create_dir(root.join("etc")).unwrap();
+        fs::write(root.join("etc/motd"), "hi").unwrap();
+        let mut permissions = fs::metadata(root.join("etc/motd")).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(root.join("etc/motd"), permissions).unwrap();
+
+        let defaults = MetadataDefaults::new().gid(10).mtime(Some(1));
+        let (output, _) = TreeArchiver::new(&root)
+            .portable(Some(defaults))
+            .write(vec![])
+            .unwrap();
+        let archive = Archive::from_bytes(&output).unwrap();
+        let dir = archive.get("etc").unwrap().entry();
+        assert_eq!(dir.mode(), 0o040755);
+        let motd = archive.get("etc/motd").unwrap().entry();
+        assert_eq!(motd.mode(), 0o100444);
+        assert_eq!((motd.uid(), motd.gid(), motd.mtime()), (0, 10, 1));
+        assert_eq!((motd.ino(), motd.nlink()), (1, 1));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_metadata_db() {
+        use crate::extract::Extractor;
+
+        let root = tempdir("fakeroot");
+        let db_path = root.with_extension("db");
+        let (archive, _) = crate::write_cpio_bytes(
+            vec![
+                (Builder::new("dev").mode(0o040755), &b""[..]),
+                (
+                    Builder::new("dev/console")
+                        .mode(0o020600)
+                        .gid(5)
+                        .rdev_major(5)
+                        .rdev_minor(1),
+                    b"",
+                ),
+                (Builder::new("etc").mode(0o040755).uid(1234), b""),
+            ],
+            vec![],
+        )
+        .unwrap();
+        Extractor::new(&root)
+            .owner_map(OwnerMap::new().uid(Some(0)))
+            .metadata_db(&db_path)
+            .extract(archive.as_slice())
+            .unwrap();
+        let db = MetadataDb::open(&db_path).unwrap();
+        assert!(db.get("dev/console").is_some());
+        assert!(fs::metadata(root.join("dev/console")).unwrap().is_file());
+
+        let (output, _) = TreeArchiver::new(&root)
+            .metadata_db(db)
+            .write(vec![])
+            .unwrap();
+        let archive = Archive::from_bytes(&output).unwrap();
+        let console = archive.get("dev/console").unwrap().entry();
+        assert_eq!(console.mode(), 0o020600);
+        assert_eq!((console.uid(), console.gid()), (0, 5));
+        assert_eq!((console.rdev_major(), console.rdev_minor()), (5, 1));
+        assert_eq!(console.file_size(), 0);
+        // Whether etc could be given to root depends on who runs the test, but either way it
+        // comes back owned by root.
+        assert_eq!(archive.get("etc").unwrap().entry().uid(), 0);
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn test_outside_root() {
+        let root = tempdir("outside");
+        let err = TreeArchiver::new(&root)
+            .input(&std::env::temp_dir())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(feature = "walkdir")]
+    #[test]
+    fn test_walkdir() {
+        let root = tempdir("walkdir");
+        fs::create_dir(root.join("bin")).unwrap();
+        fs::write(root.join("bin/sh"), "sh").unwrap();
+
+        let walk = walkdir::WalkDir::new(&root).sort_by_file_name();
+        let (output, _) = TreeArchiver::new(&root)
+            .write_entries(walk, vec![])
+            .unwrap();
+        let (expected, _) = TreeArchiver::new(&root).write(vec![]).unwrap();
+        assert_eq!(output, expected);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
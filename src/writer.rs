@@ -0,0 +1,1815 @@
+//! High-level writer for whole archives.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::mem;
+
+use crate::diagnostics::{Diagnostics, Warning};
+use crate::name::{NamePolicy, PortableNames};
+use crate::newc::{self, Builder, ModeFileType};
+use crate::owner::OwnerMap;
+use crate::progress::{Observer, Progress, ProgressReader};
+
+/// Default size of the [`ArchiveWriter`] output buffer.
+const DEFAULT_BUF_SIZE: usize = 64 * 1024;
+
+/// Converts the length of an input into an entry file size, which the format limits to 32 bits.
+pub(crate) fn entry_len(len: u64) -> io::Result<u32> {
+    u32::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "input exceeds 4 GiB"))
+}
+
+/// Counts the bytes written through it.
+struct Counter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// How [`ArchiveWriter::finish`] ends an archive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Trailer {
+    /// Write a trailer with the `newc` magic, as most tools do.
+    #[default]
+    Newc,
+    /// Write a trailer with the `crc` magic.
+    Crc,
+    /// Write a trailer with the `crc` magic if every entry appended was in the `crc` format,
+    /// so that the whole archive uses one magic, and with the `newc` magic otherwise.
+    Matching,
+    /// Write no trailer, leaving an archive segment that is only valid once something ending
+    /// in a trailer is concatenated after it.
+    Omit,
+}
+
+/// What [`ArchiveWriter`] does on finding two entries with the same device and inode numbers
+/// that don't look like hard links to one file.
+///
+/// Entries sharing an inode look like hard links if both have a link count above one, they
+/// have the same mode, and, where both carry data, it is the same size (and has the same
+/// checksum, where both have one). Extractors such as GNU cpio group entries by inode alone,
+/// so anything else would be silently linked together on extraction.
+#[derive(Clone, Debug, Default)]
+pub enum InodeCollisions {
+    /// Don't check inode numbers.
+    #[default]
+    Ignore,
+    /// Record a [`Warning::InodeCollision`] in the sink for each collision, and write the
+    /// entry anyway.
+    Warn(Diagnostics),
+    /// Fail to append the entry with an `InvalidInput` error.
+    Error,
+}
+
+/// What [`ArchiveWriter`] does on finding a name that may not unpack as it is on other
+/// systems, as checked by [`PortableNames`].
+#[derive(Clone, Debug, Default)]
+pub enum NonPortableNames {
+    /// Don't check names.
+    #[default]
+    Ignore,
+    /// Record a [`Warning::NonPortableName`] in the sink for each problem, and write the
+    /// entry anyway.
+    Warn(Diagnostics),
+    /// Fail to append the entry with an `InvalidInput` error.
+    Error,
+}
+
+/// What [`ArchiveWriter::finish`] does when a preallocated archive turns out to be a different
+/// size than was predicted.
+#[derive(Clone, Debug, Default)]
+pub enum SizeMismatch {
+    /// Finish the archive as usual.
+    #[default]
+    Ignore,
+    /// Record a [`Warning::SizeMismatch`] in the sink, and finish the archive as usual.
+    Warn(Diagnostics),
+    /// Fail with an `InvalidInput` error once the archive is written.
+    Error,
+}
+
+/// The space reserved in the output by [`ArchiveWriter::preallocate`].
+struct Preallocation<W> {
+    /// The predicted length of the whole archive.
+    predicted: u64,
+    /// The position in the output at which the archive starts.
+    start: u64,
+    on_mismatch: SizeMismatch,
+    /// Sets the length of the output, to trim the space reserved past a short archive.
+    set_len: fn(&W, u64) -> io::Result<()>,
+}
+
+impl<W> Preallocation<W> {
+    /// Trims the output to the `actual` length of the archive, and handles any difference
+    /// from the prediction.
+    fn finish(self, output: &W, actual: u64) -> io::Result<()> {
+        if actual < self.predicted {
+            (self.set_len)(output, self.start + actual)?;
+        }
+        if actual == self.predicted {
+            return Ok(());
+        }
+        match self.on_mismatch {
+            SizeMismatch::Ignore => Ok(()),
+            SizeMismatch::Warn(diagnostics) => {
+                diagnostics.push(Warning::SizeMismatch {
+                    predicted: self.predicted,
+                    actual,
+                });
+                Ok(())
+            }
+            SizeMismatch::Error => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "archive is {} bytes, but {} were preallocated",
+                    actual, self.predicted
+                ),
+            )),
+        }
+    }
+}
+
+/// Reserves disk space for `len` bytes at `start` in `file`, extending it to at least
+/// `start + len` bytes.
+///
+/// On Linux, the blocks are allocated with `fallocate`, so writing into them cannot run out of
+/// space; elsewhere, and where the kernel or filesystem refuses it as unsupported, the file is
+/// only extended.
+fn reserve(file: &File, start: u64, len: u64) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if len > 0 {
+        use std::os::unix::io::AsRawFd;
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                0,
+                start as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        let unsupported = [libc::EOPNOTSUPP, libc::ENOSYS, libc::EXDEV, libc::EINVAL];
+        if !err
+            .raw_os_error()
+            .map_or(false, |errno| unsupported.contains(&errno))
+        {
+            return Err(err);
+        }
+    }
+    if file.metadata()?.len() < start + len {
+        file.set_len(start + len)?;
+    }
+    Ok(())
+}
+
+/// Which of the entries sharing an inode [`ArchiveWriter`] writes the file's data with.
+///
+/// Archives store the data of a file with several hard links once. GNU cpio writes it with
+/// the last link, and extractors that wait for every link of a file before creating it expect
+/// that; others, including libarchive in some modes, expect it with the first.
+///
+/// Unless data is written as given, only one link to each file may be appended with data;
+/// appending data with a second fails with an `InvalidInput` error, and writes nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HardLinkData {
+    /// Write every entry with the data it is appended with.
+    #[default]
+    AsGiven,
+    /// Write the data with the first link of each file to be written. Links appended without
+    /// data before the one with it are held back and written straight after it, or once as
+    /// many as its link count have been appended, if none has data.
+    First,
+    /// Write the data with the last link of each file, holding back its links, and its data
+    /// in memory, until as many as its link count have been appended. The links of files with
+    /// some missing are written when the archive is finished.
+    Last,
+}
+
+/// The links of a file appended so far, as grouped for [`HardLinkData::First`] and
+/// [`HardLinkData::Last`].
+struct LinkGroup {
+    /// The links held back, and whether each is in the `crc` format.
+    pending: Vec<(Builder, bool)>,
+    /// The number of links still to be appended, going by the link count.
+    remaining: u32,
+    /// The data of the file, held for writing with its last link.
+    data: Option<Vec<u8>>,
+    /// Whether the data has been written.
+    written: bool,
+}
+
+/// The first entry written with an inode, as remembered for collision checks.
+struct Inode {
+    name: String,
+    mode: u32,
+    nlink: u32,
+    size: u32,
+    checksum: Option<u32>,
+}
+
+impl Inode {
+    /// Returns true if `other` could be another link to this inode.
+    fn links(&self, other: &Inode) -> bool {
+        let data = self.size != 0 && other.size != 0;
+        self.nlink > 1
+            && other.nlink > 1
+            && self.mode == other.mode
+            && !(data && self.size != other.size)
+            && !(data
+                && self.checksum.is_some()
+                && other.checksum.is_some()
+                && self.checksum != other.checksum)
+    }
+}
+
+/// An input that could not be archived in full, as recorded in the [`Summary`] by
+/// [`write_cpio_lazy_with_policy`](crate::write_cpio_lazy_with_policy).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputFailure {
+    pub(crate) index: usize,
+    pub(crate) name: String,
+    pub(crate) kind: io::ErrorKind,
+    pub(crate) message: String,
+    pub(crate) partial: bool,
+}
+
+impl InputFailure {
+    /// Returns the position of the input among the inputs.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the name of the input's entry.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the kind of the error the input failed with.
+    pub fn kind(&self) -> io::ErrorKind {
+        self.kind
+    }
+
+    /// Returns the message of the error the input failed with.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns true if the input failed partway through being read, once its header had been
+    /// written, so that the rest of its data was written as zeros.
+    pub fn partial(&self) -> bool {
+        self.partial
+    }
+}
+
+impl fmt::Display for InputFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "input {} ({}): {}", self.index, self.name, self.message)
+    }
+}
+
+/// What [`write_cpio_lazy_with_policy`](crate::write_cpio_lazy_with_policy) does with an input
+/// that fails to open or to be read.
+///
+/// An input that fails partway through being read has already had its header, and so its size,
+/// written, so it can't be left out: only [`SubstituteEmpty`](Self::SubstituteEmpty) carries
+/// on past it, writing the rest of its data as zeros and marking the failure
+/// [`partial`](InputFailure::partial). The other policies stop the archive with its error.
+#[derive(Default)]
+pub enum InputErrors<'a> {
+    /// Stop at the first input that fails, returning its error.
+    #[default]
+    FailFast,
+    /// Leave out inputs that fail to open, passing each failure to the callback, and carry on.
+    /// An input that fails once it has started to be read stops the archive.
+    Skip(Box<dyn FnMut(&InputFailure) + 'a>),
+    /// Write inputs that fail to open as entries without data, and carry on.
+    SubstituteEmpty,
+}
+
+/// Passes through the data of an input until it fails, then makes up the rest of its `len`
+/// bytes with zeros, keeping the error.
+pub(crate) struct ZeroFill<'e, R> {
+    pub(crate) inner: R,
+    pub(crate) remaining: u64,
+    pub(crate) error: &'e mut Option<io::Error>,
+}
+
+impl<R: Read> Read for ZeroFill<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let buf = &mut buf[..len];
+        if len == 0 {
+            return Ok(0);
+        }
+        if self.error.is_none() {
+            match self.inner.read(buf) {
+                Ok(0) => {
+                    *self.error = Some(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "input ended before the specified file size",
+                    ))
+                }
+                Ok(n) => {
+                    self.remaining -= n as u64;
+                    return Ok(n);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => return Err(err),
+                Err(err) => *self.error = Some(err),
+            }
+        }
+        for byte in buf.iter_mut() {
+            *byte = 0;
+        }
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+/// Statistics about a written archive.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub(crate) entries: u64,
+    pub(crate) data_bytes: u64,
+    pub(crate) archive_bytes: u64,
+    pub(crate) offsets: Option<Vec<u64>>,
+    pub(crate) failures: Vec<InputFailure>,
+}
+
+impl Summary {
+    /// Returns the number of entries written, not counting the trailer.
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Returns the total size of the file data of all entries, in bytes.
+    pub fn data_bytes(&self) -> u64 {
+        self.data_bytes
+    }
+
+    /// Returns the total size of the archive, including headers, padding, and the trailer, in
+    /// bytes.
+    pub fn archive_bytes(&self) -> u64 {
+        self.archive_bytes
+    }
+
+    /// Returns the offset of each entry's header from the start of the archive, if offset
+    /// tracking was enabled with [`ArchiveWriter::track_offsets`] (or the archive was written
+    /// with positioned writes, which always records them).
+    pub fn offsets(&self) -> Option<&[u64]> {
+        self.offsets.as_deref()
+    }
+
+    /// Returns the inputs that could not be archived in full, in input order, if the archive
+    /// was written with an [`InputErrors`] policy that carries on past them.
+    pub fn failures(&self) -> &[InputFailure] {
+        &self.failures
+    }
+}
+
+/// The state of an [`ArchiveWriter`] between entries, from which writing can resume after a
+/// crash.
+///
+/// Take one with [`ArchiveWriter::checkpoint`] and persist it with
+/// [`write_to`](Self::write_to). To resume, truncate the output to
+/// [`position`](Self::position), reopen it positioned at its end, pass it to
+/// [`ArchiveWriter::resume`] along with the checkpoint read back with
+/// [`read_from`](Self::read_from), and carry on appending from input number
+/// [`entries`](Self::entries), given the same ordering of inputs.
+///
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// use std::fs::{File, OpenOptions};
+/// use cpio::writer::Checkpoint;
+/// use cpio::ArchiveWriter;
+///
+/// let checkpoint = Checkpoint::read_from(File::open("image.cpio.checkpoint")?)?;
+/// let output = OpenOptions::new().append(true).open("image.cpio")?;
+/// output.set_len(checkpoint.position())?;
+/// let mut archive = ArchiveWriter::resume(output, &checkpoint);
+/// // ... append the inputs from `checkpoint.entries()` on ...
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    summary: Summary,
+    position: u64,
+}
+
+impl Checkpoint {
+    /// Returns the number of entries written before the checkpoint.
+    pub fn entries(&self) -> u64 {
+        self.summary.entries
+    }
+
+    /// Returns the length of the output at the checkpoint, in bytes.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns the statistics for the entries written before the checkpoint.
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// Writes the checkpoint out as a few lines of text.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "entries {}", self.summary.entries)?;
+        writeln!(writer, "data_bytes {}", self.summary.data_bytes)?;
+        writeln!(writer, "position {}", self.position)?;
+        if let Some(ref offsets) = self.summary.offsets {
+            write!(writer, "offsets")?;
+            for offset in offsets {
+                write!(writer, " {}", offset)?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+
+    /// Reads back a checkpoint written by [`write_to`](Self::write_to).
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Checkpoint> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid checkpoint");
+        let parse = |value: &str| value.parse::<u64>().map_err(|_| invalid());
+        let (mut entries, mut data_bytes, mut position) = (None, None, None);
+        let mut offsets = None;
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            let mut fields = line.split(' ');
+            match fields.next() {
+                Some("entries") => entries = Some(parse(fields.next().ok_or_else(invalid)?)?),
+                Some("data_bytes") => data_bytes = Some(parse(fields.next().ok_or_else(invalid)?)?),
+                Some("position") => position = Some(parse(fields.next().ok_or_else(invalid)?)?),
+                Some("offsets") => offsets = Some(fields.map(parse).collect::<Result<_, _>>()?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(Checkpoint {
+            summary: Summary {
+                entries: entries.ok_or_else(invalid)?,
+                data_bytes: data_bytes.ok_or_else(invalid)?,
+                archive_bytes: 0,
+                offsets,
+                failures: vec![],
+            },
+            position: position.ok_or_else(invalid)?,
+        })
+    }
+}
+
+/// Writes a sequence of entries followed by a trailer into an archive.
+///
+/// Output is buffered internally, so the header, name, data, and padding of many small
+/// entries are coalesced into a few large writes to the underlying sink.
+pub struct ArchiveWriter<W: Write> {
+    inner: BufWriter<Counter<W>>,
+    summary: Summary,
+    observer: Option<Box<dyn Observer>>,
+    buffers: newc::Buffers,
+    name_policy: NamePolicy,
+    owner_map: OwnerMap,
+    trailer: Trailer,
+    newc_entries: bool,
+    align: u64,
+    inode_collisions: InodeCollisions,
+    inodes: HashMap<(u32, u32, u32), Inode>,
+    non_portable_names: NonPortableNames,
+    portable_names: PortableNames,
+    hard_link_data: HardLinkData,
+    link_groups: Vec<LinkGroup>,
+    link_index: HashMap<(u32, u32, u32), usize>,
+    preallocated: Option<Preallocation<W>>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Creates a new archive writer with a 64 KiB output buffer.
+    pub fn new(inner: W) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    /// Creates an archive writer that carries on from `checkpoint`, as though the entries
+    /// written before it had been appended through it.
+    ///
+    /// `inner` must be positioned just after those entries, at [`Checkpoint::position`], with
+    /// anything that was written after the checkpoint discarded. Offset tracking is enabled if
+    /// it was when the checkpoint was taken.
+    pub fn resume(inner: W, checkpoint: &Checkpoint) -> Self {
+        let mut archive = Self::new(inner);
+        archive.inner.get_mut().count = checkpoint.position;
+        archive.summary = checkpoint.summary.clone();
+        archive
+    }
+
+    /// Creates a new archive writer with an output buffer of `capacity` bytes.
+    pub fn with_capacity(capacity: usize, inner: W) -> Self {
+        Self {
+            inner: BufWriter::with_capacity(capacity, Counter { inner, count: 0 }),
+            summary: Summary::default(),
+            observer: None,
+            // Only the header buffer is used; data is copied through the output buffer.
+            buffers: newc::Buffers::with_copy_size(0),
+            name_policy: NamePolicy::default(),
+            owner_map: OwnerMap::default(),
+            trailer: Trailer::default(),
+            newc_entries: false,
+            align: 1,
+            inode_collisions: InodeCollisions::default(),
+            inodes: HashMap::new(),
+            non_portable_names: NonPortableNames::default(),
+            portable_names: PortableNames::default(),
+            hard_link_data: HardLinkData::default(),
+            link_groups: vec![],
+            link_index: HashMap::new(),
+            preallocated: None,
+        }
+    }
+
+    /// Copy entry data from readers in chunks of `size` bytes, instead of the 8 KiB chunks of
+    /// `io::copy`. Larger chunks mean fewer, larger reads from each input; a `size` of 0
+    /// restores the default.
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.buffers = newc::Buffers::with_copy_size(size);
+        self
+    }
+
+    /// Record the offset of every entry in the [`Summary`].
+    pub fn track_offsets(mut self, track: bool) -> Self {
+        self.summary.offsets = if track { Some(vec![]) } else { None };
+        self
+    }
+
+    /// Report progress to `observer` as entries are written.
+    pub fn progress<O: Observer + 'static>(mut self, observer: O) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Apply `policy` to the name of every entry appended, failing with an `InvalidInput`
+    /// error for any name it rejects.
+    pub fn name_policy(mut self, policy: NamePolicy) -> Self {
+        self.name_policy = policy;
+        self
+    }
+
+    /// Pass the owner of every entry appended through `map`.
+    pub fn owner_map(mut self, map: OwnerMap) -> Self {
+        self.owner_map = map;
+        self
+    }
+
+    /// End the archive as `trailer` describes when it is finished.
+    ///
+    /// With [`Trailer::Matching`], only entries appended through this writer count: after
+    /// [`resume`](Self::resume), the entries written before the checkpoint are assumed to have
+    /// matched.
+    pub fn trailer(mut self, trailer: Trailer) -> Self {
+        self.trailer = trailer;
+        self
+    }
+
+    /// Pad the finished archive with zeros to a multiple of `alignment` bytes, such as the 512
+    /// bytes of a block or the 4 KiB of a page that bootloaders and kernels expect concatenated
+    /// segments to be aligned to.
+    ///
+    /// `alignment` must be a power of two; [`finish`](Self::finish) fails with an
+    /// `InvalidInput` error otherwise. The padding follows the trailer, or the last entry if it
+    /// is [omitted](Trailer::Omit), and is counted in [`Summary::archive_bytes`].
+    pub fn align(mut self, alignment: u64) -> Self {
+        self.align = alignment;
+        self
+    }
+
+    /// Check every entry appended against the earlier entries with the same device and inode
+    /// numbers, handling those that aren't hard links to each other as `policy` says.
+    ///
+    /// Entries written before a [`resume`](Self::resume) aren't checked against.
+    pub fn inode_collisions(mut self, policy: InodeCollisions) -> Self {
+        self.inode_collisions = policy;
+        self
+    }
+
+    /// Write the data of files with several hard links with the link that `placement` says,
+    /// whichever link it was appended with.
+    ///
+    /// Entries other than directories that have a link count above one are grouped by their
+    /// device and inode numbers. Links that are held back are only counted in the
+    /// [`summary`](Self::summary), and any error in writing them returned, once they are
+    /// written.
+    pub fn hard_link_data(mut self, placement: HardLinkData) -> Self {
+        self.hard_link_data = placement;
+        self
+    }
+
+    /// Returns a reference to the underlying sink.
+    pub fn get_ref(&self) -> &W {
+        &self.inner.get_ref().inner
+    }
+
+    /// Returns the number of bytes of archive written so far, including any still buffered.
+    pub fn position(&self) -> u64 {
+        self.inner.get_ref().count + self.inner.buffer().len() as u64
+    }
+
+    /// Returns the statistics for the entries written so far.
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    /// Flushes all buffered output and returns the state to resume from if writing is
+    /// interrupted after this point.
+    ///
+    /// The checkpoint is only as durable as the output: sync the underlying sink to disk (for
+    /// example with [`File::sync_data`](std::fs::File::sync_data) through
+    /// [`get_ref`](Self::get_ref)) before persisting the checkpoint.
+    ///
+    /// Links held back by [`hard_link_data`](Self::hard_link_data) are not part of a
+    /// checkpoint, so checkpointing while any are held back fails with an `InvalidInput`
+    /// error; a writer resumed from it would never write them.
+    pub fn checkpoint(&mut self) -> io::Result<Checkpoint> {
+        if let Some(group) = self
+            .link_groups
+            .iter()
+            .find(|group| !group.pending.is_empty())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "cannot checkpoint while links to {} are held back",
+                    group.pending[0].0.name()
+                ),
+            ));
+        }
+        self.inner.flush()?;
+        trace_event!(
+            entries = self.summary.entries,
+            position = self.position(),
+            "checkpoint"
+        );
+        Ok(Checkpoint {
+            summary: self.summary.clone(),
+            position: self.position(),
+        })
+    }
+
+    /// Updates the summary for an entry of `len` bytes written at `position`, once all of it
+    /// has been written, so that a failed append isn't counted.
+    fn record_entry(&mut self, position: u64, len: u32, crc: bool) {
+        self.newc_entries |= !crc;
+        if let Some(ref mut offsets) = self.summary.offsets {
+            offsets.push(position);
+        }
+        self.summary.entries += 1;
+        self.summary.data_bytes += u64::from(len);
+    }
+
+    /// Set what to do with names that may not unpack as they are on other systems:
+    /// characters outside the POSIX portable filename character set, components longer than
+    /// `names` allows, and names that differ only in case from earlier ones. Names are
+    /// checked after the [`name_policy`](Self::name_policy) is applied.
+    pub fn non_portable_names(mut self, policy: NonPortableNames, names: PortableNames) -> Self {
+        self.non_portable_names = policy;
+        self.portable_names = names;
+        self
+    }
+
+    /// Checks the name of an entry about to be written for portability.
+    fn check_name(&mut self, builder: &Builder) -> io::Result<()> {
+        if let NonPortableNames::Ignore = self.non_portable_names {
+            return Ok(());
+        }
+        let mut problems = self.portable_names.check(builder.name()).into_iter();
+        match self.non_portable_names {
+            NonPortableNames::Warn(ref diagnostics) => {
+                for problem in problems {
+                    diagnostics.push(Warning::NonPortableName { problem });
+                }
+                Ok(())
+            }
+            _ => match problems.next() {
+                Some(problem) => Err(io::Error::new(io::ErrorKind::InvalidInput, problem)),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Checks the inode of an entry about to be written against those written before.
+    fn check_inode(
+        &mut self,
+        builder: &Builder,
+        len: u32,
+        checksum: Option<u32>,
+    ) -> io::Result<()> {
+        if let InodeCollisions::Ignore = self.inode_collisions {
+            return Ok(());
+        }
+        let entry = builder.to_entry(len, checksum);
+        let key = (entry.dev_major(), entry.dev_minor(), entry.ino());
+        let inode = Inode {
+            name: entry.name().to_string(),
+            mode: entry.mode(),
+            nlink: entry.nlink(),
+            size: len,
+            checksum,
+        };
+        let first = match self.inodes.get_mut(&key) {
+            Some(first) => first,
+            None => {
+                self.inodes.insert(key, inode);
+                return Ok(());
+            }
+        };
+        if first.links(&inode) {
+            // Hard links in `newc` carry their data once; remember it to compare with others.
+            if first.size == 0 {
+                first.size = inode.size;
+                first.checksum = inode.checksum;
+            }
+            return Ok(());
+        }
+        match self.inode_collisions {
+            InodeCollisions::Warn(ref diagnostics) => {
+                diagnostics.push(Warning::InodeCollision {
+                    name: inode.name,
+                    other: first.name.clone(),
+                });
+                Ok(())
+            }
+            _ => {
+                let used = format!("inode {} on device {}:{}", key.2, key.0, key.1);
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{}: {} is already used by {}, which is not a hard link to it",
+                        inode.name, used, first.name
+                    ),
+                ))
+            }
+        }
+    }
+
+    /// Returns the position in `link_groups` of the file that `builder` is a link to, if its
+    /// data is being moved to one of its links.
+    fn link_group(&mut self, builder: &Builder) -> Option<usize> {
+        if self.hard_link_data == HardLinkData::AsGiven {
+            return None;
+        }
+        let entry = builder.to_entry(0, None);
+        if entry.nlink() < 2
+            || ModeFileType::from_mode(entry.mode()) == Some(ModeFileType::Directory)
+        {
+            return None;
+        }
+        let key = (entry.dev_major(), entry.dev_minor(), entry.ino());
+        let groups = &mut self.link_groups;
+        let idx = *self.link_index.entry(key).or_insert_with(|| {
+            groups.push(LinkGroup {
+                pending: vec![],
+                remaining: entry.nlink(),
+                data: None,
+                written: false,
+            });
+            groups.len() - 1
+        });
+        Some(idx)
+    }
+
+    /// Returns true if the data of the file that `builder` is a link to has already been
+    /// appended with another of its links, so that this one must be appended without it.
+    pub(crate) fn link_has_data(&self, builder: &Builder) -> bool {
+        if self.hard_link_data == HardLinkData::AsGiven {
+            return false;
+        }
+        let entry = builder.to_entry(0, None);
+        let key = (entry.dev_major(), entry.dev_minor(), entry.ino());
+        self.link_index.get(&key).map_or(false, |&idx| {
+            let group = &self.link_groups[idx];
+            group.written || group.data.is_some()
+        })
+    }
+
+    /// Appends a link to the file at `idx` in `link_groups`, moving the data to the link
+    /// chosen with [`hard_link_data`](Self::hard_link_data).
+    fn append_link<R: Read>(
+        &mut self,
+        idx: usize,
+        builder: Builder,
+        len: u32,
+        checksum: Option<u32>,
+        data: R,
+    ) -> io::Result<()> {
+        let crc = checksum.is_some();
+        let group = &mut self.link_groups[idx];
+        if len != 0 && (group.written || group.data.is_some()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{}: data was already appended with another link to this file",
+                    builder.name()
+                ),
+            ));
+        }
+        group.remaining = group.remaining.saturating_sub(1);
+        if group.written {
+            return self.write_entry(builder, 0, checksum.map(|_| 0), io::empty());
+        }
+        match self.hard_link_data {
+            HardLinkData::First if len != 0 => {
+                group.written = true;
+                self.write_entry(builder, len, checksum, data)?;
+                self.write_links(idx)
+            }
+            HardLinkData::First => {
+                group.pending.push((builder, crc));
+                if group.remaining == 0 {
+                    self.write_links(idx)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => {
+                if len != 0 {
+                    let mut buf = Vec::with_capacity(len as usize);
+                    data.take(len.into()).read_to_end(&mut buf)?;
+                    if buf.len() != len as usize {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "input ended before the specified file size",
+                        ));
+                    }
+                    group.data = Some(buf);
+                }
+                group.pending.push((builder, crc));
+                if group.remaining == 0 {
+                    self.write_links(idx)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Writes the links held back for the file at `idx` in `link_groups`, the last of them
+    /// with the data held for it, if any.
+    fn write_links(&mut self, idx: usize) -> io::Result<()> {
+        let group = &mut self.link_groups[idx];
+        group.written = true;
+        let pending = mem::take(&mut group.pending);
+        let held = group.data.take().unwrap_or_default();
+        let last = pending.len().saturating_sub(1);
+        for (i, (builder, crc)) in pending.into_iter().enumerate() {
+            let data = if i == last { &held[..] } else { &[][..] };
+            let checksum = if crc {
+                Some(newc::checksum(data))
+            } else {
+                None
+            };
+            self.write_entry(builder, data.len() as u32, checksum, data)?;
+        }
+        Ok(())
+    }
+
+    /// Records an input that could not be archived in full in the summary.
+    pub(crate) fn record_failure(&mut self, failure: InputFailure) {
+        self.summary.failures.push(failure);
+    }
+
+    /// Reports the completion of the entry called `name` to the observer, if any.
+    fn finish_entry(&mut self, name: Option<String>) {
+        if let (Some(observer), Some(name)) = (self.observer.as_mut(), name) {
+            observer.update(&Progress {
+                entries: self.summary.entries,
+                bytes: self.summary.data_bytes,
+                name: &name,
+            });
+        }
+    }
+
+    /// Appends an entry with the metadata in `builder` whose data is the next `len` bytes of
+    /// `data`.
+    ///
+    /// Returns an `UnexpectedEof` error if `data` ends before `len` bytes have been read.
+    pub fn append<R: Read>(&mut self, builder: Builder, len: u32, data: R) -> io::Result<()> {
+        self.append_entry(builder, len, None, data)
+    }
+
+    /// Appends an entry with the metadata in `builder` whose data is held in memory.
+    ///
+    /// The data is written with a single `write_all`, without going through a copy loop.
+    pub fn append_bytes(&mut self, builder: Builder, data: &[u8]) -> io::Result<()> {
+        let len = entry_len(data.len() as u64)?;
+        if let Some(idx) = self.link_group(&builder) {
+            return self.append_link(idx, builder, len, None, data);
+        }
+        let builder = builder
+            .name_policy(&self.name_policy)?
+            .owner_map(&self.owner_map);
+        self.check_name(&builder)?;
+        self.check_inode(&builder, len, None)?;
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("append", name = builder.name(), size = len).entered();
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+        let name = self.observer.as_ref().map(|_| builder.name().to_string());
+        let position = self.position();
+        let mut fp = builder.write_with_buffers(&mut self.inner, len, None, &mut self.buffers);
+        fp.write_all(data)?;
+        fp.finish_with_buffers(&mut self.buffers)?;
+        self.record_entry(position, len, false);
+        self.finish_entry(name);
+        trace_event!(
+            path = "write_all",
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "wrote entry"
+        );
+        Ok(())
+    }
+
+    /// Appends an entry with the metadata in `builder` whose data is `len` zero bytes, such as
+    /// a placeholder to be patched in place once the archive is written.
+    ///
+    /// The zeros are generated as they are written, so no memory is needed for them.
+    pub fn append_zeros(&mut self, builder: Builder, len: u32) -> io::Result<()> {
+        self.append(builder, len, io::repeat(0))
+    }
+
+    /// Appends an entry in the "new crc" format with the metadata in `builder` whose data is the
+    /// next `len` bytes of `data`.
+    ///
+    /// `checksum` must be the checksum of that data, for example as computed by
+    /// [`newc::checksum`] or, for very large entries, [`newc::checksum_parallel`].
+    pub fn append_crc<R: Read>(
+        &mut self,
+        builder: Builder,
+        len: u32,
+        checksum: u32,
+        data: R,
+    ) -> io::Result<()> {
+        self.append_entry(builder, len, Some(checksum), data)
+    }
+
+    fn append_entry<R: Read>(
+        &mut self,
+        builder: Builder,
+        len: u32,
+        checksum: Option<u32>,
+        data: R,
+    ) -> io::Result<()> {
+        match self.link_group(&builder) {
+            Some(idx) => self.append_link(idx, builder, len, checksum, data),
+            None => self.write_entry(builder, len, checksum, data),
+        }
+    }
+
+    /// Writes an entry as it is given.
+    fn write_entry<R: Read>(
+        &mut self,
+        builder: Builder,
+        len: u32,
+        checksum: Option<u32>,
+        data: R,
+    ) -> io::Result<()> {
+        let builder = builder
+            .name_policy(&self.name_policy)?
+            .owner_map(&self.owner_map);
+        self.check_name(&builder)?;
+        self.check_inode(&builder, len, checksum)?;
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("append", name = builder.name(), size = len).entered();
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+        let name = self.observer.as_ref().map(|_| builder.name().to_string());
+        let progress = Progress {
+            entries: self.summary.entries,
+            bytes: self.summary.data_bytes,
+            name: name.as_deref().unwrap_or_default(),
+        };
+        let position = self.position();
+        let fp = builder.write_with_buffers(&mut self.inner, len, checksum, &mut self.buffers);
+        match self.observer {
+            Some(ref mut observer) => {
+                let data = ProgressReader {
+                    inner: data,
+                    observer: observer.as_mut(),
+                    progress,
+                };
+                copy_entry(fp, len, data, &mut self.buffers)?
+            }
+            None => copy_entry(fp, len, data, &mut self.buffers)?,
+        }
+        self.record_entry(position, len, checksum.is_some());
+        self.finish_entry(name);
+        trace_event!(
+            path = "io::copy",
+            crc = checksum.is_some(),
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "wrote entry"
+        );
+        Ok(())
+    }
+
+    /// Writes the trailer chosen with [`trailer`](Self::trailer), flushes all buffered output,
+    /// and returns the underlying sink along with the statistics for the whole archive.
+    ///
+    /// Any links still held back for [`hard_link_data`](Self::hard_link_data) are written
+    /// before the trailer.
+    pub fn finish(mut self) -> io::Result<(W, Summary)> {
+        if !self.align.is_power_of_two() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "archive alignment must be a power of two",
+            ));
+        }
+        for idx in 0..self.link_groups.len() {
+            if !self.link_groups[idx].written {
+                self.write_links(idx)?;
+            }
+        }
+        let mut summary = self.summary;
+        let crc = match self.trailer {
+            Trailer::Newc => Some(false),
+            Trailer::Crc => Some(true),
+            Trailer::Matching => Some(summary.entries > 0 && !self.newc_entries),
+            Trailer::Omit => None,
+        };
+        let mut inner = match crc {
+            Some(false) => newc::trailer(self.inner)?,
+            Some(true) => newc::trailer_crc(self.inner)?,
+            None => self.inner,
+        };
+        let position = inner.get_ref().count + inner.buffer().len() as u64;
+        let padding = (self.align - position % self.align) % self.align;
+        io::copy(&mut io::repeat(0).take(padding), &mut inner)?;
+        inner.flush()?;
+        let counter = inner.into_inner().map_err(|err| err.into_error())?;
+        summary.archive_bytes = counter.count;
+        if let Some(preallocated) = self.preallocated {
+            preallocated.finish(&counter.inner, summary.archive_bytes)?;
+        }
+        Ok((counter.inner, summary))
+    }
+}
+
+impl ArchiveWriter<File> {
+    /// Reserves space in the output file for an archive of `len` bytes in all, as computed by
+    /// [`predicted_len`](crate::predicted_len), so that the file is laid out in one piece and
+    /// running out of disk space shows up before anything is written.
+    ///
+    /// Call this before appending anything, with the file positioned where the archive is to
+    /// start. The file is extended to hold the whole archive; if the archive ends up shorter,
+    /// [`finish`](Self::finish) trims the file back to its end. Any difference between `len`
+    /// and the length of the archive is handled as `on_mismatch` says.
+    pub fn preallocate(&mut self, len: u64, on_mismatch: SizeMismatch) -> io::Result<()> {
+        self.inner.flush()?;
+        let file = &self.inner.get_ref().inner;
+        let position = (&*file).stream_position()?;
+        reserve(file, position, len.saturating_sub(self.position()))?;
+        self.preallocated = Some(Preallocation {
+            predicted: len,
+            start: position.saturating_sub(self.position()),
+            on_mismatch,
+            set_len: File::set_len,
+        });
+        Ok(())
+    }
+}
+
+/// Copies exactly `len` bytes of `data` into the entry being written by `fp`.
+pub(crate) fn copy_entry<W: Write, R: Read>(
+    mut fp: newc::Writer<W>,
+    len: u32,
+    mut data: R,
+    buffers: &mut newc::Buffers,
+) -> io::Result<()> {
+    let copied = match buffers.copy_buffer() {
+        [] => io::copy(&mut (&mut data).take(len.into()), &mut fp)?,
+        buf => newc::copy_up_to(&mut data, &mut fp, len.into(), buf)?,
+    };
+    if copied != u64::from(len) {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "input ended before the specified file size",
+        ));
+    }
+    fp.finish_with_buffers(buffers)?;
+    Ok(())
+}
+
+/// A sink that copies everything written to it to several other sinks, for example to write
+/// an archive to disk while hashing and uploading it.
+///
+/// By default the first error from any sink fails the write, and every write after that.
+/// With [`tolerate_failures`](Self::tolerate_failures), a failed sink is dropped and writing
+/// carries on to the rest, failing only once every sink has failed. Either way the errors
+/// are kept, by the index of the sink they came from, in [`failures`](Self::failures).
+#[derive(Default)]
+pub struct MultiWriter<'a> {
+    sinks: Vec<Option<Box<dyn Write + 'a>>>,
+    failures: Vec<(usize, io::Error)>,
+    tolerate_failures: bool,
+}
+
+impl<'a> MultiWriter<'a> {
+    /// Creates a writer with no sinks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `sink` to the sinks written to. Sinks are numbered in the order they are added.
+    pub fn sink<W: Write + 'a>(mut self, sink: W) -> Self {
+        self.sinks.push(Some(Box::new(sink)));
+        self
+    }
+
+    /// Keep writing to the remaining sinks when one fails.
+    pub fn tolerate_failures(mut self, tolerate: bool) -> Self {
+        self.tolerate_failures = tolerate;
+        self
+    }
+
+    /// Returns the errors from the sinks that have failed, along with the index of each.
+    pub fn failures(&self) -> &[(usize, io::Error)] {
+        &self.failures
+    }
+
+    /// Runs `op` against every sink that has not failed, recording any that do, and returns
+    /// an error if the writer as a whole has failed.
+    fn each<F>(&mut self, mut op: F) -> io::Result<()>
+    where
+        F: FnMut(&mut dyn Write) -> io::Result<()>,
+    {
+        if !self.tolerate_failures && !self.failures.is_empty() {
+            return Err(self.error());
+        }
+        for (idx, slot) in self.sinks.iter_mut().enumerate() {
+            if let Some(ref mut sink) = *slot {
+                if let Err(err) = op(sink.as_mut()) {
+                    trace_event!(sink = idx, error = %err, "sink failed");
+                    self.failures.push((idx, err));
+                    *slot = None;
+                }
+            }
+        }
+        let failed = if self.tolerate_failures {
+            !self.sinks.is_empty() && self.sinks.iter().all(Option::is_none)
+        } else {
+            !self.failures.is_empty()
+        };
+        if failed {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    /// Describes the failures so far as a single error, of the same kind as the first.
+    fn error(&self) -> io::Error {
+        let kind = self
+            .failures
+            .first()
+            .map_or(io::ErrorKind::Other, |f| f.1.kind());
+        let mut message = format!(
+            "{} of {} sinks failed",
+            self.failures.len(),
+            self.sinks.len()
+        );
+        for (idx, err) in &self.failures {
+            message.push_str(&format!("; sink {}: {}", idx, err));
+        }
+        io::Error::new(kind, message)
+    }
+}
+
+impl<'a> Write for MultiWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.each(|sink| sink.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.each(|sink| sink.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NewcReader;
+
+    /// Counts the number of `write` calls made against it.
+    struct CountingSink {
+        data: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Write for CountingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_coalesces_small_entries() {
+        let sink = CountingSink {
+            data: vec![],
+            writes: 0,
+        };
+        let mut archive = ArchiveWriter::new(sink);
+        for idx in 0..100 {
+            let data = format!("file {}", idx);
+            let builder = Builder::new(&format!("./file{}", idx)).ino(idx);
+            archive
+                .append(builder, data.len() as u32, data.as_bytes())
+                .unwrap();
+        }
+        let (sink, summary) = archive.finish().unwrap();
+        assert_eq!(sink.writes, 1);
+        assert_eq!(summary.entries(), 100);
+        assert_eq!(summary.archive_bytes(), sink.data.len() as u64);
+
+        let mut handle = sink.data.as_slice();
+        for idx in 0..100 {
+            let reader = NewcReader::new(handle).unwrap();
+            assert_eq!(reader.entry().name(), format!("./file{}", idx));
+            handle = reader.finish().unwrap();
+        }
+        assert!(NewcReader::new(handle).unwrap().entry().is_trailer());
+    }
+
+    #[test]
+    fn test_short_input() {
+        let mut archive = ArchiveWriter::new(vec![]);
+        let err = archive
+            .append(Builder::new("./short"), 10, &b"abc"[..])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_append_crc() {
+        let data: &[u8] = b"Hello, World";
+        let sum = newc::checksum_parallel(data, 2).unwrap();
+        let mut archive = ArchiveWriter::new(vec![]);
+        archive
+            .append_crc(Builder::new("./hello_world"), data.len() as u32, sum, data)
+            .unwrap();
+        let (output, _) = archive.finish().unwrap();
+
+        let reader = NewcReader::new(output.as_slice()).unwrap();
+        assert_eq!(reader.entry().checksum(), Some(newc::checksum(data)));
+    }
+
+    #[test]
+    fn test_trailer() {
+        // Returns the magic of the trailer at the end of the archive, if there is one.
+        let magic = |trailer, crc: &[bool]| {
+            let mut archive = ArchiveWriter::new(vec![]).trailer(trailer);
+            for &crc in crc {
+                let data: &[u8] = b"Hello";
+                if crc {
+                    let sum = newc::checksum(data);
+                    archive
+                        .append_crc(Builder::new("./a"), 5, sum, data)
+                        .unwrap();
+                } else {
+                    archive.append_bytes(Builder::new("./a"), data).unwrap();
+                }
+            }
+            let (output, _) = archive.finish().unwrap();
+            let mut rest = output.as_slice();
+            for _ in crc {
+                rest = NewcReader::new(rest).unwrap().finish().unwrap();
+            }
+            if rest.is_empty() {
+                return None;
+            }
+            assert!(NewcReader::new(rest).unwrap().entry().is_trailer());
+            Some(String::from_utf8(rest[..6].to_vec()).unwrap())
+        };
+
+        assert_eq!(magic(Trailer::Newc, &[true]).unwrap(), "070701");
+        assert_eq!(magic(Trailer::Crc, &[false]).unwrap(), "070702");
+        assert_eq!(magic(Trailer::Matching, &[true, true]).unwrap(), "070702");
+        assert_eq!(magic(Trailer::Matching, &[true, false]).unwrap(), "070701");
+        assert_eq!(magic(Trailer::Matching, &[]).unwrap(), "070701");
+        assert_eq!(magic(Trailer::Omit, &[false, true]), None);
+    }
+
+    #[test]
+    fn test_align() {
+        for &alignment in &[4, 512, 1024, 4096] {
+            let mut archive = ArchiveWriter::new(vec![]).align(alignment);
+            archive.append_bytes(Builder::new("./a"), b"Hello").unwrap();
+            let (output, summary) = archive.finish().unwrap();
+            assert_eq!(output.len() as u64 % alignment, 0);
+            assert_eq!(summary.archive_bytes(), output.len() as u64);
+            let reader = NewcReader::new(output.as_slice()).unwrap();
+            let rest = reader.finish().unwrap();
+            assert!(NewcReader::new(rest).unwrap().entry().is_trailer());
+            assert!(rest[124..].iter().all(|&byte| byte == 0));
+        }
+
+        // Already aligned output is left alone.
+        let (unaligned, _) = ArchiveWriter::new(vec![]).finish().unwrap();
+        let (aligned, _) = ArchiveWriter::new(vec![]).align(4).finish().unwrap();
+        assert_eq!(aligned, unaligned);
+
+        let err = ArchiveWriter::new(vec![]).align(1000).finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = ArchiveWriter::new(vec![]).align(0).finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_append_zeros() {
+        let mut archive = ArchiveWriter::new(vec![]);
+        archive
+            .append_zeros(Builder::new("./slot").mode(0o100644), 100_003)
+            .unwrap();
+        let (output, summary) = archive.finish().unwrap();
+        assert_eq!(summary.data_bytes(), 100_003);
+
+        let mut reader = NewcReader::new(output.as_slice()).unwrap();
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 100_003);
+        assert!(data.iter().all(|&byte| byte == 0));
+        assert!(NewcReader::new(reader.finish().unwrap())
+            .unwrap()
+            .entry()
+            .is_trailer());
+    }
+
+    #[test]
+    fn test_non_portable_names() {
+        let diagnostics = Diagnostics::new();
+        let policy = NonPortableNames::Warn(diagnostics.clone());
+        let mut archive =
+            ArchiveWriter::new(vec![]).non_portable_names(policy, PortableNames::new());
+        archive.append_bytes(Builder::new("./README"), b"").unwrap();
+        archive.append_bytes(Builder::new("./readme"), b"").unwrap();
+        archive.append_bytes(Builder::new("./a b"), b"").unwrap();
+        assert_eq!(archive.summary().entries(), 3);
+        let warnings = diagnostics.take();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].name(), "./readme");
+        assert_eq!(warnings[1].name(), "./a b");
+
+        let names = PortableNames::new().max_component(8);
+        let mut archive =
+            ArchiveWriter::new(vec![]).non_portable_names(NonPortableNames::Error, names);
+        archive.append_bytes(Builder::new("./short"), b"").unwrap();
+        let err = archive
+            .append(Builder::new("./much-too-long"), 0, io::empty())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(archive.summary().entries(), 1);
+    }
+
+    #[test]
+    fn test_inode_collisions() {
+        let append = |archive: &mut ArchiveWriter<Vec<u8>>, name, nlink, data: &[u8]| {
+            let builder = Builder::new(name).ino(7).mode(0o100644).nlink(nlink);
+            archive.append_bytes(builder, data)
+        };
+
+        // Hard links, with the data on the last link, pass.
+        let mut archive = ArchiveWriter::new(vec![]).inode_collisions(InodeCollisions::Error);
+        append(&mut archive, "./a", 2, b"").unwrap();
+        append(&mut archive, "./b", 2, b"Hello").unwrap();
+        // Another link with different data does not.
+        let err = append(&mut archive, "./c", 2, b"World!").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "./c: inode 7 on device 0:0 is already used by ./a, which is not a hard link to it"
+        );
+        // Nor does an entry with a single link.
+        assert!(append(&mut archive, "./d", 1, b"Hello").is_err());
+        // Other inodes are unaffected.
+        let builder = Builder::new("./e").ino(8).mode(0o100644);
+        archive.append_bytes(builder, b"Hello").unwrap();
+
+        let diagnostics = Diagnostics::new();
+        let policy = InodeCollisions::Warn(diagnostics.clone());
+        let mut archive = ArchiveWriter::new(vec![]).inode_collisions(policy);
+        append(&mut archive, "./a", 1, b"Hello").unwrap();
+        append(&mut archive, "./b", 1, b"Hello").unwrap();
+        assert_eq!(archive.summary().entries(), 2);
+        assert_eq!(
+            diagnostics.take(),
+            vec![Warning::InodeCollision {
+                name: "./b".to_string(),
+                other: "./a".to_string()
+            }]
+        );
+
+        // Nothing is checked by default.
+        let mut archive = ArchiveWriter::new(vec![]);
+        append(&mut archive, "./a", 1, b"Hello").unwrap();
+        append(&mut archive, "./b", 1, b"World!").unwrap();
+    }
+
+    #[test]
+    fn test_preallocate() {
+        let path = std::env::temp_dir().join(format!("cpio-preallocate-{}", std::process::id()));
+        let write = |predicted, on_mismatch| {
+            let file = File::create(&path).unwrap();
+            let mut archive = ArchiveWriter::new(file);
+            archive.preallocate(predicted, on_mismatch).unwrap();
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), predicted);
+            archive
+                .append_bytes(Builder::new("./a").mode(0o100644), b"Hello")
+                .unwrap();
+            archive.finish().map(|(_, summary)| summary.archive_bytes())
+        };
+
+        let predicted = crate::predicted_len(vec![("./a", 5)]);
+        assert_eq!(write(predicted, SizeMismatch::Error).unwrap(), predicted);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), predicted);
+
+        // Space reserved past the end of the archive is trimmed.
+        let diagnostics = Diagnostics::new();
+        let policy = SizeMismatch::Warn(diagnostics.clone());
+        write(predicted + 100, policy).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), predicted);
+        assert_eq!(
+            diagnostics.take(),
+            vec![Warning::SizeMismatch {
+                predicted: predicted + 100,
+                actual: predicted
+            }]
+        );
+
+        let err = write(predicted - 4, SizeMismatch::Error).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), predicted);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_hard_link_data() {
+        // Returns the names and sizes of the entries written, given the links to append.
+        let write = |placement, links: &[(&str, &[u8])]| {
+            let mut archive = ArchiveWriter::new(vec![]).hard_link_data(placement);
+            for &(name, data) in links {
+                let builder = Builder::new(name).ino(7).mode(0o100644).nlink(3);
+                archive.append_bytes(builder, data).unwrap();
+            }
+            let builder = Builder::new("./other").ino(8).mode(0o100644);
+            archive.append(builder, 2, &b"Hi"[..]).unwrap();
+            let (archive, summary) = archive.finish().unwrap();
+            assert_eq!(summary.entries(), links.len() as u64 + 1);
+            let mut entries = vec![];
+            let mut rest = archive.as_slice();
+            loop {
+                let mut reader = NewcReader::new(rest).unwrap();
+                if reader.entry().is_trailer() {
+                    return entries;
+                }
+                let mut data = vec![];
+                reader.read_to_end(&mut data).unwrap();
+                entries.push((reader.entry().name().to_string(), data));
+                rest = reader.finish().unwrap();
+            }
+        };
+        let entry = |name: &str, data: &[u8]| (name.to_string(), data.to_vec());
+        let links: &[(&str, &[u8])] = &[("./a", b""), ("./b", b"Hello"), ("./c", b"")];
+
+        assert_eq!(
+            write(HardLinkData::AsGiven, links),
+            vec![
+                entry("./a", b""),
+                entry("./b", b"Hello"),
+                entry("./c", b""),
+                entry("./other", b"Hi"),
+            ]
+        );
+        assert_eq!(
+            write(HardLinkData::First, links),
+            vec![
+                entry("./b", b"Hello"),
+                entry("./a", b""),
+                entry("./c", b""),
+                entry("./other", b"Hi"),
+            ]
+        );
+        assert_eq!(
+            write(HardLinkData::Last, links),
+            vec![
+                entry("./a", b""),
+                entry("./b", b""),
+                entry("./c", b"Hello"),
+                entry("./other", b"Hi"),
+            ]
+        );
+        // Links of a file with some missing are written when the archive is finished.
+        assert_eq!(
+            write(HardLinkData::Last, &links[..2]),
+            vec![
+                entry("./other", b"Hi"),
+                entry("./a", b""),
+                entry("./b", b"Hello"),
+            ]
+        );
+
+        // Data appended with a second link is refused rather than dropped.
+        for &placement in &[HardLinkData::First, HardLinkData::Last] {
+            let mut archive = ArchiveWriter::new(vec![]).hard_link_data(placement);
+            let link = |name| Builder::new(name).ino(7).mode(0o100644).nlink(2);
+            archive.append_bytes(link("./a"), b"Hello").unwrap();
+            let err = archive.append_bytes(link("./b"), b"World").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_concatenate_segments() {
+        let mut segment = ArchiveWriter::new(vec![]).trailer(Trailer::Omit);
+        segment.append_bytes(Builder::new("./a"), b"Hello").unwrap();
+        let (mut output, summary) = segment.finish().unwrap();
+        assert_eq!(summary.archive_bytes(), output.len() as u64);
+
+        let mut rest = ArchiveWriter::new(vec![]);
+        rest.append_bytes(Builder::new("./b"), b"World").unwrap();
+        output.extend(rest.finish().unwrap().0);
+
+        let reader = NewcReader::new(output.as_slice()).unwrap();
+        assert_eq!(reader.entry().name(), "./a");
+        let reader = NewcReader::new(reader.finish().unwrap()).unwrap();
+        assert_eq!(reader.entry().name(), "./b");
+        let reader = NewcReader::new(reader.finish().unwrap()).unwrap();
+        assert!(reader.entry().is_trailer());
+    }
+
+    #[test]
+    fn test_summary_offsets() {
+        let mut archive = ArchiveWriter::new(vec![]).track_offsets(true);
+        archive.append_bytes(Builder::new("./a"), b"Hello").unwrap();
+        archive
+            .append_bytes(Builder::new("./b"), b"World!")
+            .unwrap();
+        let (output, summary) = archive.finish().unwrap();
+        assert_eq!(summary.entries(), 2);
+        assert_eq!(summary.data_bytes(), 11);
+        assert_eq!(summary.archive_bytes(), output.len() as u64);
+
+        let offsets = summary.offsets().unwrap();
+        assert_eq!(offsets[0], 0);
+        let reader = NewcReader::new(&output[offsets[1] as usize..]).unwrap();
+        assert_eq!(reader.entry().name(), "./b");
+    }
+
+    #[test]
+    fn test_failed_append_not_counted() {
+        let mut archive = ArchiveWriter::new(vec![]).track_offsets(true);
+        archive.append_bytes(Builder::new("./a"), b"Hello").unwrap();
+        // The data ends before its length.
+        let err = archive
+            .append(Builder::new("./b"), 10, &b"short"[..])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(archive.summary().entries(), 1);
+        assert_eq!(archive.summary().data_bytes(), 5);
+        assert_eq!(archive.summary().offsets().unwrap(), [0]);
+    }
+
+    #[test]
+    fn test_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let updates = Arc::new(Mutex::new(vec![]));
+        let recorded = updates.clone();
+        let mut archive = ArchiveWriter::new(vec![]).progress(move |progress: &Progress| {
+            recorded.lock().unwrap().push((
+                progress.entries(),
+                progress.bytes(),
+                progress.name().to_string(),
+            ));
+        });
+        archive
+            .append(Builder::new("./a"), 5, &b"Hello"[..])
+            .unwrap();
+        archive
+            .append_bytes(Builder::new("./b"), b"World!")
+            .unwrap();
+        archive.finish().unwrap();
+
+        let updates = updates.lock().unwrap();
+        assert_eq!(
+            *updates,
+            vec![
+                (0, 5, "./a".to_string()),
+                (1, 5, "./a".to_string()),
+                (2, 11, "./b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_name_policy() {
+        use crate::name::Action;
+
+        let policy = NamePolicy::strict().absolute(Action::Normalize);
+        let mut archive = ArchiveWriter::new(vec![]).name_policy(policy);
+        archive.append_bytes(Builder::new("/etc"), b"").unwrap();
+        let err = archive
+            .append_bytes(Builder::new("../etc"), b"")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let (output, summary) = archive.finish().unwrap();
+        assert_eq!(summary.entries(), 1);
+
+        let reader = NewcReader::new(output.as_slice()).unwrap();
+        assert_eq!(reader.entry().name(), "etc");
+        let reader = NewcReader::with_name_policy(reader.finish().unwrap(), &policy).unwrap();
+        assert!(reader.entry().is_trailer());
+
+        let (output, _) =
+            crate::write_cpio_bytes(vec![(Builder::new("dir/"), &b""[..])], vec![]).unwrap();
+        let err = NewcReader::with_name_policy(output.as_slice(), &policy)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let reader =
+            NewcReader::with_name_policy(output.as_slice(), &NamePolicy::normalizing()).unwrap();
+        assert_eq!(reader.entry().name(), "dir");
+    }
+
+    #[test]
+    fn test_owner_map() {
+        let mut archive = ArchiveWriter::new(vec![]).owner_map(OwnerMap::root());
+        archive
+            .append_bytes(Builder::new("a").uid(1000).gid(1000), b"")
+            .unwrap();
+        archive
+            .append(Builder::new("b").uid(1000).gid(1000), 1, &b"b"[..])
+            .unwrap();
+        let (output, _) = archive.finish().unwrap();
+
+        let reader = NewcReader::new(output.as_slice()).unwrap();
+        assert_eq!((reader.entry().uid(), reader.entry().gid()), (0, 0));
+        let reader = NewcReader::new(reader.finish().unwrap()).unwrap();
+        assert_eq!((reader.entry().uid(), reader.entry().gid()), (0, 0));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let entries = [("./a", &b"Hello"[..]), ("./b", b"World!"), ("./c", b"!")];
+        let append = |archive: &mut ArchiveWriter<Vec<u8>>, entries: &[(&str, &[u8])]| {
+            for &(name, data) in entries {
+                archive.append_bytes(Builder::new(name), data).unwrap();
+            }
+        };
+        let mut archive = ArchiveWriter::new(vec![]).track_offsets(true);
+        append(&mut archive, &entries);
+        let expected = archive.finish().unwrap();
+
+        let mut archive = ArchiveWriter::new(vec![]).track_offsets(true);
+        append(&mut archive, &entries[..2]);
+        let mut saved = vec![];
+        archive.checkpoint().unwrap().write_to(&mut saved).unwrap();
+        // The crash interrupts the next entry partway through.
+        append(&mut archive, &entries[2..]);
+        let mut output = archive.get_ref().clone();
+
+        let checkpoint = Checkpoint::read_from(saved.as_slice()).unwrap();
+        assert_eq!(checkpoint.entries(), 2);
+        output.truncate(checkpoint.position() as usize);
+        let mut archive = ArchiveWriter::resume(output, &checkpoint);
+        append(&mut archive, &entries[checkpoint.entries() as usize..]);
+        assert_eq!(archive.finish().unwrap(), expected);
+
+        let err = Checkpoint::read_from(&b"entries 2\n"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Held-back links can't be resumed, so they can't be checkpointed either.
+        let mut archive = ArchiveWriter::new(vec![]).hard_link_data(HardLinkData::Last);
+        let link = |name| Builder::new(name).ino(7).mode(0o100644).nlink(2);
+        archive.append_bytes(link("./a"), b"Hello").unwrap();
+        let err = archive.checkpoint().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        archive.append_bytes(link("./b"), b"").unwrap();
+        assert_eq!(archive.checkpoint().unwrap().entries(), 2);
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        use std::sync::{Arc, Mutex};
+
+        let updates = Arc::new(Mutex::new(vec![]));
+        let recorded = updates.clone();
+        let mut archive = ArchiveWriter::new(vec![])
+            .chunk_size(4)
+            .progress(move |progress: &Progress| recorded.lock().unwrap().push(progress.bytes()));
+        archive
+            .append(Builder::new("./a"), 10, &b"0123456789"[..])
+            .unwrap();
+        let err = archive
+            .append(Builder::new("./short"), 10, &b"abc"[..])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(updates.lock().unwrap()[..4], [4, 8, 10, 10]);
+    }
+
+    /// Fails every write after the first `limit` bytes.
+    struct FailingSink {
+        limit: usize,
+        written: usize,
+    }
+
+    impl Write for FailingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written + buf.len() > self.limit {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "upload failed"));
+            }
+            self.written += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_multi_writer() {
+        let (expected, _) =
+            crate::write_cpio_bytes(vec![(Builder::new("./a"), &b"Hello"[..])], vec![]).unwrap();
+
+        let (mut first, mut second) = (vec![], vec![]);
+        let sink = MultiWriter::new().sink(&mut first).sink(&mut second);
+        let (sink, _) =
+            crate::write_cpio_bytes(vec![(Builder::new("./a"), &b"Hello"[..])], sink).unwrap();
+        assert!(sink.failures().is_empty());
+        drop(sink);
+        assert_eq!(first, expected);
+        assert_eq!(second, expected);
+
+        let failing = FailingSink {
+            limit: 0,
+            written: 0,
+        };
+        let mut sink = MultiWriter::new().sink(vec![]).sink(failing);
+        let err = sink.write_all(b"data").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(
+            err.to_string(),
+            "1 of 2 sinks failed; sink 1: upload failed"
+        );
+        assert!(sink.write_all(b"more").is_err());
+        assert_eq!(sink.failures()[0].0, 1);
+    }
+
+    #[test]
+    fn test_multi_writer_tolerate_failures() {
+        let mut output = vec![];
+        let failing = FailingSink {
+            limit: 200,
+            written: 0,
+        };
+        let sink = MultiWriter::new()
+            .tolerate_failures(true)
+            .sink(failing)
+            .sink(&mut output);
+        let data = vec![0u8; 1000];
+        let (sink, _) = crate::write_cpio_bytes(vec![(Builder::new("./a"), &data)], sink).unwrap();
+        assert_eq!(sink.failures().len(), 1);
+        drop(sink);
+        let reader = NewcReader::new(output.as_slice()).unwrap();
+        assert_eq!(reader.entry().file_size(), 1000);
+
+        let failing = || FailingSink {
+            limit: 0,
+            written: 0,
+        };
+        let mut sink = MultiWriter::new()
+            .tolerate_failures(true)
+            .sink(failing())
+            .sink(failing());
+        assert!(sink.write_all(b"data").is_err());
+        assert_eq!(sink.failures().len(), 2);
+    }
+}